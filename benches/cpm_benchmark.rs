@@ -1,4 +1,4 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
 
 use ndarray::{Array1};
 use ndarray_linalg::{Cholesky, UPLO};
@@ -7,6 +7,8 @@ use rand_pcg::Pcg64;
 use rand_distr::{Distribution, StandardNormal};
 
 use credit_portfolio_model::borrower::Borrower;
+use credit_portfolio_model::migration;
+use statrs::distribution::{ContinuousCDF, Normal};
 
 pub fn benchmark(c: &mut Criterion) {
 
@@ -25,7 +27,7 @@ pub fn benchmark(c: &mut Criterion) {
     let eps = 0.85;
     let p_mig = vec![1. / n as f64; n];
 
-    let mut borr = Borrower::new(w, r, rho, eps, p_mig);
+    let mut borr = Borrower::new("B0".to_string(), w, r, rho, eps, p_mig);
     borr.set_norm(&cov);
 
     let rf = Array1::from_elem(n, 1.);
@@ -49,5 +51,24 @@ pub fn benchmark(c: &mut Criterion) {
     c.bench_function("borrower::get_loss", |b| b.iter(|| borr.get_loss(black_box(&(n - 1)))));
 }
 
-criterion_group!(benches, benchmark);
+/// Linear scan compared to binary search of the migration thresholds by size of the rating scale
+pub fn benchmark_migration(c: &mut Criterion) {
+    let mut rng = Pcg64::seed_from_u64(0);
+    let normal = Normal::new(0.0, 1.0).unwrap();
+    let z: Vec<f64> = StandardNormal.sample_iter(&mut rng).take(1_000).collect();
+
+    let mut group = c.benchmark_group("migration::rating_index");
+    for num_ratings in [8, 20, 50, 200] {
+        let thresholds: Vec<f64> = (1..num_ratings).map(|r| normal.inverse_cdf(r as f64 / num_ratings as f64)).collect();
+        group.bench_with_input(BenchmarkId::new("linear_scan", num_ratings), &thresholds, |b, t| {
+            b.iter(|| z.iter().map(|&z| migration::linear_scan(black_box(t), z)).sum::<usize>())
+        });
+        group.bench_with_input(BenchmarkId::new("binary_search", num_ratings), &thresholds, |b, t| {
+            b.iter(|| z.iter().map(|&z| migration::binary_search(black_box(t), z)).sum::<usize>())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, benchmark, benchmark_migration);
 criterion_main!(benches);
@@ -0,0 +1,26 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use credit_portfolio_model::generate::Generator;
+use credit_portfolio_model::input::Input;
+
+/// Reading the input files of synthetic portfolios by size, the files are read concurrently and
+/// joined afterwards
+pub fn benchmark_read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("input::read");
+    group.sample_size(10);
+
+    for (borrowers, risk_factors) in [(10_000, 25), (100_000, 50)] {
+        let dir = std::env::temp_dir().join(format!("cpm_bench_input_{}_{}", borrowers, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Generator { borrowers, risk_factors, ..Default::default() }.input().write(&dir);
+
+        group.throughput(Throughput::Elements(borrowers as u64));
+        group.bench_with_input(BenchmarkId::new("read", borrowers), &dir, |b, dir| b.iter(|| Input::read(black_box(dir))));
+        group.bench_with_input(BenchmarkId::new("into_portfolio", borrowers), &dir, |b, dir| b.iter(|| Input::read(black_box(dir)).into_portfolio()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, benchmark_read);
+criterion_main!(benches);
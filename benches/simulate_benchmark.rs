@@ -0,0 +1,156 @@
+use criterion::measurement::WallTime;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkGroup, BenchmarkId, Criterion, Throughput};
+
+use credit_portfolio_model::generate::Generator;
+use credit_portfolio_model::migration::MigrationTracking;
+use credit_portfolio_model::portfolio::Portfolio;
+use credit_portfolio_model::rng::{Philox4x32, SimulationRng};
+use rand_chacha::ChaCha8Rng;
+use rand_pcg::Pcg64;
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+/// Synthetic portfolio of given size
+fn portfolio(borrowers: usize, risk_factors: usize) -> Portfolio {
+    Generator { borrowers, risk_factors, ..Default::default() }.input().into_portfolio()
+}
+
+/// Scaling with the number of borrowers at a fixed number of borrower evaluations
+pub fn benchmark_borrowers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("simulate::borrowers");
+    group.sample_size(10);
+
+    for (borrowers, risk_factors) in [(1_000, 10), (10_000, 25), (100_000, 50)] {
+        let pf = portfolio(borrowers, risk_factors);
+        let num_trials = 10_000_000 / borrowers;
+
+        group.throughput(Throughput::Elements((num_trials * borrowers) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(borrowers), &num_trials, |b, &num_trials| {
+            b.iter(|| pf.simulate::<Pcg64>(black_box(num_trials), 100, 0, MigrationTracking::None, &[]))
+        });
+    }
+    group.finish();
+}
+
+/// Borrowers sharing their risk factor weights in few classes compared to individual weights, the
+/// systematic factor is computed once per distinct weight vector
+pub fn benchmark_weight_classes(c: &mut Criterion) {
+    let num_trials = 100;
+
+    let mut group = c.benchmark_group("simulate::weight_classes");
+    group.sample_size(10);
+
+    for weight_classes in [50, 0] {
+        let pf = Generator { borrowers: 100_000, risk_factors: 50, weight_classes, ..Default::default() }.input().into_portfolio();
+        let name = if weight_classes > 0 { format!("{}_classes", weight_classes) } else { "individual".to_string() };
+
+        group.throughput(Throughput::Elements((num_trials * pf.num_borrower()) as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| pf.simulate::<Pcg64>(black_box(num_trials), 10, 0, MigrationTracking::None, &[]))
+        });
+    }
+    group.finish();
+}
+
+/// Large portfolio, the per-trial loop walks the borrower data of the whole portfolio once
+pub fn benchmark_large(c: &mut Criterion) {
+    let pf = portfolio(50_000, 25);
+    let num_trials = 200;
+
+    let mut group = c.benchmark_group("simulate::large");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements((num_trials * pf.num_borrower()) as u64));
+    group.bench_function("50000", |b| {
+        b.iter(|| pf.simulate::<Pcg64>(black_box(num_trials), 10, 0, MigrationTracking::None, &[]))
+    });
+    group.finish();
+}
+
+/// Scaling with the number of trials per thread
+pub fn benchmark_chunk_size(c: &mut Criterion) {
+    let pf = portfolio(10_000, 25);
+    let num_trials = 2_000;
+
+    let mut group = c.benchmark_group("simulate::chunk_size");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements((num_trials * pf.num_borrower()) as u64));
+
+    for chunk_size in [10, 100, 1_000] {
+        group.bench_with_input(BenchmarkId::from_parameter(chunk_size), &chunk_size, |b, &chunk_size| {
+            b.iter(|| pf.simulate::<Pcg64>(black_box(num_trials), chunk_size, 0, MigrationTracking::None, &[]))
+        });
+    }
+    group.finish();
+}
+
+/// Scaling with the number of threads
+pub fn benchmark_threads(c: &mut Criterion) {
+    let pf = portfolio(10_000, 25);
+    let num_trials = 2_000;
+
+    let mut group = c.benchmark_group("simulate::threads");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements((num_trials * pf.num_borrower()) as u64));
+
+    let max_threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let mut threads: Vec<usize> = [1, 2, 4, 8].into_iter().filter(|&n| n < max_threads).collect();
+    threads.push(max_threads);
+
+    for num_threads in threads {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(num_threads).build().unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(num_threads), &num_threads, |b, _| {
+            b.iter(|| pool.install(|| pf.simulate::<Pcg64>(black_box(num_trials), 50, 0, MigrationTracking::None, &[])))
+        });
+    }
+    group.finish();
+}
+
+/// Many risk factors of which the borrowers load onto a few only, with all factors simulated and
+/// with the unused ones dropped
+pub fn benchmark_sparse_loading(c: &mut Criterion) {
+    let num_trials = 2_000;
+    let sparse = || {
+        let mut input = Generator { borrowers: 2_000, risk_factors: 500, ..Default::default() }.input();
+        input.risk_factors.values_mut().for_each(|weights| weights[10..].fill(0.));
+        input.into_portfolio()
+    };
+
+    let mut group = c.benchmark_group("simulate::sparse_loading");
+    group.sample_size(10);
+
+    let pf = sparse();
+    group.throughput(Throughput::Elements((num_trials * pf.num_borrower()) as u64));
+    group.bench_function("all_factors", |b| {
+        b.iter(|| pf.simulate::<Pcg64>(black_box(num_trials), 100, 0, MigrationTracking::None, &[]))
+    });
+
+    let mut pf = sparse();
+    pf.drop_unused_factors();
+    group.bench_function("used_factors", |b| {
+        b.iter(|| pf.simulate::<Pcg64>(black_box(num_trials), 100, 0, MigrationTracking::None, &[]))
+    });
+    group.finish();
+}
+
+/// Full simulation with one of the random number generators
+fn bench_rng<R: SimulationRng>(group: &mut BenchmarkGroup<'_, WallTime>, name: &str, pf: &Portfolio, num_trials: usize) {
+    group.bench_function(name, |b| b.iter(|| pf.simulate::<R>(black_box(num_trials), 100, 0, MigrationTracking::None, &[])));
+}
+
+/// Comparison of the random number generators
+pub fn benchmark_rng(c: &mut Criterion) {
+    let pf = portfolio(10_000, 25);
+    let num_trials = 2_000;
+
+    let mut group = c.benchmark_group("simulate::rng");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements((num_trials * pf.num_borrower()) as u64));
+
+    bench_rng::<Pcg64>(&mut group, "pcg64", &pf, num_trials);
+    bench_rng::<ChaCha8Rng>(&mut group, "chacha8", &pf, num_trials);
+    bench_rng::<Philox4x32>(&mut group, "philox", &pf, num_trials);
+    bench_rng::<Xoshiro256PlusPlus>(&mut group, "xoshiro", &pf, num_trials);
+    group.finish();
+}
+
+criterion_group!(benches, benchmark_borrowers, benchmark_chunk_size, benchmark_threads, benchmark_rng, benchmark_weight_classes, benchmark_large, benchmark_sparse_loading);
+criterion_main!(benches);
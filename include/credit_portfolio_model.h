@@ -0,0 +1,60 @@
+/*
+ * C ABI of credit_portfolio_model, built with the `ffi` feature, see the README:
+ *
+ *     cargo rustc --release --lib --features ffi --crate-type cdylib
+ *
+ * builds the shared library target/release/libcredit_portfolio_model.so to link against.
+ * Matrices are row-major. Every function but the free functions returns one of the error codes,
+ * the message of a failed call is available from cpm_last_error.
+ */
+#ifndef CREDIT_PORTFOLIO_MODEL_H
+#define CREDIT_PORTFOLIO_MODEL_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The call succeeded */
+#define CPM_OK 0
+/* A pointer is null or a size is zero */
+#define CPM_INVALID_ARGUMENT 1
+/* The model rejected the input, e.g. a covariance matrix that is not positive semi-definite */
+#define CPM_PANIC 2
+
+typedef struct CpmPortfolio CpmPortfolio;
+
+/*
+ * Build a portfolio of num_borrowers borrowers with num_ratings rating classes each. Per borrower:
+ * risk_groups, ratings, rho and eps (length num_borrowers), risk_factor_weights
+ * (num_borrowers x num_risk_factors), migration_probabilities and absolute valuations
+ * (num_borrowers x num_ratings). covariance is num_risk_factors x num_risk_factors.
+ * Release the portfolio with cpm_portfolio_free.
+ */
+int32_t cpm_portfolio_new(size_t num_risk_factors, const double *covariance, size_t num_borrowers,
+                          size_t num_ratings, const size_t *risk_groups, const size_t *ratings,
+                          const double *rho, const double *eps, const double *risk_factor_weights,
+                          const double *migration_probabilities, const double *valuations,
+                          CpmPortfolio **out);
+
+/*
+ * Simulate num_trials trials in chunks of chunk_size trials with the seed, the portfolio loss of
+ * each trial is stored in losses with its length in len. Release the losses with cpm_losses_free.
+ */
+int32_t cpm_simulate(const CpmPortfolio *portfolio, size_t num_trials, size_t chunk_size,
+                     uint64_t seed, double **losses, size_t *len);
+
+void cpm_losses_free(double *losses, size_t len);
+
+void cpm_portfolio_free(CpmPortfolio *portfolio);
+
+/* Message of the last failed call of the thread, valid until its next failing call */
+const char *cpm_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
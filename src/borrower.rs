@@ -1,9 +1,23 @@
 use statrs::distribution::{Normal, ContinuousCDF};
 use ndarray::{Array1, Array2};
-use crate::exposure;
+use crate::{exposure, migration};
+
+/// Shares of the unit variance of a borrower's asset value by the source of randomness
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VarianceDecomposition {
+    /// Systematic risk factors, `rho`
+    pub systematic: f64,
+    /// Risk group shared by its borrowers, `(1-rho)*eps`
+    pub group: f64,
+    /// Name-specific randomness, `(1-rho)*(1-eps)`
+    pub idiosyncratic: f64,
+}
 
 /// Represents a borrower that is the atomic unit for rating migrations
+#[derive(Clone)]
 pub struct Borrower {
+    /// Identifier of the borrower
+    id: String,
     /// Dependency of the borrower to external weights, must match used covariance matrix
     risk_factor_weights: Array1<f64>,
     /// Current rating class as index
@@ -12,62 +26,155 @@ pub struct Borrower {
     rho: f64,
     /// Dependency on the risk group
     eps: f64,
-    /// Migration probabilities into all possible classes
+    /// Migration probabilities into all possible classes, those of the input unless the current
+    /// rating is absorbing
     p_mig: Array1<f64>,
+    /// Migration probabilities of the input, possibly with an overridden probability of default
+    p_input: Array1<f64>,
+    /// Whether the current rating is absorbing, e.g. default, so the borrower stays in it
+    absorbing: bool,
     /// Migration probabilities transformed into a correpsonding threshold of a standard normal random
     /// variable
     c_mig: Box<[f64]>,
+    /// Thresholds calibrated directly, e.g. from a structural model, replacing those derived from
+    /// the migration probabilities
+    c_input: Option<Box<[f64]>>,
     /// Container of all exposures of the borrower
     exposures: Vec<exposure::Exposure>,
-    /// Current valuation of all of the borrowers positions, based on exposures
-    value: f64,
     /// Current valuations for all rating classes, based on exposures
     valuations: Array1<f64>,
-    /// Losses derived from valuations and current valuation. Positive values indicate losses
+    /// Current value of all exposures, each at its facility rating if it has one and else at the
+    /// current rating of the borrower
+    current_value: f64,
+    /// Losses derived from valuations and current valuation, set by `finalize`. Positive values
+    /// indicate losses
     losses: Array1<f64>,
     /// Given a covariance matrix, this is the corresponding norm `N=\sqrt{\phi^T\dot\Sigma\dot\phi}`
     /// to result in a standard normal distributed random variable `z=\frac{\phi\dot\y}{N}`.
     norm: f64,
-    /// Analytical calculation of expected loss given valuations and migration probabilities
-    el: f64,
+    /// Index of the systematic factor slot of the portfolio shared by all borrowers with the same
+    /// risk factor weights
+    factor_slot: usize,
+    /// Whether the borrower is the parent of its risk group, whose default drags the other members
+    parent: bool,
+    /// Whether the rating classes are those of a rating scale of the borrower's own instead of the
+    /// common one
+    own_rating_scale: bool,
+    /// Ordering of the rating classes along the asset value
+    z_convention: migration::ZConvention,
 }
 
 impl Borrower {
-    /// Create new borrower given risk factor weights, current rating with migration probabilities
-    /// and the dependency on the factor model. Thresholds of migrations will be calculated and
-    /// empty containers created for exposures, valuations, losses, etc.
-    pub fn new(risk_factor_weights: Vec<f64>, rating: usize, rho: f64, eps: f64, p_mig: Vec<f64>) -> Self {
-        // Get migration thresholds
-        // First, get cumulative probabilities
-        let cum_p: Vec<f64> = p_mig.iter()
-           .scan(0.0_f64, |sum, &x| {
-               *sum += x;
-               Some(*sum)
-           }).collect();
-
-        // Convert to normal distribution for all but last value
-        let normal = Normal::new(0.0, 1.0).unwrap();
-        let c_mig: Vec<f64> = cum_p.iter().take(cum_p.len() - 1)
-           .map(|&p| normal.inverse_cdf(p))
-           .collect();
-
-        Self {
+    /// Create new borrower given its identifier, risk factor weights, current rating with migration
+    /// probabilities and the dependency on the factor model. Thresholds of migrations will be
+    /// calculated and empty containers created for exposures, valuations, losses, etc. A borrower
+    /// with a single rating class, e.g. fully provisioned, always stays in it, and so does a
+    /// borrower already in default, which is absorbing (see `set_absorbing_rating`)
+    pub fn new(id: String, risk_factor_weights: Vec<f64>, rating: usize, rho: f64, eps: f64, p_mig: Vec<f64>) -> Self {
+        assert!(!p_mig.is_empty(), "Borrower {} needs at least one rating class", id);
+        assert!(rating < p_mig.len(), "Borrower {} has current rating {} without migration probability", id, rating);
+
+        let mut borrower = Self {
+            id,
             risk_factor_weights: Array1::from(risk_factor_weights),
-            rating: rating,
-            rho: rho,
-            eps: eps,
+            rating,
+            rho,
+            eps,
             p_mig: Array1::from(p_mig.clone()),
-            c_mig: c_mig.into_boxed_slice(),
+            p_input: Array1::from(p_mig.clone()),
+            absorbing: false,
+            c_mig: Box::new([]),
+            c_input: None,
             exposures: Vec::new(),
-            value: 0.0_f64,
             valuations: Array1::zeros(p_mig.len()),
+            current_value: 0.,
             losses: Array1::zeros(p_mig.len()),
             norm: f64::NAN,
-            el: 0.0_f64,
+            factor_slot: 0,
+            parent: false,
+            own_rating_scale: false,
+            z_convention: migration::ZConvention::LowIsBest,
+        };
+        borrower.set_absorbing_rating(None);
+        borrower
+    }
+
+    /// Make the rating class with the index absorbing, by default the default class: a borrower
+    /// currently in it stays there with certainty instead of migrating with its input
+    /// probabilities, its trials skip the migration and it keeps the loss of its current rating
+    pub fn set_absorbing_rating(&mut self, rating: Option<usize>) {
+        let last = self.p_input.len() - 1;
+        self.absorbing = last > 0 && rating.unwrap_or(last) == self.rating;
+        self.update_probabilities();
+    }
+
+    /// Whether the current rating is absorbing
+    pub fn is_absorbing(&self) -> bool {
+        self.absorbing
+    }
+
+    /// Derive the migration probabilities from the input, certain to stay in an absorbing
+    /// rating, and the thresholds from them unless given
+    fn update_probabilities(&mut self) {
+        self.p_mig = if self.absorbing {
+            Array1::from_shape_fn(self.p_input.len(), |rating| if rating == self.rating { 1. } else { 0. })
+        } else {
+            self.p_input.clone()
+        };
+        self.c_mig = match self.c_input.as_ref() {
+            Some(c_input) if !self.absorbing => c_input.clone(),
+            _ => thresholds(self.p_mig.as_slice().unwrap()),
+        };
+    }
+
+    /// Set the thresholds of the asset value between the rating classes directly instead of
+    /// deriving them from the migration probabilities, which are replaced by the probabilities the
+    /// thresholds imply. The thresholds must be strictly increasing, one between each pair of
+    /// adjacent rating classes
+    pub fn set_thresholds(&mut self, c_mig: Vec<f64>) {
+        assert_eq!(c_mig.len(), self.p_input.len() - 1, "Borrower {} has {} thresholds, but {} rating classes", self.id, c_mig.len(), self.p_input.len());
+        assert!(c_mig.iter().all(|c| !c.is_nan()), "Borrower {} has a NaN threshold", self.id);
+        if let Some(rating) = c_mig.windows(2).position(|pair| pair[0] >= pair[1]) {
+            panic!("Borrower {} has thresholds that are not strictly increasing, the one of rating {} is not above the one of rating {}", self.id, rating + 1, rating);
+        }
+
+        self.p_input = Array1::from(probabilities(&c_mig));
+        self.c_input = Some(c_mig.into_boxed_slice());
+        self.update_probabilities();
+    }
+
+    /// Replace the probability of default, i.e. of the last rating class, e.g. by the PD of a
+    /// separate model. The shape of the migrations is kept: the other probabilities are scaled to
+    /// sum to the remaining probability, and the thresholds are recomputed, also if they were set
+    /// directly. A borrower in an absorbing rating keeps staying in it
+    pub fn override_default_probability(&mut self, pd: f64) {
+        assert!((0. ..=1.).contains(&pd), "Borrower {} has probability of default {} outside of [0, 1]", self.id, pd);
+        assert!(self.p_input.len() > 1, "Borrower {} has a single rating class without default", self.id);
+
+        let last = self.p_input.len() - 1;
+        let rest: f64 = self.p_input.iter().take(last).sum();
+        assert!(rest > 0. || pd == 1., "Borrower {} has no probability outside of default to scale to {}", self.id, 1. - pd);
+
+        let scale = if rest > 0. { (1. - pd) / rest } else { 0. };
+        self.p_input.iter_mut().take(last).for_each(|p| *p *= scale);
+        self.p_input[last] = pd;
+        self.c_input = None;
+        self.update_probabilities();
+    }
+
+    /// Scale the probability of default by a stress multiplier, at most to one, and rescale the
+    /// other probabilities like `override_default_probability`. A borrower with a single rating
+    /// class has no default and one in an absorbing rating does not migrate, both are left
+    /// unchanged
+    pub fn stress_default_probability(&mut self, multiplier: f64) {
+        assert!(multiplier >= 0., "Borrower {} has PD stress multiplier {}, which must not be negative", self.id, multiplier);
+        if self.p_mig.len() > 1 && !self.absorbing {
+            self.override_default_probability((self.default_probability() * multiplier).min(1.));
         }
     }
 
-    /// Add an exposure to the borrower, the valuations and losses of the borrower will be updated
+    /// Add an exposure to the borrower, its valuations are accumulated. The losses of the borrower
+    /// are only updated by `finalize` after all exposures have been added
     pub fn add_exposure(&mut self, exposure: exposure::Exposure) {
         // Check if number of valuations of exposure is same as borrower expects
         if self.valuations.len() != exposure.num_values() {
@@ -78,17 +185,37 @@ impl Borrower {
         for (index, value) in self.valuations.iter_mut().enumerate() {
             *value += exposure.get_value(&index);
         }
+        self.current_value += exposure.current_value(self.rating);
 
         self.exposures.push(exposure);
+    }
 
-        // Update current valuation
-        self.value = self.valuations[self.rating];
+    /// Derive the losses of all rating classes from the accumulated valuations, needed before
+    /// the borrower is simulated
+    pub fn finalize(&mut self) {
+        let value = self.value();
+        self.losses.iter_mut().zip(self.valuations.iter()).for_each(|(a, &b)| *a = value - b);
+    }
 
-        // Update losses
-        self.losses.iter_mut().zip(self.valuations.iter()).for_each(|(a, &b)| *a = self.value - b);
+    /// Key of the parameters of the factor model and the migrations, i.e. everything but the
+    /// identifier and the exposures. Borrowers with equal keys migrate identically given the same
+    /// random variables
+    pub fn structure_key(&self) -> Vec<u64> {
+        [self.rating as u64, self.rho.to_bits(), self.eps.to_bits(), self.parent as u64, self.own_rating_scale as u64].into_iter()
+            .chain(self.risk_factor_weights.iter().map(|w| w.to_bits()))
+            .chain(self.p_mig.iter().map(|p| p.to_bits()))
+            .chain(self.c_mig.iter().map(|c| c.to_bits()))
+            .collect()
+    }
 
-        // Update expected loss
-        self.el = self.expected_loss();
+    /// Take over all exposures of a structurally identical borrower (see `structure_key`), so that
+    /// both are simulated as one with a single idiosyncratic draw
+    pub fn merge(&mut self, other: Borrower) {
+        assert_eq!(self.structure_key(), other.structure_key(), "Only structurally identical borrowers can be merged");
+        self.valuations += &other.valuations;
+        self.current_value += other.current_value;
+        self.exposures.extend(other.exposures);
+        self.finalize();
     }
 
     /// Set the relevant norm value given a covariance matrix to result in a standard normal
@@ -97,6 +224,94 @@ impl Borrower {
         self.norm = self.risk_factor_weights.dot(&cov.dot(&self.risk_factor_weights)).sqrt();
     }
 
+    /// Dependency on the systematic factor
+    pub fn rho(&self) -> f64 {
+        self.rho
+    }
+
+    /// Dependency on the risk group
+    pub fn eps(&self) -> f64 {
+        self.eps
+    }
+
+    /// Decomposition of the unit variance of the asset value into its systematic, risk group and
+    /// name-specific share
+    pub fn variance_decomposition(&self) -> VarianceDecomposition {
+        VarianceDecomposition {
+            systematic: self.rho,
+            group: (1. - self.rho) * self.eps,
+            idiosyncratic: (1. - self.rho) * (1. - self.eps),
+        }
+    }
+
+    /// Designate the borrower as parent of its risk group: with contagion, its default downgrades
+    /// the other members of the group
+    pub fn set_parent(&mut self) {
+        self.parent = true;
+    }
+
+    /// Whether the borrower is the parent of its risk group
+    pub fn is_parent(&self) -> bool {
+        self.parent
+    }
+
+    /// Declare the rating classes of the borrower as those of a rating scale of its own, e.g. of a
+    /// different asset class. Outputs by rating class pool only its default with the common scale
+    pub fn set_own_rating_scale(&mut self) {
+        self.own_rating_scale = true;
+    }
+
+    /// Whether the borrower is rated on a rating scale of its own
+    pub fn has_own_rating_scale(&self) -> bool {
+        self.own_rating_scale
+    }
+
+    /// Thresholds of the asset value between the rating classes
+    pub fn thresholds(&self) -> &[f64] {
+        &self.c_mig
+    }
+
+    /// Valuations of all exposures of the borrower by rating class
+    pub fn valuations(&self) -> &Array1<f64> {
+        &self.valuations
+    }
+
+    /// Losses of all rating classes
+    pub fn losses(&self) -> &Array1<f64> {
+        &self.losses
+    }
+
+    /// Weights of the risk factors
+    pub fn risk_factor_weights(&self) -> &Array1<f64> {
+        &self.risk_factor_weights
+    }
+
+    /// Norm of the risk factor weights given the covariance matrix
+    pub fn norm(&self) -> f64 {
+        self.norm
+    }
+
+    /// Index of the systematic factor slot of the portfolio
+    pub fn factor_slot(&self) -> usize {
+        self.factor_slot
+    }
+
+    /// Set the index of the systematic factor slot of the portfolio
+    pub fn set_factor_slot(&mut self, slot: usize) {
+        self.factor_slot = slot;
+    }
+
+    /// Set the ordering of the rating classes along the asset value, by default low asset values
+    /// migrate to the first rating class
+    pub fn set_z_convention(&mut self, z_convention: migration::ZConvention) {
+        self.z_convention = z_convention;
+    }
+
+    /// Ordering of the rating classes along the asset value
+    pub fn z_convention(&self) -> migration::ZConvention {
+        self.z_convention
+    }
+
     /// Given external risk factors, calculate the resulting standard normal variable of
     /// external/systematic factor
     pub fn risk_factor(&self, risk_factors: &Array1<f64>) -> f64 {
@@ -109,9 +324,42 @@ impl Borrower {
         self.rho.sqrt() * y + (1. - self.rho).sqrt() * ((1. - self.eps).sqrt() * e1 + self.eps.sqrt() * e2)
     }
 
-    /// Get the resulting rating grade given the result of the factor model
+    /// Expected loss given the systematic and risk group random variables, with the idiosyncratic
+    /// one integrated out: the asset value is normal with mean `sqrt(rho)*y+sqrt((1-rho)*eps)*e2`
+    /// and variance `(1-rho)*(1-eps)`, so each rating class has the probability
+    /// `Φ((c_k-mean)/std)-Φ((c_{k-1}-mean)/std)`. Without idiosyncratic variance the rating is
+    /// certain
+    pub fn conditional_expected_loss(&self, y: f64, e2: f64) -> f64 {
+        let mean = self.rho.sqrt() * y + ((1. - self.rho) * self.eps).sqrt() * e2;
+        let std = ((1. - self.rho) * (1. - self.eps)).sqrt();
+        if std == 0. {
+            return self.losses[self.migration(&mean)];
+        }
+        let mean = self.z_convention.sign() * mean;
+
+        let normal = Normal::new(0., 1.).unwrap();
+        let mut below = 0.;
+        let mut loss = 0.;
+        for (threshold, class_loss) in self.c_mig.iter().chain(std::iter::once(&f64::INFINITY)).zip(self.losses.iter()) {
+            let cumulative = normal.cdf((threshold - mean) / std);
+            loss += (cumulative - below) * class_loss;
+            below = cumulative;
+        }
+        loss
+    }
+
+    /// Get the resulting rating grade given the result of the factor model, in the ordering of the
+    /// `ZConvention`. An asset value exactly on a threshold takes the lower rating index, see
+    /// `migration::rating_index`
     pub fn migration(&self, z: &f64) -> usize {
-        self.c_mig.binary_search_by(|a| a.partial_cmp(&z).expect("Only finite values should appear")).unwrap_or_else(|i| i)
+        // Single rating class without thresholds, or an absorbing one
+        if self.absorbing {
+            return self.rating;
+        }
+        if self.c_mig.is_empty() {
+            return 0;
+        }
+        migration::rating_index(&self.c_mig, self.z_convention.sign() * z)
     }
 
     /// Get the loss for a specified rating class (given by its index)
@@ -119,8 +367,115 @@ impl Borrower {
         self.losses.get(*index).expect("Value not in range")
     }
 
+    /// Get identifier of the borrower
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Current rating class as index
+    pub fn rating(&self) -> usize {
+        self.rating
+    }
+
+    /// Current valuation of all exposures of the borrower, the loss base of its migrations.
+    /// Exposures with a facility rating contribute their value at that rating
+    pub fn value(&self) -> f64 {
+        self.current_value
+    }
+
+    /// Guarantor and the loss of the covered fraction of each guaranteed exposure by rating class,
+    /// i.e. the loss if the covered fraction were valued at that rating
+    pub fn guaranteed_losses(&self) -> Vec<(&str, Array1<f64>)> {
+        self.exposures.iter()
+            .filter_map(|exp| exp.guarantee().map(|(guarantor, coverage)| {
+                let current = exp.current_value(self.rating);
+                (guarantor, (0..exp.num_values()).map(|r| coverage * (current - exp.get_value(&r))).collect())
+            }))
+            .collect()
+    }
+
+    /// Outstanding amount of all exposures of the borrower
+    pub fn outstanding(&self) -> f64 {
+        self.exposures.iter().map(|exp| exp.outstanding()).sum()
+    }
+
+    /// Expected rating index after one period given the migration probabilities
+    pub fn expected_rating(&self) -> f64 {
+        self.p_mig.iter().enumerate().map(|(index, p)| index as f64 * p).sum()
+    }
+
+    /// Number of exposures of the borrower
+    pub fn num_exposures(&self) -> usize {
+        self.exposures.len()
+    }
+
+    /// Migration probabilities into all rating classes
+    pub fn migration_probabilities(&self) -> &Array1<f64> {
+        &self.p_mig
+    }
+
+    /// Number of risk factors the borrower depends on
+    pub fn num_risk_factors(&self) -> usize {
+        self.risk_factor_weights.len()
+    }
+
+    /// Probability of default, i.e. of the last rating class, zero with a single rating class
+    pub fn default_probability(&self) -> f64 {
+        if self.p_mig.len() > 1 { self.p_mig[self.p_mig.len() - 1] } else { 0. }
+    }
+
+    /// Number of rating classes the borrower can migrate into
+    pub fn num_ratings(&self) -> usize {
+        self.p_mig.len()
+    }
+
+    /// Whether the resulting rating class is a default. The last rating class is the default,
+    /// unless it is the only one: such a borrower always stays current and never defaults anew
+    pub fn is_default(&self, rating: usize) -> bool {
+        self.p_mig.len() > 1 && rating + 1 == self.p_mig.len()
+    }
+
     /// Calculate loss (analytically `EL=\sum_i{p_i\cdot l_i}`
     pub fn expected_loss(&self) -> f64 {
-        self.p_mig.iter().zip(self.losses.iter()).map(|(p, l)| p * l).sum()
+        let value = self.value();
+        self.p_mig.iter().zip(self.valuations.iter()).map(|(p, v)| p * (value - v)).sum()
+    }
+}
+
+/// Migration probabilities transformed into thresholds of a standard normal random variable, one
+/// between each pair of adjacent rating classes
+fn thresholds(p_mig: &[f64]) -> Box<[f64]> {
+    // First, get cumulative probabilities
+    let cum_p: Vec<f64> = p_mig.iter()
+       .scan(0.0_f64, |sum, &x| {
+           *sum += x;
+           Some(*sum)
+       }).collect();
+
+    // Rating classes from which on all probabilities are zero are never reached, even if the
+    // cumulative probability falls short of one by rounding
+    let reachable = p_mig.iter().rposition(|&p| p > 0.).unwrap_or(0);
+
+    // Convert to normal distribution for all but last value, rounding may also take the
+    // cumulative probability above one
+    let normal = Normal::new(0.0, 1.0).unwrap();
+    cum_p.iter().take(cum_p.len() - 1).enumerate()
+       .map(|(rating, &p)| if rating >= reachable { f64::INFINITY } else { normal.inverse_cdf(p.min(1.)) })
+       .collect()
+}
+
+/// Migration probabilities implied by the thresholds of a standard normal random variable, the
+/// inverse of `thresholds`
+fn probabilities(c_mig: &[f64]) -> Vec<f64> {
+    let normal = Normal::new(0.0, 1.0).unwrap();
+    let cdf: Vec<f64> = std::iter::once(0.)
+        .chain(c_mig.iter().map(|&c| normal.cdf(c)))
+        .chain(std::iter::once(1.))
+        .collect();
+    let mut p_mig: Vec<f64> = cdf.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    // The upper tail directly, it is the probability of default and may be tiny
+    if let (Some(last), Some(&c)) = (p_mig.last_mut(), c_mig.last()) {
+        *last = normal.sf(c);
     }
+    p_mig
 }
@@ -1,21 +1,181 @@
+use serde::{Deserialize, Serialize};
+
+/// Direction of an exposure to the credit of its borrower
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// The exposure loses value as the borrower deteriorates, e.g. a loan or bond
+    #[default]
+    Long,
+    /// The exposure gains value as the borrower deteriorates, e.g. a hedge such as bought
+    /// protection, its valuations count negatively
+    Short,
+}
+
+/// Interpretation of the valuations of an exposure
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValuationMode {
+    /// Valuations are absolute values of the exposure, the outstanding is informational only
+    #[default]
+    Absolute,
+    /// Valuations are prices per unit of outstanding
+    UnitPrice,
+    /// Valuations are loss fractions of the outstanding, i.e. the exposure at default
+    LossFraction,
+}
+
+/// Horizon in years of an exposure without its own, i.e. the one year of the simulated migration
+pub const DEFAULT_HORIZON: f64 = 1.;
+
+/// Discount factors expressing the valuations at the horizon of an exposure in present-value
+/// terms
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiscountCurve {
+    /// Flat annually compounded rate, the discount factor is `(1 + rate)^-horizon`
+    Flat(f64),
+    /// Discount factors by positive tenor in years in ascending order, interpolated log-linearly with a
+    /// discount factor of one at tenor zero and extrapolated at the zero rate of the last tenor
+    Curve(Vec<(f64, f64)>),
+}
+
+impl DiscountCurve {
+    /// Discount factor of the horizon in years
+    pub fn discount_factor(&self, horizon: f64) -> f64 {
+        match self {
+            Self::Flat(rate) => (1. + rate).powf(-horizon),
+            Self::Curve(points) => {
+                let upper = points.partition_point(|&(tenor, _)| tenor < horizon);
+                let (t0, d0) = if upper == 0 { (0., 1.) } else { points[upper - 1] };
+                let (t1, d1) = match points.get(upper) {
+                    Some(&point) => point,
+                    None => return d0.powf(horizon / t0),
+                };
+                (d0.ln() + (d1.ln() - d0.ln()) * (horizon - t0) / (t1 - t0)).exp()
+            }
+        }
+    }
+}
 
 /// Simple container of a single exposure and its valuations
+#[derive(Clone)]
 pub struct Exposure {
-    /// Valuations for each rating class
+    /// Valuations, prices or loss fractions for each rating class depending on the mode
     valuation: Box<[f64]>,
+    /// Current outstanding, i.e. the exposure at default
+    outstanding: f64,
+    /// Interpretation of the valuations
+    mode: ValuationMode,
+    /// Current rating of the facility if it differs from the borrower's, e.g. due to collateral
+    rating: Option<usize>,
+    /// Guarantor borrower and the covered fraction of the exposure
+    guarantee: Option<(String, f64)>,
+    /// Direction of the exposure, short exposures offset the long ones of the borrower
+    direction: Direction,
+    /// Discount factor of the horizon of the exposure, all valuations are multiplied with it
+    discount_factor: f64,
 }
 
 impl Exposure {
-    /// Create a new instance with its valuations
-    pub fn new(valuation: Vec<f64>) -> Self {
+    /// Create a new instance from prices per unit of outstanding for each rating class, the
+    /// valuations are the prices multiplied by the outstanding
+    pub fn new(valuation: Vec<f64>, outstanding: f64) -> Self {
+        Self::with_mode(valuation, outstanding, ValuationMode::UnitPrice)
+    }
+
+    /// Create a new instance with its absolute valuations for each rating class
+    pub fn from_values(valuation: Vec<f64>, outstanding: f64) -> Self {
+        Self::with_mode(valuation, outstanding, ValuationMode::Absolute)
+    }
+
+    /// Create a new instance from the exposure at default and the loss fraction of each rating
+    /// class, the valuations are the exposure at default less the loss
+    pub fn from_loss_fractions(loss_fraction: Vec<f64>, ead: f64) -> Self {
+        Self::with_mode(loss_fraction, ead, ValuationMode::LossFraction)
+    }
+
+    /// Create a new instance given the interpretation of the valuations
+    pub fn with_mode(valuation: Vec<f64>, outstanding: f64, mode: ValuationMode) -> Self {
         Self {
             valuation: valuation.into_boxed_slice(),
+            outstanding,
+            mode,
+            rating: None,
+            guarantee: None,
+            direction: Direction::Long,
+            discount_factor: 1.,
+        }
+    }
+
+    /// Set the current rating of the facility, which gives its current value instead of the
+    /// current rating of the borrower. Migrations still follow the borrower
+    pub fn set_rating(&mut self, rating: usize) {
+        assert!(rating < self.valuation.len(), "Facility rating {} exceeds the {} valuations of the exposure", rating, self.valuation.len());
+        self.rating = Some(rating);
+    }
+
+    /// Current rating of the facility if it has its own
+    pub fn rating(&self) -> Option<usize> {
+        self.rating
+    }
+
+    /// Let a guarantor borrower cover a fraction of the exposure: if the borrower defaults, the
+    /// covered fraction is valued at the simulated rating of the guarantor instead
+    pub fn set_guarantee(&mut self, guarantor: String, coverage: f64) {
+        assert!((0. ..=1.).contains(&coverage), "Coverage {} of the guarantee by {} outside of [0, 1]", coverage, guarantor);
+        self.guarantee = Some((guarantor, coverage));
+    }
+
+    /// Set the direction of the exposure, the valuations of a short exposure, e.g. a hedge, are
+    /// negated so that they net with the long exposures of the borrower
+    pub fn set_direction(&mut self, direction: Direction) {
+        self.direction = direction;
+    }
+
+    /// Direction of the exposure
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Set the discount factor of the horizon of the exposure, which expresses all its valuations
+    /// and therefore its losses in present-value terms
+    pub fn set_discount_factor(&mut self, discount_factor: f64) {
+        assert!(discount_factor.is_finite() && discount_factor > 0., "Discount factor {} is not positive", discount_factor);
+        self.discount_factor = discount_factor;
+    }
+
+    /// Discount factor of the valuations, one without discounting
+    pub fn discount_factor(&self) -> f64 {
+        self.discount_factor
+    }
+
+    /// Guarantor borrower and covered fraction if the exposure is guaranteed
+    pub fn guarantee(&self) -> Option<(&str, f64)> {
+        self.guarantee.as_ref().map(|(guarantor, coverage)| (guarantor.as_str(), *coverage))
+    }
+
+    /// Current value given the current rating of the borrower, unless the facility has its own
+    pub fn current_value(&self, borrower_rating: usize) -> f64 {
+        self.get_value(&self.rating.unwrap_or(borrower_rating))
+    }
+
+    /// Get the discounted valuation of a specified rating class by its index, negative for a short
+    /// exposure
+    pub fn get_value(&self, index: &usize) -> f64 {
+        let value = *self.valuation.get(*index).expect("Index out of range");
+        let value = match self.mode {
+            ValuationMode::Absolute => value,
+            ValuationMode::UnitPrice => value * self.outstanding,
+            ValuationMode::LossFraction => self.outstanding * (1. - value),
+        };
+        match self.direction {
+            Direction::Long => self.discount_factor * value,
+            Direction::Short => -self.discount_factor * value,
         }
     }
 
-    /// Get valuation of a specified rating class by its index
-    pub fn get_value(&self, index: &usize) -> &f64 {
-        self.valuation.get(*index).expect("Index out of range")
+    /// Current outstanding, gross of the direction
+    pub fn outstanding(&self) -> f64 {
+        self.outstanding
     }
 
     /// Number of valuations, should be equal to number of rating classes
@@ -0,0 +1,183 @@
+//! C ABI of the simulator for risk systems calling it in-process, declared in
+//! `include/credit_portfolio_model.h`. The functions wrap the safe types of the crate: a portfolio
+//! is built from flat arrays in row-major order, each borrower with a single exposure of absolute
+//! valuations, and simulated with the Pcg64 generator. Panics are caught and returned as error
+//! codes instead of unwinding across the boundary, the message is available from `cpm_last_error`
+
+use ndarray::Array2;
+use rand_pcg::Pcg64;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ffi::{c_char, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::{borrower, exposure, migration, portfolio, risk_group};
+
+/// The call succeeded
+pub const CPM_OK: i32 = 0;
+/// A pointer is null or a size is zero
+pub const CPM_INVALID_ARGUMENT: i32 = 1;
+/// The model rejected the input, e.g. a covariance matrix that is not positive semi-definite
+pub const CPM_PANIC: i32 = 2;
+
+/// Portfolio behind the opaque pointer of the C ABI
+pub struct CpmPortfolio(portfolio::Portfolio);
+
+thread_local! {
+    /// Message of the last failed call of the thread
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_last_error(message: &str) {
+    let message = CString::new(message.replace('\0', " ")).expect("Message without null bytes");
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+/// Run the call, an invalid argument or a panic becomes the error code with its message as last
+/// error of the thread
+fn guard(call: impl FnOnce() -> Result<(), String>) -> i32 {
+    match catch_unwind(AssertUnwindSafe(call)) {
+        Ok(Ok(())) => CPM_OK,
+        Ok(Err(message)) => {
+            set_last_error(&message);
+            CPM_INVALID_ARGUMENT
+        }
+        Err(payload) => {
+            let message = payload.downcast_ref::<String>().map(String::as_str)
+                .or_else(|| payload.downcast_ref::<&str>().copied())
+                .unwrap_or("Unknown panic");
+            set_last_error(message);
+            CPM_PANIC
+        }
+    }
+}
+
+/// Slice of the array of the given length, failing on a null pointer
+///
+/// # Safety
+/// A non-null `data` must point to `len` initialized values
+unsafe fn slice<'a, T>(data: *const T, len: usize, name: &str) -> Result<&'a [T], String> {
+    if data.is_null() {
+        return Err(format!("{} is null", name));
+    }
+    Ok(unsafe { std::slice::from_raw_parts(data, len) })
+}
+
+/// Build a portfolio of `num_borrowers` borrowers with `num_ratings` rating classes each, driven
+/// by `num_risk_factors` systematic risk factors, and store it in `out`. Per borrower, the arrays
+/// give the index of its risk group, its current rating, `rho` and `eps` of the factor model, and
+/// as rows of the matrices its risk factor weights, migration probabilities and absolute
+/// valuations of each rating class. The covariance matrix of the risk factors is row-major. The
+/// portfolio must be released by `cpm_portfolio_free`
+///
+/// # Safety
+/// All pointers must be valid for the sizes given, `out` for writing one pointer
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cpm_portfolio_new(
+    num_risk_factors: usize,
+    covariance: *const f64,
+    num_borrowers: usize,
+    num_ratings: usize,
+    risk_groups: *const usize,
+    ratings: *const usize,
+    rho: *const f64,
+    eps: *const f64,
+    risk_factor_weights: *const f64,
+    migration_probabilities: *const f64,
+    valuations: *const f64,
+    out: *mut *mut CpmPortfolio,
+) -> i32 {
+    guard(|| {
+        if out.is_null() {
+            return Err("Output pointer is null".to_string());
+        }
+        if num_risk_factors == 0 || num_ratings == 0 {
+            return Err("Portfolio needs at least one risk factor and rating class".to_string());
+        }
+        let (covariance, risk_groups, ratings, rho, eps, weights, probabilities, valuations) = unsafe {
+            (
+                slice(covariance, num_risk_factors * num_risk_factors, "Covariance")?,
+                slice(risk_groups, num_borrowers, "Risk groups")?,
+                slice(ratings, num_borrowers, "Ratings")?,
+                slice(rho, num_borrowers, "Rho")?,
+                slice(eps, num_borrowers, "Eps")?,
+                slice(risk_factor_weights, num_borrowers * num_risk_factors, "Risk factor weights")?,
+                slice(migration_probabilities, num_borrowers * num_ratings, "Migration probabilities")?,
+                slice(valuations, num_borrowers * num_ratings, "Valuations")?,
+            )
+        };
+
+        // Risk groups in order of their index, so that the simulation is reproducible
+        let mut groups: BTreeMap<usize, risk_group::RiskGroup> = BTreeMap::new();
+        for index in 0..num_borrowers {
+            let row = |values: &[f64], len: usize| values[index * len..(index + 1) * len].to_vec();
+            let mut borr = borrower::Borrower::new(index.to_string(), row(weights, num_risk_factors), ratings[index], rho[index], eps[index], row(probabilities, num_ratings));
+            let values = row(valuations, num_ratings);
+            let current = values[ratings[index]];
+            borr.add_exposure(exposure::Exposure::from_values(values, current));
+            groups.entry(risk_groups[index]).or_insert_with(|| risk_group::RiskGroup::new(risk_groups[index].to_string())).add_borrower(borr);
+        }
+
+        let cov = Array2::from_shape_vec((num_risk_factors, num_risk_factors), covariance.to_vec()).expect("Covariance matrix is square");
+        let mut pf = portfolio::Portfolio::new(cov);
+        groups.into_values().for_each(|rg| pf.add_risk_group(rg));
+        unsafe { *out = Box::into_raw(Box::new(CpmPortfolio(pf))) };
+        Ok(())
+    })
+}
+
+/// Simulate `num_trials` trials in chunks of `chunk_size` trials with the seed and store the
+/// portfolio loss of each trial in `losses` with its length in `len`. The losses must be released
+/// by `cpm_losses_free`
+///
+/// # Safety
+/// `portfolio` must come from `cpm_portfolio_new` and not be freed, `losses` and `len` must be
+/// valid for writing
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cpm_simulate(portfolio: *const CpmPortfolio, num_trials: usize, chunk_size: usize, seed: u64, losses: *mut *mut f64, len: *mut usize) -> i32 {
+    guard(|| {
+        if portfolio.is_null() || losses.is_null() || len.is_null() {
+            return Err("Portfolio or output pointer is null".to_string());
+        }
+        if num_trials == 0 || chunk_size == 0 {
+            return Err("Number of trials and chunk size must be positive".to_string());
+        }
+        let pf = unsafe { &(*portfolio).0 };
+        let result = pf.simulate::<Pcg64>(num_trials, chunk_size, seed, migration::MigrationTracking::None, &[]);
+        let loss = result.loss.into_boxed_slice();
+        unsafe {
+            *len = loss.len();
+            *losses = Box::into_raw(loss) as *mut f64;
+        }
+        Ok(())
+    })
+}
+
+/// Release the losses of `cpm_simulate`
+///
+/// # Safety
+/// `losses` and `len` must be those of a single call of `cpm_simulate`, or `losses` null
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cpm_losses_free(losses: *mut f64, len: usize) {
+    if !losses.is_null() {
+        drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(losses, len)) });
+    }
+}
+
+/// Release a portfolio of `cpm_portfolio_new`
+///
+/// # Safety
+/// `portfolio` must come from `cpm_portfolio_new` and not be freed before, or be null
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cpm_portfolio_free(portfolio: *mut CpmPortfolio) {
+    if !portfolio.is_null() {
+        drop(unsafe { Box::from_raw(portfolio) });
+    }
+}
+
+/// Message of the last failed call of the calling thread, empty if none failed. The pointer is
+/// valid until the next failing call of the thread
+#[unsafe(no_mangle)]
+pub extern "C" fn cpm_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
@@ -0,0 +1,193 @@
+use ndarray::Array2;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, StandardNormal};
+use rand_pcg::Pcg64;
+use std::collections::HashMap;
+
+use crate::input;
+
+/// Generator of a synthetic but valid portfolio, e.g. for benchmarks and tests. The last rating
+/// class is the default class
+pub struct Generator {
+    /// Number of borrowers
+    pub borrowers: usize,
+    /// Number of risk factors of the covariance matrix
+    pub risk_factors: usize,
+    /// Number of rating classes including default
+    pub ratings: usize,
+    /// Number of exposures of each borrower
+    pub exposures_per_borrower: usize,
+    /// Average number of borrowers per risk group
+    pub borrowers_per_group: usize,
+    /// Seed of the random number generator
+    pub seed: u64,
+    /// Number of distinct risk factor weight vectors shared by the borrowers, zero for individual
+    /// weights of every borrower
+    pub weight_classes: usize,
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Self {
+            borrowers: 1_000,
+            risk_factors: 10,
+            ratings: 8,
+            exposures_per_borrower: 2,
+            borrowers_per_group: 4,
+            seed: 0,
+            weight_classes: 0,
+        }
+    }
+}
+
+impl Generator {
+    /// Generate the input data of the portfolio
+    pub fn input(&self) -> input::Input {
+        assert!(self.ratings >= 2, "At least one rating class besides default is needed");
+        assert!(self.risk_factors >= 1, "At least one risk factor is needed");
+
+        let mut rng = Pcg64::seed_from_u64(self.seed);
+
+        let cov = self.correlation_matrix(&mut rng);
+        let num_groups = self.borrowers.div_ceil(self.borrowers_per_group.max(1)).max(1);
+
+        let mut risk_groups: HashMap<String, Vec<input::Borrower>> = HashMap::new();
+        let mut mig_probs: HashMap<String, Vec<f64>> = HashMap::new();
+        let mut risk_factors: HashMap<String, Vec<f64>> = HashMap::new();
+        let mut exposures: HashMap<String, Vec<input::Exposure>> = HashMap::new();
+        let mut valuations: HashMap<String, Vec<f64>> = HashMap::new();
+        let classes: Vec<Vec<f64>> = (0..self.weight_classes).map(|_| self.weights(&mut rng)).collect();
+
+        for index in 0..self.borrowers {
+            let borrower_id = format!("B{:07}", index);
+            let risk_group = format!("G{:07}", rng.gen_range(0..num_groups));
+            let rating = rng.gen_range(0..self.ratings - 1);
+
+            let weights = if classes.is_empty() {
+                self.weights(&mut rng)
+            } else {
+                classes[rng.gen_range(0..classes.len())].clone()
+            };
+
+            // Exposures with valuations decreasing with the rating class
+            let lgd = rng.gen_range(0.2..0.7);
+            for exp_index in 0..self.exposures_per_borrower {
+                let exposure_id = format!("{}_{}", borrower_id, exp_index);
+                let outstanding: f64 = (rng.gen_range(8.0..16.0_f64)).exp().round();
+
+                let val: Vec<f64> = (0..self.ratings).map(|r| {
+                    if r == self.ratings - 1 {
+                        outstanding * (1. - lgd)
+                    } else {
+                        outstanding * (1. - 0.1 * r as f64 / (self.ratings - 1) as f64)
+                    }
+                }).collect();
+
+                valuations.insert(exposure_id.to_string(), val);
+                exposures.entry(borrower_id.to_string()).or_default().push(input::Exposure {
+                    exposure_id,
+                    borrower_id: borrower_id.to_string(),
+                    outstanding,
+                    rating: None,
+                    direction: None,
+                    horizon: None,
+                    currency: None,
+                });
+            }
+
+            mig_probs.insert(borrower_id.to_string(), self.transition_row(rating));
+            risk_factors.insert(borrower_id.to_string(), weights);
+            risk_groups.entry(risk_group.to_string()).or_default().push(input::Borrower {
+                borrower_id,
+                risk_group,
+                rating,
+                r2: rng.gen_range(0.05..0.3),
+                eps: rng.gen_range(0.0..0.5),
+                parent: false,
+                rating_classes: None,
+            });
+        }
+
+        let rating_labels = (0..self.ratings).map(|r| {
+            if r == self.ratings - 1 { "D".to_string() } else { format!("R{}", r + 1) }
+        }).collect();
+
+        // Standardized risk factors, declared as such so the correlation matrix is not mistaken
+        input::Input {
+            cov,
+            risk_groups,
+            mig_probs,
+            risk_factors,
+            exposures,
+            valuations,
+            rating_labels,
+            volatilities: Some(vec![1.; self.risk_factors]),
+            pd_overrides: HashMap::new(),
+            thresholds: HashMap::new(),
+            guarantees: HashMap::new(),
+            hierarchy: HashMap::new(),
+            copula: HashMap::new(),
+            discounting: None,
+            fx_rates: HashMap::new(),
+            read_durations: Vec::new(),
+        }
+    }
+
+    /// Risk factor weights, each borrower depends on up to three risk factors
+    fn weights(&self, rng: &mut Pcg64) -> Vec<f64> {
+        let mut weights = vec![0.; self.risk_factors];
+        for _ in 0..rng.gen_range(1..=3) {
+            weights[rng.gen_range(0..self.risk_factors)] += rng.gen_range(0.1..1.0);
+        }
+        weights
+    }
+
+    /// Correlation matrix from random loadings on a few latent factors plus a unit diagonal, which
+    /// is positive definite by construction
+    fn correlation_matrix(&self, rng: &mut Pcg64) -> Array2<f64> {
+        let latent = 2;
+        let loadings = Array2::from_shape_fn((self.risk_factors, latent), |_| {
+            let x: f64 = StandardNormal.sample(rng);
+            0.7 * x
+        });
+
+        let mut cov = loadings.dot(&loadings.t());
+        cov.diag_mut().iter_mut().for_each(|x| *x += 1.);
+
+        let std: Vec<f64> = cov.diag().iter().map(|x| x.sqrt()).collect();
+        for ((i, j), x) in cov.indexed_iter_mut() {
+            *x = if i == j { 1. } else { *x / (std[i] * std[j]) };
+        }
+
+        cov
+    }
+
+    /// Migration probabilities of a rating class. Default probabilities increase exponentially
+    /// from 0.03% for the best to 20% for the worst rating, most of the remaining probability is
+    /// assigned to the current rating and the rest decays with the distance to the current rating
+    fn transition_row(&self, rating: usize) -> Vec<f64> {
+        let num_classes = self.ratings - 1;
+        let position = if num_classes > 1 { rating as f64 / (num_classes - 1) as f64 } else { 0. };
+        let pd = 0.0003 * (0.2_f64 / 0.0003).powf(position);
+
+        let mut row = vec![0.; self.ratings];
+        row[self.ratings - 1] = pd;
+
+        // Migrations into other non-default classes
+        let weights: Vec<f64> = (0..num_classes).map(|r| {
+            if r == rating { 0. } else { (-1.5 * (r as f64 - rating as f64).abs()).exp() }
+        }).collect();
+        let total: f64 = weights.iter().sum();
+        if total > 0. {
+            let migration = 0.15 * (1. - pd);
+            for (r, w) in weights.iter().enumerate() {
+                row[r] = migration * w / total;
+            }
+        }
+
+        // Remaining probability stays in the current rating
+        row[rating] = 1. - row.iter().sum::<f64>();
+
+        row
+    }
+}
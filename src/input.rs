@@ -0,0 +1,1775 @@
+use csv::{Reader, ReaderBuilder, StringRecord, Writer};
+use ndarray::{s, Array2};
+use ndarray_linalg::{Cholesky, UPLO};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::{borrower, exposure, portfolio, rating, risk_group};
+
+// File input formats for serde
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CovarianceCell {
+    pub risk_factor_1: usize,
+    pub risk_factor_2: usize,
+    pub correlation: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Borrower {
+    pub borrower_id: String,
+    pub risk_group: String,
+    pub rating: usize,
+    pub r2: f64,
+    pub eps: f64,
+    /// Parent of its risk group whose default drags the other members down under contagion
+    #[serde(default)]
+    pub parent: bool,
+    /// Number of rating classes of the borrower's own rating scale, e.g. of a different asset
+    /// class, if it differs from the common one
+    #[serde(default)]
+    pub rating_classes: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MigrationProb {
+    pub borrower_id: String,
+    pub rating: usize,
+    pub probability: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RiskFactor {
+    pub borrower_id: String,
+    pub risk_factor: usize,
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Exposure {
+    pub exposure_id: String,
+    pub borrower_id: String,
+    /// Current outstanding, scales the valuations in unit-price mode
+    pub outstanding: f64,
+    /// Current rating of the facility if it differs from the borrower's rating
+    #[serde(default)]
+    pub rating: Option<usize>,
+    /// Direction of the exposure if it is not long, e.g. short for a hedge
+    #[serde(default)]
+    pub direction: Option<exposure::Direction>,
+    /// Horizon in years the valuations are discounted from, one year if not given
+    #[serde(default)]
+    pub horizon: Option<f64>,
+    /// Currency of the outstanding and the absolute valuations, the base currency if not given
+    #[serde(default)]
+    pub currency: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Valuation {
+    pub exposure_id: String,
+    pub rating: usize,
+    pub valuation: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscountPoint {
+    /// Tenor in years
+    pub tenor: f64,
+    pub discount_factor: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Volatility {
+    pub risk_factor: usize,
+    pub volatility: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FactorLoading {
+    pub risk_factor: usize,
+    pub latent_factor: usize,
+    pub loading: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FactorResidual {
+    pub risk_factor: usize,
+    pub variance: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RatingLabel {
+    pub rating: usize,
+    pub label: String,
+}
+
+/// Threshold of the asset value between a rating class and the next worse one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MigrationThreshold {
+    pub borrower_id: String,
+    pub rating: usize,
+    pub threshold: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FxRate {
+    pub currency: String,
+    /// Amount of the base currency per unit of the currency
+    pub rate_to_base: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PdOverride {
+    pub borrower_id: String,
+    pub pd: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Guarantee {
+    pub exposure_id: String,
+    pub guarantor_borrower_id: String,
+    pub coverage_fraction: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RiskGroupLink {
+    pub risk_group: String,
+    pub parent: String,
+    /// Correlation of the shock of the risk group to the shock of its parent
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RiskGroupCopula {
+    pub risk_group: String,
+    /// Degrees of freedom of the Student-t copula of the borrowers of the risk group
+    pub degrees_of_freedom: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RatingScaleEntry {
+    pub label: String,
+    pub index: usize,
+    pub is_default: bool,
+}
+
+// Rows of the files with a rating column, the rating is given by index or by label
+#[derive(Deserialize)]
+struct BorrowerRecord {
+    borrower_id: String,
+    risk_group: String,
+    rating: rating::RatingKey,
+    r2: f64,
+    eps: f64,
+    #[serde(default)]
+    parent: bool,
+    #[serde(default)]
+    rating_classes: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct MigrationProbRecord<'a> {
+    borrower_id: &'a str,
+    rating: rating::RatingKey,
+    probability: f64,
+}
+
+#[derive(Deserialize)]
+struct MigrationThresholdRecord<'a> {
+    borrower_id: &'a str,
+    rating: rating::RatingKey,
+    threshold: f64,
+}
+
+#[derive(Deserialize)]
+struct ExposureRecord {
+    exposure_id: String,
+    borrower_id: String,
+    outstanding: f64,
+    #[serde(default)]
+    rating: Option<rating::RatingKey>,
+    #[serde(default)]
+    direction: Option<exposure::Direction>,
+    #[serde(default)]
+    horizon: Option<f64>,
+    #[serde(default)]
+    currency: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ValuationRecord<'a> {
+    exposure_id: &'a str,
+    rating: rating::RatingKey,
+    valuation: f64,
+}
+
+/// Value of a systematic risk factor fixed by a stress scenario
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScenarioFactor {
+    pub risk_factor: usize,
+    pub value: f64,
+}
+
+/// Multiplier of the probability of default of the borrowers of a current rating
+#[derive(Deserialize)]
+struct PdStressRecord {
+    rating: rating::RatingKey,
+    multiplier: f64,
+}
+
+/// Shift of the mean of a systematic risk factor in a macro scenario
+#[derive(Deserialize)]
+struct FactorShiftRecord {
+    risk_factor: usize,
+    shift: f64,
+}
+
+/// Macro scenario as given in the scenarios file
+#[derive(Deserialize)]
+struct MacroScenarioRecord {
+    id: String,
+    weight: f64,
+    #[serde(default)]
+    pd_multipliers: Vec<PdStressRecord>,
+    #[serde(default)]
+    factor_shifts: Vec<FactorShiftRecord>,
+}
+
+/// Scenarios file of a mixture of macro scenarios
+#[derive(Deserialize)]
+struct ScenariosFile {
+    scenarios: Vec<MacroScenarioRecord>,
+}
+
+/// Macro scenario of a mixture: its probability weight, the multipliers of the probability of
+/// default by current rating (see `read_pd_stress`) and the shifts of the means of systematic risk
+/// factors (see `Portfolio::shift_factors`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct MacroScenario {
+    pub id: String,
+    pub weight: f64,
+    pub pd_multipliers: Vec<f64>,
+    pub factor_shifts: Vec<(usize, f64)>,
+}
+
+/// Handling of valuations missing for some rating classes of an exposure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingValuations {
+    /// Missing valuations are a failure of reading the input
+    Error,
+    /// Missing valuations are filled with the valuation of the current rating of the borrower,
+    /// i.e. migrating into them incurs no loss
+    Current,
+}
+
+/// Handling of borrowers without exposures, e.g. repaid loans still in the extract
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmptyBorrowers {
+    /// Borrowers without exposures are a failure of reading the input
+    Error,
+    /// Borrowers without exposures are simulated with no exposures, they consume their draws but
+    /// contribute no loss
+    Allow,
+}
+
+/// Handling of exposure rows and valuations of a rating class listed more than once, e.g. by an
+/// extract repeating rows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Duplicates {
+    /// Duplicates are a failure of reading the input
+    Error,
+    /// The outstandings of duplicate exposure rows and the duplicate valuations are summed
+    Sum,
+}
+
+/// Failures of reading the input files, the files are read completely, so that the failures of
+/// all files are reported at once
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputError {
+    /// File name and failure message
+    pub failures: Vec<(String, String)>,
+}
+
+impl InputError {
+    /// Error with a single failure
+    fn single(file: &str, message: &str) -> Self {
+        Self { failures: vec![(file.to_string(), message.to_string())] }
+    }
+}
+
+impl std::fmt::Display for InputError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Input could not be read, {} failures", self.failures.len())?;
+        for (file, message) in self.failures.iter() {
+            write!(f, "\n{}: {}", file, message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InputError {}
+
+/// Input data of the portfolio as read from the csv files, keyed by the identifiers used to
+/// join the files
+pub struct Input {
+    /// Covariance matrix of the risk factors, scaled by the volatilities if given
+    pub cov: Array2<f64>,
+    /// Volatilities of the risk factors if declared, the matrix file is then a correlation matrix
+    pub volatilities: Option<Vec<f64>>,
+    /// Borrowers by risk group
+    pub risk_groups: HashMap<String, Vec<Borrower>>,
+    /// Migration probabilities by borrower
+    pub mig_probs: HashMap<String, Vec<f64>>,
+    /// Risk factor weights by borrower
+    pub risk_factors: HashMap<String, Vec<f64>>,
+    /// Exposures by borrower
+    pub exposures: HashMap<String, Vec<Exposure>>,
+    /// Valuations by exposure
+    pub valuations: HashMap<String, Vec<f64>>,
+    /// Probabilities of default by borrower replacing the one of the migration probabilities
+    pub pd_overrides: HashMap<String, f64>,
+    /// Thresholds of the asset value by borrower calibrated directly, replacing those derived from
+    /// the migration probabilities
+    pub thresholds: HashMap<String, Vec<f64>>,
+    /// Guarantees by exposure
+    pub guarantees: HashMap<String, Guarantee>,
+    /// Parent of each nested risk group
+    pub hierarchy: HashMap<String, RiskGroupLink>,
+    /// Degrees of freedom of the Student-t copula by risk group, other groups have the Gaussian
+    /// copula
+    pub copula: HashMap<String, f64>,
+    /// Discount factors of the horizons of the exposures if the valuations are discounted
+    pub discounting: Option<exposure::DiscountCurve>,
+    /// Rates converting the currencies of the exposures to the base currency, the amounts of the
+    /// input stay in their original currency until the portfolio is set up
+    pub fx_rates: HashMap<String, f64>,
+    /// Labels of the rating classes
+    pub rating_labels: Vec<String>,
+    /// Time spent on reading each input file, empty if not read from files
+    pub read_durations: Vec<(String, Duration)>,
+}
+
+impl Input {
+    /// Read all input files from a directory, panics with the failures of all files if any file
+    /// cannot be read
+    pub fn read(path: &Path) -> Self {
+        Self::read_with(path, MissingValuations::Error, EmptyBorrowers::Error, Duplicates::Error)
+    }
+
+    /// Read all input files from a directory with the given handling of missing valuations,
+    /// borrowers without exposures and duplicate rows, panics with the failures of all files if any
+    /// file cannot be read
+    pub fn read_with(path: &Path, missing: MissingValuations, empty: EmptyBorrowers, duplicates: Duplicates) -> Self {
+        Self::try_read_with(path, missing, empty, duplicates).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Read all input files from a directory. The files are independent until they are joined
+    /// into the portfolio, so each file is read by a thread of its own with its rows deserialized
+    /// directly into the maps. Failures of all files are collected instead of stopping at the first
+    pub fn try_read(path: &Path) -> Result<Self, InputError> {
+        Self::try_read_with(path, MissingValuations::Error, EmptyBorrowers::Error, Duplicates::Error)
+    }
+
+    /// Read all input files from a directory with the given handling of missing valuations,
+    /// borrowers without exposures and duplicate rows, see `try_read`
+    pub fn try_read_with(path: &Path, missing: MissingValuations, empty: EmptyBorrowers, duplicates: Duplicates) -> Result<Self, InputError> {
+        // Covariance, either assembled from the loadings of a factor model, which skips the matrix
+        // files, or as square grid or as list of cells
+        let loadings = path.join("factor_loadings.csv");
+        let dense = path.join("correlation_matrix_dense.csv");
+        let cells = path.join("correlation_matrix.csv");
+        let cov_file = if loadings.exists() {
+            "factor_loadings.csv"
+        } else if dense.exists() && cells.exists() {
+            return Err(InputError::single("correlation_matrix_dense.csv", "Both correlation_matrix.csv and correlation_matrix_dense.csv found, only one is allowed"));
+        } else if dense.exists() {
+            "correlation_matrix_dense.csv"
+        } else {
+            "correlation_matrix.csv"
+        };
+
+        // The rating labels resolve the ratings of the other files, so they are read first and
+        // their failures stop the reading
+        let mut failures: Vec<(String, String)> = Vec::new();
+        let mut read_durations: Vec<(String, Duration)> = Vec::new();
+        let (rating_labels, rating_file) = if path.join("rating_scale.csv").exists() {
+            if path.join("rating_labels.csv").exists() {
+                return Err(InputError::single("rating_scale.csv", "Both rating_labels.csv and rating_scale.csv found, only one is allowed"));
+            }
+            (timed(|| read_rating_scale(&path.join("rating_scale.csv"))), "rating_scale.csv")
+        } else {
+            (timed(|| read_rating_labels(path)), "rating_labels.csv")
+        };
+        let rating_labels = collect(rating_file, rating_labels, &mut failures, &mut read_durations)
+            .ok_or_else(|| InputError { failures: failures.clone() })?;
+        let scale = rating::RatingScale::new(rating_labels.clone());
+
+        let (cov, risk_groups, mig_probs, risk_factors, exposures, valuations, volatilities, pd_overrides, thresholds, guarantees, hierarchy, copula, discounting, fx_rates) = std::thread::scope(|scope| {
+            let cov = scope.spawn(|| timed(|| match cov_file {
+                "factor_loadings.csv" => read_factor_model(&loadings, &path.join("factor_residuals.csv")),
+                "correlation_matrix_dense.csv" => read_dense_covariance(&dense),
+                _ => read_covariance_cells(&cells),
+            }));
+            let risk_groups = scope.spawn(|| timed(|| read_borrowers(&path.join("borrower.csv"), &scale)));
+            let mig_probs = scope.spawn(|| timed(|| read_migration_probabilities(&path.join("transition_probabilities.csv"), &scale)));
+            let risk_factors = scope.spawn(|| timed(|| read_risk_factors(&path.join("risk_factors.csv"))));
+            let exposures = scope.spawn(|| timed(|| read_exposures(&path.join("exposures.csv"), &scale, duplicates)));
+            let valuations = scope.spawn(|| timed(|| read_valuations(&path.join("valuations.csv"), &scale, duplicates)));
+            let volatilities = timed(|| read_volatilities(path));
+            let pd_overrides = timed(|| read_pd_overrides(path));
+            let thresholds = timed(|| read_thresholds(path, &scale));
+            let guarantees = timed(|| read_guarantees(path));
+            let hierarchy = timed(|| read_hierarchy(path));
+            let copula = timed(|| read_copula(path));
+            let discounting = timed(|| read_discounting(path));
+            let fx_rates = timed(|| read_fx_rates(path));
+
+            (join(cov), join(risk_groups), join(mig_probs), join(risk_factors), join(exposures), join(valuations), volatilities, pd_overrides, thresholds, guarantees, hierarchy, copula, discounting, fx_rates)
+        });
+
+        // Collect the failures of all files
+        let mut cov = collect(cov_file, cov, &mut failures, &mut read_durations);
+        let risk_groups = collect("borrower.csv", risk_groups, &mut failures, &mut read_durations);
+        let mig_probs = collect("transition_probabilities.csv", mig_probs, &mut failures, &mut read_durations);
+        let mut risk_factors = collect("risk_factors.csv", risk_factors, &mut failures, &mut read_durations);
+        let mut exposures = collect("exposures.csv", exposures, &mut failures, &mut read_durations);
+        let mut valuations = collect("valuations.csv", valuations, &mut failures, &mut read_durations);
+        let volatilities = collect("volatilities.csv", volatilities, &mut failures, &mut read_durations);
+        let pd_overrides = collect("pd_override.csv", pd_overrides, &mut failures, &mut read_durations);
+        let thresholds = collect("thresholds.csv", thresholds, &mut failures, &mut read_durations);
+        let guarantees = collect("guarantees.csv", guarantees, &mut failures, &mut read_durations);
+        let hierarchy = collect("risk_group_hierarchy.csv", hierarchy, &mut failures, &mut read_durations);
+        let copula = collect("risk_group_copula.csv", copula, &mut failures, &mut read_durations);
+        let discounting = collect("discounting.csv", discounting, &mut failures, &mut read_durations);
+        let fx_rates = collect("fx_rates.csv", fx_rates, &mut failures, &mut read_durations);
+
+        // Declared volatilities scale a correlation matrix to the covariance matrix
+        if let (Some(cov), Some(Some(volatilities))) = (cov.as_mut(), volatilities.as_ref())
+            && let Err(message) = scale_correlation(cov, volatilities) {
+            failures.push(("volatilities.csv".to_string(), message));
+        }
+
+        // Risk factor weights are only read up to the largest given risk factor, the covariance
+        // matrix gives their number
+        if let (Some(cov), Some(risk_factors)) = (cov.as_ref(), risk_factors.as_mut()) {
+            let mut unknown: Vec<String> = Vec::new();
+            for (id, weights) in risk_factors.iter_mut() {
+                if weights.len() > cov.nrows() {
+                    unknown.push(format!("Borrower {} has risk factor {} not in the covariance matrix with {} risk factors", id, weights.len() - 1, cov.nrows()));
+                }
+                weights.resize(cov.nrows().max(weights.len()), 0.);
+            }
+            unknown.sort();
+            failures.extend(unknown.into_iter().map(|message| ("risk_factors.csv".to_string(), message)));
+        }
+
+        // Borrowers without exposures are listed at once, or get an empty list of exposures if
+        // allowed
+        if let (Some(risk_groups), Some(exposures)) = (risk_groups.as_ref(), exposures.as_mut()) {
+            let mut ids: Vec<&str> = risk_groups.values().flatten().map(|borr| borr.borrower_id.as_str()).filter(|id| !exposures.contains_key(*id)).collect();
+            ids.sort();
+            match empty {
+                EmptyBorrowers::Error if !ids.is_empty() => failures.push(("exposures.csv".to_string(), format!("{} borrowers have no exposures: {}", ids.len(), ids.join(", ")))),
+                EmptyBorrowers::Error => (),
+                EmptyBorrowers::Allow => ids.into_iter().for_each(|id| { exposures.insert(id.to_string(), Vec::new()); }),
+            }
+        }
+
+        // Rating classes without a migration probability or valuation are failures, unless the
+        // missing valuations are filled
+        if let (Some(risk_groups), Some(mig_probs), Some(exposures), Some(valuations)) = (risk_groups.as_ref(), mig_probs.as_ref(), exposures.as_ref(), valuations.as_mut()) {
+            failures.extend(check_rating_dimensions(&scale, risk_groups, mig_probs, exposures, valuations, missing));
+        }
+
+        // Thresholds must separate all rating classes of the borrower in increasing order
+        if let (Some(mig_probs), Some(thresholds)) = (mig_probs.as_ref(), thresholds.as_ref()) {
+            failures.extend(check_thresholds(&scale, mig_probs, thresholds).into_iter().map(|message| ("thresholds.csv".to_string(), message)));
+        }
+
+        // Every currency of the exposures needs a rate to the base currency
+        if let (Some(exposures), Some(fx_rates)) = (exposures.as_ref(), fx_rates.as_ref()) {
+            failures.extend(check_currencies(exposures, fx_rates).into_iter().map(|message| ("fx_rates.csv".to_string(), message)));
+        }
+
+        // Guarantors must be borrowers with the rating classes of the guaranteed exposure, and
+        // guarantees must not be circular
+        if let (Some(risk_groups), Some(mig_probs), Some(exposures), Some(guarantees)) = (risk_groups.as_ref(), mig_probs.as_ref(), exposures.as_ref(), guarantees.as_ref()) {
+            failures.extend(check_guarantees(guarantees, risk_groups, mig_probs, exposures).into_iter().map(|message| ("guarantees.csv".to_string(), message)));
+        }
+
+        match (cov, risk_groups, mig_probs, risk_factors, exposures, valuations, volatilities, pd_overrides, thresholds, guarantees, hierarchy, copula, discounting, fx_rates) {
+            (Some(cov), Some(risk_groups), Some(mig_probs), Some(risk_factors), Some(exposures), Some(valuations), Some(volatilities), Some(pd_overrides), Some(thresholds), Some(guarantees), Some(hierarchy), Some(copula), Some(discounting), Some(fx_rates)) if failures.is_empty() => Ok(Self {
+                cov,
+                volatilities,
+                risk_groups,
+                mig_probs,
+                risk_factors,
+                exposures,
+                valuations,
+                pd_overrides,
+                thresholds,
+                guarantees,
+                hierarchy,
+                copula,
+                discounting,
+                fx_rates,
+                rating_labels,
+                read_durations,
+            }),
+            _ => Err(InputError { failures }),
+        }
+    }
+
+    /// Write the covariance matrix as it is used by the model, i.e. scaled by the volatilities and
+    /// assembled from a factor model, as list of cells for audit
+    pub fn write_covariance(&self, path: &Path) {
+        let mut writer = Writer::from_path(path).expect("Output path not found");
+        for ((risk_factor_1, risk_factor_2), &correlation) in self.cov.indexed_iter() {
+            writer.serialize(CovarianceCell { risk_factor_1, risk_factor_2, correlation }).unwrap();
+        }
+        writer.flush().unwrap();
+    }
+
+    /// Write all input files into a directory, in the same format as they are read
+    pub fn write(&self, path: &Path) {
+        // Covariance, as correlation matrix if the volatilities are declared
+        let mut writer = Writer::from_path(path.join("correlation_matrix.csv")).expect("Output path not found");
+        for ((risk_factor_1, risk_factor_2), &covariance) in self.cov.indexed_iter() {
+            let correlation = match self.volatilities.as_ref() {
+                Some(vol) => covariance / (vol[risk_factor_1] * vol[risk_factor_2]),
+                None => covariance,
+            };
+            writer.serialize(CovarianceCell { risk_factor_1, risk_factor_2, correlation }).unwrap();
+        }
+        writer.flush().unwrap();
+
+        if let Some(volatilities) = self.volatilities.as_ref() {
+            let mut writer = Writer::from_path(path.join("volatilities.csv")).expect("Output path not found");
+            for (risk_factor, &volatility) in volatilities.iter().enumerate() {
+                writer.serialize(Volatility { risk_factor, volatility }).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+
+        // Borrowers in deterministic order
+        let mut groups: Vec<&String> = self.risk_groups.keys().collect();
+        groups.sort();
+        let borrowers: Vec<&Borrower> = groups.iter().flat_map(|rg| self.risk_groups[*rg].iter()).collect();
+
+        let mut writer = Writer::from_path(path.join("borrower.csv")).expect("Output path not found");
+        for borr in borrowers.iter() {
+            writer.serialize(borr).unwrap();
+        }
+        writer.flush().unwrap();
+
+        // Transition probabilities and risk factors
+        let mut probs = Writer::from_path(path.join("transition_probabilities.csv")).expect("Output path not found");
+        let mut factors = Writer::from_path(path.join("risk_factors.csv")).expect("Output path not found");
+        let mut exposures = Writer::from_path(path.join("exposures.csv")).expect("Output path not found");
+        let mut valuations = Writer::from_path(path.join("valuations.csv")).expect("Output path not found");
+        for borr in borrowers.iter() {
+            let borrower_id = &borr.borrower_id;
+
+            for (rating, &probability) in self.mig_probs.get(borrower_id).into_iter().flatten().enumerate() {
+                probs.serialize(MigrationProb { borrower_id: borrower_id.to_string(), rating, probability }).unwrap();
+            }
+
+            for (risk_factor, &weight) in self.risk_factors.get(borrower_id).into_iter().flatten().enumerate() {
+                if weight != 0. {
+                    factors.serialize(RiskFactor { borrower_id: borrower_id.to_string(), risk_factor, weight }).unwrap();
+                }
+            }
+
+            // Exposures and valuations
+            for exp in self.exposures.get(borrower_id).into_iter().flatten() {
+                exposures.serialize(exp).unwrap();
+
+                for (rating, &valuation) in self.valuations.get(&exp.exposure_id).into_iter().flatten().enumerate() {
+                    valuations.serialize(Valuation { exposure_id: exp.exposure_id.to_string(), rating, valuation }).unwrap();
+                }
+            }
+        }
+        probs.flush().unwrap();
+        factors.flush().unwrap();
+        exposures.flush().unwrap();
+        valuations.flush().unwrap();
+
+        // Overrides of the probability of default
+        if !self.pd_overrides.is_empty() {
+            let mut overrides: Vec<(&String, &f64)> = self.pd_overrides.iter().collect();
+            overrides.sort_by(|a, b| a.0.cmp(b.0));
+            let mut writer = Writer::from_path(path.join("pd_override.csv")).expect("Output path not found");
+            for (borrower_id, &pd) in overrides {
+                writer.serialize(PdOverride { borrower_id: borrower_id.to_string(), pd }).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+
+        // Thresholds calibrated directly
+        if !self.thresholds.is_empty() {
+            let mut thresholds: Vec<(&String, &Vec<f64>)> = self.thresholds.iter().collect();
+            thresholds.sort_by(|a, b| a.0.cmp(b.0));
+            let mut writer = Writer::from_path(path.join("thresholds.csv")).expect("Output path not found");
+            for (borrower_id, list) in thresholds {
+                for (rating, &threshold) in list.iter().enumerate() {
+                    writer.serialize(MigrationThreshold { borrower_id: borrower_id.to_string(), rating, threshold }).unwrap();
+                }
+            }
+            writer.flush().unwrap();
+        }
+
+        // Guarantees
+        if !self.guarantees.is_empty() {
+            let mut guarantees: Vec<&Guarantee> = self.guarantees.values().collect();
+            guarantees.sort_by(|a, b| a.exposure_id.cmp(&b.exposure_id));
+            let mut writer = Writer::from_path(path.join("guarantees.csv")).expect("Output path not found");
+            for guarantee in guarantees {
+                writer.serialize(guarantee).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+
+        // Risk group hierarchy
+        if !self.hierarchy.is_empty() {
+            let mut links: Vec<&RiskGroupLink> = self.hierarchy.values().collect();
+            links.sort_by(|a, b| a.risk_group.cmp(&b.risk_group));
+            let mut writer = Writer::from_path(path.join("risk_group_hierarchy.csv")).expect("Output path not found");
+            for link in links {
+                writer.serialize(link).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+
+        // Copula of the risk groups
+        if !self.copula.is_empty() {
+            let mut rows: Vec<RiskGroupCopula> = self.copula.iter()
+                .map(|(risk_group, &degrees_of_freedom)| RiskGroupCopula { risk_group: risk_group.to_string(), degrees_of_freedom })
+                .collect();
+            rows.sort_by(|a, b| a.risk_group.cmp(&b.risk_group));
+            let mut writer = Writer::from_path(path.join("risk_group_copula.csv")).expect("Output path not found");
+            for row in rows {
+                writer.serialize(row).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+
+        // Discount curve, a flat rate is not part of the input files
+        if let Some(exposure::DiscountCurve::Curve(points)) = self.discounting.as_ref() {
+            let mut writer = Writer::from_path(path.join("discounting.csv")).expect("Output path not found");
+            for &(tenor, discount_factor) in points.iter() {
+                writer.serialize(DiscountPoint { tenor, discount_factor }).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+
+        // Exchange rates to the base currency
+        if !self.fx_rates.is_empty() {
+            let mut rates: Vec<(&String, &f64)> = self.fx_rates.iter().collect();
+            rates.sort_by(|a, b| a.0.cmp(b.0));
+            let mut writer = Writer::from_path(path.join("fx_rates.csv")).expect("Output path not found");
+            for (currency, &rate_to_base) in rates {
+                writer.serialize(FxRate { currency: currency.to_string(), rate_to_base }).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+
+        // Rating labels
+        if !self.rating_labels.is_empty() {
+            let mut writer = Writer::from_path(path.join("rating_labels.csv")).expect("Output path not found");
+            for (rating, label) in self.rating_labels.iter().enumerate() {
+                writer.serialize(RatingLabel { rating, label: label.to_string() }).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+    }
+
+    /// Check the input for inconsistencies that would either fail the construction of the
+    /// portfolio or lead to wrong results. Returns a list of all issues found
+    pub fn validate(&self) -> Vec<String> {
+        let mut issues: Vec<String> = Vec::new();
+        let rating_scale = rating::RatingScale::new(self.rating_labels.clone());
+
+        // Covariance matrix
+        let num_risk_factors = self.cov.nrows();
+        if self.cov.ncols() != num_risk_factors {
+            issues.push(format!("Covariance matrix is not square ({}x{})", self.cov.nrows(), self.cov.ncols()));
+        } else {
+            for ((i, j), &value) in self.cov.indexed_iter() {
+                if i < j && (value - self.cov[[j, i]]).abs() > 1e-12 {
+                    issues.push(format!("Covariance matrix is not symmetric for risk factors {} and {}", i, j));
+                }
+            }
+            if let Some(index) = self.cov.diag().iter().position(|&var| var <= 0.) {
+                issues.push(format!("Covariance matrix has non-positive variance {} of risk factor {}", self.cov[[index, index]], index));
+            }
+            if self.cov.cholesky(UPLO::Lower).is_err() {
+                issues.push(format!("Covariance matrix is not positive definite, {}", portfolio::spectrum_diagnostics(&self.cov)));
+            }
+        }
+
+        // Risk groups with more than one parent, in deterministic order
+        let mut groups: Vec<&String> = self.risk_groups.keys().collect();
+        groups.sort();
+        for rg in groups {
+            let parents: Vec<&str> = self.risk_groups[rg].iter().filter(|borr| borr.parent).map(|borr| borr.borrower_id.as_str()).collect();
+            if parents.len() > 1 {
+                issues.push(format!("Risk group {} has parent borrowers {}, only one is allowed", rg, parents.join(", ")));
+            }
+        }
+
+        // Risk group hierarchy
+        let mut links: Vec<&RiskGroupLink> = self.hierarchy.values().collect();
+        links.sort_by(|a, b| a.risk_group.cmp(&b.risk_group));
+        for link in links.iter().filter(|link| !(0. ..=1.).contains(&link.weight)) {
+            issues.push(format!("Risk group {} has weight {} on its parent {} outside of [0, 1]", link.risk_group, link.weight, link.parent));
+        }
+        if let Some(cycle) = hierarchy_cycle(&self.hierarchy) {
+            issues.push(format!("Circular risk group hierarchy {}", cycle.join(" -> ")));
+        }
+
+        // Copula of risk groups that exist
+        let mut copula: Vec<(&String, &f64)> = self.copula.iter().collect();
+        copula.sort_by(|a, b| a.0.cmp(b.0));
+        for (rg, df) in copula {
+            if !(*df > 0. && df.is_finite()) {
+                issues.push(format!("Risk group {} has degrees of freedom {}, they must be positive", rg, df));
+            }
+            if !self.risk_groups.contains_key(rg) && !self.hierarchy.values().any(|link| &link.risk_group == rg || &link.parent == rg) {
+                issues.push(format!("Risk group {} has degrees of freedom, but neither borrowers nor a place in the hierarchy", rg));
+            }
+        }
+
+        // Borrowers
+        for borr in self.risk_groups.values().flatten() {
+            let id = &borr.borrower_id;
+
+            if !(0. ..=1.).contains(&borr.r2) {
+                issues.push(format!("Borrower {} has r2 {} outside of [0, 1]", id, borr.r2));
+            }
+            if !(0. ..=1.).contains(&borr.eps) {
+                issues.push(format!("Borrower {} has eps {} outside of [0, 1]", id, borr.eps));
+            }
+
+            match self.risk_factors.get(id) {
+                None => issues.push(format!("Borrower {} has no risk factors", id)),
+                Some(weights) => {
+                    if weights.len() != num_risk_factors {
+                        issues.push(format!("Borrower {} has risk factors not in the covariance matrix", id));
+                    }
+                    if weights.iter().all(|&w| w == 0.) {
+                        issues.push(format!("Borrower {} has only zero risk factor weights", id));
+                    }
+                }
+            }
+
+            let num_ratings = match self.mig_probs.get(id) {
+                None => {
+                    issues.push(format!("Borrower {} has no migration probabilities", id));
+                    None
+                }
+                Some(prob) => {
+                    if borr.rating >= prob.len() {
+                        issues.push(format!("Borrower {} has current rating {} without migration probability", id, rating_scale.label(borr.rating)));
+                    }
+                    if let Some(rating) = prob.iter().position(|&p| !(0. ..=1.).contains(&p)) {
+                        issues.push(format!("Borrower {} has migration probability {} into rating {} outside of [0, 1]", id, prob[rating], rating_scale.label(rating)));
+                    }
+                    let total: f64 = prob.iter().sum();
+                    if (total - 1.).abs() > 1e-6 {
+                        issues.push(format!("Borrower {} has migration probabilities summing to {}", id, total));
+                    }
+                    if let Some(&pd) = self.pd_overrides.get(id) {
+                        let rest: f64 = prob.iter().take(prob.len().saturating_sub(1)).sum();
+                        if !(0. ..=1.).contains(&pd) {
+                            issues.push(format!("Borrower {} has PD override {} outside of [0, 1]", id, pd));
+                        } else if prob.len() < 2 {
+                            issues.push(format!("Borrower {} has a PD override, but a single rating class without default", id));
+                        } else if rest <= 0. && pd < 1. {
+                            issues.push(format!("Borrower {} has PD override {}, but no probability outside of default to scale to {}", id, pd, 1. - pd));
+                        }
+                    }
+                    Some(prob.len())
+                }
+            };
+
+            match self.exposures.get(id) {
+                None => issues.push(format!("Borrower {} has no exposures", id)),
+                Some(exp_list) => {
+                    for exp in exp_list {
+                        match (self.valuations.get(&exp.exposure_id), num_ratings) {
+                            (None, _) => issues.push(format!("Exposure {} has no valuations", exp.exposure_id)),
+                            (Some(val), Some(num_ratings)) if val.len() != num_ratings => {
+                                issues.push(format!("Exposure {} has valuations up to rating {}, but borrower {} migrates up to rating {}", exp.exposure_id, rating_scale.label(val.len() - 1), id, rating_scale.label(num_ratings - 1)));
+                            }
+                            (Some(val), _) if exp.rating.is_some_and(|rating| rating >= val.len()) => {
+                                issues.push(format!("Exposure {} has facility rating {} without valuation", exp.exposure_id, rating_scale.label(exp.rating.unwrap())));
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+            }
+        }
+
+        // Entries that do not match any borrower or exposure
+        let borrower_ids: HashSet<&String> = self.risk_groups.values().flatten().map(|borr| &borr.borrower_id).collect();
+        let exposure_ids: HashSet<&String> = self.exposures.values().flatten().map(|exp| &exp.exposure_id).collect();
+        let mut unknown: Vec<String> = Vec::new();
+        unknown.extend(self.mig_probs.keys().filter(|id| !borrower_ids.contains(id)).map(|id| format!("Migration probabilities of unknown borrower {}", id)));
+        unknown.extend(self.risk_factors.keys().filter(|id| !borrower_ids.contains(id)).map(|id| format!("Risk factors of unknown borrower {}", id)));
+        unknown.extend(self.exposures.keys().filter(|id| !borrower_ids.contains(id)).map(|id| format!("Exposures of unknown borrower {}", id)));
+        unknown.extend(self.valuations.keys().filter(|id| !exposure_ids.contains(id)).map(|id| format!("Valuations of unknown exposure {}", id)));
+        unknown.extend(self.pd_overrides.keys().filter(|id| !borrower_ids.contains(id)).map(|id| format!("PD override of unknown borrower {}", id)));
+        unknown.extend(self.thresholds.keys().filter(|id| !borrower_ids.contains(id)).map(|id| format!("Thresholds of unknown borrower {}", id)));
+        unknown.extend(self.guarantees.keys().filter(|id| !exposure_ids.contains(id)).map(|id| format!("Guarantee of unknown exposure {}", id)));
+        unknown.sort();
+        issues.extend(unknown);
+
+        issues
+    }
+
+    /// Number of borrowers with an empty list of exposures, as read with `EmptyBorrowers::Allow`
+    pub fn num_empty_borrowers(&self) -> usize {
+        self.risk_groups.values().flatten().filter(|borr| self.exposures.get(&borr.borrower_id).is_some_and(Vec::is_empty)).count()
+    }
+
+    /// Warnings about the covariance matrix that are cheap enough to be checked on every load
+    pub fn covariance_warnings(&self) -> Vec<String> {
+        let mut warnings: Vec<String> = Vec::new();
+
+        // A unit diagonal without declared volatilities is likely a correlation matrix supplied
+        // where covariances were intended, the risk factors are then all equally volatile
+        if self.volatilities.is_none() && self.cov.diag().iter().all(|&var| (var - 1.).abs() < 1e-6) {
+            warnings.push("Covariance matrix has a unit diagonal and is likely a correlation matrix, all risk factors are treated as equally volatile. Declare the volatilities in volatilities.csv to confirm".to_string());
+        }
+        warnings
+    }
+
+    /// Check the input for entries that are valid but economically suspicious and often indicate a
+    /// data error. Returns a list of all warnings found
+    pub fn warnings(&self) -> Vec<String> {
+        let rating_scale = rating::RatingScale::new(self.rating_labels.clone());
+        let mut warnings: Vec<String> = self.covariance_warnings();
+
+        // Staying in the current rating should be possible, otherwise the transition matrix is
+        // likely misaligned with the rating index
+        for borr in self.risk_groups.values().flatten() {
+            if let Some(&p) = self.mig_probs.get(&borr.borrower_id).and_then(|prob| prob.get(borr.rating))
+                && p < 1e-6 {
+                warnings.push(format!("Borrower {} has migration probability {} of staying in its current rating {}", borr.borrower_id, p, rating_scale.label(borr.rating)));
+            }
+            if self.mig_probs.get(&borr.borrower_id).is_some_and(|prob| prob.len() == 1) {
+                warnings.push(format!("Borrower {} has a single rating class and always stays in it", borr.borrower_id));
+            }
+            // Negative loadings are valid, but often a sign error of the input
+            let negative: Vec<String> = self.risk_factors.get(&borr.borrower_id).into_iter().flatten().enumerate()
+                .filter(|(_, w)| **w < 0.).map(|(index, _)| index.to_string()).collect();
+            if !negative.is_empty() {
+                warnings.push(format!("Borrower {} has negative weights on risk factors {}", borr.borrower_id, negative.join(", ")));
+            }
+        }
+        warnings.sort();
+
+        warnings
+    }
+
+    /// Join the input data and set up the portfolio with absolute valuations
+    pub fn into_portfolio(self) -> portfolio::Portfolio {
+        self.into_portfolio_with(exposure::ValuationMode::Absolute, 0.)
+    }
+
+    /// Join the input data and set up the portfolio given the interpretation of the valuations and
+    /// the diagonal jitter for a nearly positive semi-definite covariance matrix. Top-level risk
+    /// groups are added ordered by their identifier, each followed by its nested groups, so that
+    /// simulations are reproducible
+    pub fn into_portfolio_with(mut self, mode: exposure::ValuationMode, psd_jitter: f64) -> portfolio::Portfolio {
+        let rating_scale = rating::RatingScale::new(self.rating_labels);
+
+        let mut pf = portfolio::Portfolio::with_psd_jitter(self.cov, psd_jitter);
+
+        let mut groups: HashMap<String, risk_group::RiskGroup> = HashMap::new();
+        for (rg_id, borr_list) in self.risk_groups.drain() {
+            let mut rg = risk_group::RiskGroup::new(rg_id);
+
+            for borr in borr_list {
+                let prob = self.mig_probs.remove(&borr.borrower_id).expect("Probability not found");
+                let rf = self.risk_factors.remove(&borr.borrower_id).expect("Risk Factor not found");
+                let exp_list = self.exposures.remove(&borr.borrower_id).expect("Exposure List not found");
+
+                if borr.rating >= prob.len() {
+                    panic!("Borrower {} has current rating {} without migration probability", borr.borrower_id, rating_scale.label(borr.rating));
+                }
+
+                let num_ratings = prob.len();
+                let parent = borr.parent;
+                let own_rating_scale = borr.rating_classes.is_some();
+                let mut borr = borrower::Borrower::new(borr.borrower_id, rf, borr.rating, borr.r2, borr.eps, prob);
+                if parent {
+                    borr.set_parent();
+                }
+                if own_rating_scale {
+                    borr.set_own_rating_scale();
+                }
+                if let Some(thresholds) = self.thresholds.remove(borr.id()) {
+                    borr.set_thresholds(thresholds);
+                }
+                if let Some(&pd) = self.pd_overrides.get(borr.id()) {
+                    borr.override_default_probability(pd);
+                }
+
+                for exp in exp_list {
+                    let val = self.valuations.remove(&exp.exposure_id).expect("Valuation not found");
+
+                    if val.len() != num_ratings {
+                        panic!("Exposure {} has valuations up to rating {}, but borrower {} migrates up to rating {}", exp.exposure_id, rating_scale.label(val.len() - 1), borr.id(), rating_scale.label(num_ratings - 1));
+                    }
+
+                    // Amounts in base currency, prices and loss fractions scale with the outstanding
+                    let rate = exp.currency.as_ref().map_or(1., |currency| self.fx_rates[currency]);
+                    let val = match mode {
+                        exposure::ValuationMode::Absolute => val.iter().map(|v| rate * v).collect(),
+                        _ => val,
+                    };
+                    let mut facility = exposure::Exposure::with_mode(val, rate * exp.outstanding, mode);
+                    if let Some(rating) = exp.rating {
+                        facility.set_rating(rating);
+                    }
+                    if let Some(direction) = exp.direction {
+                        facility.set_direction(direction);
+                    }
+                    if let Some(curve) = self.discounting.as_ref() {
+                        facility.set_discount_factor(curve.discount_factor(exp.horizon.unwrap_or(exposure::DEFAULT_HORIZON)));
+                    }
+                    if let Some(guarantee) = self.guarantees.remove(&exp.exposure_id) {
+                        facility.set_guarantee(guarantee.guarantor_borrower_id, guarantee.coverage_fraction);
+                    }
+
+                    borr.add_exposure(facility);
+                }
+
+                rg.add_borrower(borr);
+            }
+
+            groups.insert(rg.id().to_string(), rg);
+        }
+
+        // Groups of the hierarchy without borrowers of their own, e.g. a global parent, are empty
+        // and the children of each group are nested in order of their identifier
+        let mut children: HashMap<&str, Vec<(&str, f64)>> = HashMap::new();
+        for link in self.hierarchy.values() {
+            for id in [&link.risk_group, &link.parent] {
+                groups.entry(id.to_string()).or_insert_with(|| risk_group::RiskGroup::new(id.to_string()));
+            }
+            children.entry(link.parent.as_str()).or_default().push((link.risk_group.as_str(), link.weight));
+        }
+        children.values_mut().for_each(|list| list.sort_by(|a, b| a.0.cmp(b.0)));
+
+        // Risk groups with a Student-t copula
+        for (id, &df) in self.copula.iter() {
+            groups.get_mut(id).unwrap_or_else(|| panic!("Risk group {} with degrees of freedom not found", id)).set_degrees_of_freedom(Some(df));
+        }
+
+        let mut roots: Vec<String> = groups.keys().filter(|id| !self.hierarchy.contains_key(*id)).cloned().collect();
+        roots.sort();
+        for root in roots {
+            pf.add_risk_group(nest(&root, &mut groups, &children));
+        }
+        assert!(groups.is_empty(), "Circular risk group hierarchy of {} risk groups", groups.len());
+
+        pf.set_rating_scale(rating_scale);
+        pf.freeze();
+
+        pf
+    }
+}
+
+/// Risk group with its child groups, removed from the groups by identifier
+fn nest(id: &str, groups: &mut HashMap<String, risk_group::RiskGroup>, children: &HashMap<&str, Vec<(&str, f64)>>) -> risk_group::RiskGroup {
+    let mut rg = groups.remove(id).expect("Risk group not found");
+    for &(child, weight) in children.get(id).into_iter().flatten() {
+        rg.add_child(nest(child, groups, children), weight);
+    }
+    rg
+}
+
+/// Result of a reader of an input file together with the time it took
+fn timed<T>(read: impl FnOnce() -> Result<T, String>) -> (Result<T, String>, Duration) {
+    let start = Instant::now();
+    let result = read();
+    (result, start.elapsed())
+}
+
+/// Wait for the reader of an input file
+fn join<T>(handle: std::thread::ScopedJoinHandle<'_, T>) -> T {
+    handle.join().expect("Input file reader panicked")
+}
+
+/// Record the failure and the read time of an input file, returns the data if it was read
+fn collect<T>(file: &str, (result, duration): (Result<T, String>, Duration), failures: &mut Vec<(String, String)>, durations: &mut Vec<(String, Duration)>) -> Option<T> {
+    durations.push((file.to_string(), duration));
+    result.map_err(|message| failures.push((file.to_string(), message))).ok()
+}
+
+/// Open an input file with headers
+fn open(path: &Path) -> Result<Reader<std::fs::File>, String> {
+    Reader::from_path(path).map_err(|error| format!("File could not be opened: {}", error))
+}
+
+/// Visit the rows of a large input file given by key and rating, like the migration
+/// probabilities or valuations, one at a time. The rows are read into a single reused record and
+/// borrow their key from it, so that a row allocates no memory unless it adds a new key
+fn for_each_rating_row<F>(path: &Path, mut visit: F) -> Result<(), String>
+where
+    F: FnMut(&StringRecord, &StringRecord) -> Result<(), String>,
+{
+    let mut rdr = open(path)?;
+    let headers = rdr.headers().map_err(|error| error.to_string())?.clone();
+    let mut record = StringRecord::new();
+    while rdr.read_record(&mut record).map_err(|error| error.to_string())? {
+        visit(&headers, &record)?;
+    }
+    Ok(())
+}
+
+/// Values by rating of a key, inserted with the capacity of the number of rating classes seen so
+/// far if the key is new
+fn rating_values<'m>(values: &'m mut HashMap<String, Vec<f64>>, key: &str, num_ratings: usize) -> &'m mut Vec<f64> {
+    if !values.contains_key(key) {
+        values.insert(key.to_string(), Vec::with_capacity(num_ratings));
+    }
+    values.get_mut(key).unwrap()
+}
+
+/// Set the value of a rating class, classes not given so far are set to the missing value.
+/// Returns false if the class was already given, i.e. with a NaN missing value a duplicate row
+fn set_rating_value(values: &mut Vec<f64>, rating: usize, value: f64, missing: f64) -> bool {
+    if values.len() < rating + 1 {
+        values.resize(rating + 1, missing);
+    }
+    let given = !values[rating].is_nan();
+    values[rating] = value;
+    !given
+}
+
+/// Check the migration probabilities and valuations against the number of rating classes, which
+/// is given by the rating labels or else by the largest rating of both files. A borrower with a
+/// single rating class always stays in it, its exposures have a single valuation. A borrower on a
+/// rating scale of its own needs exactly its number of classes and does not size the common
+/// scale. Returns the failures by file
+fn check_rating_dimensions(scale: &rating::RatingScale, risk_groups: &HashMap<String, Vec<Borrower>>, mig_probs: &HashMap<String, Vec<f64>>, exposures: &HashMap<String, Vec<Exposure>>, valuations: &mut HashMap<String, Vec<f64>>, missing: MissingValuations) -> Vec<(String, String)> {
+    let own_scales: HashMap<&str, usize> = risk_groups.values().flatten()
+        .filter_map(|borr| borr.rating_classes.map(|classes| (borr.borrower_id.as_str(), classes)))
+        .collect();
+    let own_exposures: HashSet<&str> = own_scales.keys()
+        .filter_map(|id| exposures.get(*id))
+        .flatten()
+        .map(|exp| exp.exposure_id.as_str())
+        .collect();
+    let num_ratings = match scale.num_labels() {
+        0 => mig_probs.iter().filter(|(id, _)| !own_scales.contains_key(id.as_str())).map(|(_, prob)| prob.len())
+            .chain(valuations.iter().filter(|(id, _)| !own_exposures.contains(id.as_str())).map(|(_, val)| val.len()))
+            .max().unwrap_or(0),
+        num_labels => num_labels,
+    };
+    let mut failures: Vec<(String, String)> = Vec::new();
+
+    for (id, prob) in mig_probs.iter() {
+        if let Some(&classes) = own_scales.get(id.as_str()) {
+            if prob.len() != classes {
+                failures.push(("transition_probabilities.csv".to_string(), format!("Borrower {} has migration probabilities up to rating {}, but its own rating scale has {} rating classes", id, scale.label(prob.len() - 1), classes)));
+            } else if let Some(rating) = prob.iter().position(|p| p.is_nan()) {
+                failures.push(("transition_probabilities.csv".to_string(), format!("Borrower {} has no migration probability into rating {}", id, scale.label(rating))));
+            }
+        } else if prob.len() > 1 && prob.len() < num_ratings {
+            failures.push(("transition_probabilities.csv".to_string(), format!("Borrower {} has migration probabilities up to rating {}, but there are {} rating classes", id, scale.label(prob.len() - 1), num_ratings)));
+        } else if let Some(rating) = prob.iter().position(|p| p.is_nan()) {
+            failures.push(("transition_probabilities.csv".to_string(), format!("Borrower {} has no migration probability into rating {}", id, scale.label(rating))));
+        }
+    }
+
+    let ratings: HashMap<&str, usize> = risk_groups.values().flatten().map(|borr| (borr.borrower_id.as_str(), borr.rating)).collect();
+    for (borrower_id, list) in exposures.iter() {
+        let expected = match (own_scales.get(borrower_id.as_str()), mig_probs.get(borrower_id)) {
+            (Some(&classes), _) => classes,
+            (None, Some(prob)) if prob.len() == 1 => 1,
+            _ => num_ratings,
+        };
+        for exp in list.iter() {
+            // Exposures without valuations are reported by the validation
+            let Some(val) = valuations.get_mut(&exp.exposure_id) else { continue };
+            if val.len() > expected {
+                failures.push(("valuations.csv".to_string(), format!("Exposure {} has valuations up to rating {}, but borrower {} migrates up to rating {}", exp.exposure_id, scale.label(val.len() - 1), borrower_id, scale.label(expected - 1))));
+                continue;
+            }
+            val.resize(expected, f64::NAN);
+
+            let Some(rating) = val.iter().position(|v| v.is_nan()) else { continue };
+            let current = exp.rating.or_else(|| ratings.get(borrower_id.as_str()).copied()).and_then(|current| val.get(current)).copied().filter(|v| !v.is_nan());
+            match (missing, current) {
+                (MissingValuations::Current, Some(current)) => val.iter_mut().filter(|v| v.is_nan()).for_each(|v| *v = current),
+                _ => failures.push(("valuations.csv".to_string(), format!("Exposure {} has no valuation of rating {}", exp.exposure_id, scale.label(rating)))),
+            }
+        }
+    }
+
+    failures.sort();
+    failures
+}
+
+/// Read the borrowers by risk group
+fn read_borrowers(path: &Path, scale: &rating::RatingScale) -> Result<HashMap<String, Vec<Borrower>>, String> {
+    let mut risk_groups: HashMap<String, Vec<Borrower>> = HashMap::new();
+    let mut ids: HashSet<String> = HashSet::new();
+    for result in open(path)?.deserialize() {
+        let row: BorrowerRecord = result.map_err(|error| error.to_string())?;
+        if !ids.insert(row.borrower_id.to_string()) {
+            return Err(format!("Borrower {} is listed more than once", row.borrower_id));
+        }
+        let rating = scale.resolve(&row.rating).map_err(|message| format!("Borrower {}: {}", row.borrower_id, message))?;
+        if row.rating_classes == Some(0) {
+            return Err(format!("Borrower {} has a rating scale without rating classes", row.borrower_id));
+        }
+        let borr = Borrower { borrower_id: row.borrower_id, risk_group: row.risk_group, rating, r2: row.r2, eps: row.eps, parent: row.parent, rating_classes: row.rating_classes };
+        match risk_groups.get_mut(&borr.risk_group) {
+            Some(group) => group.push(borr),
+            None => {
+                risk_groups.insert(borr.risk_group.to_string(), vec![borr]);
+            }
+        }
+    }
+    Ok(risk_groups)
+}
+
+/// Read the migration probabilities by borrower, the number of rating classes seen so far sizes
+/// the following borrowers. Probabilities not given are NaN until the rating dimensions are checked
+fn read_migration_probabilities(path: &Path, scale: &rating::RatingScale) -> Result<HashMap<String, Vec<f64>>, String> {
+    let mut mig_probs: HashMap<String, Vec<f64>> = HashMap::new();
+    let mut num_ratings: usize = 0;
+    for_each_rating_row(path, |headers, record| {
+        let row: MigrationProbRecord = record.deserialize(Some(headers)).map_err(|error| error.to_string())?;
+        let rating = scale.resolve(&row.rating).map_err(|message| format!("Borrower {}: {}", row.borrower_id, message))?;
+        let entry = rating_values(&mut mig_probs, row.borrower_id, num_ratings);
+        if !set_rating_value(entry, rating, row.probability, f64::NAN) {
+            return Err(format!("Borrower {} has more than one probability of rating {}", row.borrower_id, scale.label(rating)));
+        }
+        num_ratings = num_ratings.max(entry.len());
+        Ok(())
+    })?;
+    Ok(mig_probs)
+}
+
+/// Read the risk factor weights by borrower up to the largest given risk factor, weights not
+/// given are zero
+fn read_risk_factors(path: &Path) -> Result<HashMap<String, Vec<f64>>, String> {
+    let mut risk_factors: HashMap<String, Vec<f64>> = HashMap::new();
+    for result in open(path)?.deserialize() {
+        let row: RiskFactor = result.map_err(|error| error.to_string())?;
+        if !set_rating_value(risk_factors.entry(row.borrower_id.to_string()).or_default(), row.risk_factor, row.weight, f64::NAN) {
+            return Err(format!("Borrower {} has more than one weight of risk factor {}", row.borrower_id, row.risk_factor));
+        }
+    }
+    risk_factors.values_mut().flatten().filter(|w| w.is_nan()).for_each(|w| *w = 0.);
+    Ok(risk_factors)
+}
+
+/// Read the exposures by borrower
+fn read_exposures(path: &Path, scale: &rating::RatingScale, duplicates: Duplicates) -> Result<HashMap<String, Vec<Exposure>>, String> {
+    let mut exposures: HashMap<String, Vec<Exposure>> = HashMap::new();
+    let mut borrower_ids: HashMap<String, String> = HashMap::new();
+    for result in open(path)?.deserialize() {
+        let record: ExposureRecord = result.map_err(|error| error.to_string())?;
+        let rating = record.rating.map(|rating| scale.resolve(&rating)).transpose()
+            .map_err(|message| format!("Exposure {}: {}", record.exposure_id, message))?;
+        if let Some(horizon) = record.horizon.filter(|horizon| horizon.is_nan() || *horizon < 0.) {
+            return Err(format!("Exposure {} has negative horizon {}", record.exposure_id, horizon));
+        }
+
+        // An exposure under two borrowers, e.g. after a merger, would be counted twice, repeated
+        // rows of the same borrower are summed if allowed
+        if let Some(borrower_id) = borrower_ids.get(&record.exposure_id) {
+            if *borrower_id != record.borrower_id {
+                return Err(format!("Exposure {} is listed under borrowers {} and {}", record.exposure_id, borrower_id, record.borrower_id));
+            }
+            if duplicates == Duplicates::Error {
+                return Err(format!("Exposure {} is listed more than once", record.exposure_id));
+            }
+            let exp = exposures.get_mut(borrower_id).into_iter().flatten().find(|exp| exp.exposure_id == record.exposure_id).unwrap();
+            if exp.rating != rating || exp.direction != record.direction || exp.horizon != record.horizon || exp.currency != record.currency {
+                return Err(format!("Exposure {} is listed more than once with different ratings, directions, horizons or currencies", record.exposure_id));
+            }
+            exp.outstanding += record.outstanding;
+            continue;
+        }
+        borrower_ids.insert(record.exposure_id.to_string(), record.borrower_id.to_string());
+
+        let row = Exposure { exposure_id: record.exposure_id, borrower_id: record.borrower_id, outstanding: record.outstanding, rating, direction: record.direction, horizon: record.horizon, currency: record.currency };
+        match exposures.get_mut(&row.borrower_id) {
+            Some(list) => list.push(row),
+            None => {
+                exposures.insert(row.borrower_id.to_string(), vec![row]);
+            }
+        }
+    }
+    Ok(exposures)
+}
+
+/// Read the valuations by exposure, the number of rating classes seen so far sizes the following
+/// exposures. Valuations not given are NaN until the rating dimensions are checked
+fn read_valuations(path: &Path, scale: &rating::RatingScale, duplicates: Duplicates) -> Result<HashMap<String, Vec<f64>>, String> {
+    let mut valuations: HashMap<String, Vec<f64>> = HashMap::new();
+    let mut num_ratings: usize = 0;
+    for_each_rating_row(path, |headers, record| {
+        let row: ValuationRecord = record.deserialize(Some(headers)).map_err(|error| error.to_string())?;
+        let rating = scale.resolve(&row.rating).map_err(|message| format!("Exposure {}: {}", row.exposure_id, message))?;
+        let entry = rating_values(&mut valuations, row.exposure_id, num_ratings);
+        let given = entry.get(rating).copied().filter(|value| !value.is_nan());
+        match (given, duplicates) {
+            (Some(_), Duplicates::Error) => return Err(format!("Exposure {} has more than one valuation of rating {}", row.exposure_id, scale.label(rating))),
+            (Some(value), Duplicates::Sum) => entry[rating] = value + row.valuation,
+            (None, _) => {
+                set_rating_value(entry, rating, row.valuation, f64::NAN);
+            }
+        }
+        num_ratings = num_ratings.max(entry.len());
+        Ok(())
+    })?;
+    Ok(valuations)
+}
+
+/// Read the covariance matrix as list of cells
+fn read_covariance_cells(path: &Path) -> Result<Array2<f64>, String> {
+    // The size is only known at the end, so the cells are written into a grid that doubles its
+    // size whenever a cell does not fit
+    let mut num_risk_factors: usize = 0;
+    let mut grid: Array2<f64> = Array2::zeros((0, 0));
+    for result in open(path)?.deserialize() {
+        let cell: CovarianceCell = result.map_err(|error| error.to_string())?;
+        num_risk_factors = num_risk_factors.max(cell.risk_factor_1 + 1).max(cell.risk_factor_2 + 1);
+        if num_risk_factors > grid.nrows() {
+            let size = num_risk_factors.max(2 * grid.nrows());
+            let mut larger: Array2<f64> = Array2::zeros((size, size));
+            larger.slice_mut(s![..grid.nrows(), ..grid.ncols()]).assign(&grid);
+            grid = larger;
+        }
+        grid[[cell.risk_factor_1, cell.risk_factor_2]] = cell.correlation;
+    }
+
+    if num_risk_factors == 0 {
+        return Err("Covariance matrix is empty".to_string());
+    }
+    Ok(grid.slice(s![..num_risk_factors, ..num_risk_factors]).to_owned())
+}
+
+/// Read the covariance matrix as square grid without header, one row of the matrix per line
+fn read_dense_covariance(path: &Path) -> Result<Array2<f64>, String> {
+    let mut rdr = ReaderBuilder::new().has_headers(false).flexible(true).from_path(path)
+        .map_err(|error| format!("File could not be opened: {}", error))?;
+
+    let mut rows: Vec<Vec<f64>> = Vec::new();
+    for result in rdr.records() {
+        let record = result.map_err(|error| error.to_string())?;
+        let row: Vec<f64> = record.iter()
+            .map(|cell| cell.trim().parse().map_err(|_| format!("Invalid value {} in row {} of the dense covariance matrix", cell, rows.len())))
+            .collect::<Result<Vec<f64>, String>>()?;
+        rows.push(row);
+    }
+
+    let n = rows.len();
+    if n == 0 {
+        return Err("Dense covariance matrix is empty".to_string());
+    }
+    if let Some((index, row)) = rows.iter().enumerate().find(|(_, row)| row.len() != n) {
+        return Err(format!("Dense covariance matrix has {} rows, but row {} has {} columns", n, index, row.len()));
+    }
+
+    Ok(Array2::from_shape_vec((n, n), rows.concat()).unwrap())
+}
+
+/// Assemble the covariance matrix `L·Lᵀ + D` from the loadings `L` of the risk factors on the
+/// latent factors and the residual variances `D` of the risk factors, which must be given for all
+/// risk factors
+fn read_factor_model(loadings_path: &Path, residuals_path: &Path) -> Result<Array2<f64>, String> {
+    let mut loadings: Vec<Vec<f64>> = Vec::new();
+    for result in open(loadings_path)?.deserialize() {
+        let row: FactorLoading = result.map_err(|error| error.to_string())?;
+        if loadings.len() < row.risk_factor + 1 {
+            loadings.resize(row.risk_factor + 1, Vec::new());
+        }
+        set_rating_value(&mut loadings[row.risk_factor], row.latent_factor, row.loading, 0.);
+    }
+
+    let mut residuals: Vec<f64> = Vec::new();
+    let rdr = open(residuals_path).map_err(|message| format!("factor_residuals.csv: {}", message))?;
+    for result in rdr.into_deserialize() {
+        let row: FactorResidual = result.map_err(|error| format!("factor_residuals.csv: {}", error))?;
+        if row.variance.is_nan() || row.variance < 0. {
+            return Err(format!("Risk factor {} has residual variance {}, which must not be negative", row.risk_factor, row.variance));
+        }
+        set_rating_value(&mut residuals, row.risk_factor, row.variance, f64::NAN);
+    }
+
+    let num_risk_factors = loadings.len().max(residuals.len());
+    if num_risk_factors == 0 {
+        return Err("Factor model has no risk factors".to_string());
+    }
+    residuals.resize(num_risk_factors, f64::NAN);
+    if let Some(index) = residuals.iter().position(|x| x.is_nan()) {
+        return Err(format!("No residual variance given for risk factor {}", index));
+    }
+
+    let num_latent = loadings.iter().map(Vec::len).max().unwrap_or(0);
+    let lower = Array2::from_shape_fn((num_risk_factors, num_latent), |(i, k)| loadings.get(i).and_then(|row| row.get(k)).copied().unwrap_or(0.));
+    let mut cov = lower.dot(&lower.t());
+    cov.diag_mut().iter_mut().zip(residuals.iter()).for_each(|(x, residual)| *x += residual);
+    Ok(cov)
+}
+
+/// Read a stress scenario, i.e. the values of fixed systematic risk factors, from a file
+pub fn read_scenario(path: &Path) -> Vec<ScenarioFactor> {
+    let mut rdr = Reader::from_path(path).expect("Scenario file not found");
+
+    let mut factors: Vec<ScenarioFactor> = Vec::new();
+    for result in rdr.deserialize() {
+        let row: ScenarioFactor = result.unwrap();
+        factors.push(row);
+    }
+
+    factors
+}
+
+/// Read the multipliers of the probability of default by current rating from a file, ratings not
+/// given keep their probability of default, i.e. have the multiplier one
+pub fn read_pd_stress(path: &Path, scale: &rating::RatingScale) -> Vec<f64> {
+    let mut rdr = Reader::from_path(path).expect("PD stress file not found");
+    let rows: Vec<PdStressRecord> = rdr.deserialize().map(|result| result.unwrap()).collect();
+    rating_multipliers(rows, scale, "PD stress")
+}
+
+/// Multipliers of the probability of default by rating index, ratings not given have the
+/// multiplier one. The source names the file or scenario in failures
+fn rating_multipliers(rows: Vec<PdStressRecord>, scale: &rating::RatingScale, source: &str) -> Vec<f64> {
+    let mut multipliers: Vec<f64> = Vec::new();
+    for row in rows {
+        let rating = scale.resolve(&row.rating).unwrap_or_else(|message| panic!("{}: {}", source, message));
+        assert!(row.multiplier >= 0., "{} multiplier {} of rating {} must not be negative", source, row.multiplier, scale.label(rating));
+        assert!(set_rating_value(&mut multipliers, rating, row.multiplier, f64::NAN), "{} has more than one multiplier of rating {}", source, scale.label(rating));
+    }
+    multipliers.iter_mut().filter(|m| m.is_nan()).for_each(|m| *m = 1.);
+
+    multipliers
+}
+
+/// Read the macro scenarios of a mixture from a TOML file, an array of tables `scenarios` with
+/// `id`, `weight` and optionally the arrays of tables `pd_multipliers` (with `rating` and
+/// `multiplier`) and `factor_shifts` (with `risk_factor` and `shift`). The weights are normalized
+/// to sum to one
+pub fn read_scenarios(path: &Path, scale: &rating::RatingScale) -> Vec<MacroScenario> {
+    let content = std::fs::read_to_string(path).expect("Scenarios file not found");
+    let parsed: ScenariosFile = toml::from_str(&content).unwrap_or_else(|error| panic!("Scenarios file is invalid: {}", error));
+    assert!(!parsed.scenarios.is_empty(), "Scenarios file has no scenarios");
+
+    let mut ids: HashSet<String> = HashSet::new();
+    for scenario in parsed.scenarios.iter() {
+        assert!(ids.insert(scenario.id.clone()), "Scenario {} is listed more than once", scenario.id);
+        assert!(scenario.weight >= 0. && scenario.weight.is_finite(), "Weight {} of scenario {} must be finite and not negative", scenario.weight, scenario.id);
+    }
+    let total: f64 = parsed.scenarios.iter().map(|scenario| scenario.weight).sum();
+    assert!(total > 0., "Weights of the scenarios must not all be zero");
+
+    parsed.scenarios.into_iter().map(|scenario| {
+        let source = format!("Scenario {}", scenario.id);
+        let mut factors: HashSet<usize> = HashSet::new();
+        for shift in scenario.factor_shifts.iter() {
+            assert!(factors.insert(shift.risk_factor), "{} has more than one shift of risk factor {}", source, shift.risk_factor);
+        }
+        MacroScenario {
+            weight: scenario.weight / total,
+            pd_multipliers: rating_multipliers(scenario.pd_multipliers, scale, &source),
+            factor_shifts: scenario.factor_shifts.iter().map(|shift| (shift.risk_factor, shift.shift)).collect(),
+            id: scenario.id,
+        }
+    }).collect()
+}
+
+/// Read the systematic factors of the trials from a file with one row per trial and the columns
+/// `factor_0` to `factor_<n-1>` of all risk factors, the layout of `factor_draws.csv`. Other
+/// columns such as `trial` and `loss` are ignored
+pub fn read_factor_draws(path: &Path, num_risk_factors: usize) -> Array2<f64> {
+    let mut rdr = Reader::from_path(path).expect("Factor input file not found");
+    let headers = rdr.headers().expect("Factor input has no header").clone();
+
+    let mut columns: Vec<Option<usize>> = vec![None; num_risk_factors];
+    for (column, name) in headers.iter().enumerate() {
+        let Some(factor) = name.strip_prefix("factor_").and_then(|index| index.parse::<usize>().ok()) else { continue };
+        assert!(factor < num_risk_factors, "Factor input has the column {}, but the covariance matrix has {} risk factors", name, num_risk_factors);
+        assert!(columns[factor].replace(column).is_none(), "Factor input has more than one column {}", name);
+    }
+    if let Some(factor) = columns.iter().position(Option::is_none) {
+        panic!("Factor input has no column factor_{}, but the covariance matrix has {} risk factors", factor, num_risk_factors);
+    }
+
+    let mut values: Vec<f64> = Vec::new();
+    let mut record = StringRecord::new();
+    while rdr.read_record(&mut record).unwrap_or_else(|error| panic!("Factor input is invalid: {}", error)) {
+        for &column in columns.iter().flatten() {
+            let value = record[column].trim().parse::<f64>()
+                .unwrap_or_else(|_| panic!("Factor input has the invalid value {:?} of {} in trial {}", &record[column], &headers[column], values.len() / num_risk_factors));
+            values.push(value);
+        }
+    }
+    assert!(!values.is_empty(), "Factor input has no trials");
+
+    Array2::from_shape_vec((values.len() / num_risk_factors, num_risk_factors), values).unwrap()
+}
+
+/// Read the losses of a `loss_distribution.csv` written by a run or merge. A distribution in the
+/// P&L convention, with the column `PnL` instead of `Loss`, is negated to losses
+pub fn read_loss_distribution(path: &Path) -> Vec<f64> {
+    let mut rdr = Reader::from_path(path).unwrap_or_else(|_| panic!("Loss distribution {} not found", path.display()));
+    let headers = rdr.headers().expect("Loss distribution has no header").clone();
+    let pnl = match headers.get(0) {
+        Some("Loss") => false,
+        Some("PnL") => true,
+        _ => panic!("Loss distribution {} must have the column Loss or PnL", path.display()),
+    };
+
+    let loss: Vec<f64> = rdr.records().enumerate().map(|(trial, record)| {
+        let record = record.unwrap();
+        let value: f64 = record[0].trim().parse()
+            .unwrap_or_else(|_| panic!("Loss distribution {} has the invalid value {:?} in row {}", path.display(), &record[0], trial));
+        if pnl { 0. - value } else { value }
+    }).collect();
+    assert!(!loss.is_empty(), "Loss distribution {} has no trials", path.display());
+    loss
+}
+
+/// Read the optional overrides of the probability of default by borrower
+fn read_pd_overrides(path: &Path) -> Result<HashMap<String, f64>, String> {
+    // Without the file the probabilities of default are those of the migration probabilities
+    let file = path.join("pd_override.csv");
+    if !file.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let mut overrides: HashMap<String, f64> = HashMap::new();
+    for result in open(&file)?.deserialize() {
+        let row: PdOverride = result.map_err(|error| error.to_string())?;
+        if overrides.contains_key(&row.borrower_id) {
+            return Err(format!("Borrower {} has more than one PD override", row.borrower_id));
+        }
+        overrides.insert(row.borrower_id, row.pd);
+    }
+    Ok(overrides)
+}
+
+/// Read the optional rates converting the currencies of the exposures to the base currency
+fn read_fx_rates(path: &Path) -> Result<HashMap<String, f64>, String> {
+    // Without the file all exposures are in the base currency
+    let file = path.join("fx_rates.csv");
+    if !file.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let mut rates: HashMap<String, f64> = HashMap::new();
+    for result in open(&file)?.deserialize() {
+        let row: FxRate = result.map_err(|error| error.to_string())?;
+        if row.rate_to_base.is_nan() || row.rate_to_base <= 0. || row.rate_to_base.is_infinite() {
+            return Err(format!("Currency {} has non-positive rate {}", row.currency, row.rate_to_base));
+        }
+        if rates.contains_key(&row.currency) {
+            return Err(format!("Currency {} has more than one rate", row.currency));
+        }
+        rates.insert(row.currency, row.rate_to_base);
+    }
+    Ok(rates)
+}
+
+/// Currencies of exposures without a rate to the base currency, with the exposures in them
+fn check_currencies(exposures: &HashMap<String, Vec<Exposure>>, fx_rates: &HashMap<String, f64>) -> Vec<String> {
+    let mut missing: HashMap<&str, Vec<&str>> = HashMap::new();
+    for exp in exposures.values().flatten() {
+        if let Some(currency) = exp.currency.as_deref().filter(|currency| !fx_rates.contains_key(*currency)) {
+            missing.entry(currency).or_default().push(exp.exposure_id.as_str());
+        }
+    }
+
+    let mut failures: Vec<String> = missing.into_iter().map(|(currency, mut ids)| {
+        ids.sort();
+        format!("{} exposures have currency {} without rate: {}", ids.len(), currency, ids.join(", "))
+    }).collect();
+    failures.sort();
+    failures
+}
+
+/// Read the optional thresholds of the asset value by borrower, the threshold of a rating class
+/// separates it from the next worse one. Thresholds not given are NaN until they are checked
+fn read_thresholds(path: &Path, scale: &rating::RatingScale) -> Result<HashMap<String, Vec<f64>>, String> {
+    // Without the file the thresholds are derived from the migration probabilities
+    let file = path.join("thresholds.csv");
+    if !file.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let mut thresholds: HashMap<String, Vec<f64>> = HashMap::new();
+    for_each_rating_row(&file, |headers, record| {
+        let row: MigrationThresholdRecord = record.deserialize(Some(headers)).map_err(|error| error.to_string())?;
+        let rating = scale.resolve(&row.rating).map_err(|message| format!("Borrower {}: {}", row.borrower_id, message))?;
+        if row.threshold.is_nan() {
+            return Err(format!("Borrower {} has a NaN threshold of rating {}", row.borrower_id, scale.label(rating)));
+        }
+        if !set_rating_value(rating_values(&mut thresholds, row.borrower_id, 0), rating, row.threshold, f64::NAN) {
+            return Err(format!("Borrower {} has more than one threshold of rating {}", row.borrower_id, scale.label(rating)));
+        }
+        Ok(())
+    })?;
+    Ok(thresholds)
+}
+
+/// Check that the thresholds of each borrower separate all of its rating classes, one threshold
+/// below each class but the last, and are strictly increasing. Returns the failures
+fn check_thresholds(scale: &rating::RatingScale, mig_probs: &HashMap<String, Vec<f64>>, thresholds: &HashMap<String, Vec<f64>>) -> Vec<String> {
+    let mut failures: Vec<String> = Vec::new();
+    for (id, list) in thresholds.iter() {
+        // Thresholds of unknown borrowers are reported by the validation
+        let Some(prob) = mig_probs.get(id) else { continue };
+        if list.len() >= prob.len() {
+            failures.push(format!("Borrower {} has a threshold of rating {}, but migrates up to rating {}", id, scale.label(list.len() - 1), scale.label(prob.len() - 1)));
+        } else if let Some(rating) = (0..prob.len() - 1).find(|&rating| list.get(rating).is_none_or(|c| c.is_nan())) {
+            failures.push(format!("Borrower {} has no threshold of rating {}", id, scale.label(rating)));
+        } else if let Some(rating) = list.windows(2).position(|pair| pair[0] >= pair[1]) {
+            failures.push(format!("Borrower {} has thresholds that are not strictly increasing, the one of rating {} is not above the one of rating {}", id, scale.label(rating + 1), scale.label(rating)));
+        }
+    }
+    failures.sort();
+    failures
+}
+
+/// Read the optional guarantees by exposure
+fn read_guarantees(path: &Path) -> Result<HashMap<String, Guarantee>, String> {
+    // Without the file no exposure is guaranteed
+    let file = path.join("guarantees.csv");
+    if !file.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let mut guarantees: HashMap<String, Guarantee> = HashMap::new();
+    for result in open(&file)?.deserialize() {
+        let row: Guarantee = result.map_err(|error| error.to_string())?;
+        if !(0. ..=1.).contains(&row.coverage_fraction) {
+            return Err(format!("Exposure {} has coverage fraction {} outside of [0, 1]", row.exposure_id, row.coverage_fraction));
+        }
+        if guarantees.contains_key(&row.exposure_id) {
+            return Err(format!("Exposure {} has more than one guarantee", row.exposure_id));
+        }
+        guarantees.insert(row.exposure_id.to_string(), row);
+    }
+    Ok(guarantees)
+}
+
+/// Read the optional degrees of freedom of the Student-t copula by risk group
+fn read_copula(path: &Path) -> Result<HashMap<String, f64>, String> {
+    // Without the file all risk groups have the Gaussian copula
+    let file = path.join("risk_group_copula.csv");
+    if !file.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let mut copula: HashMap<String, f64> = HashMap::new();
+    for result in open(&file)?.deserialize() {
+        let row: RiskGroupCopula = result.map_err(|error| error.to_string())?;
+        if !(row.degrees_of_freedom > 0. && row.degrees_of_freedom.is_finite()) {
+            return Err(format!("Risk group {} has degrees of freedom {}, they must be positive", row.risk_group, row.degrees_of_freedom));
+        }
+        if copula.contains_key(&row.risk_group) {
+            return Err(format!("Risk group {} has more than one degrees of freedom", row.risk_group));
+        }
+        copula.insert(row.risk_group, row.degrees_of_freedom);
+    }
+    Ok(copula)
+}
+
+/// Read the optional parents of nested risk groups by risk group
+fn read_hierarchy(path: &Path) -> Result<HashMap<String, RiskGroupLink>, String> {
+    // Without the file all risk groups are top-level groups
+    let file = path.join("risk_group_hierarchy.csv");
+    if !file.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let mut hierarchy: HashMap<String, RiskGroupLink> = HashMap::new();
+    for result in open(&file)?.deserialize() {
+        let row: RiskGroupLink = result.map_err(|error| error.to_string())?;
+        if !(0. ..=1.).contains(&row.weight) {
+            return Err(format!("Risk group {} has weight {} on its parent {} outside of [0, 1]", row.risk_group, row.weight, row.parent));
+        }
+        if hierarchy.contains_key(&row.risk_group) {
+            return Err(format!("Risk group {} has more than one parent", row.risk_group));
+        }
+        hierarchy.insert(row.risk_group.to_string(), row);
+    }
+
+    if let Some(cycle) = hierarchy_cycle(&hierarchy) {
+        return Err(format!("Circular risk group hierarchy {}", cycle.join(" -> ")));
+    }
+    Ok(hierarchy)
+}
+
+/// First cycle of the risk group hierarchy, e.g. a group that is its own parent, as the groups
+/// along the cycle
+fn hierarchy_cycle(hierarchy: &HashMap<String, RiskGroupLink>) -> Option<Vec<String>> {
+    let edges: Vec<(&str, &str)> = hierarchy.values().map(|link| (link.risk_group.as_str(), link.parent.as_str())).collect();
+    portfolio::find_cycle(&edges)
+}
+
+/// Check that the guarantors are borrowers with as many rating classes as the obligors of the
+/// guaranteed exposures and that no borrower guarantees itself, directly or along a cycle
+fn check_guarantees(guarantees: &HashMap<String, Guarantee>, risk_groups: &HashMap<String, Vec<Borrower>>, mig_probs: &HashMap<String, Vec<f64>>, exposures: &HashMap<String, Vec<Exposure>>) -> Vec<String> {
+    if guarantees.is_empty() {
+        return Vec::new();
+    }
+    let borrower_ids: HashSet<&str> = risk_groups.values().flatten().map(|borr| borr.borrower_id.as_str()).collect();
+    let num_ratings = |id: &str| mig_probs.get(id).map(Vec::len).unwrap_or(0);
+
+    let mut failures: Vec<String> = Vec::new();
+    let mut edges: Vec<(&str, &str)> = Vec::new();
+    for exp in exposures.values().flatten() {
+        let Some(guarantee) = guarantees.get(&exp.exposure_id) else { continue };
+        let guarantor = guarantee.guarantor_borrower_id.as_str();
+        if !borrower_ids.contains(guarantor) {
+            failures.push(format!("Exposure {} is guaranteed by unknown borrower {}", exp.exposure_id, guarantor));
+        } else if num_ratings(guarantor) != num_ratings(&exp.borrower_id) {
+            failures.push(format!("Guarantor {} of exposure {} has {} rating classes, but borrower {} has {}", guarantor, exp.exposure_id, num_ratings(guarantor), exp.borrower_id, num_ratings(&exp.borrower_id)));
+        }
+        edges.push((exp.borrower_id.as_str(), guarantor));
+    }
+    failures.sort();
+
+    if let Some(cycle) = portfolio::find_cycle(&edges) {
+        failures.push(format!("Circular guarantee {}", cycle.join(" -> ")));
+    }
+    failures
+}
+
+/// Read the optional volatilities of the risk factors
+fn read_volatilities(path: &Path) -> Result<Option<Vec<f64>>, String> {
+    // Without the file the matrix is taken as covariance matrix
+    let file = path.join("volatilities.csv");
+    if !file.exists() {
+        return Ok(None);
+    }
+
+    let mut volatilities: Vec<f64> = Vec::new();
+    for result in open(&file)?.deserialize() {
+        let row: Volatility = result.map_err(|error| error.to_string())?;
+        if row.volatility.is_nan() || row.volatility <= 0. {
+            return Err(format!("Risk factor {} has non-positive volatility {}", row.risk_factor, row.volatility));
+        }
+        set_rating_value(&mut volatilities, row.risk_factor, row.volatility, 0.);
+    }
+
+    if let Some(index) = volatilities.iter().position(|&vol| vol == 0.) {
+        return Err(format!("No volatility given for risk factor {}", index));
+    }
+
+    Ok(Some(volatilities))
+}
+
+/// Read the discount factors by tenor if the valuations are discounted. Tenors must be positive
+/// and unique, discount factors positive
+fn read_discounting(path: &Path) -> Result<Option<exposure::DiscountCurve>, String> {
+    let file = path.join("discounting.csv");
+    if !file.exists() {
+        return Ok(None);
+    }
+
+    let mut points: Vec<(f64, f64)> = Vec::new();
+    for result in open(&file)?.deserialize() {
+        let row: DiscountPoint = result.map_err(|error| error.to_string())?;
+        if row.tenor.is_nan() || row.tenor <= 0. || row.tenor.is_infinite() {
+            return Err(format!("Tenor {} is not positive", row.tenor));
+        }
+        if row.discount_factor.is_nan() || row.discount_factor <= 0. || row.discount_factor.is_infinite() {
+            return Err(format!("Tenor {} has non-positive discount factor {}", row.tenor, row.discount_factor));
+        }
+        points.push((row.tenor, row.discount_factor));
+    }
+
+    if points.is_empty() {
+        return Err("No discount factors given".to_string());
+    }
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    if let Some(pair) = points.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        return Err(format!("Tenor {} has more than one discount factor", pair[0].0));
+    }
+
+    Ok(Some(exposure::DiscountCurve::Curve(points)))
+}
+
+/// Scale a correlation matrix with unit diagonal by the volatilities to the covariance matrix
+fn scale_correlation(cov: &mut Array2<f64>, volatilities: &[f64]) -> Result<(), String> {
+    if volatilities.len() != cov.nrows() {
+        return Err(format!("{} volatilities given for {} risk factors of the correlation matrix", volatilities.len(), cov.nrows()));
+    }
+    if let Some(index) = cov.diag().iter().position(|&x| (x - 1.).abs() > 1e-6) {
+        return Err(format!("Correlation matrix has diagonal value {} for risk factor {}, but must have a unit diagonal with volatilities given", cov[[index, index]], index));
+    }
+
+    for ((i, j), x) in cov.indexed_iter_mut() {
+        *x *= volatilities[i] * volatilities[j];
+    }
+    Ok(())
+}
+
+/// Read the rating scale with the labels ordered by index. The indices must be contiguous from 0
+/// and exactly one class, the last one, must be flagged as default
+fn read_rating_scale(path: &Path) -> Result<Vec<String>, String> {
+    let mut entries: Vec<RatingScaleEntry> = Vec::new();
+    for result in open(path)?.deserialize() {
+        entries.push(result.map_err(|error| error.to_string())?);
+    }
+    entries.sort_by_key(|entry| entry.index);
+
+    if let Some((position, entry)) = entries.iter().enumerate().find(|(position, entry)| entry.index != *position) {
+        return Err(format!("Rating indices must be contiguous from 0, but rating {} has index {} instead of {}", entry.label, entry.index, position));
+    }
+    let mut labels: Vec<String> = entries.iter().map(|entry| entry.label.to_string()).collect();
+    labels.sort();
+    if let Some(pair) = labels.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Err(format!("Label {} is given for more than one rating", pair[0]));
+    }
+
+    let defaults: Vec<&RatingScaleEntry> = entries.iter().filter(|entry| entry.is_default).collect();
+    match defaults.as_slice() {
+        [default] if default.index + 1 == entries.len() => {},
+        [default] => return Err(format!("Default rating {} must be the last rating with index {}", default.label, entries.len() - 1)),
+        _ => return Err(format!("Exactly one rating must be flagged as default, but {} are", defaults.len())),
+    }
+
+    Ok(entries.into_iter().map(|entry| entry.label).collect())
+}
+
+/// Read the optional rating labels
+fn read_rating_labels(path: &Path) -> Result<Vec<String>, String> {
+    // Labels are optional, without the file ratings are reported by their index
+    let file = path.join("rating_labels.csv");
+    if !file.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut labels: Vec<String> = Vec::new();
+    for result in open(&file)?.deserialize() {
+        let row: RatingLabel = result.map_err(|error| error.to_string())?;
+        if labels.len() < row.rating + 1 {
+            labels.resize(row.rating + 1, String::new());
+        }
+        labels[row.rating] = row.label;
+    }
+
+    if let Some(index) = labels.iter().position(|label| label.is_empty()) {
+        return Err(format!("No label given for rating {}", index));
+    }
+
+    Ok(labels)
+}
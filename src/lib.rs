@@ -2,3 +2,15 @@ pub mod exposure;
 pub mod borrower;
 pub mod risk_group;
 pub mod portfolio;
+pub mod migration;
+pub mod rating;
+pub mod statistics;
+pub mod summary;
+pub mod input;
+pub mod generate;
+pub mod rng;
+pub mod profile;
+pub mod simulation;
+pub mod shard;
+#[cfg(feature = "ffi")]
+pub mod ffi;
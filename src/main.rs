@@ -7,7 +7,7 @@
 //! The binary of the code takes the input data
 //! * Covariance Matrix
 //! * Borrower information (rating, correlation to external factors and groups of connected
-//! clients)
+//!   clients)
 //! * Migration probabilities of borrowers
 //! * Borrower dependency on external risk factors
 //! * Exposure information
@@ -15,31 +15,40 @@
 //!
 //! Based on this input, simulations are calculated and the total loss distribution is output
 
-use clap::Parser;
-use csv::{Reader, Writer};
-use serde::Deserialize;
+use clap::{Parser, Subcommand, ValueEnum};
+use csv::Writer;
+use std::fs::File;
+use std::io::Write;
 use std::path::Path;
-use std::collections::HashMap;
-use ndarray::Array2;
-use statrs::statistics::{Data, Distribution, Median, OrderStatistics};
+use std::time::{Duration, Instant};
+use rayon::prelude::*;
+use statrs::statistics::{Data, Distribution, Min, Max};
+use statrs::distribution::{ContinuousCDF, Normal};
 use chrono::Local;
 
-mod portfolio;
-mod risk_group;
-mod borrower;
-mod exposure;
+use credit_portfolio_model::{borrower, exposure, generate, input, migration, portfolio, profile, rating, rng, shard, simulation, statistics, summary};
+use rand_chacha::ChaCha8Rng;
+use rand_pcg::Pcg64;
+use rand_xoshiro::Xoshiro256PlusPlus;
 
 // Arguments
 #[derive(Parser, Debug)]
-#[command(author, version, about)]
+#[command(author, version, about, subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input path
-    #[arg(short, long)]
-    input: String,
+    #[arg(short, long, required = true)]
+    input: Option<String>,
+
+    /// Output path, `-` streams the data selected by --stdout-data to stdout instead
+    #[arg(short, long, required = true)]
+    output: Option<String>,
 
-    /// Output path
-    #[arg(short, long)]
-    output: String,
+    /// Data streamed to stdout with `--output -`, the printed results then go to stderr
+    #[arg(long, value_enum, default_value_t = StdoutData::LossDistribution)]
+    stdout_data: StdoutData,
 
     /// Number of simulated trials
     #[arg(short, long, default_value_t = 10)]
@@ -48,215 +57,1977 @@ struct Args {
     /// Number of simulated trials per thread
     #[arg(short, long, default_value_t = 10_000)]
     chunk_size: usize,
+
+    /// Seed of the random number generator
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Separate seed of the systematic factors, defaults to --seed. With a separate seed of either
+    /// the systematic or the idiosyncratic draws, both are sampled from generators of their own
+    #[arg(long, conflicts_with_all = ["seeds", "shard"])]
+    systematic_seed: Option<u64>,
+
+    /// Separate seed of the idiosyncratic draws of the risk groups and borrowers, defaults to
+    /// --seed
+    #[arg(long, conflicts_with_all = ["seeds", "shard"])]
+    idiosyncratic_seed: Option<u64>,
+
+    /// Number of threads, zero uses all available cores
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+
+    /// Random number generator
+    #[arg(long, value_enum, default_value_t = RngChoice::Pcg64)]
+    rng: RngChoice,
+
+    /// Sampling of the systematic factors: independent draws (mc) or a Latin hypercube sample of
+    /// each chunk of trials (lhs), which reduces the variance of estimates driven by the factors
+    #[arg(long, value_enum, default_value_t = SamplerChoice::Mc)]
+    sampler: SamplerChoice,
+
+    /// Rescale the systematic draws of each chunk of trials to a sample mean of exactly zero and a
+    /// sample covariance of exactly the identity before they are correlated
+    #[arg(long)]
+    moment_matching: bool,
+
+    /// Quantile levels for value at risk and expected shortfall (comma-separated)
+    #[arg(short, long, value_delimiter = ',', default_value = "0.9,0.99,0.999")]
+    quantiles: Vec<f64>,
+
+    /// Write value at risk and expected shortfall on a grid of quantile levels in basis point steps
+    /// from this level up to 99.99% to tail_quantiles.csv
+    #[arg(long)]
+    tail_quantiles: Option<f64>,
+
+    /// Output realized migration frequencies per rating class
+    #[arg(long)]
+    migration_frequencies: bool,
+
+    /// Break realized migration frequencies down by risk group (implies --migration-frequencies)
+    #[arg(long)]
+    migration_by_group: bool,
+
+    /// Output the realized migration matrix and the distribution of the number of defaults per
+    /// trial
+    #[arg(long)]
+    migration_matrix: bool,
+
+    /// Test the realized migration frequencies of each rating class against the input
+    /// probabilities (chi-square goodness-of-fit)
+    #[arg(long)]
+    self_test: bool,
+
+    /// Significance level of the self-test
+    #[arg(long, default_value_t = 0.001)]
+    self_test_level: f64,
+
+    /// Number of seeds to repeat the simulation with, starting at --seed, to assess the sampling
+    /// error of the risk measures
+    #[arg(long, default_value_t = 1)]
+    seeds: u64,
+
+    /// Loss thresholds to report the probability of a loss above, also points of the exceedance
+    /// curve (comma-separated)
+    #[arg(long, value_delimiter = ',')]
+    thresholds: Vec<f64>,
+
+    /// Number of equidistant thresholds of the exceedance curve between zero and the maximum
+    /// simulated loss
+    #[arg(long, default_value_t = 0)]
+    threshold_count: usize,
+
+    /// Borrowers to report the incremental value at risk and expected shortfall for, i.e. the
+    /// difference to the portfolio without the borrower (comma-separated)
+    #[arg(long, value_delimiter = ',')]
+    incremental: Vec<String>,
+
+    /// Quantile level of the expected shortfall to allocate to the risk groups, the trials are
+    /// replayed to average the losses per risk group in the tail
+    #[arg(long)]
+    es_contributions: Option<f64>,
+
+    /// Quantile level of the value at risk and expected shortfall to allocate to the borrowers
+    /// (Euler allocation), the trials are replayed to average the losses per borrower in the tail
+    /// and in a band of quantile levels around the value at risk
+    #[arg(long)]
+    contributions: Option<f64>,
+
+    /// Half width of the band of quantile levels around the value at risk whose trials estimate
+    /// the contributions to the value at risk
+    #[arg(long, default_value_t = 0.001, requires = "contributions")]
+    contribution_band: f64,
+
+    /// Quantile level of the tail to attribute to the rating classes the borrowers migrated to,
+    /// the trials are replayed to average the losses per destination rating over all trials and
+    /// over those at or above the value at risk
+    #[arg(long)]
+    rating_contributions: Option<f64>,
+
+    /// Quantile level of the tail to report the probability of default of every borrower
+    /// conditional on, the trials are replayed to count the defaults in the trials at or above the
+    /// value at risk
+    #[arg(long)]
+    tail_pd: Option<f64>,
+
+    /// Write the rating every borrower migrated to in the first N trials to ratings.csv, e.g. for
+    /// a valuation outside of this crate. The trials are replayed and match the loss distribution
+    #[arg(long)]
+    dump_ratings: Option<usize>,
+
+    /// Write the loss and the realized systematic factors of every trial to factor_draws.csv, e.g.
+    /// to regress the losses against the factors. The draws are replayed and streamed per chunk
+    #[arg(long, conflicts_with = "scenarios")]
+    export_factors: bool,
+
+    /// Interpretation of the valuations: absolute values, prices per unit of outstanding or loss
+    /// fractions of the outstanding
+    #[arg(long, value_enum, default_value_t = ValuationModeChoice::Absolute)]
+    valuation_mode: ValuationModeChoice,
+
+    /// Fill valuations missing for some rating classes of an exposure instead of failing, with
+    /// `current` the valuation of the current rating, i.e. migrating into them incurs no loss
+    #[arg(long, value_enum)]
+    fill_missing_valuations: Option<MissingValuationsChoice>,
+
+    /// Simulate borrowers without exposures, e.g. repaid loans still in the extract, with zero
+    /// valuations instead of failing. They consume their draws but contribute no loss
+    #[arg(long)]
+    allow_empty_borrowers: bool,
+
+    /// Sum the outstandings of exposure rows and the valuations of a rating class listed more than
+    /// once instead of failing. An exposure under two borrowers always fails
+    #[arg(long)]
+    sum_duplicates: bool,
+
+    /// Flat annually compounded rate discounting the valuations from the horizon of each exposure
+    /// to present value, replacing discounting.csv
+    #[arg(long)]
+    discount_rate: Option<f64>,
+
+    /// Currency the amounts of the exposures are converted to by fx_rates.csv, recorded in the
+    /// summary. If it is listed in fx_rates.csv, its rate must be 1
+    #[arg(long)]
+    base_currency: Option<String>,
+
+    /// Write the covariance matrix as used by the model, e.g. assembled from a factor model, to
+    /// covariance_matrix.csv for audit
+    #[arg(long)]
+    dump_covariance: bool,
+
+    /// Diagonal jitter added to a nearly positive semi-definite covariance matrix if its Cholesky
+    /// decomposition fails, e.g. 1e-10
+    #[arg(long, default_value_t = 0.)]
+    psd_jitter: f64,
+
+    /// Shrink the covariance matrix towards --shrinkage-target as (1 - lambda) * Σ + lambda * T
+    /// before its Cholesky decomposition, e.g. 0.1 for a noisy estimate. Zero leaves it unchanged
+    #[arg(long, default_value_t = 0.)]
+    shrinkage: f64,
+
+    /// Target of the shrinkage: identity (uncorrelated risk factors) or constant-correlation (the
+    /// average correlation), both keep the variances
+    #[arg(long, value_enum, default_value_t = ShrinkageTargetChoice::Identity)]
+    shrinkage_target: ShrinkageTargetChoice,
+
+    /// Downgrade the other members of a risk group by this many rating classes when its parent
+    /// borrower, flagged in borrower.csv, defaults. Zero disables contagion
+    #[arg(long, default_value_t = 0)]
+    contagion_notches: usize,
+
+    /// Rating class (label or index) that borrowers currently in it never leave, by default the
+    /// default class, whose borrowers keep their default loss in every trial
+    #[arg(long)]
+    absorbing_rating: Option<rating::RatingKey>,
+
+    /// Ordering of the rating classes along the asset value: low-is-best migrates low asset values
+    /// to the first rating class and high ones to default, low-is-default the other way round
+    #[arg(long, global = true, value_enum, default_value_t = ZConventionChoice::LowIsBest)]
+    z_convention: ZConventionChoice,
+
+    /// Degrees of freedom of a portfolio-wide Student-t copula of the borrowers of every risk group
+    /// without own degrees of freedom in risk_group_copula.csv, which share one mixing variable
+    #[arg(long)]
+    t_df: Option<f64>,
+
+    /// Stop the simulation before --num-trials once the standard error of the target metric
+    /// relative to its value is below this precision
+    #[arg(long, visible_alias = "target-error")]
+    target_precision: Option<f64>,
+
+    /// Metric of the target precision: mean, q<percent> (value at risk) or es<percent> (expected
+    /// shortfall)
+    #[arg(long, default_value = "q99.9")]
+    target_metric: statistics::TargetMetric,
+
+    /// Quantile level of the value at risk as metric of the target precision, e.g. 0.999, instead
+    /// of --target-metric
+    #[arg(long, requires = "target_precision", conflicts_with = "target_metric")]
+    on_quantile: Option<f64>,
+
+    /// Confidence level of the intervals around the simulated mean, value at risk and expected
+    /// shortfall
+    #[arg(long, default_value_t = 0.95)]
+    confidence: f64,
+
+    /// Number of bootstrap repetitions for confidence intervals of the value at risk
+    #[arg(long, default_value_t = 0)]
+    bootstrap: usize,
+
+    /// CSV file with systematic risk factors fixed to a stress scenario (columns `risk_factor`,
+    /// `value`), results are conditional on the scenario
+    #[arg(long)]
+    scenario: Option<String>,
+
+    /// CSV file with multipliers of the probability of default by current rating (columns
+    /// `rating`, `multiplier`), the other migration probabilities are rescaled to the remainder
+    #[arg(long)]
+    pd_stress: Option<String>,
+
+    /// TOML file with a mixture of macro scenarios, each with a weight, multipliers of the
+    /// probability of default by current rating and shifts of systematic factor means. The trials
+    /// are allocated to the scenarios by weight and blended into one loss distribution
+    #[arg(long, conflicts_with_all = ["pd_stress", "target_precision", "es_contributions", "contributions", "rating_contributions", "tail_pd", "dump_ratings", "seeds", "self_test"])]
+    scenarios: Option<String>,
+
+    /// Allocation of the trials to the macro scenarios: proportional to their weights, or equal
+    /// numbers of trials weighted by the scenario weights, which resolves the tail of rare
+    /// scenarios with more trials
+    #[arg(long, value_enum, default_value_t = ScenarioAllocationChoice::Proportional, requires = "scenarios")]
+    scenario_allocation: ScenarioAllocationChoice,
+
+    /// CSV file with the systematic factors of each trial (columns `factor_0` to `factor_<n-1>`,
+    /// e.g. factor_draws.csv), which replace the drawn factors, only the idiosyncratic draws are
+    /// sampled. The number of trials is the number of rows
+    #[arg(long, conflicts_with_all = ["scenario", "scenarios", "target_precision", "es_contributions", "contributions", "rating_contributions", "tail_pd", "dump_ratings", "export_factors", "self_test"])]
+    factor_input: Option<String>,
+
+    /// Write only this number of evenly spaced order statistics of the sorted losses to
+    /// loss_distribution.csv instead of every trial, the statistics use all trials
+    #[arg(long)]
+    sample_output: Option<usize>,
+
+    /// Also compute the loss of each trial expected given its systematic factors and risk group
+    /// draws, with the idiosyncratic risk of the borrowers integrated out analytically. The trials
+    /// are replayed and the conditional losses written as second column of loss_distribution.csv
+    #[arg(long, conflicts_with_all = ["scenarios", "factor_input", "sample_output", "shard", "contagion_notches", "t_df"])]
+    conditional_loss: bool,
+
+    /// Additional distributions to report (comma-separated): value for the portfolio value at the
+    /// horizon, i.e. the current value less the loss of each trial, with its lower quantiles
+    #[arg(long, value_enum, value_delimiter = ',')]
+    report: Vec<ReportChoice>,
+
+    /// Number of equidistant histogram bins between the minimum and maximum simulated loss
+    #[arg(long, default_value_t = 0)]
+    histogram_bins: usize,
+
+    /// CSV file with explicit histogram bin edges (column `edge`), overrides --histogram-bins
+    #[arg(long)]
+    histogram_edges: Option<String>,
+
+    /// Simulate structurally identical borrowers of a risk group (same rating, dependencies and
+    /// migration probabilities) as one. Caveat: the borrowers of a bucket then share their
+    /// idiosyncratic draw, i.e. they are perfectly correlated
+    #[arg(long)]
+    bucket_identical: bool,
+
+    /// Print the wall time of each phase of the run
+    #[arg(long)]
+    timings: bool,
+
+    /// Suppress the progress messages, only warnings and errors are logged
+    #[arg(long, global = true, conflicts_with = "log_level")]
+    quiet: bool,
+
+    /// Level of the messages logged to stderr
+    #[arg(long, global = true, value_enum, default_value_t = LogLevel::Info)]
+    log_level: LogLevel,
+
+    /// Format of the messages logged to stderr: text for reading or json lines for collection by
+    /// other systems
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Precision the simulated losses are stored in, f32 halves the memory of the loss
+    /// distribution while the simulation itself computes in f64
+    #[arg(long, value_enum, default_value_t = LossPrecision::F64)]
+    loss_precision: LossPrecision,
+
+    /// Sign convention of the printed results and the loss distribution: loss (positive values are
+    /// losses) or pnl (profit and loss, positive values are gains)
+    #[arg(long, value_enum, default_value_t = Convention::Loss)]
+    convention: Convention,
+
+    /// Simulate only the share `index/count` (zero-based index) of the chunks of trials and write
+    /// partial outputs, to be combined by the merge subcommand
+    #[arg(long, conflicts_with_all = ["target_precision", "incremental", "es_contributions", "contributions", "rating_contributions", "tail_pd", "dump_ratings", "seeds", "bootstrap", "scenarios", "export_factors", "factor_input"])]
+    shard: Option<shard::Shard>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum StdoutData {
+    LossDistribution,
+    Summary,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum ReportChoice {
+    Value,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
 
-// File input formats for serde
-#[derive(Debug, Deserialize)]
-struct CovarianceCell {
-    risk_factor_1: usize,
-    risk_factor_2: usize,
-    correlation: f64,
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum LogFormat {
+    Text,
+    Json,
 }
 
-#[derive(Debug, Deserialize)]
-struct Borrower {
-    borrower_id: String,
-    risk_group: String,
-    rating: usize,
-    r2: f64,
-    eps: f64,
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Convention {
+    Loss,
+    Pnl,
 }
 
-#[derive(Debug, Deserialize)]
-struct MigrationProb {
-    borrower_id: String,
-    rating: usize,
-    probability: f64,
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ZConventionChoice {
+    LowIsBest,
+    LowIsDefault,
 }
 
-#[derive(Debug, Deserialize)]
-struct RiskFactor {
-    borrower_id: String,
-    risk_factor: usize,
-    weight: f64,
+impl ZConventionChoice {
+    fn convention(&self) -> migration::ZConvention {
+        match self {
+            ZConventionChoice::LowIsBest => migration::ZConvention::LowIsBest,
+            ZConventionChoice::LowIsDefault => migration::ZConvention::LowIsDefault,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct Exposure {
-    exposure_id: String,
-    borrower_id: String,
-    outstanding: f64,
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LossPrecision {
+    F64,
+    F32,
 }
 
-#[derive(Debug, Deserialize)]
-struct Valuation {
-    exposure_id: String,
-    rating: usize,
-    valuation: f64,
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum RngChoice {
+    Pcg64,
+    Chacha8,
+    Philox,
+    Xoshiro,
 }
 
-fn read_input(path: &Path, risk_groups: &mut HashMap<String, Vec<Borrower>>, mig_probs: &mut HashMap<String, Vec<f64>>, risk_factors: &mut HashMap<String, Vec<f64>>, exposures: &mut HashMap<String, Vec<Exposure>>, valuations: &mut HashMap<String, Vec<f64>>) -> Array2<f64> {
-    // Covariance
-    let mut rdr = Reader::from_path(path.join("correlation_matrix.csv")).expect("Covariance file not found");
+/// Evaluate the expression with the type name bound to the generator of the `RngChoice`
+macro_rules! with_rng {
+    ($choice:expr, $rng:ident => $call:expr) => {
+        match $choice {
+            RngChoice::Pcg64 => {
+                type $rng = Pcg64;
+                $call
+            }
+            RngChoice::Chacha8 => {
+                type $rng = ChaCha8Rng;
+                $call
+            }
+            RngChoice::Philox => {
+                type $rng = rng::Philox4x32;
+                $call
+            }
+            RngChoice::Xoshiro => {
+                type $rng = Xoshiro256PlusPlus;
+                $call
+            }
+        }
+    };
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum SamplerChoice {
+    Mc,
+    Lhs,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ValuationModeChoice {
+    Absolute,
+    UnitPrice,
+    LossFraction,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ShrinkageTargetChoice {
+    Identity,
+    ConstantCorrelation,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum ScenarioAllocationChoice {
+    Proportional,
+    Equal,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum MissingValuationsChoice {
+    Current,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check the input files for inconsistencies without running a simulation
+    Validate {
+        /// Input path
+        #[arg(short, long)]
+        input: String,
+    },
+    /// Write a profile of the portfolio composition without running a simulation
+    Stats {
+        /// Input path
+        #[arg(short, long)]
+        input: String,
+
+        /// Output path
+        #[arg(short, long)]
+        output: String,
+
+        /// Number of largest borrowers to report
+        #[arg(long, default_value_t = 20)]
+        top: usize,
+    },
+    /// Write a synthetic portfolio as input files
+    Generate {
+        /// Output path
+        #[arg(short, long)]
+        output: String,
+
+        /// Number of borrowers
+        #[arg(long, default_value_t = 1_000)]
+        borrowers: usize,
+
+        /// Number of risk factors
+        #[arg(long, default_value_t = 10)]
+        risk_factors: usize,
+
+        /// Number of rating classes including default
+        #[arg(long, default_value_t = 8)]
+        ratings: usize,
+
+        /// Number of exposures per borrower
+        #[arg(long, default_value_t = 2)]
+        exposures_per_borrower: usize,
+
+        /// Average number of borrowers per risk group
+        #[arg(long, default_value_t = 4)]
+        borrowers_per_group: usize,
 
-    let mut cells: Vec<CovarianceCell> = Vec::new();
-    for result in rdr.deserialize() {
-        let cell: CovarianceCell = result.unwrap();
-        cells.push(cell);
+        /// Seed of the random number generator
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+
+        /// Number of distinct risk factor weight vectors shared by the borrowers, 0 for
+        /// individual weights of every borrower
+        #[arg(long, default_value_t = 0)]
+        weight_classes: usize,
+    },
+    /// Combine the partial outputs of all shards of a run into its loss distribution
+    Merge {
+        /// Directory with the partial outputs of the shards
+        #[arg(short, long)]
+        input: String,
+
+        /// Output path
+        #[arg(short, long)]
+        output: String,
+
+        /// Quantile levels for value at risk and expected shortfall (comma-separated)
+        #[arg(short, long, value_delimiter = ',', default_value = "0.9,0.99,0.999")]
+        quantiles: Vec<f64>,
+
+        /// Loss thresholds to report the probability of a loss above (comma-separated)
+        #[arg(long, value_delimiter = ',')]
+        thresholds: Vec<f64>,
+    },
+    /// Compare the loss distributions of two runs, e.g. before and after a parameter change
+    Compare {
+        /// Loss distribution of the first run
+        #[arg(long)]
+        before: String,
+
+        /// Loss distribution of the second run
+        #[arg(long)]
+        after: String,
+
+        /// Quantile levels for value at risk and expected shortfall (comma-separated)
+        #[arg(short, long, value_delimiter = ',', default_value = "0.9,0.99,0.999")]
+        quantiles: Vec<f64>,
+    },
+}
+
+
+fn write_migration_frequencies(path: &Path, pf: &portfolio::Portfolio, migrations: &migration::MigrationCounts, num_trials: usize) {
+    let expected = pf.expected_migrations(migrations.by_group());
+    let groups: Vec<&str> = pf.iter_risk_group().map(|rg| rg.id()).collect();
+    let sizes: Vec<usize> = pf.iter_risk_group().map(|rg| rg.num_borrower()).collect();
+
+    let mut writer = Writer::from_path(path).expect("Output path not found");
+    if migrations.by_group() {
+        writer.write_record(vec!["risk_group", "rating", "count", "frequency", "expected_frequency"]).unwrap();
+    } else {
+        writer.write_record(vec!["rating", "count", "frequency", "expected_frequency"]).unwrap();
+    }
+
+    for (row, counts) in migrations.counts().outer_iter().enumerate() {
+        // Number of borrowers the frequencies relate to
+        let num_borrower = if migrations.by_group() { sizes[row] } else { pf.num_borrower() };
+
+        for (rating, &count) in counts.iter().enumerate() {
+            let mut record: Vec<String> = Vec::new();
+            if migrations.by_group() {
+                record.push(groups[row].to_string());
+            }
+            record.push(pf.rating_column_label(rating));
+            record.push(count.to_string());
+            record.push((count as f64 / (num_trials * num_borrower) as f64).to_string());
+            record.push((expected[[row, rating]] / num_borrower as f64).to_string());
+            writer.write_record(record).unwrap();
+        }
     }
+}
+
+fn write_migration_matrix(path: &Path, pf: &portfolio::Portfolio, migrations: &migration::MigrationCounts) {
+    let expected = pf.expected_migration_matrix();
+
+    let mut writer = Writer::from_path(path).expect("Output path not found");
+    writer.write_record(vec!["from_rating", "to_rating", "count", "frequency", "probability", "relative_difference"]).unwrap();
 
-    let num_risk_factors = cells.iter().map(|x| x.risk_factor_1).max().expect("Only finite values") + 1;
+    for (current, (counts, probs)) in migrations.matrix().outer_iter().zip(expected.outer_iter()).enumerate() {
+        // Rating classes without borrowers have no migrations
+        let total = counts.sum();
+        if total == 0 {
+            continue;
+        }
 
-    let mut cov: Array2<f64> = Array2::zeros((num_risk_factors, num_risk_factors));
-    for cell in cells {
-        cov[[cell.risk_factor_1, cell.risk_factor_2]] = cell.correlation;
+        for (rating, (&count, &p)) in counts.iter().zip(probs.iter()).enumerate() {
+            let frequency = count as f64 / total as f64;
+            let probability = p / probs.sum();
+            let difference = if probability > 0. { (frequency / probability - 1.).to_string() } else { String::new() };
+            writer.write_record(vec![
+                pf.rating_column_label(current),
+                pf.rating_column_label(rating),
+                count.to_string(),
+                frequency.to_string(),
+                probability.to_string(),
+                difference,
+            ]).unwrap();
+        }
     }
-    
-    // Borrower
-    let mut rdr = Reader::from_path(path.join("borrower.csv")).expect("Borrower file not found");
+    writer.flush().unwrap();
+}
+
+fn write_default_counts(path: &Path, migrations: &migration::MigrationCounts, num_trials: usize) {
+    let mut writer = Writer::from_path(path).expect("Output path not found");
+    writer.write_record(vec!["count", "trials", "frequency"]).unwrap();
+    for (count, &trials) in migrations.defaults().iter().enumerate() {
+        writer.write_record(vec![count.to_string(), trials.to_string(), (trials as f64 / num_trials as f64).to_string()]).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+fn self_test(path: &Path, pf: &portfolio::Portfolio, migrations: &migration::MigrationCounts, level: f64) {
+    let expected = pf.expected_migration_matrix();
+
+    let mut writer = Writer::from_path(path).expect("Output path not found");
+    writer.write_record(vec!["rating", "migrations", "statistic", "degrees_of_freedom", "p_value", "passed"]).unwrap();
+
+    for (current, (counts, probs)) in migrations.matrix().outer_iter().zip(expected.outer_iter()).enumerate() {
+        // Rating classes without borrowers have no migrations
+        if counts.sum() == 0 {
+            continue;
+        }
+
+        let test = statistics::chi_square_test(counts.as_slice().unwrap(), probs.as_slice().unwrap());
+        let passed = test.p_value >= level;
+        if !passed {
+            log::warn!("Realized migrations of rating {} deviate from the input probabilities (p-value {:.2e})", pf.rating_column_label(current), test.p_value);
+        }
+
+        writer.write_record(vec![
+            pf.rating_column_label(current),
+            counts.sum().to_string(),
+            test.statistic.to_string(),
+            test.degrees_of_freedom.to_string(),
+            test.p_value.to_string(),
+            passed.to_string(),
+        ]).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+fn read_histogram_edges(path: &Path) -> Vec<f64> {
+    let mut reader = csv::Reader::from_path(path).expect("Histogram edges file not found");
+    reader.records()
+        .map(|record| record.expect("Histogram edges could not be read")[0].trim().parse::<f64>().expect("Histogram edge is not a number"))
+        .collect()
+}
+
+fn write_histogram(path: &Path, histogram: &statistics::Histogram) {
+    let total = histogram.total() as f64;
+    let mut cumulative = 0_u64;
+
+    let mut writer = Writer::from_path(path).expect("Output path not found");
+    writer.write_record(vec!["bin_lower", "bin_upper", "count", "cumulative_probability"]).unwrap();
+    for (lower, upper, count) in histogram.bins() {
+        cumulative += count;
+        writer.write_record(vec![lower.to_string(), upper.to_string(), count.to_string(), (cumulative as f64 / total).to_string()]).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+/// Seeds of the run, split only if a separate seed of the systematic or idiosyncratic draws is given
+fn seeds(args: &Args) -> rng::Seeds {
+    match (args.systematic_seed, args.idiosyncratic_seed) {
+        (None, None) => rng::Seeds::Single(args.seed),
+        (systematic, idiosyncratic) => rng::Seeds::Split {
+            systematic: systematic.unwrap_or(args.seed),
+            idiosyncratic: idiosyncratic.unwrap_or(args.seed),
+        },
+    }
+}
+
+fn simulate<L: simulation::LossScalar>(pf: &portfolio::Portfolio, args: &Args, num_trials: usize, seeds: rng::Seeds, tracking: migration::MigrationTracking, tracked: &[usize]) -> simulation::SimulationResult<L> {
+    with_rng!(args.rng, R => simulate_with::<R, L>(pf, args, num_trials, seeds, tracking, tracked))
+}
+
+/// Metric of the target precision, the value at risk of --on-quantile if given
+fn target_metric(args: &Args) -> statistics::TargetMetric {
+    match args.on_quantile {
+        Some(level) => {
+            assert!(level > 0. && level < 1., "Quantile level {} of the target precision outside of (0, 1)", level);
+            statistics::TargetMetric::Var(level)
+        }
+        None => args.target_metric,
+    }
+}
+
+fn simulate_with<R: rng::SimulationRng, L: simulation::LossScalar>(pf: &portfolio::Portfolio, args: &Args, num_trials: usize, seeds: rng::Seeds, tracking: migration::MigrationTracking, tracked: &[usize]) -> simulation::SimulationResult<L> {
+    match args.target_precision {
+        // Rounds of one chunk per thread until the target metric is precise enough
+        Some(precision) => pf.simulate_until::<R, L, _>(num_trials, args.chunk_size, seeds, tracking, tracked, rayon::current_num_threads(), |loss| {
+            target_metric(args).relative_error(loss, args.chunk_size).is_some_and(|error| error < precision)
+        }),
+        None => pf.simulate_until::<R, L, _>(num_trials, args.chunk_size, seeds, tracking, tracked, usize::MAX, |_| false),
+    }
+}
+
+/// Simulate the trials with the systematic factors of the factor input
+fn simulate_factors<L: simulation::LossScalar>(pf: &portfolio::Portfolio, args: &Args, factors: &ndarray::Array2<f64>, tracking: migration::MigrationTracking, tracked: &[usize]) -> simulation::SimulationResult<L> {
+    with_rng!(args.rng, R => pf.simulate_factors::<R, L>(factors, args.chunk_size, seeds(args), tracking, tracked))
+}
+
+/// Simulate the trials allocated to each macro scenario with a copy of the portfolio stressed by
+/// the scenario and seeds offset by the index of the scenario. The trials of the scenarios follow
+/// each other in the combined result, which comes with the statistics of each scenario. With an
+/// equal allocation, the expected loss per borrower is weighted by the scenario weights
+fn simulate_mixture<L: simulation::LossScalar>(pf: &portfolio::Portfolio, args: &Args, scenarios: &[input::MacroScenario], tracking: migration::MigrationTracking, tracked: &[usize]) -> (simulation::SimulationResult<L>, Vec<summary::MacroScenarioSummary>) {
+    let equal = args.scenario_allocation == ScenarioAllocationChoice::Equal;
+    let weights: Vec<f64> = scenarios.iter().map(|scenario| if equal { 1. } else { scenario.weight }).collect();
+    let allocation = simulation::allocate_trials(&weights, args.num_trials);
+
+    let mut combined = simulation::SimulationResult::<L> {
+        loss: Vec::with_capacity(args.num_trials),
+        expected_loss: ndarray::Array1::zeros(pf.num_borrower()),
+        migrations: None,
+        tracked: Vec::new(),
+    };
+    let mut summaries: Vec<summary::MacroScenarioSummary> = Vec::with_capacity(scenarios.len());
+    for (index, (scenario, &num_trials)) in scenarios.iter().zip(allocation.iter()).enumerate() {
+        let mut stressed = pf.clone();
+        stressed.stress_default_probabilities(&scenario.pd_multipliers);
+        stressed.shift_factors(&scenario.factor_shifts);
+        stressed.freeze();
+
+        let mut result = if num_trials > 0 {
+            simulate::<L>(&stressed, args, num_trials, seeds(args).offset(index as u64), tracking, tracked)
+        } else {
+            simulation::SimulationResult { loss: Vec::new(), expected_loss: ndarray::Array1::zeros(pf.num_borrower()), migrations: None, tracked: Vec::new() }
+        };
+
+        // Expected loss per borrower weighted by the trials or the weight of the scenario
+        let share = if equal { scenario.weight } else { num_trials as f64 / args.num_trials.max(1) as f64 };
+        combined.loss.extend_from_slice(&result.loss);
+        if num_trials > 0 {
+            combined.expected_loss.scaled_add(share, &result.expected_loss);
+        }
+        combined.tracked.extend_from_slice(&result.tracked);
+        match (combined.migrations.as_mut(), result.migrations.as_ref()) {
+            (Some(all), Some(migrations)) => all.merge(migrations),
+            (None, Some(migrations)) => combined.migrations = Some(migrations.clone()),
+            _ => {},
+        }
+
+        let mean = if num_trials > 0 { result.mean() } else { f64::NAN };
+        summaries.push(summary::MacroScenarioSummary {
+            id: scenario.id.clone(),
+            weight: scenario.weight,
+            num_trials,
+            expected_loss: stressed.expected_loss(),
+            mean,
+            quantiles: statistics::risk_measures(&mut result.loss, &args.quantiles),
+        });
+    }
+
+    (combined, summaries)
+}
+
+/// Loss of each trial with the macro scenario it was simulated in, in order of the trials
+fn write_scenario_losses<L: simulation::LossScalar>(path: &Path, header: &str, loss: &[L], scenarios: &[summary::MacroScenarioSummary], pnl: bool) {
+    let mut writer = Writer::from_path(path).expect("Output path not found");
+    writer.write_record(vec!["scenario", header]).unwrap();
+    let ids = scenarios.iter().flat_map(|scenario| std::iter::repeat_n(scenario.id.as_str(), scenario.num_trials));
+    for (id, l) in ids.zip(loss.iter()) {
+        let value = if pnl { 0. - l.to_f64() } else { l.to_f64() };
+        writer.write_record(vec![id.to_string(), value.to_string()]).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+/// Print the statistics conditional on each macro scenario in the sign convention
+fn print_macro_scenarios(report: &mut dyn Write, scenarios: &[summary::MacroScenarioSummary], pnl: bool) {
+    let signed = |x: f64| if pnl { 0. - x } else { x };
+    let level_of = |level: f64| if pnl { 1. - level } else { level };
+    let measure = if pnl { "PnL" } else { "Loss" };
+
+    let mut header = format!("{:<14}{:>15} {:>15} {:>15} {:>15}", "Scenario", "Weight", "Trials", format!("Exp {}", measure), "Mean");
+    for q in scenarios.first().map(|scenario| scenario.quantiles.as_slice()).unwrap_or_default() {
+        header.push_str(&format!(" {:>15} {:>15}", format!("({:.1}%)", level_of(q.level) * 100.), format!("ES ({:.1}%)", level_of(q.level) * 100.)));
+    }
+    writeln!(report, "{}", header).unwrap();
+    for scenario in scenarios.iter() {
+        let mut line = format!("{:<14}{:14.2}% {:15} {:15.2} {:15.2}", scenario.id, scenario.weight * 100., scenario.num_trials, signed(scenario.expected_loss), signed(scenario.mean));
+        for q in scenario.quantiles.iter() {
+            line.push_str(&format!(" {:15.2} {:15.2}", signed(q.var), signed(q.es)));
+        }
+        writeln!(report, "{}", line).unwrap();
+    }
+}
+
+/// Statistics conditional on each macro scenario, one row per scenario
+fn write_scenario_statistics(path: &Path, scenarios: &[summary::MacroScenarioSummary], levels: &[f64]) {
+    let mut writer = Writer::from_path(path).expect("Output path not found");
+    let mut header = vec!["scenario".to_string(), "weight".to_string(), "trials".to_string(), "expected_loss".to_string(), "mean".to_string()];
+    for level in levels.iter() {
+        header.push(format!("var_{}", level));
+        header.push(format!("es_{}", level));
+    }
+    writer.write_record(header).unwrap();
+    for scenario in scenarios.iter() {
+        let mut record = vec![scenario.id.clone(), scenario.weight.to_string(), scenario.num_trials.to_string(), scenario.expected_loss.to_string(), scenario.mean.to_string()];
+        for q in scenario.quantiles.iter() {
+            record.push(q.var.to_string());
+            record.push(q.es.to_string());
+        }
+        writer.write_record(record).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+/// Percentage of a contribution to the expected shortfall, empty if it is zero, e.g. without
+/// losses in the tail
+fn percentage(contribution: f64, es: f64) -> String {
+    if es == 0. {
+        return String::new();
+    }
+    (contribution / es * 100.).to_string()
+}
+
+fn es_contributions<L: simulation::LossScalar>(path: &Path, pf: &portfolio::Portfolio, args: &Args, num_trials: usize, level: f64, var: f64) {
+    let contributions = with_rng!(args.rng, R => pf.tail_contributions::<R, L>(num_trials, args.chunk_size, seeds(args), var));
+    let es = contributions.sum();
+
+    let mut writer = Writer::from_path(path).expect("Output path not found");
+    writer.write_record(vec!["risk_group", "level", "contribution", "percentage"]).unwrap();
+    for (rg, contribution) in pf.iter_risk_group().zip(contributions.iter()) {
+        writer.write_record(vec![rg.id().to_string(), level.to_string(), contribution.to_string(), percentage(*contribution, es)]).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+fn rating_contributions<L: simulation::LossScalar>(path: &Path, pf: &portfolio::Portfolio, args: &Args, num_trials: usize, level: f64, var: f64) {
+    let (mean, tail) = with_rng!(args.rng, R => pf.destination_contributions::<R, L>(num_trials, args.chunk_size, seeds(args), var));
+    let es = tail.sum();
+
+    let mut writer = Writer::from_path(path).expect("Output path not found");
+    writer.write_record(vec!["rating", "level", "mean_loss", "tail_loss", "tail_percentage"]).unwrap();
+    for (rating, (mean, tail)) in mean.iter().zip(tail.iter()).enumerate() {
+        writer.write_record(vec![pf.rating_column_label(rating), level.to_string(), mean.to_string(), tail.to_string(), percentage(*tail, es)]).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+/// Write the contributions of the borrowers to the value at risk, estimated from the trials with
+/// a loss between the quantiles of the band and rescaled to sum to the value at risk, and to the
+/// expected shortfall
+fn borrower_contributions<L: simulation::LossScalar>(path: &Path, pf: &portfolio::Portfolio, args: &Args, num_trials: usize, level: f64, band: (f64, f64), var: f64) {
+    let (var_contributions, es_contributions) = with_rng!(args.rng, R => pf.borrower_contributions::<R, L>(num_trials, args.chunk_size, seeds(args), band, var));
+    let band_sum = var_contributions.sum();
+    let var_contributions = if band_sum == 0. { var_contributions } else { &var_contributions * (var / band_sum) };
+    let es = es_contributions.sum();
+
+    let mut writer = Writer::from_path(path).expect("Output path not found");
+    writer.write_record(vec!["borrower_id", "level", "var_contribution", "es_contribution", "percentage"]).unwrap();
+    for ((borr, var_contribution), es_contribution) in pf.iter_risk_group().flat_map(|rg| rg.iter_borrower()).zip(var_contributions.iter()).zip(es_contributions.iter()) {
+        writer.write_record(vec![borr.id().to_string(), level.to_string(), var_contribution.to_string(), es_contribution.to_string(), percentage(*es_contribution, es)]).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+fn tail_default_probabilities<L: simulation::LossScalar>(path: &Path, pf: &portfolio::Portfolio, args: &Args, num_trials: usize, level: f64, var: f64) {
+    let tail_pd = with_rng!(args.rng, R => pf.tail_default_probabilities::<R, L>(num_trials, args.chunk_size, seeds(args), var));
 
-    let mut rows: Vec<Borrower> = Vec::new();
-    for result in rdr.deserialize() {
-        let row: Borrower = result.unwrap();
-        rows.push(row);
+    let mut writer = Writer::from_path(path).expect("Output path not found");
+    writer.write_record(vec!["borrower_id", "level", "pd", "tail_pd", "ratio"]).unwrap();
+    for (borr, tail_pd) in pf.iter_risk_group().flat_map(|rg| rg.iter_borrower()).zip(tail_pd.iter()) {
+        let pd = borr.default_probability();
+        let ratio = if pd > 0. { (tail_pd / pd).to_string() } else { String::new() };
+        writer.write_record(vec![borr.id().to_string(), level.to_string(), pd.to_string(), tail_pd.to_string(), ratio]).unwrap();
     }
+    writer.flush().unwrap();
+}
+
+fn dump_ratings(path: &Path, pf: &portfolio::Portfolio, args: &Args, num_trials: usize) {
+    let ratings = with_rng!(args.rng, R => pf.replay_ratings::<R>(num_trials, args.chunk_size, seeds(args)));
+    let borrowers: Vec<&borrower::Borrower> = pf.iter_risk_group().flat_map(|rg| rg.iter_borrower()).collect();
+
+    let mut writer = Writer::from_path(path).expect("Output path not found");
+    writer.write_record(vec!["trial", "borrower_id", "rating"]).unwrap();
+    for (trial, trial_ratings) in ratings.iter().enumerate() {
+        for (borr, &rating) in borrowers.iter().zip(trial_ratings.iter()) {
+            writer.write_record(vec![trial.to_string(), borr.id().to_string(), pf.rating_column_label(pf.rating_column(borr, rating))]).unwrap();
+        }
+    }
+    writer.flush().unwrap();
+}
+
+fn export_factors<L: simulation::LossScalar>(path: &Path, pf: &portfolio::Portfolio, args: &Args, loss: &[L]) {
+    let mut writer = Writer::from_path(path).expect("Output path not found");
+    let mut header = vec!["trial".to_string(), "loss".to_string()];
+    header.extend((0..pf.num_risk_factors()).map(|index| format!("factor_{}", index)));
+    writer.write_record(header).unwrap();
+
+    // Rows of a round of chunks in order of the trials
+    let write = |first: usize, factors: &[ndarray::Array1<f64>]| {
+        for (trial, rf) in (first..).zip(factors.iter()) {
+            let mut record = vec![trial.to_string(), loss[trial].to_string()];
+            record.extend(rf.iter().map(|x| x.to_string()));
+            writer.write_record(record).unwrap();
+        }
+    };
+    with_rng!(args.rng, R => pf.replay_factors::<R>(loss.len(), args.chunk_size, seeds(args), write));
+    writer.flush().unwrap();
+}
+
+fn incremental_var<L: simulation::LossScalar>(path: &Path, args: &Args, loss: &[L], tracked: &[f64]) {
+    let quantiles = statistics::risk_measures(&mut loss.to_vec(), &args.quantiles);
+
+    let mut writer = Writer::from_path(path).expect("Output path not found");
+    writer.write_record(vec!["borrower_id", "level", "var", "var_excluding", "incremental_var", "es", "es_excluding", "incremental_es"]).unwrap();
 
-    for borr in rows {
-        risk_groups.entry(borr.risk_group.to_string()).or_insert(Vec::new()).push(borr);
+    for (index, id) in args.incremental.iter().enumerate() {
+        // Portfolio loss without the borrower in each trial
+        let mut excluding: Vec<L> = loss.iter().zip(tracked.chunks(args.incremental.len()))
+            .map(|(l, row)| L::from_f64(l.to_f64() - row[index]))
+            .collect();
+        let excluding = statistics::risk_measures(&mut excluding, &args.quantiles);
+
+        for (with, without) in quantiles.iter().zip(excluding.iter()) {
+            writer.write_record(vec![
+                id.to_string(),
+                with.level.to_string(),
+                with.var.to_string(),
+                without.var.to_string(),
+                (with.var - without.var).to_string(),
+                with.es.to_string(),
+                without.es.to_string(),
+                (with.es - without.es).to_string(),
+            ]).unwrap();
+        }
     }
+    writer.flush().unwrap();
+}
 
-    // Transition probabilities
-    let mut rdr = Reader::from_path(path.join("transition_probabilities.csv")).expect("Transition probability file not found");
+/// Quantile levels in basis point steps from `from` up to 99.99%
+fn tail_levels(from: f64) -> Vec<f64> {
+    ((from * 1e4).round() as u32..10_000).map(|bp| bp as f64 / 1e4).collect()
+}
 
-    let mut rows: Vec<MigrationProb> = Vec::new();
-    for result in rdr.deserialize() {
-        let row: MigrationProb = result.unwrap();
-        rows.push(row);
+fn write_tail_quantiles(path: &Path, quantiles: &[summary::QuantileSummary], num_trials: usize) {
+    let mut writer = Writer::from_path(path).expect("Output path not found");
+    writer.write_record(vec!["level", "var", "var_standard_error", "es", "es_standard_error", "tail_trials"]).unwrap();
+    for q in quantiles.iter() {
+        let se = |se: Option<f64>| se.map(|se| se.to_string()).unwrap_or_default();
+        writer.write_record(vec![
+            q.level.to_string(),
+            q.var.to_string(),
+            se(q.var_standard_error),
+            q.es.to_string(),
+            se(q.es_standard_error),
+            ((1. - q.level) * num_trials as f64).round().to_string(),
+        ]).unwrap();
     }
+    writer.flush().unwrap();
+}
+
+fn seed_sweep<L: simulation::LossScalar>(report: &mut dyn Write, path: &Path, pf: &portfolio::Portfolio, args: &Args, mean: f64, quantiles: &[summary::QuantileSummary], pnl: bool) {
+    // The first seed has been simulated already, the others run in parallel to each other
+    let mut results: Vec<(u64, f64, Vec<summary::QuantileSummary>)> = vec![(args.seed, mean, quantiles.to_vec())];
+    results.par_extend((1..args.seeds).into_par_iter().map(|i| {
+        let seed = args.seed + i;
+        let mut result = simulate::<L>(pf, args, args.num_trials, rng::Seeds::Single(seed), migration::MigrationTracking::None, &[]);
+        let mean = result.mean();
+        let quantiles = statistics::risk_measures(&mut result.loss, &args.quantiles);
+        (seed, mean, quantiles)
+    }));
 
-    for row in rows {
-        let entry = &mut mig_probs.entry(row.borrower_id.to_string()).or_insert(Vec::new());
-        if entry.len() < row.rating + 1 {
-            entry.resize(row.rating + 1, 0.);
+    // One row per seed
+    let mut writer = Writer::from_path(path).expect("Output path not found");
+    let mut header = vec!["seed".to_string(), "mean".to_string()];
+    for level in args.quantiles.iter() {
+        header.push(format!("var_{}", level));
+        header.push(format!("es_{}", level));
+    }
+    writer.write_record(header).unwrap();
+    for (seed, mean, quantiles) in results.iter() {
+        let mut record = vec![seed.to_string(), mean.to_string()];
+        for q in quantiles.iter() {
+            record.push(q.var.to_string());
+            record.push(q.es.to_string());
         }
-        entry[row.rating] = row.probability;
+        writer.write_record(record).unwrap();
     }
 
-    // Risk Factors
-    let mut rdr = Reader::from_path(path.join("risk_factors.csv")).expect("Risk Factor file not found");
+    // Distribution of the measures across seeds, printed in the sign convention like the main report
+    let signed = |x: f64| if pnl { 0. - x } else { x };
+    let level_of = |level: f64| if pnl { 1. - level } else { level };
+    let mut measures: Vec<(String, Vec<f64>)> = vec![("Mean".to_string(), results.iter().map(|r| signed(r.1)).collect())];
+    for (index, level) in args.quantiles.iter().enumerate() {
+        measures.push((format!("({:.1}%)", level_of(*level) * 100.), results.iter().map(|r| signed(r.2[index].var)).collect()));
+        measures.push((format!("ES ({:.1}%)", level_of(*level) * 100.), results.iter().map(|r| signed(r.2[index].es)).collect()));
+    }
 
-    let mut rows: Vec<RiskFactor> = Vec::new();
-    for result in rdr.deserialize() {
-        let row: RiskFactor = result.unwrap();
-        rows.push(row);
+    writeln!(report, "Seed sweep over {} seeds", args.seeds).unwrap();
+    writeln!(report, "{:<14}{:>15} {:>15} {:>15} {:>15}", "", "Mean", "Std", "Min", "Max").unwrap();
+    for (name, values) in measures {
+        let data = Data::new(values);
+        writeln!(report, "{:<14}{:15.2} {:15.2} {:15.2} {:15.2}", name, data.mean().unwrap(), data.std_dev().unwrap(), data.min(), data.max()).unwrap();
     }
+}
+
+/// Probability of a loss above each threshold, `None` without thresholds
+fn exceedance_probabilities<L: simulation::LossScalar>(loss: &[L], thresholds: &[f64]) -> Option<Vec<summary::ExceedanceSummary>> {
+    (!thresholds.is_empty()).then(|| thresholds.iter()
+        .map(|&threshold| summary::ExceedanceSummary { threshold, probability: portfolio::Portfolio::exceedance_probability(loss, threshold) })
+        .collect())
+}
+
+/// Write the loss (or profit and loss) of each trial as csv with the given header, to a file or to
+/// stdout
+fn write_loss_distribution<L: std::fmt::Display>(output: impl Write, header: &str, loss: impl IntoIterator<Item = L>) {
+    let mut writer = Writer::from_writer(output);
+    writer.write_record(vec![header]).unwrap();
+    loss.into_iter().for_each(|row| writer.write_record(vec![row.to_string()]).unwrap());
+    writer.flush().unwrap();
+}
+
+/// Write the realized and the conditional loss of each trial in order of the trials
+fn write_conditional_loss_distribution<L: std::fmt::Display>(output: impl Write, header: &str, loss: impl IntoIterator<Item = L>, conditional: &[f64]) {
+    let mut writer = Writer::from_writer(output);
+    writer.write_record(vec![header.to_string(), format!("Conditional {}", header)]).unwrap();
+    loss.into_iter().zip(conditional.iter()).for_each(|(l, c)| writer.write_record(vec![l.to_string(), c.to_string()]).unwrap());
+    writer.flush().unwrap();
+}
+
+/// Statistics of the portfolio value at the horizon of each trial, i.e. the current value less the
+/// loss including the relief of guarantees, with the lower quantiles at the complements of the
+/// quantile levels. These are the negated risk measures of the negated values, so that weighted
+/// trials are supported as well
+fn value_summary(values: &[f64], weights: Option<&[f64]>, levels: &[f64]) -> summary::ValueSummary {
+    let mut negated: Vec<f64> = values.iter().map(|v| -v).collect();
+    let quantiles = statistics::risk_measures_with(&mut negated, weights, levels).into_iter()
+        .map(|q| summary::ValueQuantileSummary { level: 1. - q.level, value: -q.var, tail_mean: -q.es })
+        .collect();
+    let (mean, median) = match weights {
+        Some(weights) => (statistics::Moments::weighted(values, weights).mean(), statistics::weighted_quantile(values, weights, 0.5)),
+        None => (values.iter().copied().sum::<statistics::CompensatedSum>().value() / values.len() as f64, statistics::median(&mut values.to_vec())),
+    };
+    summary::ValueSummary { mean, median, quantiles }
+}
+
+fn conditional_losses(pf: &portfolio::Portfolio, args: &Args, num_trials: usize) -> Vec<f64> {
+    with_rng!(args.rng, R => pf.conditional_losses::<R>(num_trials, args.chunk_size, seeds(args)))
+}
 
-    for row in rows {
-        let entry = &mut risk_factors.entry(row.borrower_id.to_string()).or_insert(vec![0.; num_risk_factors]);
-        entry[row.risk_factor] = row.weight;
+/// Loss rates relative to the current portfolio value, none with a warning unless the value is
+/// positive, so that the outputs are not filled with infinite or undefined rates
+fn loss_rates(total_value: f64, mean: f64, median: f64, quantiles: &[summary::QuantileSummary]) -> Option<summary::LossRateSummary> {
+    if total_value.partial_cmp(&0.) != Some(std::cmp::Ordering::Greater) {
+        log::warn!("Portfolio value {} is not positive, loss rates are not reported", total_value);
+        return None;
     }
+    let rate = |x: f64| x / total_value;
+    Some(summary::LossRateSummary {
+        mean: rate(mean),
+        median: rate(median),
+        quantiles: quantiles.iter().map(|q| summary::QuantileSummary {
+            level: q.level,
+            var: rate(q.var),
+            es: rate(q.es),
+            var_standard_error: q.var_standard_error.map(rate),
+            es_standard_error: q.es_standard_error.map(rate),
+        }).collect(),
+    })
+}
 
-    // Exposure
-    let mut rdr = Reader::from_path(path.join("exposures.csv")).expect("Exposure file not found");
+/// Print the loss rates in percent, negated with the quantile levels of the P&L convention
+fn print_loss_rates(report: &mut dyn Write, rates: Option<&summary::LossRateSummary>, pnl: bool) {
+    let Some(rates) = rates else { return };
+    let signed = |x: f64| if pnl { 0. - x } else { x };
+    let level_of = |level: f64| if pnl { 1. - level } else { level };
+    writeln!(report, "Rate Mean:    {:14.4}%", signed(rates.mean) * 100.).unwrap();
+    writeln!(report, "Rate Median:  {:14.4}%", signed(rates.median) * 100.).unwrap();
+    for q in rates.quantiles.iter() {
+        writeln!(report, "{:<14}{:14.4}%", format!("Rate ({:.1}%):", level_of(q.level) * 100.), signed(q.var) * 100.).unwrap();
+    }
+    for q in rates.quantiles.iter() {
+        writeln!(report, "{:<14}{:14.4}%", format!("Rate ES {:.1}%:", level_of(q.level) * 100.), signed(q.es) * 100.).unwrap();
+    }
+}
 
-    let mut rows: Vec<Exposure> = Vec::new();
-    for result in rdr.deserialize() {
-        let row: Exposure = result.unwrap();
-        rows.push(row);
+fn print_exceedance(report: &mut dyn Write, exceedance: Option<&[summary::ExceedanceSummary]>) {
+    for e in exceedance.unwrap_or_default() {
+        writeln!(report, "{:<14}{:15.6}", format!("P(L>{}):", e.threshold), e.probability).unwrap();
     }
+}
 
-    for row in rows {
-        exposures.entry(row.borrower_id.to_string()).or_insert(Vec::new()).push(row);
+fn print_timings(report: &mut dyn Write, durations: &summary::Durations, files: &[(String, Duration)]) {
+    writeln!(report, "{:<34}{:>12}", "Phase", "Seconds").unwrap();
+    let mut phases: Vec<(String, Option<f64>)> = vec![
+        ("Initialization".to_string(), Some(durations.initialization)),
+        ("  Reading".to_string(), durations.reading),
+    ];
+    phases.extend(files.iter().map(|(file, duration)| (format!("    {}", file), Some(duration.as_secs_f64()))));
+    phases.extend([
+        ("  Cholesky".to_string(), durations.cholesky),
+        ("  Norms".to_string(), durations.norms),
+        ("Simulation".to_string(), Some(durations.simulation)),
+        ("Statistics".to_string(), durations.statistics),
+        ("Output".to_string(), Some(durations.output)),
+    ]);
+    for (phase, seconds) in phases {
+        if let Some(seconds) = seconds {
+            writeln!(report, "{:<34}{:12.3}", phase, seconds).unwrap();
+        }
     }
+}
+
+fn validate(path: &Path, z_convention: migration::ZConvention) {
+    let input = input::Input::read(path);
+    let issues = input.validate();
 
-    // Valuations
-    let mut rdr = Reader::from_path(path.join("valuations.csv")).expect("Valuation file not found");
+    println!("Z convention: {}", z_convention.describe());
+
+    for warning in input.warnings().iter() {
+        log::warn!("{}", warning);
+    }
+    for issue in issues.iter() {
+        log::error!("{}", issue);
+    }
 
-    let mut rows: Vec<Valuation> = Vec::new();
-    for result in rdr.deserialize() {
-        let row: Valuation = result.unwrap();
-        rows.push(row);
+    if issues.is_empty() {
+        println!("No issues found");
+    } else {
+        println!("{} issues found", issues.len());
+        std::process::exit(1);
     }
+}
+
+fn stats(input: &Path, output: &Path, top: usize) {
+    let pf = input::Input::read(input).into_portfolio();
+    let profile = profile::PortfolioProfile::new(&pf, top);
+
+    println!("Borrowers:    {:15}", profile.borrowers);
+    println!("Exposures:    {:15}", profile.exposures);
+    println!("Total Value:  {:15.2}", profile.total_value);
+    println!("Herfindahl:   {:15.4}", profile.herfindahl_index);
+
+    let variance = pf.variance_decomposition();
+    println!("Systematic:   {:15.4}", variance.systematic);
+    println!("Risk Group:   {:15.4}", variance.group);
+    println!("Idiosyncratic:{:15.4}", variance.idiosyncratic);
+
+    profile.write_csv(&output.join("portfolio_profile.csv"));
+    profile.write_json(&output.join("portfolio_profile.json"));
+    write_variance_decomposition(&output.join("variance_decomposition.csv"), &pf);
+}
 
-    for row in rows {
-        let entry = &mut valuations.entry(row.exposure_id.to_string()).or_insert(Vec::new());
-        if entry.len() < row.rating + 1 {
-            entry.resize(row.rating + 1, 0.);
+/// Write the variance decomposition of the asset value of each borrower and of the portfolio,
+/// weighted by the outstanding amounts
+fn write_variance_decomposition(path: &Path, pf: &portfolio::Portfolio) {
+    let mut writer = Writer::from_path(path).expect("Output path not found");
+    writer.write_record(vec!["section", "name", "outstanding", "systematic", "group", "idiosyncratic"]).unwrap();
+    let total = pf.variance_decomposition();
+    let outstanding: f64 = pf.iter_risk_group().flat_map(|rg| rg.iter_borrower()).map(|borr| borr.outstanding()).sum();
+    writer.write_record(vec!["portfolio".to_string(), "total".to_string(), outstanding.to_string(), total.systematic.to_string(), total.group.to_string(), total.idiosyncratic.to_string()]).unwrap();
+
+    for borr in pf.iter_risk_group().flat_map(|rg| rg.iter_borrower()) {
+        let decomposition = borr.variance_decomposition();
+        writer.write_record(vec!["borrower".to_string(), borr.id().to_string(), borr.outstanding().to_string(), decomposition.systematic.to_string(), decomposition.group.to_string(), decomposition.idiosyncratic.to_string()]).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+fn simulate_shard(pf: &portfolio::Portfolio, args: &Args, shard: shard::Shard, init_duration: f64) {
+    let num_chunks = args.num_trials.div_ceil(args.chunk_size);
+    let chunks = shard.chunks(num_chunks);
+
+    let sim = Instant::now();
+    let result = with_rng!(args.rng, R => pf.simulate_chunk_range::<R>(args.num_trials, args.chunk_size, args.seed, chunks.clone()));
+    log::info!("Shard {} simulated {} trials", shard.index, result.num_trials());
+
+    let partial = shard::PartialResult {
+        info: shard::ShardInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            input: args.input.clone().expect("Input path required"),
+            rng: args.rng.to_possible_value().expect("Random number generator has a name").get_name().to_string(),
+            sampler: args.sampler.to_possible_value().expect("Sampler has a name").get_name().to_string(),
+            moment_matching: args.moment_matching,
+            seed: args.seed,
+            num_trials: args.num_trials,
+            chunk_size: args.chunk_size,
+            shard_index: shard.index,
+            shard_count: shard.count,
+            first_trial: (chunks.start * args.chunk_size).min(args.num_trials),
+            trials: result.num_trials(),
+            initialization: init_duration,
+            simulation: sim.elapsed().as_secs_f64(),
+            portfolio: summary::PortfolioSize {
+                borrowers: pf.num_borrower(),
+                exposures: pf.num_exposures(),
+                risk_factors: pf.num_risk_factors(),
+                rating_classes: pf.num_ratings(),
+            },
+            expected_loss: pf.expected_loss(),
+            mean_scenario_loss: pf.mean_scenario_loss(),
+            total_value: pf.total_value(),
+            total_outstanding: pf.total_outstanding(),
+            average_rating: pf.average_rating(),
+            expected_average_rating: pf.expected_average_rating(),
+            scenario: args.scenario.as_ref().map(|path| input::read_scenario(Path::new(path))),
+            base_currency: args.base_currency.clone(),
+        },
+        borrower_ids: pf.iter_risk_group().flat_map(|rg| rg.iter_borrower()).map(|borr| borr.id().to_string()).collect(),
+        borrower_loss: result.expected_loss.iter().map(|el| el * result.num_trials() as f64).collect(),
+        loss: result.loss,
+    };
+    partial.write(Path::new(args.output.as_ref().expect("Output path required")));
+}
+
+fn merge(input: &Path, output: &Path, levels: &[f64], thresholds: &[f64]) {
+    let reading = Instant::now();
+    let merged = shard::merge(shard::read_dir(input));
+    let info = &merged.info;
+    let reading_duration = reading.elapsed();
+    let statistics_start = Instant::now();
+    log::info!("Merged {} trials", info.num_trials);
+
+    let mut loss = merged.loss.clone();
+    let standard_errors = statistics::batch_standard_errors(&loss, info.chunk_size, levels);
+    let mut quantiles = statistics::risk_measures(&mut loss, levels);
+    for (q, (var_se, es_se)) in quantiles.iter_mut().zip(standard_errors.unwrap_or_default()) {
+        q.var_standard_error = Some(var_se);
+        q.es_standard_error = Some(es_se);
+    }
+    let mut moments = statistics::Moments::default();
+    loss.iter().for_each(|&l| moments.add(l));
+    let mean_standard_error = (moments.count() > 1).then(|| (moments.variance() / moments.count() as f64).sqrt());
+    let median = statistics::median(&mut loss);
+    let el = merged.expected_loss();
+    let exceedance = exceedance_probabilities(&loss, thresholds);
+
+    println!("Value:        {:15.2}", info.total_value);
+    println!("Outstanding:  {:15.2}", info.total_outstanding);
+    println!("Exp Loss:     {:15.2}", info.expected_loss);
+    println!("Exp Loss Sim: {:15.2}", el.iter().sum::<f64>());
+    println!("Mean:         {:15.2}", moments.mean());
+    println!("Median:       {:15.2}", median);
+    for q in quantiles.iter() {
+        println!("{:<14}{:15.2}", format!("({:.1}%):", q.level * 100.), q.var);
+    }
+    for q in quantiles.iter() {
+        println!("{:<14}{:15.2}", format!("ES ({:.1}%):", q.level * 100.), q.es);
+    }
+    let rates = loss_rates(info.total_value, moments.mean(), median, &quantiles);
+    print_loss_rates(&mut std::io::stdout(), rates.as_ref(), false);
+    print_exceedance(&mut std::io::stdout(), exceedance.as_deref());
+    let statistics_duration = statistics_start.elapsed();
+    let write = Instant::now();
+
+    // Loss distribution in order of the trials
+    write_loss_distribution(File::create(output.join("loss_distribution.csv")).expect("Output path not found"), "Loss", merged.loss.iter());
+
+    // Expected loss per borrower
+    let mut writer = Writer::from_path(output.join("expected_loss_by_borrower.csv")).expect("Output path not found");
+    writer.write_record(vec!["borrower_id", "expected_loss"]).unwrap();
+    for (id, el) in merged.borrower_ids.iter().zip(el.iter()) {
+        writer.write_record(vec![id.to_string(), el.to_string()]).unwrap();
+    }
+    writer.flush().unwrap();
+
+    let summary = summary::Summary {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        input: info.input.clone(),
+        seed: info.seed,
+        systematic_seed: None,
+        idiosyncratic_seed: None,
+        num_trials: info.num_trials,
+        chunk_size: info.chunk_size,
+        durations: summary::Durations {
+            initialization: info.initialization,
+            reading: Some(reading_duration.as_secs_f64()),
+            cholesky: None,
+            norms: None,
+            simulation: info.simulation,
+            statistics: Some(statistics_duration.as_secs_f64()),
+            output: write.elapsed().as_secs_f64(),
+        },
+        portfolio: info.portfolio.clone(),
+        expected_loss: info.expected_loss,
+        expected_loss_simulated: el.iter().sum(),
+        mean_scenario_loss: Some(info.mean_scenario_loss),
+        total_value: Some(info.total_value),
+        total_outstanding: Some(info.total_outstanding),
+        mean: moments.mean(),
+        mean_standard_error,
+        confidence: None,
+        skewness: Some(moments.skewness()),
+        excess_kurtosis: Some(moments.excess_kurtosis()),
+        median,
+        quantiles,
+        exceedance,
+        average_rating: info.average_rating,
+        expected_average_rating: info.expected_average_rating,
+        scenario: info.scenario.clone(),
+        macro_scenarios: None,
+        conditional_loss: None,
+        base_currency: info.base_currency.clone(),
+        value: None,
+        loss_rates: rates,
+    };
+    summary.write(&output.join("summary.json"));
+}
+
+/// Print the differences of the statistics of two loss distributions and the distances between
+/// them
+fn compare(before: &Path, after: &Path, levels: &[f64]) {
+    let mut distributions = [input::read_loss_distribution(before), input::read_loss_distribution(after)];
+    for loss in distributions.iter_mut() {
+        loss.sort_by(|a, b| a.total_cmp(b));
+    }
+    let ks = statistics::ks_statistic(&distributions[0], &distributions[1]);
+    let wasserstein = statistics::wasserstein_distance(&distributions[0], &distributions[1]);
+    let (n, m) = (distributions[0].len() as f64, distributions[1].len() as f64);
+
+    let [before, after] = distributions.map(|mut loss| {
+        let mut moments = statistics::Moments::default();
+        loss.iter().for_each(|&l| moments.add(l));
+        let median = statistics::median(&mut loss);
+        (loss.len(), moments.mean(), median, statistics::risk_measures(&mut loss, levels))
+    });
+
+    println!("{:<14}{:>15} {:>15} {:>15}", "", "Before", "After", "Difference");
+    println!("{:<14}{:15} {:15} {:15}", "Trials:", before.0, after.0, after.0 as i64 - before.0 as i64);
+    let line = |label: String, a: f64, b: f64| println!("{:<14}{:15.2} {:15.2} {:15.2}", label, a, b, b - a);
+    line("Mean:".to_string(), before.1, after.1);
+    line("Median:".to_string(), before.2, after.2);
+    for (a, b) in before.3.iter().zip(after.3.iter()) {
+        line(format!("({:.1}%):", a.level * 100.), a.var, b.var);
+    }
+    for (a, b) in before.3.iter().zip(after.3.iter()) {
+        line(format!("ES ({:.1}%):", a.level * 100.), a.es, b.es);
+    }
+    // Asymptotic critical value of the two-sample test at 5% significance
+    println!("KS Statistic: {:15.4}", ks);
+    println!("KS Crit 95%:  {:15.4}", 1.358 * ((n + m) / (n * m)).sqrt());
+    println!("Wasserstein:  {:15.2}", wasserstein);
+}
+
+/// Logger of the progress, warning and error messages to stderr, so that stdout carries only the
+/// results
+struct Logger {
+    format: LogFormat,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
         }
-        entry[row.rating] = row.valuation;
+        let line = match self.format {
+            LogFormat::Text => match record.level() {
+                log::Level::Error => format!("Error: {}", record.args()),
+                log::Level::Warn => format!("Warning: {}", record.args()),
+                _ => record.args().to_string(),
+            },
+            LogFormat::Json => serde_json::json!({
+                "timestamp": Local::now().to_rfc3339(),
+                "level": record.level().as_str(),
+                "message": record.args().to_string(),
+            }).to_string(),
+        };
+        // A closed stderr leaves nobody to tell
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
     }
 
-    // return
-    cov
+    fn flush(&self) {}
+}
+
+fn init_logger(args: &Args) {
+    let level = match (args.quiet, args.log_level) {
+        (true, _) => log::LevelFilter::Warn,
+        (false, LogLevel::Off) => log::LevelFilter::Off,
+        (false, LogLevel::Error) => log::LevelFilter::Error,
+        (false, LogLevel::Warn) => log::LevelFilter::Warn,
+        (false, LogLevel::Info) => log::LevelFilter::Info,
+        (false, LogLevel::Debug) => log::LevelFilter::Debug,
+    };
+    log::set_boxed_logger(Box::new(Logger { format: args.log_format })).expect("Logger already set");
+    log::set_max_level(level);
 }
 
 fn main() {
     let args = Args::parse();
+    init_logger(&args);
 
-    let path = Path::new(&args.input);
+    match args.command {
+        Some(Command::Validate { input }) => validate(Path::new(&input), args.z_convention.convention()),
+        Some(Command::Stats { input, output, top }) => stats(Path::new(&input), Path::new(&output), top),
+        Some(Command::Generate { output, borrowers, risk_factors, ratings, exposures_per_borrower, borrowers_per_group, seed, weight_classes }) => {
+            let generator = generate::Generator { borrowers, risk_factors, ratings, exposures_per_borrower, borrowers_per_group, seed, weight_classes };
+            std::fs::create_dir_all(&output).expect("Output path could not be created");
+            generator.input().write(Path::new(&output));
+        }
+        Some(Command::Merge { input, output, quantiles, thresholds }) => merge(Path::new(&input), Path::new(&output), &quantiles, &thresholds),
+        Some(Command::Compare { before, after, quantiles }) => compare(Path::new(&before), Path::new(&after), &quantiles),
+        None if args.threads > 0 => {
+            // Dedicated pool, so that the global pool is left untouched
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(args.threads).build().expect("Thread pool could not be created");
+            pool.install(|| run_with_precision(args))
+        }
+        None => run_with_precision(args),
+    }
+}
 
-    // Empty container of input data
-    let mut risk_groups: HashMap<String, Vec<Borrower>> = HashMap::new();
-    let mut mig_probs: HashMap<String, Vec<f64>> = HashMap::new();
-    let mut risk_factors: HashMap<String, Vec<f64>> = HashMap::new();
-    let mut exposures: HashMap<String, Vec<Exposure>> = HashMap::new();
-    let mut valuations: HashMap<String, Vec<f64>> = HashMap::new();
-    
-    // Fill containers and get covariance matrix
-    let cov = read_input(&path, &mut risk_groups, &mut mig_probs, &mut risk_factors, &mut exposures, &mut valuations);
+fn run_with_precision(args: Args) {
+    match args.loss_precision {
+        LossPrecision::F64 => run::<f64>(args),
+        LossPrecision::F32 => run::<f32>(args),
+    }
+}
 
-    // Initialize
-    let mut pf = portfolio::Portfolio::new(cov);
+fn run<L: simulation::LossScalar>(args: Args) {
+    let init = Instant::now();
 
-    for (_rg, borr_list) in risk_groups.drain() {
-        let mut rg = risk_group::RiskGroup::new();
+    let input = args.input.clone().expect("Input path required");
+    let output = args.output.clone().expect("Output path required");
 
-        for borr in borr_list {
-            let prob = mig_probs.remove(&borr.borrower_id).expect("Probability not found");
-            let rf = risk_factors.remove(&borr.borrower_id).expect("Risk Factor not found");
-            let exp_list = exposures.remove(&borr.borrower_id).expect("Exposure List not found");
+    // Streaming to stdout leaves no directory for the outputs of the options writing files, the
+    // printed results go to stderr so that stdout carries only the requested data
+    let to_stdout = output == "-";
+    if to_stdout {
+        let file_options: Vec<&str> = [
+            ("--incremental", !args.incremental.is_empty()),
+            ("--tail-quantiles", args.tail_quantiles.is_some()),
+            ("--es-contributions", args.es_contributions.is_some()),
+            ("--contributions", args.contributions.is_some()),
+            ("--rating-contributions", args.rating_contributions.is_some()),
+            ("--tail-pd", args.tail_pd.is_some()),
+            ("--dump-ratings", args.dump_ratings.is_some()),
+            ("--export-factors", args.export_factors),
+            ("--bootstrap", args.bootstrap > 0),
+            ("--seeds", args.seeds > 1),
+            ("--threshold-count", args.threshold_count > 0),
+            ("--self-test", args.self_test),
+            ("--histogram-bins", args.histogram_bins > 0 || args.histogram_edges.is_some()),
+            ("--migration-frequencies", args.migration_frequencies || args.migration_by_group),
+            ("--migration-matrix", args.migration_matrix),
+            ("--shard", args.shard.is_some()),
+            ("--dump-covariance", args.dump_covariance),
+            ("--scenarios", args.scenarios.is_some()),
+        ].into_iter().filter(|(_, set)| *set).map(|(option, _)| option).collect();
+        if !file_options.is_empty() {
+            panic!("Options {} write files and need an output directory instead of -", file_options.join(", "));
+        }
+    }
+    let mut report: Box<dyn Write> = if to_stdout { Box::new(std::io::stderr()) } else { Box::new(std::io::stdout()) };
 
-            let mut borr = borrower::Borrower::new(rf, borr.rating, borr.r2, borr.eps, prob);
+    // Equally allocated scenarios weight the trials, which only the summary statistics support
+    if args.scenario_allocation == ScenarioAllocationChoice::Equal {
+        let unweighted: Vec<&str> = [
+            ("--incremental", !args.incremental.is_empty()),
+            ("--bootstrap", args.bootstrap > 0),
+            ("--thresholds", !args.thresholds.is_empty()),
+            ("--threshold-count", args.threshold_count > 0),
+            ("--histogram-bins", args.histogram_bins > 0 || args.histogram_edges.is_some()),
+            ("--sample-output", args.sample_output.is_some()),
+        ].into_iter().filter(|(_, set)| *set).map(|(option, _)| option).collect();
+        if !unweighted.is_empty() {
+            panic!("Options {} do not support the weighted trials of --scenario-allocation equal", unweighted.join(", "));
+        }
+    }
 
-            for exp in exp_list {
-                let val = valuations.remove(&exp.exposure_id).expect("Valuation not found");
+    // Read input and initialize
+    let mode = match args.valuation_mode {
+        ValuationModeChoice::Absolute => exposure::ValuationMode::Absolute,
+        ValuationModeChoice::UnitPrice => exposure::ValuationMode::UnitPrice,
+        ValuationModeChoice::LossFraction => exposure::ValuationMode::LossFraction,
+    };
+    let reading = Instant::now();
+    let missing = match args.fill_missing_valuations {
+        Some(MissingValuationsChoice::Current) => input::MissingValuations::Current,
+        None => input::MissingValuations::Error,
+    };
+    let empty = if args.allow_empty_borrowers { input::EmptyBorrowers::Allow } else { input::EmptyBorrowers::Error };
+    let duplicates = if args.sum_duplicates { input::Duplicates::Sum } else { input::Duplicates::Error };
+    let mut input_data = input::Input::read_with(Path::new(&input), missing, empty, duplicates);
+    let reading_duration = reading.elapsed();
+    let file_durations = input_data.read_durations.clone();
+    log::debug!("Read {} risk groups from {} in {:.3} s", input_data.risk_groups.len(), input, reading_duration.as_secs_f64());
+    for warning in input_data.covariance_warnings().iter() {
+        log::warn!("{}", warning);
+    }
+    let empty_borrowers = input_data.num_empty_borrowers();
+    if empty_borrowers > 0 {
+        log::info!("{} borrowers without exposures are simulated with zero valuations", empty_borrowers);
+    }
+    if let Some(rate) = args.discount_rate {
+        assert!(rate > -1., "Discount rate {} must be above -1", rate);
+        if input_data.discounting.is_some() {
+            log::warn!("The discount rate {} replaces the discount factors of discounting.csv", rate);
+        }
+        input_data.discounting = Some(exposure::DiscountCurve::Flat(rate));
+    }
+    if let Some(base) = args.base_currency.as_ref()
+        && let Some(&rate) = input_data.fx_rates.get(base) {
+        assert_eq!(rate, 1., "Base currency {} has rate {} in fx_rates.csv, but must have rate 1", base, rate);
+    }
+    if args.shrinkage > 0. {
+        let target = match args.shrinkage_target {
+            ShrinkageTargetChoice::Identity => portfolio::ShrinkageTarget::Identity,
+            ShrinkageTargetChoice::ConstantCorrelation => portfolio::ShrinkageTarget::ConstantCorrelation,
+        };
+        let before = portfolio::condition_number(&input_data.cov);
+        input_data.cov = portfolio::shrink_covariance(&input_data.cov, args.shrinkage, target);
+        let name = args.shrinkage_target.to_possible_value().expect("Shrinkage target has a name");
+        log::info!("Shrunk covariance matrix by {} towards {}, condition number {:.3e} before and {:.3e} after", args.shrinkage, name.get_name(), before, portfolio::condition_number(&input_data.cov));
+    }
+    if args.dump_covariance {
+        input_data.write_covariance(&Path::new(&output).join("covariance_matrix.csv"));
+    }
+    let mut pf = input_data.into_portfolio_with(mode, args.psd_jitter);
+    pf.set_degrees_of_freedom(args.t_df);
+    if let Some(key) = args.absorbing_rating.as_ref() {
+        let rating = pf.rating_scale().resolve(key).unwrap_or_else(|message| panic!("Absorbing rating: {}", message));
+        pf.set_absorbing_rating(Some(rating));
+    }
+    pf.set_z_convention(args.z_convention.convention());
+    log::info!("Z convention: {}", args.z_convention.convention().describe());
 
-                let exp = exposure::Exposure::new(val);
+    // Stressed probabilities of default with the average before and after
+    let pd_stress = args.pd_stress.as_ref().map(|path| {
+        let base = pf.average_default_probability();
+        let multipliers = input::read_pd_stress(Path::new(path), pf.rating_scale());
+        pf.stress_default_probabilities(&multipliers);
+        (base, pf.average_default_probability())
+    });
 
-                borr.add_exposure(exp);
-            }
+    // Buckets of identical borrowers
+    if args.bucket_identical {
+        let borrowers = pf.num_borrower();
+        let buckets = pf.bucket_identical();
+        log::info!("Bucketed {} borrowers into {} buckets, borrowers of a bucket are perfectly correlated", borrowers, buckets);
+    }
 
-            rg.add_borrower(borr);
-        }
+    // Risk factors no borrower loads onto only cost time in every trial
+    let dropped = pf.drop_unused_factors();
+    if dropped > 0 {
+        log::info!("Dropped {} of {} risk factors no borrower loads onto", dropped, pf.num_risk_factors());
+    }
+
+    // Stress scenario
+    let scenario = args.scenario.as_ref().map(|path| input::read_scenario(Path::new(path)));
+    if let Some(scenario) = scenario.as_ref() {
+        pf.set_scenario(&scenario.iter().map(|f| (f.risk_factor, f.value)).collect::<Vec<(usize, f64)>>());
+    }
+    if let Some(jitter) = pf.jitter_applied() {
+        log::warn!("Covariance matrix is not positive definite, added diagonal jitter {} for its Cholesky decomposition", jitter);
+    }
+    pf.set_contagion_notches(args.contagion_notches);
+    pf.set_sampler(match args.sampler {
+        SamplerChoice::Mc => rng::Sampler::MonteCarlo,
+        SamplerChoice::Lhs => rng::Sampler::LatinHypercube,
+    });
+    pf.set_moment_matching(args.moment_matching);
+    if pf.has_contagion() {
+        log::info!("Note: Exp Loss is analytic without contagion and does not match Exp Loss Sim");
+    }
+    pf.freeze();
+    if args.conditional_loss && !pf.supports_conditional_losses() {
+        log::error!("--conditional-loss needs the Gaussian copula without guarantees and contagion, but the portfolio has guarantees or Student-t copulas");
+        std::process::exit(1);
+    }
+
+    // Macro scenarios of a mixture, each simulated with a stressed copy of the portfolio
+    let macro_scenarios = args.scenarios.as_ref().map(|path| input::read_scenarios(Path::new(path), pf.rating_scale()));
+    if macro_scenarios.iter().flatten().any(|scenario| !scenario.factor_shifts.is_empty()) {
+        log::info!("Note: Exp Loss of the scenarios is analytic without factor shifts and does not match Exp Loss Sim");
+    }
 
-        pf.add_risk_group(rg);
+    // Systematic factors of the trials given instead of drawn
+    let factor_input = args.factor_input.as_ref().map(|path| input::read_factor_draws(Path::new(path), pf.num_risk_factors()));
+    if let Some(factors) = factor_input.as_ref() {
+        log::info!("Note: Simulating the {} trials of the factor input, Exp Loss is analytic with drawn factors", factors.nrows());
+    }
+
+    // Share of the trials of a run split across processes
+    if let Some(shard) = args.shard {
+        simulate_shard(&pf, &args, shard, init.elapsed().as_secs_f64());
+        return;
     }
 
     // Do simulation
     let start = Local::now();
-    println!("Finished initialization {}", start.format("%Y-%m-%d %H:%M:%S"));
+    log::info!("Finished initialization {}", start.format("%Y-%m-%d %H:%M:%S"));
+    let init_duration = init.elapsed();
 
     // Simulation
-    let (loss, el) = pf.simulate(args.num_trials, args.chunk_size, 0);
+    let sim = Instant::now();
+    let tracking = if args.migration_by_group {
+        migration::MigrationTracking::RiskGroup
+    } else if args.migration_frequencies || args.migration_matrix || args.self_test {
+        migration::MigrationTracking::Portfolio
+    } else {
+        migration::MigrationTracking::None
+    };
+    let tracked: Vec<usize> = args.incremental.iter()
+        .map(|id| pf.borrower_index(id).unwrap_or_else(|| panic!("Borrower {} not found", id)))
+        .collect();
+    let (result, scenario_summaries) = match macro_scenarios.as_ref() {
+        Some(scenarios) => {
+            let (result, summaries) = simulate_mixture::<L>(&pf, &args, scenarios, tracking, &tracked);
+            (result, Some(summaries))
+        }
+        None => match factor_input.as_ref() {
+            Some(factors) => (simulate_factors::<L>(&pf, &args, factors, tracking, &tracked), None),
+            None => (simulate::<L>(&pf, &args, args.num_trials, seeds(&args), tracking, &tracked), None),
+        },
+    };
+    let simulation::SimulationResult { mut loss, expected_loss: el, migrations, tracked: tracked_loss } = result;
+
+    // Weight of each trial if the scenarios are allocated equally, the weights of a scenario's
+    // trials sum to its weight
+    let weights: Option<Vec<f64>> = scenario_summaries.as_ref()
+        .filter(|_| args.scenario_allocation == ScenarioAllocationChoice::Equal)
+        .map(|summaries| summaries.iter()
+            .flat_map(|scenario| std::iter::repeat_n(scenario.weight / scenario.num_trials as f64, scenario.num_trials))
+            .collect());
+    let sim_duration = sim.elapsed();
+    let statistics_start = Instant::now();
+    let num_trials = loss.len();
     
     let elapsed = Local::now() - start;
-    println!("Done after {:.3} s", elapsed.num_milliseconds() as f64 / 1000.);
+    log::info!("Done after {:.3} s", elapsed.num_milliseconds() as f64 / 1000.);
+    if let Some(precision) = args.target_precision {
+        let error = target_metric(&args).relative_error(&loss, args.chunk_size);
+        if num_trials < args.num_trials {
+            log::info!("Target precision reached after {} trials", num_trials);
+        } else if !error.is_some_and(|error| error < precision) {
+            log::warn!("Target precision {} not reached within the cap of {} trials, relative error {}", precision, args.num_trials, error.map_or("unknown".to_string(), |error| format!("{:.6}", error)));
+        }
+    }
 
-    let mut out = Data::new(loss);
-    println!("Exp Loss:     {:15.2}", pf.expected_loss());
-    println!("Exp Loss Sim: {:15.2}", el.sum());
-    println!("Mean:         {:15.2}", out.mean().unwrap());
-    println!("Median:       {:15.2}", out.median());
-    println!("(90.0%):      {:15.2}", out.quantile(0.900));
-    println!("(99.0%):      {:15.2}", out.quantile(0.990));
-    println!("(99.9%):      {:15.2}", out.quantile(0.999));
+    // Risk measures with and without selected borrowers, needs the losses in order of the trials
+    if !args.incremental.is_empty() {
+        incremental_var(&Path::new(&output).join("incremental_var.csv"), &args, &loss, &tracked_loss);
+    }
+
+    // Systematic factors of each trial, needs the losses in order of the trials
+    if args.export_factors {
+        export_factors(&Path::new(&output).join("factor_draws.csv"), &pf, &args, &loss);
+    }
+
+    // Scenario of each trial of a mixture, needs the losses in order of the trials
+    if let Some(summaries) = scenario_summaries.as_ref() {
+        let pnl = args.convention == Convention::Pnl;
+        write_scenario_losses(&Path::new(&output).join("scenario_losses.csv"), if pnl { "PnL" } else { "Loss" }, &loss, summaries, pnl);
+    }
+
+    // Losses expected given the systematic factors and risk group draws of each trial, written next
+    // to the realized losses, which are kept in order of the trials for that
+    let conditional = args.conditional_loss.then(|| (conditional_losses(&pf, &args, num_trials), loss.clone()));
+
+    // Portfolio value at the horizon, needs the losses in order of the trials for the weights
+    let value_summary = args.report.contains(&ReportChoice::Value).then(|| {
+        let total = pf.total_value();
+        let values: Vec<f64> = loss.iter().map(|l| total - l.to_f64()).collect();
+        value_summary(&values, weights.as_deref(), &args.quantiles)
+    });
+
+    // Standard errors by batch means over the chunks, needs the losses in order of the trials. The
+    // chunks of a mixture are not identically distributed, as the scenarios follow each other
+    let tail_levels = args.tail_quantiles.map(tail_levels).unwrap_or_default();
+    let (standard_errors, tail_standard_errors) = if scenario_summaries.is_none() {
+        (statistics::batch_standard_errors(&loss, args.chunk_size, &args.quantiles), statistics::batch_standard_errors(&loss, args.chunk_size, &tail_levels))
+    } else {
+        (None, None)
+    };
+
+    // Analytic expected loss, of a mixture weighted over the scenarios
+    let expected_loss = match scenario_summaries.as_ref() {
+        Some(summaries) => summaries.iter().map(|scenario| scenario.weight * scenario.expected_loss).sum(),
+        None => pf.expected_loss(),
+    };
+
+    // Exceedance curve on sorted losses, including the analytic expected loss for reference. The
+    // thresholds alone are only reported, not written as curve, when streaming to stdout
+    let exceedance = if (!args.thresholds.is_empty() && !to_stdout) || args.threshold_count > 0 {
+        loss.sort_by(|a, b| a.to_f64().total_cmp(&b.to_f64()));
+        let max_loss = loss.last().map(|l| l.to_f64()).unwrap_or(0.);
+
+        let mut thresholds = args.thresholds.clone();
+        if args.threshold_count > 1 {
+            thresholds.extend((0..args.threshold_count).map(|i| max_loss * i as f64 / (args.threshold_count - 1) as f64));
+        }
+        thresholds.push(expected_loss);
+
+        Some(statistics::exceedance_curve(&loss, &thresholds))
+    } else {
+        None
+    };
+
+    let set_standard_errors = |quantiles: &mut [summary::QuantileSummary], standard_errors: Option<Vec<(f64, f64)>>| {
+        for (q, (var_se, es_se)) in quantiles.iter_mut().zip(standard_errors.unwrap_or_default()) {
+            q.var_standard_error = Some(var_se);
+            q.es_standard_error = Some(es_se);
+        }
+    };
+    let mut quantiles = statistics::risk_measures_with(&mut loss, weights.as_deref(), &args.quantiles);
+    set_standard_errors(&mut quantiles, standard_errors);
+
+    // Dense grid of tail quantiles, adjacent levels are one basis point apart, so they can only be
+    // told apart with at least 10000 trials
+    if !tail_levels.is_empty() {
+        if num_trials < 10_000 {
+            log::warn!("{} trials resolve quantiles only to {:.4}%, tail quantiles in basis point steps need at least 10000 trials", num_trials, 100. / num_trials as f64);
+        }
+        let mut tail_quantiles = statistics::risk_measures_with(&mut loss, weights.as_deref(), &tail_levels);
+        set_standard_errors(&mut tail_quantiles, tail_standard_errors);
+        write_tail_quantiles(&Path::new(&output).join("tail_quantiles.csv"), &tail_quantiles, num_trials);
+    }
+    let moments = match weights.as_deref() {
+        Some(weights) => statistics::Moments::weighted(&loss, weights),
+        None => loss
+            .par_chunks(args.chunk_size)
+            .map(|chunk| {
+                let mut moments = statistics::Moments::default();
+                chunk.iter().for_each(|l| moments.add(l.to_f64()));
+                moments
+            })
+            .reduce(statistics::Moments::default, |mut a, b| { a.merge(&b); a }),
+    };
+    let mean_standard_error = (moments.count() > 1 && weights.is_none()).then(|| (moments.variance() / moments.count() as f64).sqrt());
+    let median = match weights.as_deref() {
+        Some(weights) => statistics::weighted_quantile(&loss, weights, 0.5),
+        None => statistics::median(&mut loss),
+    };
+    let exceedance_probabilities = exceedance_probabilities(&loss, &args.thresholds);
+
+    // Half width of the confidence interval given a standard error
+    let z = Normal::new(0., 1.).unwrap().inverse_cdf(0.5 + args.confidence / 2.);
+    let interval = |se: Option<f64>| se.map(|se| format!(" ± {:.2}", z * se)).unwrap_or_default();
+
+    // Printed results in the sign convention, the P&L is the negated loss, so the 0.1% quantile of
+    // the P&L is the negated 99.9% quantile of the loss. Subtracting from zero keeps zero from
+    // being printed as -0
+    let pnl = args.convention == Convention::Pnl;
+    let signed = |x: f64| if pnl { 0. - x } else { x };
+    let level_of = |level: f64| if pnl { 1. - level } else { level };
+    let measure = if pnl { "PnL" } else { "Loss" };
+
+    if let (Some(current), Some(expected)) = (pf.average_rating(), pf.expected_average_rating()) {
+        writeln!(report, "Avg Rating:   {:15.2}", current).unwrap();
+        writeln!(report, "Exp Avg Rtg:  {:15.2}", expected).unwrap();
+    }
+    if let Some((base, stressed)) = pd_stress {
+        writeln!(report, "PD Base:      {:14.4}%", base * 100.).unwrap();
+        writeln!(report, "PD Stressed:  {:14.4}%", stressed * 100.).unwrap();
+    }
+    if args.target_precision.is_some() {
+        writeln!(report, "Trials:       {:15}", num_trials).unwrap();
+    }
+    writeln!(report, "Value:        {:15.2}", pf.total_value()).unwrap();
+    writeln!(report, "Outstanding:  {:15.2}", pf.total_outstanding()).unwrap();
+    writeln!(report, "{:<14}{:15.2}", format!("Exp {}:", measure), signed(expected_loss)).unwrap();
+    writeln!(report, "Mean Scen:    {:15.2}", signed(pf.mean_scenario_loss())).unwrap();
+    if let Some(scenario) = scenario.as_ref() {
+        writeln!(report, "Conditional on scenario with {} fixed risk factors", scenario.len()).unwrap();
+    }
+    writeln!(report, "{:<14}{:15.2}", format!("Exp {} Sim:", measure), signed(el.sum())).unwrap();
+    writeln!(report, "Mean:         {:15.2}{}", signed(moments.mean()), interval(mean_standard_error)).unwrap();
+    writeln!(report, "Median:       {:15.2}", signed(median)).unwrap();
+    writeln!(report, "Skewness:     {:15.2}", signed(moments.skewness())).unwrap();
+    writeln!(report, "Ex Kurtosis:  {:15.2}", moments.excess_kurtosis()).unwrap();
+    for q in quantiles.iter() {
+        writeln!(report, "{:<14}{:15.2}{}", format!("({:.1}%):", level_of(q.level) * 100.), signed(q.var), interval(q.var_standard_error)).unwrap();
+    }
+    for q in quantiles.iter() {
+        writeln!(report, "{:<14}{:15.2}{}", format!("ES ({:.1}%):", level_of(q.level) * 100.), signed(q.es), interval(q.es_standard_error)).unwrap();
+    }
+    let rates = loss_rates(pf.total_value(), moments.mean(), median, &quantiles);
+    print_loss_rates(&mut report, rates.as_ref(), pnl);
+    if let Some(value) = value_summary.as_ref() {
+        writeln!(report, "Value Mean:   {:15.2}", value.mean).unwrap();
+        writeln!(report, "Value Median: {:15.2}", value.median).unwrap();
+        for q in value.quantiles.iter() {
+            writeln!(report, "{:<14}{:15.2}", format!("Value {:.1}%:", q.level * 100.), q.value).unwrap();
+        }
+        for q in value.quantiles.iter() {
+            writeln!(report, "{:<14}{:15.2}", format!("Val ES {:.1}%:", q.level * 100.), q.tail_mean).unwrap();
+        }
+    }
+    print_exceedance(&mut report, exceedance_probabilities.as_deref());
+    if mean_standard_error.is_some() {
+        writeln!(report, "Intervals at {:.1}% confidence", args.confidence * 100.).unwrap();
+    }
+    if let Some(summaries) = scenario_summaries.as_ref() {
+        print_macro_scenarios(&mut report, summaries, pnl);
+    }
+    let conditional_summary = conditional.as_ref().map(|(conditional, _)| {
+        let mut sorted = conditional.clone();
+        let quantiles = statistics::risk_measures(&mut sorted, &args.quantiles);
+        summary::ConditionalLossSummary {
+            mean: conditional.iter().copied().sum::<statistics::CompensatedSum>().value() / num_trials as f64,
+            median: statistics::median(&mut sorted),
+            quantiles,
+        }
+    });
+    if let Some(conditional) = conditional_summary.as_ref() {
+        writeln!(report, "Conditional on the systematic factors and risk group draws of each trial").unwrap();
+        writeln!(report, "Cond Mean:    {:15.2}", signed(conditional.mean)).unwrap();
+        writeln!(report, "Cond Median:  {:15.2}", signed(conditional.median)).unwrap();
+        for q in conditional.quantiles.iter() {
+            writeln!(report, "{:<14}{:15.2}", format!("Cond ({:.1}%):", level_of(q.level) * 100.), signed(q.var)).unwrap();
+        }
+        for q in conditional.quantiles.iter() {
+            writeln!(report, "{:<14}{:15.2}", format!("Cond ES {:.1}%:", level_of(q.level) * 100.), signed(q.es)).unwrap();
+        }
+    }
 
     // Output
-    let outpath = Path::new(&args.output);
+    let statistics_duration = statistics_start.elapsed();
+    let write = Instant::now();
+    let outpath = Path::new(&output);
+
+    // Loss distribution in the sign convention, or a sample of its order statistics in ascending
+    // order of the convention
+    let sample = args.sample_output.map(|count| {
+        loss.sort_by(|a, b| a.to_f64().total_cmp(&b.to_f64()));
+        let mut sample = statistics::order_statistics(&loss, count);
+        if pnl {
+            sample.reverse();
+        }
+        sample
+    });
+    let ordered = conditional.as_ref().map(|(_, ordered)| ordered).unwrap_or(&loss);
+    let distribution = sample.as_deref().unwrap_or(ordered).iter().map(|&l| if pnl { L::from_f64(signed(l.to_f64())) } else { l });
+    let conditional_signed: Option<Vec<f64>> = conditional.as_ref().map(|(conditional, _)| conditional.iter().map(|&c| signed(c)).collect());
+    match (to_stdout, args.stdout_data, conditional_signed) {
+        (true, StdoutData::Summary, _) => {},
+        (true, StdoutData::LossDistribution, None) => write_loss_distribution(std::io::stdout().lock(), measure, distribution),
+        (true, StdoutData::LossDistribution, Some(conditional)) => write_conditional_loss_distribution(std::io::stdout().lock(), measure, distribution, &conditional),
+        (false, _, None) => write_loss_distribution(File::create(outpath.join("loss_distribution.csv")).expect("Output path not found"), measure, distribution),
+        (false, _, Some(conditional)) => write_conditional_loss_distribution(File::create(outpath.join("loss_distribution.csv")).expect("Output path not found"), measure, distribution, &conditional),
+    }
+
+    // Portfolio value of the rows of the loss distribution
+    if value_summary.is_some() && !to_stdout {
+        let total = pf.total_value();
+        let values = sample.as_deref().unwrap_or(ordered).iter().map(|l| total - l.to_f64());
+        write_loss_distribution(File::create(outpath.join("value_distribution.csv")).expect("Output path not found"), "Value", values);
+    }
+
+    // Contributions of the risk groups to the expected shortfall
+    if let Some(level) = args.es_contributions {
+        es_contributions::<L>(&outpath.join("es_contributions_by_group.csv"), &pf, &args, num_trials, level, statistics::quantile(&mut loss, level));
+    }
+
+    // Contributions of the borrowers to the value at risk and the expected shortfall
+    if let Some(level) = args.contributions {
+        let band = (statistics::quantile(&mut loss, (level - args.contribution_band).max(0.)), statistics::quantile(&mut loss, (level + args.contribution_band).min(1.)));
+        borrower_contributions::<L>(&outpath.join("borrower_es_contributions.csv"), &pf, &args, num_trials, level, band, statistics::quantile(&mut loss, level));
+    }
+
+    // Probabilities of default of the borrowers in the tail
+    if let Some(level) = args.tail_pd {
+        tail_default_probabilities::<L>(&outpath.join("tail_default_probabilities.csv"), &pf, &args, num_trials, level, statistics::quantile(&mut loss, level));
+    }
 
-    // Loss distribution
-    let mut writer = Writer::from_path(outpath.join("loss_distribution.csv")).expect("Output path not found");
-    writer.write_record(vec!["Loss"]).unwrap();
-    out.iter().for_each(|row| writer.write_record(vec![row.to_string()]).unwrap());
+    // Losses by the rating class the borrowers migrated to
+    if let Some(level) = args.rating_contributions {
+        rating_contributions::<L>(&outpath.join("loss_by_destination_rating.csv"), &pf, &args, num_trials, level, statistics::quantile(&mut loss, level));
+    }
+
+    // Ratings of the first trials
+    if let Some(count) = args.dump_ratings {
+        dump_ratings(&outpath.join("ratings.csv"), &pf, &args, count.min(num_trials));
+    }
+
+    // Bootstrap of the value at risk
+    if args.bootstrap > 0 {
+        let mut writer = Writer::from_path(outpath.join("bootstrap.csv")).expect("Output path not found");
+        writer.write_record(vec!["level", "var", "lower", "upper"]).unwrap();
+        for &level in args.quantiles.iter() {
+            let (var, lower, upper) = statistics::bootstrap_quantile(&loss, level, args.bootstrap, args.seed);
+            let (lower_signed, upper_signed) = if pnl { (signed(upper), signed(lower)) } else { (lower, upper) };
+            writeln!(report, "{:<14}{:15.2} [{:.2}, {:.2}]", format!("Boot ({:.1}%):", level_of(level) * 100.), signed(var), lower_signed, upper_signed).unwrap();
+            writer.write_record(vec![level.to_string(), var.to_string(), lower.to_string(), upper.to_string()]).unwrap();
+        }
+        writer.flush().unwrap();
+    }
+
+    // Seed sweep
+    if args.seeds > 1 {
+        seed_sweep::<L>(&mut report, &outpath.join("seed_sweep.csv"), &pf, &args, moments.mean(), &quantiles, pnl);
+    }
+
+    // Statistics of the macro scenarios
+    if let Some(summaries) = scenario_summaries.as_ref() {
+        write_scenario_statistics(&outpath.join("scenario_statistics.csv"), summaries, &args.quantiles);
+    }
+
+    // Exceedance curve
+    if let Some(exceedance) = exceedance {
+        let mut writer = Writer::from_path(outpath.join("exceedance_curve.csv")).expect("Output path not found");
+        writer.write_record(vec!["threshold", "exceedance_probability", "mean_excess_loss", "loss_rate"]).unwrap();
+        let total_value = pf.total_value();
+        for point in exceedance.iter() {
+            let rate = rates.as_ref().map(|_| (point.threshold / total_value).to_string()).unwrap_or_default();
+            writer.write_record(vec![point.threshold.to_string(), point.probability.to_string(), point.mean_excess.to_string(), rate]).unwrap();
+        }
+        writer.flush().unwrap();
+    }
+
+    // Self-test of the migrations
+    if let Some(migrations) = migrations.as_ref().filter(|_| args.self_test) {
+        self_test(&outpath.join("self_test.csv"), &pf, migrations, args.self_test_level);
+    }
+
+    // Histogram, binned per chunk of trials and merged
+    if args.histogram_bins > 0 || args.histogram_edges.is_some() {
+        let empty = match &args.histogram_edges {
+            Some(path) => statistics::Histogram::new(read_histogram_edges(Path::new(path))),
+            None => {
+                let min = loss.iter().map(|l| l.to_f64()).fold(f64::INFINITY, f64::min);
+                let max = loss.iter().map(|l| l.to_f64()).fold(f64::NEG_INFINITY, f64::max);
+                statistics::Histogram::equidistant(min, max, args.histogram_bins)
+            }
+        };
+        let histogram = loss
+            .par_chunks(args.chunk_size)
+            .map(|chunk| {
+                let mut histogram = empty.clone();
+                chunk.iter().for_each(|l| histogram.add(l.to_f64()));
+                histogram
+            })
+            .reduce(|| empty.clone(), |mut a, b| { a.merge(&b); a });
+        if histogram.nan_count() > 0 {
+            log::warn!("{} trials with a NaN loss are left out of the loss histogram", histogram.nan_count());
+        }
+        write_histogram(&outpath.join("loss_histogram.csv"), &histogram);
+    }
+
+    // Migration frequencies
+    if let Some(migrations) = migrations.as_ref().filter(|_| args.migration_frequencies || args.migration_by_group) {
+        write_migration_frequencies(&outpath.join("migration_frequencies.csv"), &pf, migrations, num_trials);
+    }
+
+    // Realized migration matrix and defaults per trial
+    if let Some(migrations) = migrations.as_ref().filter(|_| args.migration_matrix) {
+        write_migration_matrix(&outpath.join("realized_migration_matrix.csv"), &pf, migrations);
+        write_default_counts(&outpath.join("default_count_distribution.csv"), migrations, num_trials);
+    }
+
+    // Summary
+    let summary = summary::Summary {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        input,
+        seed: args.seed,
+        systematic_seed: args.systematic_seed,
+        idiosyncratic_seed: args.idiosyncratic_seed,
+        num_trials,
+        chunk_size: args.chunk_size,
+        durations: summary::Durations {
+            initialization: init_duration.as_secs_f64(),
+            reading: Some(reading_duration.as_secs_f64()),
+            cholesky: Some(pf.cholesky_duration().as_secs_f64()),
+            norms: Some(pf.norm_duration().as_secs_f64()),
+            simulation: sim_duration.as_secs_f64(),
+            statistics: Some(statistics_duration.as_secs_f64()),
+            output: write.elapsed().as_secs_f64(),
+        },
+        portfolio: summary::PortfolioSize {
+            borrowers: pf.num_borrower(),
+            exposures: pf.num_exposures(),
+            risk_factors: pf.num_risk_factors(),
+            rating_classes: pf.num_ratings(),
+        },
+        expected_loss,
+        expected_loss_simulated: el.sum(),
+        mean_scenario_loss: Some(pf.mean_scenario_loss()),
+        total_value: Some(pf.total_value()),
+        total_outstanding: Some(pf.total_outstanding()),
+        mean: moments.mean(),
+        mean_standard_error,
+        confidence: Some(args.confidence),
+        skewness: Some(moments.skewness()),
+        excess_kurtosis: Some(moments.excess_kurtosis()),
+        median,
+        quantiles,
+        exceedance: exceedance_probabilities,
+        average_rating: pf.average_rating(),
+        expected_average_rating: pf.expected_average_rating(),
+        scenario,
+        macro_scenarios: scenario_summaries,
+        conditional_loss: conditional_summary,
+        base_currency: args.base_currency.clone(),
+        value: value_summary,
+        loss_rates: rates,
+    };
+    if args.timings {
+        print_timings(&mut report, &summary.durations, &file_durations);
+    }
+    match (to_stdout, args.stdout_data) {
+        (true, StdoutData::Summary) => summary.write_to(std::io::stdout().lock()),
+        (true, StdoutData::LossDistribution) => {},
+        (false, _) => summary.write(&outpath.join("summary.json")),
+    }
 }
@@ -0,0 +1,152 @@
+use ndarray::Array2;
+
+/// Largest number of thresholds for which `rating_index` scans linearly instead of searching
+/// binary
+pub const LINEAR_SCAN_CUTOFF: usize = 24;
+
+/// Resulting rating class of an asset value given the ascending thresholds between the rating
+/// classes, i.e. the number of thresholds below the asset value. The thresholds are
+/// upper-inclusive: class `k` covers `(c[k - 1], c[k]]`, so an asset value exactly on a threshold
+/// falls into the class below it, and never into a class of zero probability between duplicate
+/// thresholds. Small rating scales are scanned linearly, large ones searched binary
+pub fn rating_index(thresholds: &[f64], z: f64) -> usize {
+    if thresholds.len() <= LINEAR_SCAN_CUTOFF {
+        linear_scan(thresholds, z)
+    } else {
+        binary_search(thresholds, z)
+    }
+}
+
+/// Rating class by a forward scan of the thresholds, stopping at the first one not below the
+/// asset value
+pub fn linear_scan(thresholds: &[f64], z: f64) -> usize {
+    let mut index = 0;
+    while index < thresholds.len() && thresholds[index] < z {
+        index += 1;
+    }
+    index
+}
+
+/// Rating class by binary search of the thresholds, the first one not below the asset value like
+/// `linear_scan`
+pub fn binary_search(thresholds: &[f64], z: f64) -> usize {
+    thresholds.partition_point(|&t| t < z)
+}
+
+/// Ordering of the rating classes along the asset value `z`, the thresholds always ascend from the
+/// first rating class to the last, the default class
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ZConvention {
+    /// Low asset values migrate to the first rating class, usually the best one, and high ones
+    /// to the default class, so large factor values are adverse
+    #[default]
+    LowIsBest,
+    /// Low asset values migrate to the default class as in the Merton model, the asset value is
+    /// negated before its rating class is looked up, so small factor values are adverse
+    LowIsDefault,
+}
+
+impl ZConvention {
+    /// Sign the asset value is multiplied with before its rating class is looked up
+    pub fn sign(&self) -> f64 {
+        match self {
+            Self::LowIsBest => 1.,
+            Self::LowIsDefault => -1.,
+        }
+    }
+
+    /// Description of the direction of the asset values for reports
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Self::LowIsBest => "low asset values migrate to the first rating class, high ones to default",
+            Self::LowIsDefault => "low asset values migrate to default, high ones to the first rating class",
+        }
+    }
+}
+
+/// Level of detail of migration counts accumulated during the simulation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationTracking {
+    /// No migrations are counted
+    None,
+    /// Counts per rating class for the whole portfolio
+    Portfolio,
+    /// Counts per rating class for each risk group
+    RiskGroup,
+}
+
+/// Counts of simulated migrations into each rating class, either for the whole portfolio or
+/// broken down by risk group. Additionally, the migrations from the current into the resulting
+/// rating and the number of defaults per trial are counted
+#[derive(Debug, Clone)]
+pub struct MigrationCounts {
+    /// Whether rows correspond to risk groups (or the portfolio as single row)
+    by_group: bool,
+    /// Number of migrations with risk group (or portfolio) as row and resulting rating as column
+    counts: Array2<u64>,
+    /// Number of migrations with current rating as row and resulting rating as column
+    matrix: Array2<u64>,
+    /// Number of trials (value) with a given number of defaulted borrowers (index)
+    defaults: Vec<u64>,
+}
+
+impl MigrationCounts {
+    /// Create empty counts for the given number of risk groups and rating classes
+    pub fn new(num_groups: usize, num_ratings: usize, by_group: bool) -> Self {
+        let rows = if by_group { num_groups } else { 1 };
+
+        Self {
+            by_group,
+            counts: Array2::zeros((rows, num_ratings)),
+            matrix: Array2::zeros((num_ratings, num_ratings)),
+            defaults: Vec::new(),
+        }
+    }
+
+    /// Count a migration of a borrower within a risk group (given by its index) from its current
+    /// rating into a rating
+    pub fn add(&mut self, group: usize, current: usize, rating: usize) {
+        let row = if self.by_group { group } else { 0 };
+        self.counts[[row, rating]] += 1;
+        self.matrix[[current, rating]] += 1;
+    }
+
+    /// Count a trial with the given number of defaulted borrowers
+    pub fn add_trial(&mut self, num_defaults: usize) {
+        if self.defaults.len() <= num_defaults {
+            self.defaults.resize(num_defaults + 1, 0);
+        }
+        self.defaults[num_defaults] += 1;
+    }
+
+    /// Add the counts of another instance, e.g. of a different chunk
+    pub fn merge(&mut self, other: &MigrationCounts) {
+        self.counts += &other.counts;
+        self.matrix += &other.matrix;
+
+        if self.defaults.len() < other.defaults.len() {
+            self.defaults.resize(other.defaults.len(), 0);
+        }
+        self.defaults.iter_mut().zip(other.defaults.iter()).for_each(|(a, b)| *a += b);
+    }
+
+    /// Whether the counts are broken down by risk group
+    pub fn by_group(&self) -> bool {
+        self.by_group
+    }
+
+    /// Matrix of counts with risk group (or portfolio) as row and rating class as column
+    pub fn counts(&self) -> &Array2<u64> {
+        &self.counts
+    }
+
+    /// Matrix of counts with current rating class as row and resulting rating class as column
+    pub fn matrix(&self) -> &Array2<u64> {
+        &self.matrix
+    }
+
+    /// Number of trials by number of defaulted borrowers (index)
+    pub fn defaults(&self) -> &[u64] {
+        &self.defaults
+    }
+}
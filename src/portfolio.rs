@@ -1,47 +1,768 @@
-use ndarray::{Array1, Array2};
-use ndarray_linalg::{Cholesky, UPLO};
+use ndarray::{s, Array1, Array2, ArrayView1, ArrayView2, Axis};
+use ndarray_linalg::{Cholesky, EigValsh, UPLO};
 use rayon::prelude::*;
 use rand::Rng;
-use rand::SeedableRng;
-use rand_pcg::Pcg64;
-use rand_distr::{Distribution, StandardNormal};
-use std::sync::{Arc, Mutex};
+use rand_distr::{ChiSquared, Distribution, StandardNormal};
+use statrs::distribution::{ContinuousCDF, Normal, StudentsT};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
+use crate::borrower;
+use crate::exposure;
+use crate::migration;
+use crate::rating;
 use crate::risk_group;
+use crate::rng;
+use crate::simulation;
+use crate::simulation::LossScalar;
+use crate::statistics::CompensatedSum;
 
-/// Defines a portfolio that handles the simulation and correlation structure
+/// Threshold of the Student-t distribution with the probability of the standard normal threshold,
+/// the upper half is mapped by symmetry where the normal CDF is too close to one
+fn t_threshold(threshold: f64, normal: &Normal, t: &StudentsT) -> f64 {
+    if threshold > 0. {
+        -t.inverse_cdf(normal.cdf(-threshold))
+    } else {
+        t.inverse_cdf(normal.cdf(threshold))
+    }
+}
+
+/// Portfolio loss of a trial from the losses of its borrowers, summed with compensation and stored
+/// with the precision `L`. The simulation and all replays of its trials share it, so that a trial
+/// with a loss exactly at a threshold derived from the simulated losses is in the tail of every
+/// replay
+fn trial_loss<'a, L: LossScalar>(loss_borr: impl IntoIterator<Item = &'a f64>) -> L {
+    L::from_f64(loss_borr.into_iter().copied().sum::<CompensatedSum>().value())
+}
+
+/// Average of the sums over the trials of a tail of the replays, zeros with a warning if no trial
+/// falls into it, e.g. for a threshold above the largest loss
+fn tail_average(sum: Array1<f64>, count: usize, tail: &str) -> Array1<f64> {
+    if count == 0 {
+        log::warn!("No trial has a loss in the {}, its averages are zero", tail);
+        return sum;
+    }
+    sum / count as f64
+}
+
+/// Error of a modification of an existing portfolio
+#[derive(Debug, Clone, PartialEq)]
+pub enum PortfolioError {
+    /// Number of risk factor weights of a borrower does not match the covariance matrix
+    RiskFactorMismatch { expected: usize, found: usize },
+    /// Number of valuations of an exposure does not match the rating classes of the borrower
+    ValuationMismatch { expected: usize, found: usize },
+    /// Risk group with the identifier does not exist
+    UnknownRiskGroup(String),
+    /// Borrower index does not exist within the risk group
+    UnknownBorrower { risk_group: String, index: usize },
+    /// Borrower loads onto a risk factor dropped as unused
+    DroppedRiskFactor(usize),
+    /// Borrower with the identifier has no systematic variance, e.g. only zero risk factor weights
+    ZeroSystematicVariance(String),
+    /// Parent borrower added to a risk group that already has one
+    MultipleParents { risk_group: String, existing: String, added: String },
+}
+
+impl fmt::Display for PortfolioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RiskFactorMismatch { expected, found } => write!(f, "Borrower has {} risk factor weights, covariance matrix has {} risk factors", found, expected),
+            Self::ValuationMismatch { expected, found } => write!(f, "Exposure has {} valuations, borrower has {} rating classes", found, expected),
+            Self::UnknownRiskGroup(id) => write!(f, "Risk group {} does not exist", id),
+            Self::UnknownBorrower { risk_group, index } => write!(f, "Risk group {} has no borrower with index {}", risk_group, index),
+            Self::DroppedRiskFactor(index) => write!(f, "Risk factor {} was dropped as unused, no borrower can load onto it", index),
+            Self::ZeroSystematicVariance(id) => write!(f, "Borrower {} has no systematic variance, e.g. only zero risk factor weights, which would make every loss NaN", id),
+            Self::MultipleParents { risk_group, existing, added } => write!(f, "Risk group {} has parent borrowers {} and {}, only one is allowed", risk_group, existing, added),
+        }
+    }
+}
+
+impl std::error::Error for PortfolioError {}
+
+/// Data of all borrowers needed per trial in contiguous arrays (struct of arrays), so that a trial
+/// walks memory sequentially instead of the separate allocations of every borrower. Thresholds and
+/// losses of borrower `i` are `thresholds[offsets[i] - i..offsets[i + 1] - i - 1]` and
+/// `losses[offsets[i]..offsets[i + 1]]`
+#[derive(Clone)]
+struct BorrowerArena {
+    /// Start of the losses of each borrower and the end of the last one
+    offsets: Vec<usize>,
+    /// Thresholds of the asset value of all borrowers
+    thresholds: Vec<f64>,
+    /// Losses per rating class of all borrowers
+    losses: Vec<f64>,
+    /// Current rating class of each borrower
+    rating: Vec<usize>,
+    /// Whether the current rating of each borrower is absorbing, its migration is skipped
+    absorbing: Vec<bool>,
+    /// Factor slot of each borrower
+    factor_slot: Vec<usize>,
+    /// Square roots of rho, 1 - rho, 1 - eps and eps of each borrower, the first two multiplied
+    /// with the sign of its `ZConvention`
+    weights: Vec<[f64; 4]>,
+    /// End of the borrowers of each risk group
+    group_ends: Vec<usize>,
+    /// Parent of each nested risk group with the weights of its shock and its own draw
+    links: Vec<Option<(usize, f64, f64)>>,
+    /// Whether any risk group is nested, the shocks of the groups are then kept during a trial
+    nested: bool,
+    /// Parent borrower of each risk group if one is designated
+    parents: Vec<Option<usize>>,
+    /// Guarantees of exposures, resolved after all borrowers of a trial have migrated
+    guarantees: Vec<ArenaGuarantee>,
+    /// Degrees of freedom of the Student-t copula of each risk group with the index of its mixing
+    /// variable, None for the Gaussian copula
+    degrees_of_freedom: Vec<Option<(f64, usize)>>,
+    /// Distributions of the mixing variables of the risk groups with own degrees of freedom in
+    /// order, followed by the one shared by the other groups with a portfolio-wide Student-t copula
+    mixing: Vec<ChiSquared<f64>>,
+    /// Columns of the outputs by rating class
+    layout: RatingColumns,
+    /// Column of each rating class of all borrowers, arranged like the losses
+    columns: Vec<usize>,
+}
+
+/// Columns of the outputs by rating class. The classes of the common rating scale come first,
+/// followed by the classes of each rating scale of a borrower's own in ascending number of classes.
+/// The default of an own scale is pooled with the default of the common scale
+#[derive(Clone)]
+struct RatingColumns {
+    /// Number of rating classes of the common scale, i.e. the largest of the borrowers on it
+    common: usize,
+    /// Number of rating classes and first column of each own rating scale
+    own: Vec<(usize, usize)>,
+    /// Number of columns
+    len: usize,
+}
+
+impl RatingColumns {
+    fn new(borrowers: &[&borrower::Borrower]) -> Self {
+        let common = borrowers.iter().filter(|borr| !borr.has_own_rating_scale()).map(|borr| borr.num_ratings()).max().unwrap_or(1);
+        let mut sizes: Vec<usize> = borrowers.iter().filter(|borr| borr.has_own_rating_scale()).map(|borr| borr.num_ratings()).collect();
+        sizes.sort_unstable();
+        sizes.dedup();
+
+        // All classes but the default of each own scale, a single class has no default
+        let mut len = common;
+        let own = sizes.into_iter().map(|size| {
+            let first = len;
+            len += if size > 1 { size - 1 } else { 1 };
+            (size, first)
+        }).collect();
+        Self { common, own, len }
+    }
+
+    /// Column of a rating class of the borrower
+    fn column(&self, borr: &borrower::Borrower, rating: usize) -> usize {
+        let size = borr.num_ratings();
+        if !borr.has_own_rating_scale() {
+            return rating;
+        }
+        if size > 1 && rating + 1 == size {
+            return self.common - 1;
+        }
+        let (_, first) = self.own.iter().find(|(own, _)| *own == size).expect("Rating scale of the borrower");
+        first + rating
+    }
+
+    /// Label of a column, that of the common scale or the class and size of an own scale
+    fn label(&self, column: usize, scale: &rating::RatingScale) -> String {
+        match self.own.iter().rev().find(|(_, first)| *first <= column) {
+            Some((size, first)) => format!("{} of {}", column - first, size),
+            None => scale.label(column),
+        }
+    }
+}
+
+/// Guarantee of an exposure in the arena with the borrowers given by their index
+#[derive(Clone)]
+struct ArenaGuarantee {
+    /// Borrower of the guaranteed exposure
+    obligor: usize,
+    /// Default rating class of the obligor
+    default: usize,
+    /// Guarantor borrower
+    guarantor: usize,
+    /// Reduction of the obligor's default loss by rating class of the guarantor, i.e. the loss of
+    /// the covered fraction at default less its loss at the guarantor's rating
+    relief: Vec<f64>,
+}
+
+/// Generators of the draws of the trials of a chunk, with the systematic draws of all trials
+/// sampled up front by a Latin hypercube or for moment matching
+struct ChunkDraws<R> {
+    /// Generator of the systematic factors, or of all draws for a single seed
+    rng: R,
+    /// Generator of the idiosyncratic draws for split seeds
+    idiosyncratic: Option<R>,
+    /// Systematic draws of all trials of the chunk if sampled up front
+    systematic: Option<Array2<f64>>,
+    /// Index of the next trial within the chunk
+    trial: usize,
+}
+
+impl BorrowerArena {
+    /// Copy the data of all borrowers in portfolio order
+    fn new(risk_groups: &[risk_group::RiskGroup], group_parents: &[Option<(usize, f64)>], degrees_of_freedom: Option<f64>) -> Self {
+        // Groups with own degrees of freedom draw their own mixing variable, the others share the
+        // portfolio-wide one
+        let own = risk_groups.iter().filter(|rg| rg.degrees_of_freedom().is_some()).count();
+        let mut next = 0;
+        let group_copula: Vec<Option<(f64, usize)>> = risk_groups.iter()
+            .map(|rg| match rg.degrees_of_freedom() {
+                Some(df) => {
+                    next += 1;
+                    Some((df, next - 1))
+                }
+                None => degrees_of_freedom.map(|df| (df, own)),
+            })
+            .collect();
+        let shared = degrees_of_freedom.filter(|_| own < risk_groups.len());
+
+        let mut arena = Self {
+            offsets: vec![0],
+            thresholds: Vec::new(),
+            losses: Vec::new(),
+            rating: Vec::new(),
+            absorbing: Vec::new(),
+            factor_slot: Vec::new(),
+            weights: Vec::new(),
+            group_ends: Vec::new(),
+            links: group_parents.iter().map(|link| link.map(|(parent, weight)| (parent, weight, (1. - weight * weight).sqrt()))).collect(),
+            nested: group_parents.iter().any(Option::is_some),
+            parents: Vec::new(),
+            guarantees: Vec::new(),
+            mixing: risk_groups.iter().filter_map(|rg| rg.degrees_of_freedom()).chain(shared).map(|df| ChiSquared::new(df).unwrap()).collect(),
+            degrees_of_freedom: group_copula,
+            layout: RatingColumns::new(&risk_groups.iter().flat_map(|rg| rg.iter_borrower()).collect::<Vec<_>>()),
+            columns: Vec::new(),
+        };
+
+        let normal = Normal::new(0., 1.).unwrap();
+        for (group, rg) in risk_groups.iter().enumerate() {
+            arena.parents.push(rg.parent().map(|parent| arena.rating.len() + parent));
+            let t = arena.degrees_of_freedom[group].map(|(df, _)| StudentsT::new(0., 1., df).unwrap());
+            for borr in rg.iter_borrower() {
+                match t.as_ref() {
+                    None => arena.thresholds.extend_from_slice(borr.thresholds()),
+                    Some(t) => arena.thresholds.extend(borr.thresholds().iter().map(|&threshold| t_threshold(threshold, &normal, t))),
+                }
+                arena.losses.extend(borr.losses().iter());
+                arena.columns.extend((0..borr.num_ratings()).map(|rating| arena.layout.column(borr, rating)));
+                arena.offsets.push(arena.losses.len());
+                arena.rating.push(borr.rating());
+                arena.absorbing.push(borr.is_absorbing());
+                arena.factor_slot.push(borr.factor_slot());
+                let sign = borr.z_convention().sign();
+                arena.weights.push([sign * borr.rho().sqrt(), sign * (1. - borr.rho()).sqrt(), (1. - borr.eps()).sqrt(), borr.eps().sqrt()]);
+            }
+            arena.group_ends.push(arena.rating.len());
+        }
+
+        // Guarantees by the index of the guarantor
+        let borrowers: Vec<&borrower::Borrower> = risk_groups.iter().flat_map(|rg| rg.iter_borrower()).collect();
+        let edges: Vec<(&str, &str)> = borrowers.iter()
+            .flat_map(|borr| borr.guaranteed_losses().into_iter().map(|(guarantor, _)| (borr.id(), guarantor)))
+            .collect();
+        if edges.is_empty() {
+            return arena;
+        }
+        if let Some(cycle) = find_cycle(&edges) {
+            panic!("Circular guarantee {}", cycle.join(" -> "));
+        }
+        let index: HashMap<&str, usize> = borrowers.iter().enumerate().map(|(index, borr)| (borr.id(), index)).collect();
+        for (obligor, borr) in borrowers.iter().enumerate() {
+            if borr.num_ratings() < 2 {
+                continue;
+            }
+            let default = borr.num_ratings() - 1;
+            for (guarantor_id, covered) in borr.guaranteed_losses() {
+                let guarantor = *index.get(guarantor_id).unwrap_or_else(|| panic!("Guarantor {} of borrower {} not found", guarantor_id, borr.id()));
+                assert_eq!(borrowers[guarantor].num_ratings(), covered.len(), "Guarantor {} of borrower {} needs the same rating classes", guarantor_id, borr.id());
+                let relief = covered.iter().map(|loss| covered[default] - loss).collect();
+                arena.guarantees.push(ArenaGuarantee { obligor, default, guarantor, relief });
+            }
+        }
+
+        arena
+    }
+
+    /// Loss of a borrower given its rating class of a trial, counting its migration and default
+    #[inline(always)]
+    fn settle(&self, index: usize, group: usize, rating: usize, migrations: Option<&mut migration::MigrationCounts>, num_defaults: &mut usize) -> f64 {
+        if let Some(counts) = migrations {
+            let offset = self.offsets[index];
+            counts.add(group, self.columns[offset + self.rating[index]], self.columns[offset + rating]);
+        }
+        if self.is_default(index, rating) {
+            *num_defaults += 1;
+        }
+        self.losses[self.offsets[index] + rating]
+    }
+
+    /// The last rating class is the default unless it is the only one, like `Borrower::is_default`
+    #[inline(always)]
+    fn is_default(&self, index: usize, rating: usize) -> bool {
+        let (start, stop) = (self.offsets[index], self.offsets[index + 1]);
+        stop - start > 1 && start + rating + 1 == stop
+    }
+}
+
+/// Defines a portfolio that handles the simulation and correlation structure, a clone can be
+/// stressed independently, e.g. for each scenario of a mixture
+#[derive(Clone)]
 pub struct Portfolio {
     /// Number of risk factors used in the portfolio model
     risk_factors: usize,
     /// Covariance matrix (positive semi-definite)
     cov: Array2<f64>,
-    /// Cholesky decomposition of covariance matrix
+    /// Cholesky decomposition of the covariance matrix of the simulated factors, restricted to the
+    /// columns of the factors that are not fixed by a scenario
     lower: Array2<f64>,
-    /// Container of all risk groups
+    /// Mean of the simulated factors, non-zero only if a scenario or shifts are set
+    mean: Array1<f64>,
+    /// Index of each simulated factor, all factors unless unused ones have been dropped
+    factors: Vec<usize>,
+    /// Factors fixed by the scenario with their values
+    scenario: Vec<(usize, f64)>,
+    /// Shifts of the means of the factors that are not fixed by the scenario
+    shifts: Vec<(usize, f64)>,
+    /// Container of all risk groups, nested groups follow their parent
     risk_group: Vec<risk_group::RiskGroup>,
+    /// Parent of each nested risk group with the weight of the parent's shock
+    group_parents: Vec<Option<(usize, f64)>>,
     /// Number of borrowers within portfolio
     num_borrower: usize,
+    /// Distinct risk factor weights with their norm, the systematic factor of each slot is
+    /// computed once per trial for all borrowers referencing it
+    factor_slots: Vec<(Array1<f64>, f64)>,
+    /// Slot of each distinct weight vector by the bits of the weights
+    slot_index: HashMap<Vec<u64>, usize>,
+    /// Borrower data of the trials, built by `freeze` or on the first trial and dropped by every
+    /// modification of the borrowers
+    arena: OnceLock<BorrowerArena>,
+    /// Labels of the rating classes used for output and diagnostics
+    rating_scale: rating::RatingScale,
+    /// Notches the other members of a risk group are downgraded by if its parent defaults, zero
+    /// without contagion
+    contagion_notches: usize,
+    /// Sampling of the systematic draws of the trials of a chunk
+    sampler: rng::Sampler,
+    /// Whether the systematic draws of a chunk are rescaled to their exact moments
+    moment_matching: bool,
+    /// Degrees of freedom of the portfolio-wide Student-t copula, None for the Gaussian copula
+    degrees_of_freedom: Option<f64>,
+    /// Diagonal jitter added to the covariance matrix if its Cholesky decomposition fails
+    psd_jitter: f64,
+    /// Whether the jitter had to be added for a decomposition
+    jitter_applied: bool,
+    /// Time spent on Cholesky decompositions of the covariance matrix
+    cholesky_duration: Duration,
+    /// Time spent on the norms of the borrowers given the covariance matrix
+    norm_duration: Duration,
 }
 
 impl Portfolio {
     /// Create new instance with covariance matrix. The cholesky decomposition will be computed
     pub fn new(cov: Array2<f64>) -> Self {
-        let lower: Array2<f64> = cov.cholesky(UPLO::Lower).expect("No Cholesky decomposition possible");
+        Self::with_psd_jitter(cov, 0.)
+    }
+
+    /// Create new instance with covariance matrix that may be only nearly positive semi-definite.
+    /// If the Cholesky decomposition fails, it is retried with the jitter added to the diagonal
+    pub fn with_psd_jitter(cov: Array2<f64>, psd_jitter: f64) -> Self {
+        assert!(psd_jitter >= 0., "Diagonal jitter {} must not be negative", psd_jitter);
+        let start = Instant::now();
+        let (lower, jitter_applied) = decompose(&cov, psd_jitter);
+        let cholesky_duration = start.elapsed();
 
         Self {
             risk_factors: cov.ncols(),
-            cov: cov,
-            lower: lower,
+            mean: Array1::zeros(cov.ncols()),
+            factors: (0..cov.ncols()).collect(),
+            scenario: Vec::new(),
+            shifts: Vec::new(),
+            cov,
+            lower,
             risk_group: Vec::new(),
+            group_parents: Vec::new(),
             num_borrower: 0,
+            factor_slots: Vec::new(),
+            slot_index: HashMap::new(),
+            arena: OnceLock::new(),
+            rating_scale: rating::RatingScale::default(),
+            contagion_notches: 0,
+            sampler: rng::Sampler::MonteCarlo,
+            moment_matching: false,
+            degrees_of_freedom: None,
+            psd_jitter,
+            jitter_applied,
+            cholesky_duration,
+            norm_duration: Duration::ZERO,
         }
     }
 
-    /// Add a risk group to the portfolio
-    pub fn add_risk_group(&mut self, mut risk_group: risk_group::RiskGroup) {
+    /// Add a risk group to the portfolio. Nested groups are added after their parent, each group
+    /// and its descendants before the next child
+    pub fn add_risk_group(&mut self, risk_group: risk_group::RiskGroup) {
+        self.add_nested_group(risk_group, None);
+    }
+
+    /// Add a risk group with its parent and the weight on it, followed by its child groups
+    fn add_nested_group(&mut self, mut risk_group: risk_group::RiskGroup, parent: Option<(usize, f64)>) {
+        let children = risk_group.take_children();
+        let index = self.risk_group.len();
+        self.add_group(risk_group, parent);
+        for (child, weight) in children {
+            self.add_nested_group(child, Some((index, weight)));
+        }
+    }
+
+    /// Add a single risk group without child groups
+    fn add_group(&mut self, mut risk_group: risk_group::RiskGroup, parent: Option<(usize, f64)>) {
+        let start = Instant::now();
         risk_group.set_norm(&self.cov);
+        self.norm_duration += start.elapsed();
+        // The systematic factor is divided by the norm, so a zero norm poisons every trial
+        if let Some(borr) = risk_group.iter_borrower().find(|borr| borr.norm().is_nan() || borr.norm() == 0.) {
+            panic!("{}", PortfolioError::ZeroSystematicVariance(borr.id().to_string()));
+        }
+        for borr in risk_group.iter_borrower_mut() {
+            self.assign_factor_slot(borr);
+        }
         self.num_borrower += risk_group.num_borrower();
         self.risk_group.push(risk_group);
+        self.group_parents.push(parent);
+        self.arena = OnceLock::new();
+    }
+
+    /// Add a risk group to an existing portfolio after checking that all borrowers fit the
+    /// covariance matrix
+    pub fn try_add_risk_group(&mut self, risk_group: risk_group::RiskGroup) -> Result<(), PortfolioError> {
+        self.check_risk_group(&risk_group)?;
+        self.add_risk_group(risk_group);
+        Ok(())
+    }
+
+    /// Check the borrowers of a risk group and its child groups
+    fn check_risk_group(&self, risk_group: &risk_group::RiskGroup) -> Result<(), PortfolioError> {
+        for borr in risk_group.iter_borrower() {
+            self.check_borrower(borr)?;
+        }
+        let mut parents = risk_group.iter_borrower().filter(|borr| borr.is_parent());
+        if let (Some(existing), Some(added)) = (parents.next(), parents.next()) {
+            return Err(PortfolioError::MultipleParents { risk_group: risk_group.id().to_string(), existing: existing.id().to_string(), added: added.id().to_string() });
+        }
+        risk_group.iter_children().try_for_each(|(child, _)| self.check_risk_group(child))
+    }
+
+    /// Add a borrower to an existing risk group of the portfolio, e.g. for what-if analysis. The
+    /// norm of the borrower is set given the covariance matrix of the portfolio
+    pub fn try_add_borrower(&mut self, risk_group: &str, mut borrower: borrower::Borrower) -> Result<(), PortfolioError> {
+        self.check_borrower(&borrower)?;
+        let index = self.risk_group.iter()
+            .position(|rg| rg.id() == risk_group)
+            .ok_or_else(|| PortfolioError::UnknownRiskGroup(risk_group.to_string()))?;
+        if borrower.is_parent() && let Some(parent) = self.risk_group[index].parent() {
+            let existing = self.risk_group[index].iter_borrower().nth(parent).unwrap().id().to_string();
+            return Err(PortfolioError::MultipleParents { risk_group: risk_group.to_string(), existing, added: borrower.id().to_string() });
+        }
+
+        let start = Instant::now();
+        borrower.set_norm(&self.cov);
+        self.norm_duration += start.elapsed();
+        self.assign_factor_slot(&mut borrower);
+        self.risk_group[index].add_borrower(borrower);
+        self.num_borrower += 1;
+        self.arena = OnceLock::new();
+        Ok(())
+    }
+
+    /// Add an exposure to an existing borrower given by its risk group and index within the risk
+    /// group, e.g. for what-if analysis
+    pub fn try_add_exposure(&mut self, risk_group: &str, index: usize, exposure: exposure::Exposure) -> Result<(), PortfolioError> {
+        self.arena = OnceLock::new();
+        let borr = self.risk_group.iter_mut()
+            .find(|rg| rg.id() == risk_group)
+            .ok_or_else(|| PortfolioError::UnknownRiskGroup(risk_group.to_string()))?
+            .borrower_mut(index)
+            .ok_or_else(|| PortfolioError::UnknownBorrower { risk_group: risk_group.to_string(), index })?;
+
+        if borr.num_ratings() != exposure.num_values() {
+            return Err(PortfolioError::ValuationMismatch { expected: borr.num_ratings(), found: exposure.num_values() });
+        }
+        borr.add_exposure(exposure);
+        borr.finalize();
+        Ok(())
+    }
+
+    /// Check that a borrower fits the covariance matrix of the portfolio
+    fn check_borrower(&self, borrower: &borrower::Borrower) -> Result<(), PortfolioError> {
+        if borrower.num_risk_factors() != self.risk_factors {
+            return Err(PortfolioError::RiskFactorMismatch { expected: self.risk_factors, found: borrower.num_risk_factors() });
+        }
+        if let Some(index) = self.dropped_factor(borrower) {
+            return Err(PortfolioError::DroppedRiskFactor(index));
+        }
+        let weights = borrower.risk_factor_weights();
+        let variance = weights.dot(&self.cov.dot(weights));
+        if variance.is_nan() || variance <= 0. {
+            return Err(PortfolioError::ZeroSystematicVariance(borrower.id().to_string()));
+        }
+        Ok(())
+    }
+
+    /// First dropped factor the borrower loads onto
+    fn dropped_factor(&self, borrower: &borrower::Borrower) -> Option<usize> {
+        borrower.risk_factor_weights().iter().enumerate()
+            .position(|(index, &w)| w != 0. && self.factors.binary_search(&index).is_err())
+    }
+
+    /// Drop the risk factors no borrower loads onto from the simulation, so that the systematic
+    /// factors of a trial are generated from the Cholesky decomposition of the covariance matrix
+    /// of the remaining factors only. Their joint distribution is unchanged, the draws of a trial
+    /// differ if any factor is dropped. Borrowers added later must not load onto dropped factors.
+    /// Returns the number of dropped factors
+    pub fn drop_unused_factors(&mut self) -> usize {
+        // The factor slots hold the distinct weights of all borrowers
+        let kept: Vec<usize> = (0..self.factors.len())
+            .filter(|&position| self.factor_slots.iter().any(|(weights, _)| weights[position] != 0.))
+            .collect();
+        let dropped = self.factors.len() - kept.len();
+        if dropped == 0 {
+            return 0;
+        }
+
+        for (weights, _) in self.factor_slots.iter_mut() {
+            *weights = weights.select(Axis(0), &kept);
+        }
+        self.factors = kept.iter().map(|&position| self.factors[position]).collect();
+        let scenario = std::mem::take(&mut self.scenario);
+        self.set_scenario(&scenario);
+        dropped
+    }
+
+    /// Number of simulated risk factors, i.e. without the dropped ones
+    pub fn num_simulated_factors(&self) -> usize {
+        self.factors.len()
+    }
+
+    /// All risk factors given the simulated ones, dropped factors are zero
+    fn all_factors(&self, simulated: &Array1<f64>) -> Array1<f64> {
+        let mut all: Array1<f64> = Array1::zeros(self.risk_factors);
+        for (&index, &value) in self.factors.iter().zip(simulated.iter()) {
+            all[index] = value;
+        }
+        all
+    }
+
+    /// Fix some or all systematic factors (given by index and value) to a scenario. The remaining
+    /// factors are sampled from their normal distribution conditional on the fixed values, so the
+    /// simulation results are conditional on the scenario. An empty scenario restores the
+    /// unconditional model. Dropped factors fixed by the scenario still condition the simulated ones
+    pub fn set_scenario(&mut self, fixed: &[(usize, f64)]) {
+        let n = self.risk_factors;
+        let mut values: Vec<Option<f64>> = vec![None; n];
+        for &(index, value) in fixed.iter() {
+            assert!(index < n, "Scenario risk factor {} exceeds the {} risk factors", index, n);
+            values[index] = Some(value);
+        }
+        self.scenario = fixed.to_vec();
+
+        // Order fixed factors first, then the Cholesky decomposition splits into
+        // X_F = L_FF n_F and X_U = L_UF n_F + L_UU n_U, only simulated factors are free
+        let f: Vec<usize> = (0..n).filter(|&i| values[i].is_some()).collect();
+        let u: Vec<usize> = self.factors.iter().copied().filter(|&i| values[i].is_none()).collect();
+        let order: Vec<usize> = f.iter().chain(u.iter()).copied().collect();
+        let start = Instant::now();
+        let (lower, jitter_applied) = decompose(&self.cov.select(Axis(0), &order).select(Axis(1), &order), self.psd_jitter);
+        self.jitter_applied |= jitter_applied;
+        self.cholesky_duration += start.elapsed();
+
+        // Forward substitution of L_FF n_F = x_F
+        let k = f.len();
+        let mut n_f: Array1<f64> = Array1::zeros(k);
+        for i in 0..k {
+            let sum: f64 = (0..i).map(|j| lower[[i, j]] * n_f[j]).sum();
+            n_f[i] = (values[f[i]].unwrap() - sum) / lower[[i, i]];
+        }
+
+        // Conditional mean L_UF n_F and decomposition L_UU of the free factors
+        let mean_u = lower.slice(s![k.., ..k]).dot(&n_f);
+        let lower_u = lower.slice(s![k.., k..]);
+
+        // Rows of the simulated factors
+        let position = |index: usize| self.factors.binary_search(&index).ok();
+        let mut mean: Array1<f64> = Array1::zeros(self.factors.len());
+        let mut lower: Array2<f64> = Array2::zeros((self.factors.len(), u.len()));
+        for &index in f.iter() {
+            if let Some(row) = position(index) {
+                mean[row] = values[index].unwrap();
+            }
+        }
+        for (i, &index) in u.iter().enumerate() {
+            let row = position(index).unwrap();
+            mean[row] = mean_u[i];
+            lower.row_mut(row).assign(&lower_u.row(i));
+        }
+        for &(index, shift) in self.shifts.iter().filter(|&&(index, _)| values[index].is_none()) {
+            if let Some(row) = position(index) {
+                mean[row] += shift;
+            }
+        }
+        self.mean = mean;
+        self.lower = lower;
+    }
+
+    /// Shift the means of some systematic factors (given by index and shift), e.g. to a macro
+    /// scenario. A positive shift raises the asset values of the borrowers loading positively onto
+    /// the factor, i.e. is adverse with `ZConvention::LowIsBest` and favorable with
+    /// `ZConvention::LowIsDefault`. Factors fixed by a scenario or dropped are not shifted
+    pub fn shift_factors(&mut self, shifts: &[(usize, f64)]) {
+        for &(index, _) in shifts.iter() {
+            assert!(index < self.risk_factors, "Shifted risk factor {} exceeds the {} risk factors", index, self.risk_factors);
+        }
+        self.shifts = shifts.to_vec();
+        let scenario = std::mem::take(&mut self.scenario);
+        self.set_scenario(&scenario);
+    }
+
+    /// Merge structurally identical borrowers within each risk group into buckets simulated as one
+    /// borrower. This is a modeling choice: the members of a bucket share their idiosyncratic draw,
+    /// i.e. they are perfectly correlated, which leaves the expected loss unchanged but fattens the
+    /// tail. Per-borrower results refer to the buckets, named after their first member. Guarantors
+    /// are not bucketed, so that their guarantees still find them. Returns the number of borrowers
+    /// after bucketing
+    pub fn bucket_identical(&mut self) -> usize {
+        let guarantors: HashSet<String> = self.iter_risk_group().flat_map(|rg| rg.iter_borrower())
+            .flat_map(|borr| borr.guaranteed_losses().into_iter().map(|(guarantor, _)| guarantor.to_string()))
+            .collect();
+        self.num_borrower = self.risk_group.iter_mut().map(|rg| rg.bucket_identical(&guarantors)).sum();
+        self.arena = OnceLock::new();
+        self.num_borrower
+    }
+
+    /// Scale the probability of default of every borrower by the multiplier of its current rating
+    /// and rebuild its thresholds, see `Borrower::stress_default_probability`. Ratings beyond the
+    /// multipliers are not stressed
+    pub fn stress_default_probabilities(&mut self, multipliers: &[f64]) {
+        for borr in self.risk_group.iter_mut().flat_map(|rg| rg.iter_borrower_mut()) {
+            if let Some(&multiplier) = multipliers.get(borr.rating()) {
+                borr.stress_default_probability(multiplier);
+            }
+        }
+        self.arena = OnceLock::new();
+    }
+
+    /// Average probability of default of the borrowers, i.e. the expected default rate
+    pub fn average_default_probability(&self) -> f64 {
+        let pd: f64 = self.iter_risk_group().flat_map(|rg| rg.iter_borrower()).map(|borr| borr.default_probability()).sum();
+        if self.num_borrower > 0 { pd / self.num_borrower as f64 } else { 0. }
+    }
+
+    /// Let the borrower reference the factor slot of its risk factor weights, a new slot is created
+    /// for weights not seen before
+    fn assign_factor_slot(&mut self, borr: &mut borrower::Borrower) {
+        if let Some(index) = self.dropped_factor(borr) {
+            panic!("Borrower {} loads onto risk factor {} dropped as unused", borr.id(), index);
+        }
+        let key: Vec<u64> = borr.risk_factor_weights().iter().map(|w| w.to_bits()).collect();
+        let slot = *self.slot_index.entry(key).or_insert_with(|| {
+            self.factor_slots.push((borr.risk_factor_weights().select(Axis(0), &self.factors), borr.norm()));
+            self.factor_slots.len() - 1
+        });
+        borr.set_factor_slot(slot);
+    }
+
+    /// Downgrade the other members of a risk group by the number of notches, at most to default,
+    /// whenever its parent borrower defaults in a trial. The analytic expected loss does not
+    /// account for contagion
+    pub fn set_contagion_notches(&mut self, notches: usize) {
+        self.contagion_notches = notches;
+    }
+
+    /// Sample the systematic draws of the chunks of `simulate` and its replays with the sampler.
+    /// Single trials, e.g. of `trial`, are always drawn independently
+    pub fn set_sampler(&mut self, sampler: rng::Sampler) {
+        self.sampler = sampler;
+    }
+
+    /// Rescale the systematic draws of each chunk of `simulate` and its replays to a sample mean of
+    /// exactly zero and a sample covariance of exactly the identity before they are correlated,
+    /// which removes the sampling error of the first two moments of the factors. Chunks with no
+    /// more trials than free factors are left unchanged
+    pub fn set_moment_matching(&mut self, moment_matching: bool) {
+        self.moment_matching = moment_matching;
+    }
+
+    /// Join all borrowers by a Student-t copula with the degrees of freedom, or by the Gaussian
+    /// copula for None. Unlike `RiskGroup::set_degrees_of_freedom`, one mixing variable is drawn
+    /// per trial for the whole portfolio, so all risk groups share the scale of their asset values.
+    /// Risk groups with own degrees of freedom keep their own mixing variable
+    pub fn set_degrees_of_freedom(&mut self, degrees_of_freedom: Option<f64>) {
+        if let Some(df) = degrees_of_freedom {
+            assert!(df > 0. && df.is_finite(), "Portfolio has degrees of freedom {}, they must be positive", df);
+        }
+        self.degrees_of_freedom = degrees_of_freedom;
+        self.arena = OnceLock::new();
+    }
+
+    /// Set the ordering of the rating classes along the asset value for all borrowers, see
+    /// `ZConvention`
+    pub fn set_z_convention(&mut self, z_convention: migration::ZConvention) {
+        self.risk_group.iter_mut().flat_map(|rg| rg.iter_borrower_mut()).for_each(|borr| borr.set_z_convention(z_convention));
+        self.arena = OnceLock::new();
+    }
+
+    /// Make the rating class with the index absorbing for all borrowers, or the default class of
+    /// each borrower for None, see `Borrower::set_absorbing_rating`
+    pub fn set_absorbing_rating(&mut self, rating: Option<usize>) {
+        self.risk_group.iter_mut().flat_map(|rg| rg.iter_borrower_mut()).for_each(|borr| borr.set_absorbing_rating(rating));
+        self.arena = OnceLock::new();
+    }
+
+    /// Whether contagion applies, i.e. notches are set and a risk group has a parent
+    pub fn has_contagion(&self) -> bool {
+        self.contagion_notches > 0 && self.risk_group.iter().any(|rg| rg.parent().is_some())
+    }
+
+    /// Number of distinct risk factor weight vectors, i.e. systematic factors computed per trial
+    pub fn num_factor_slots(&self) -> usize {
+        self.factor_slots.len()
+    }
+
+    /// Diagonal jitter if it had to be added to the covariance matrix for a Cholesky decomposition
+    pub fn jitter_applied(&self) -> Option<f64> {
+        self.jitter_applied.then_some(self.psd_jitter)
+    }
+
+    /// Time spent on Cholesky decompositions of the covariance matrix
+    pub fn cholesky_duration(&self) -> Duration {
+        self.cholesky_duration
+    }
+
+    /// Time spent on the norms of the borrowers given the covariance matrix
+    pub fn norm_duration(&self) -> Duration {
+        self.norm_duration
+    }
+
+    /// Set the labels of the rating classes
+    pub fn set_rating_scale(&mut self, rating_scale: rating::RatingScale) {
+        self.rating_scale = rating_scale;
+    }
+
+    /// Get the labels of the rating classes
+    pub fn rating_scale(&self) -> &rating::RatingScale {
+        &self.rating_scale
+    }
+
+    /// Index of a borrower in the order of simulation, i.e. its position in the loss per borrower
+    pub fn borrower_index(&self, id: &str) -> Option<usize> {
+        self.iter_risk_group().flat_map(|rg| rg.iter_borrower()).position(|borr| borr.id() == id)
     }
 
     /// Get iterator over the risk groups
@@ -49,90 +770,950 @@ impl Portfolio {
         self.risk_group.iter()
     }
 
+    /// Parent of the risk group at an index of `iter_risk_group` with the weight of the parent's
+    /// shock, `None` for a top-level group
+    pub fn group_parent(&self, index: usize) -> Option<(&str, f64)> {
+        self.group_parents[index].map(|(parent, weight)| (self.risk_group[parent].id(), weight))
+    }
+
+    /// Number of risk factors
+    pub fn num_risk_factors(&self) -> usize {
+        self.risk_factors
+    }
+
+    /// Number of borrowers
+    pub fn num_borrower(&self) -> usize {
+        self.num_borrower
+    }
+
+    /// Number of exposures of all borrowers
+    pub fn num_exposures(&self) -> usize {
+        self.risk_group.iter().map(|rg| rg.iter_borrower().map(|borr| borr.num_exposures()).sum::<usize>()).sum()
+    }
+
+    /// Number of rating classes, i.e. the largest rating scale of all borrowers
+    pub fn num_ratings(&self) -> usize {
+        self.risk_group.iter().flat_map(|rg| rg.iter_borrower()).map(|borr| borr.num_ratings()).max().unwrap_or(0)
+    }
+
+    /// Number of columns of the outputs by rating class: the classes of the common rating scale
+    /// followed by those of the borrowers' own scales, whose defaults are pooled with the common
+    /// default. Without own scales, the number of rating classes
+    pub fn num_rating_columns(&self) -> usize {
+        self.arena().layout.len
+    }
+
+    /// Column of a rating class of a borrower of the portfolio in the outputs by rating class
+    pub fn rating_column(&self, borr: &borrower::Borrower, rating: usize) -> usize {
+        self.arena().layout.column(borr, rating)
+    }
+
+    /// Label of a column of the outputs by rating class, e.g. `1 of 3` for the second class of an
+    /// own rating scale with three classes
+    pub fn rating_column_label(&self, column: usize) -> String {
+        self.arena().layout.label(column, &self.rating_scale)
+    }
+
+    /// Decomposition of the asset value variance averaged over all borrowers weighted by their
+    /// outstanding amount, tells whether the portfolio risk is dominated by the systematic factors.
+    /// Without a positive total outstanding amount, the borrowers are weighted equally
+    pub fn variance_decomposition(&self) -> borrower::VarianceDecomposition {
+        let outstanding: f64 = self.risk_group.iter().flat_map(|rg| rg.iter_borrower()).map(|borr| borr.outstanding()).sum();
+        let weighted = outstanding > 0.;
+        if !weighted && self.num_borrower > 0 {
+            log::warn!("Total outstanding amount {} is not positive, the variance decomposition weights the borrowers equally", outstanding);
+        }
+
+        let mut total = borrower::VarianceDecomposition { systematic: 0., group: 0., idiosyncratic: 0. };
+        let mut total_weight = 0.;
+        for borr in self.risk_group.iter().flat_map(|rg| rg.iter_borrower()) {
+            let decomposition = borr.variance_decomposition();
+            let weight = if weighted { borr.outstanding() } else { 1. };
+            total.systematic += weight * decomposition.systematic;
+            total.group += weight * decomposition.group;
+            total.idiosyncratic += weight * decomposition.idiosyncratic;
+            total_weight += weight;
+        }
+
+        if total_weight > 0. {
+            total.systematic /= total_weight;
+            total.group /= total_weight;
+            total.idiosyncratic /= total_weight;
+        }
+        total
+    }
+
     /// Expected loss of portfolio
     pub fn expected_loss(&self) -> f64 {
         self.risk_group.iter().map(|rg| rg.iter_borrower().map(|borr| borr.expected_loss()).sum::<f64>()).sum()
     }
 
+    /// Empirical probability of a portfolio loss above the threshold, i.e. the fraction of the
+    /// simulated trials exceeding it, zero without trials
+    pub fn exceedance_probability<L: LossScalar>(loss: &[L], threshold: f64) -> f64 {
+        if loss.is_empty() {
+            return 0.;
+        }
+        loss.iter().filter(|l| l.to_f64() > threshold).count() as f64 / loss.len() as f64
+    }
+
+    /// Deterministic portfolio loss at the mean of all randomness, i.e. the systematic factors at
+    /// their (scenario conditional) mean and no idiosyncratic risk. Each borrower migrates to the
+    /// rating class of its median asset value, so the loss is zero unless a borrower's migration
+    /// probabilities put half of the mass away from its current rating
+    pub fn mean_scenario_loss(&self) -> f64 {
+        let mean = self.all_factors(&self.mean);
+        self.risk_group.iter().map(|rg| rg.iter_borrower().map(|borr| {
+            let y = borr.risk_factor(&mean);
+            *borr.get_loss(&borr.migration(&borr.asset_value(&y, &0., &0.)))
+        }).sum::<f64>()).sum()
+    }
+
+    /// Current value of all borrowers, the base the losses of the trials are measured against
+    pub fn total_value(&self) -> f64 {
+        self.iter_risk_group().flat_map(|rg| rg.iter_borrower()).map(|borr| borr.value()).sum()
+    }
+
+    /// Sum of the valuations of all borrowers at their ratings in portfolio order, e.g. of
+    /// `replay_ratings`. Without guarantees, this is the current value less the loss of the trial.
+    /// It leaves out the relief of guarantees, which the current value less the loss includes
+    pub fn realized_value(&self, ratings: &Array1<usize>) -> f64 {
+        assert_eq!(ratings.len(), self.num_borrower, "Number of ratings does not match the number of borrowers");
+        self.iter_risk_group().flat_map(|rg| rg.iter_borrower()).zip(ratings.iter()).map(|(borr, &rating)| borr.valuations()[rating]).sum()
+    }
+
+    /// Outstanding amount of all exposures of the portfolio
+    pub fn total_outstanding(&self) -> f64 {
+        self.iter_risk_group().flat_map(|rg| rg.iter_borrower()).map(|borr| borr.outstanding()).sum()
+    }
+
+    /// Average current rating index weighted by the current value of the borrowers. Borrowers
+    /// without positive value are excluded, `None` if no borrower has a positive value
+    pub fn average_rating(&self) -> Option<f64> {
+        self.value_weighted(|borr| borr.rating() as f64)
+    }
+
+    /// Average expected rating index after one period weighted by the current value of the
+    /// borrowers. Borrowers without positive value are excluded, `None` if no borrower has a
+    /// positive value
+    pub fn expected_average_rating(&self) -> Option<f64> {
+        self.value_weighted(|borr| borr.expected_rating())
+    }
+
+    /// Average of a borrower quantity weighted by the current value of the borrowers
+    fn value_weighted<F: Fn(&borrower::Borrower) -> f64>(&self, f: F) -> Option<f64> {
+        let (sum, weight) = self.iter_risk_group()
+            .flat_map(|rg| rg.iter_borrower())
+            .filter(|borr| borr.value() > 0.)
+            .fold((0.0_f64, 0.0_f64), |(sum, weight), borr| (sum + borr.value() * f(borr), weight + borr.value()));
+
+        if weight > 0. { Some(sum / weight) } else { None }
+    }
+
+    /// Expected number of migrations into each rating class per trial given the migration
+    /// probabilities, either for the whole portfolio or broken down by risk group
+    pub fn expected_migrations(&self, by_group: bool) -> Array2<f64> {
+        let rows = if by_group { self.risk_group.len() } else { 1 };
+        let mut out: Array2<f64> = Array2::zeros((rows, self.num_rating_columns()));
+
+        for (index, rg) in self.iter_risk_group().enumerate() {
+            let row = if by_group { index } else { 0 };
+            for borr in rg.iter_borrower() {
+                for (rating, p) in borr.migration_probabilities().iter().enumerate() {
+                    out[[row, self.rating_column(borr, rating)]] += p;
+                }
+            }
+        }
+
+        out
+    }
+
+    /// Expected number of migrations per trial with current rating class as row and resulting
+    /// rating class as column given the migration probabilities
+    pub fn expected_migration_matrix(&self) -> Array2<f64> {
+        let num_columns = self.num_rating_columns();
+        let mut out: Array2<f64> = Array2::zeros((num_columns, num_columns));
+
+        for borr in self.iter_risk_group().flat_map(|rg| rg.iter_borrower()) {
+            let current = self.rating_column(borr, borr.rating());
+            for (rating, p) in borr.migration_probabilities().iter().enumerate() {
+                out[[current, self.rating_column(borr, rating)]] += p;
+            }
+        }
+
+        out
+    }
+
     /// Calculate a trial, i.e. simulate the factor model for all entities within the portfolio
     /// given the correlation structure. A random number generator is provided to sample the random
-    /// variables. The function returns the loss per borrower.
-    pub fn trial(&self, rng: &mut rand_pcg::Pcg64) -> Array1<f64> {
+    /// variables. The function returns the loss per borrower. If migration counts are provided,
+    /// the resulting rating of each borrower and the number of defaults of the trial are counted.
+    pub fn trial<R: Rng>(&self, rng: &mut R, migrations: Option<&mut migration::MigrationCounts>) -> Array1<f64> {
+        let mut normals = vec![0.; self.num_draws()];
+        self.trial_with(rng, None, &mut normals, migrations)
+    }
+
+    /// Calculate a trial like `trial`, but with the systematic factors and the idiosyncratic draws
+    /// of the risk groups and borrowers sampled from separate random number generators, so that
+    /// either can be held fixed while the other varies
+    pub fn trial_split<R: Rng>(&self, systematic: &mut R, idiosyncratic: &mut R, migrations: Option<&mut migration::MigrationCounts>) -> Array1<f64> {
+        let mut normals = vec![0.; self.num_draws()];
+        self.trial_with(systematic, Some(idiosyncratic), &mut normals, migrations)
+    }
+
+    /// Simulate the migrations of a trial like `trial`, but return the rating each borrower migrated
+    /// to instead of its loss, e.g. for a valuation of the exposures outside of this crate. Fed
+    /// with the same generator, the ratings are those behind the losses of `trial`
+    pub fn trial_ratings<R: Rng>(&self, rng: &mut R) -> Array1<usize> {
+        let mut normals = vec![0.; self.num_draws()];
+        Array1::from(self.trial_outcomes(rng, None, &mut normals, None, true).1)
+    }
+
+    /// Ratings of all borrowers in the first trials of `simulate` with the same chunk size and
+    /// seed, replayed in order of the trials
+    pub fn replay_ratings<R: rng::SimulationRng>(&self, num_trials: usize, chunk_size: usize, seeds: impl Into<rng::Seeds>) -> Vec<Array1<usize>> {
+        let seeds = seeds.into();
+        let mut normals = vec![0.; self.num_draws()];
+        let mut ratings: Vec<Array1<usize>> = Vec::with_capacity(num_trials);
+
+        for chunk in 0..num_trials.div_ceil(chunk_size) {
+            let mut draws = self.chunk_draws::<R>(&seeds, chunk, chunk_size.min(num_trials - chunk * chunk_size));
+            for _ in 0..chunk_size.min(num_trials - ratings.len()) {
+                self.draw_next(&mut draws, &mut normals);
+                ratings.push(Array1::from(self.draw_outcomes(&normals, None, true).1));
+            }
+        }
+        ratings
+    }
+
+    /// Systematic factors of the first trials of `simulate` with the same chunk size and seed, like
+    /// `systematic_factors` all risk factors in index order with dropped factors zero. Only the
+    /// draws are replayed, in rounds of one chunk per thread, and each round is passed to `sink`
+    /// with the index of its first trial, so that the factors of all trials are never held at once
+    pub fn replay_factors<R: rng::SimulationRng>(&self, num_trials: usize, chunk_size: usize, seeds: impl Into<rng::Seeds>, mut sink: impl FnMut(usize, &[Array1<f64>])) {
+        let seeds = seeds.into();
+        let num_chunks = num_trials.div_ceil(chunk_size);
+        let round_chunks = rayon::current_num_threads().max(1);
+
+        for first in (0..num_chunks).step_by(round_chunks) {
+            let round: Vec<Array1<f64>> = (first..(first + round_chunks).min(num_chunks)).into_par_iter().flat_map_iter(|chunk| {
+                let chunk_trials = chunk_size.min(num_trials - chunk * chunk_size);
+                let mut draws = self.chunk_draws::<R>(&seeds, chunk, chunk_trials);
+                let mut normals = vec![0.; self.num_draws()];
+                (0..chunk_trials).map(|_| {
+                    self.draw_next(&mut draws, &mut normals);
+                    let systematic = ArrayView1::from(&normals[..self.lower.ncols()]);
+                    self.all_factors(&(&self.mean + &self.lower.dot(&systematic)))
+                }).collect::<Vec<Array1<f64>>>()
+            }).collect();
+            sink(first * chunk_size, &round);
+        }
+    }
+
+    /// Calculate a trial like `trial` with all draws of the trial generated into a preallocated
+    /// buffer of `num_draws` values, the idiosyncratic ones from a separate generator if given
+    fn trial_with<R: Rng>(&self, rng: &mut R, idiosyncratic: Option<&mut R>, normals: &mut [f64], migrations: Option<&mut migration::MigrationCounts>) -> Array1<f64> {
+        self.trial_outcomes(rng, idiosyncratic, normals, migrations, false).0
+    }
+
+    /// Calculate a trial like `trial_with` given all systematic factors, only the idiosyncratic
+    /// draws are generated into the buffer
+    fn trial_given<R: Rng>(&self, factors: ArrayView1<f64>, rng: &mut R, normals: &mut [f64], migrations: Option<&mut migration::MigrationCounts>) -> Array1<f64> {
+        let specific = &mut normals[self.lower.ncols()..];
+        self.fill_specific(rng, specific);
+        self.outcomes(&factors.select(Axis(0), &self.factors), specific, migrations, false).0
+    }
+
+    /// Calculate a trial like `trial_with`, returning the ratings of the borrowers as well if they
+    /// are kept
+    fn trial_outcomes<R: Rng>(&self, rng: &mut R, idiosyncratic: Option<&mut R>, normals: &mut [f64], migrations: Option<&mut migration::MigrationCounts>, keep_ratings: bool) -> (Array1<f64>, Vec<usize>) {
+        self.fill_normals(rng, idiosyncratic, normals);
+        self.draw_outcomes(normals, migrations, keep_ratings)
+    }
+
+    /// Losses and optionally ratings of the borrowers given all standard normal draws of a trial
+    fn draw_outcomes(&self, normals: &[f64], migrations: Option<&mut migration::MigrationCounts>, keep_ratings: bool) -> (Array1<f64>, Vec<usize>) {
+        let (systematic, idiosyncratic) = normals.split_at(self.lower.ncols());
+
+        // Correlated systematic factors
+        let rf = &self.mean + &self.lower.dot(&ArrayView1::from(systematic));
+
+        self.outcomes(&rf, idiosyncratic, migrations, keep_ratings)
+    }
+
+    /// Number of draws per trial: one standard normal draw per free systematic factor, risk group
+    /// and borrower, and one mixing variable per risk group with own degrees of freedom and one
+    /// for a portfolio-wide Student-t copula
+    fn num_draws(&self) -> usize {
+        self.lower.ncols() + self.risk_group.len() + self.num_borrower + self.arena().mixing.len()
+    }
+
+    /// Generate all draws of a trial. The order is fixed: the free systematic factors, then for
+    /// each risk group its draw followed by those of its borrowers, then the mixing variables.
+    /// With a separate generator for the idiosyncratic draws, the systematic factors are drawn
+    /// from `rng` and the draws of the risk groups and borrowers from `idiosyncratic`
+    fn fill_normals<R: Rng>(&self, rng: &mut R, idiosyncratic: Option<&mut R>, normals: &mut [f64]) {
+        assert_eq!(normals.len(), self.num_draws(), "Draws per trial must equal the free risk factors, risk groups, borrowers and mixing variables");
+        let (systematic, specific) = normals.split_at_mut(self.lower.ncols());
+        systematic.iter_mut().zip(StandardNormal.sample_iter(&mut *rng)).for_each(|(n, z)| *n = z);
+        self.fill_specific(idiosyncratic.unwrap_or(rng), specific);
+    }
+
+    /// Generate the idiosyncratic draws of a trial: the standard normal draws of the risk groups
+    /// and borrowers, followed by the chi-squared mixing variables of the Student-t copulas
+    fn fill_specific<R: Rng>(&self, rng: &mut R, specific: &mut [f64]) {
+        let (normals, mixing) = specific.split_at_mut(self.risk_group.len() + self.num_borrower);
+        normals.iter_mut().zip(StandardNormal.sample_iter(&mut *rng)).for_each(|(n, z)| *n = z);
+        mixing.iter_mut().zip(self.arena().mixing.iter()).for_each(|(w, chi)| *w = chi.sample(rng));
+    }
+
+    /// Generators of the chunk with the given index of `num_trials` trials, sampling the systematic
+    /// draws of all its trials first if stratified or moment matched
+    fn chunk_draws<R: rng::SimulationRng>(&self, seeds: &rng::Seeds, chunk: usize, num_trials: usize) -> ChunkDraws<R> {
+        let (mut rng, idiosyncratic) = seeds.chunk_rng::<R>(chunk as u64);
+        let mut systematic = match (self.sampler, self.moment_matching) {
+            (rng::Sampler::MonteCarlo, false) => None,
+            (rng::Sampler::MonteCarlo, true) => Some(Array2::from_shape_fn((num_trials, self.lower.ncols()), |_| StandardNormal.sample(&mut rng))),
+            (rng::Sampler::LatinHypercube, _) => Some(rng::latin_hypercube(&mut rng, num_trials, self.lower.ncols())),
+        };
+        if self.moment_matching {
+            systematic.iter_mut().for_each(rng::match_moments);
+        }
+        ChunkDraws { rng, idiosyncratic, systematic, trial: 0 }
+    }
+
+    /// Generate all standard normal draws of the next trial of a chunk like `fill_normals`, with
+    /// the systematic ones taken from the sample of the chunk if there is one
+    fn draw_next<R: Rng>(&self, draws: &mut ChunkDraws<R>, normals: &mut [f64]) {
+        match draws.systematic.as_ref() {
+            None => self.fill_normals(&mut draws.rng, draws.idiosyncratic.as_mut(), normals),
+            Some(sample) => {
+                let (systematic, specific) = normals.split_at_mut(self.lower.ncols());
+                systematic.iter_mut().zip(sample.row(draws.trial)).for_each(|(n, &z)| *n = z);
+                self.fill_specific(draws.idiosyncratic.as_mut().unwrap_or(&mut draws.rng), specific);
+            }
+        }
+        draws.trial += 1;
+    }
+
+    /// Sample the systematic factors given the correlation structure, only the factors not fixed
+    /// by a scenario are random and dropped factors are zero
+    pub fn systematic_factors<R: Rng>(&self, rng: &mut R) -> Array1<f64> {
+        let n = Array1::from_iter(StandardNormal.sample_iter(&mut *rng).take(self.lower.ncols()));
+        self.all_factors(&(&self.mean + &self.lower.dot(&n)))
+    }
+
+    /// Calculate the loss per borrower given externally provided systematic factors, only the
+    /// idiosyncratic risk is sampled with the random number generator. Fed with the factors of
+    /// `systematic_factors` and the same generator, the result equals `trial`
+    pub fn loss_given_factors<R: Rng>(&self, systematic: &Array1<f64>, rng: &mut R) -> Array1<f64> {
+        assert_eq!(systematic.len(), self.risk_factors, "Number of systematic factors does not match the covariance matrix");
+        let mut idiosyncratic: Vec<f64> = vec![0.; self.num_draws() - self.lower.ncols()];
+        self.fill_specific(rng, &mut idiosyncratic);
+        self.losses(&systematic.select(Axis(0), &self.factors), &idiosyncratic, None)
+    }
+
+    /// Copy the data of all borrowers needed per trial into contiguous arrays. Done automatically
+    /// on the first trial after the borrowers have been modified, calling it after construction
+    /// keeps the copy out of the simulation
+    pub fn freeze(&self) {
+        self.arena();
+    }
+
+    /// Contiguous borrower data of the trials
+    fn arena(&self) -> &BorrowerArena {
+        self.arena.get_or_init(|| BorrowerArena::new(&self.risk_group, &self.group_parents, self.degrees_of_freedom))
+    }
+
+    /// Calculate the loss per borrower given the systematic factors and the idiosyncratic draws of
+    /// the risk groups and borrowers in portfolio order
+    fn losses(&self, rf: &Array1<f64>, idiosyncratic: &[f64], migrations: Option<&mut migration::MigrationCounts>) -> Array1<f64> {
+        self.outcomes(rf, idiosyncratic, migrations, false).0
+    }
+
+    /// Calculate the loss per borrower like `losses` together with the rating of each borrower,
+    /// which is empty unless it is kept or needed for guarantees or contagion
+    fn outcomes(&self, rf: &Array1<f64>, idiosyncratic: &[f64], mut migrations: Option<&mut migration::MigrationCounts>, keep_ratings: bool) -> (Array1<f64>, Vec<usize>) {
+        let arena = self.arena();
         let mut out_borr: Array1<f64> = Array1::zeros(self.num_borrower);
 
-        // Random Number Generator
-        let mut nrm_gen = StandardNormal.sample_iter(rng);
+        // Draws of the risk groups and borrowers in order, followed by the mixing variables
+        let (draws, mixing) = idiosyncratic.split_at(self.risk_group.len() + self.num_borrower);
+        let mut nrm_gen = draws.iter().copied();
+
+        // Systematic factor of each distinct weight vector, like `Borrower::risk_factor`
+        let y_slot: Vec<f64> = self.factor_slots.iter().map(|(weights, norm)| rf.dot(weights) / norm).collect();
 
-        // Generate systematic factors once
-        let n = Array1::from_iter(nrm_gen.by_ref().take(self.risk_factors));
-        let rf = self.lower.dot(&n);
+        // Guarantees and contagion need the rating of every borrower of the trial, without them the
+        // ratings are only kept if requested
+        let guaranteed = !arena.guarantees.is_empty();
+        let contagion = self.contagion_notches > 0;
+        let keep = keep_ratings || guaranteed || contagion;
+        let mut ratings: Vec<usize> = if keep { vec![0; self.num_borrower] } else { Vec::new() };
+
+        // Shocks of the risk groups, kept only if a nested group needs the shock of its parent
+        let mut shocks: Vec<f64> = if arena.nested { Vec::with_capacity(arena.group_ends.len()) } else { Vec::new() };
 
         // Loop over portfolio
         let mut index: usize = 0;
-        for rg in self.iter_risk_group() {
-            // Risk Group idiosyncratic risk
-            let e2 = nrm_gen.next().unwrap();
+        let mut num_defaults: usize = 0;
+        for (group, &end) in arena.group_ends.iter().enumerate() {
+            // Risk Group idiosyncratic risk, a nested group combines its draw with the shock of its
+            // parent, which precedes it
+            let x = nrm_gen.next().unwrap();
+            let e2 = match arena.links[group] {
+                Some((parent, weight, own)) => weight * shocks[parent] + own * x,
+                None => x,
+            };
+            if arena.nested {
+                shocks.push(e2);
+            }
 
-            for borr in rg.iter_borrower() {
+            // Scale of the asset values of a group with a Student-t copula
+            let scale = match arena.degrees_of_freedom[group] {
+                Some((df, variable)) => (df / mixing[variable]).sqrt(),
+                None => 1.,
+            };
+
+            // With contagion, a group with a parent settles once all its members have migrated
+            let first = index;
+            let parent = if contagion { arena.parents[group] } else { None };
+
+            while index < end {
                 // Borrower idiosyncratic risk
                 let e1 = nrm_gen.next().unwrap();
 
-                // Systematic risk factor
-                let y = borr.risk_factor(&rf);
+                // Migration, like `Borrower::migration`, a single rating class has no thresholds and
+                // an absorbing one is kept without evaluating the asset value. Its draw is still
+                // consumed, so that the draws of the other borrowers are unchanged
+                let rating = if arena.absorbing[index] {
+                    arena.rating[index]
+                } else {
+                    // Systematic risk factor
+                    let y = y_slot[arena.factor_slot[index]];
 
-                // Get correlated asset value
-                let z = borr.asset_value(&y, &e1, &e2);
+                    // Get correlated asset value, like `Borrower::asset_value`
+                    let [rho, rho_c, eps_c, eps] = arena.weights[index];
+                    let z = scale * (rho * y + rho_c * (eps_c * e1 + eps * e2));
 
-                // Migration
-                let rating = borr.migration(&z);
+                    let (start, stop) = (arena.offsets[index], arena.offsets[index + 1]);
+                    migration::rating_index(&arena.thresholds[start - index..stop - index - 1], z)
+                };
 
-                // Incurred Loss
-                out_borr[index] = *borr.get_loss(&rating);
+                if keep {
+                    ratings[index] = rating;
+                }
+                if parent.is_none() {
+                    out_borr[index] = arena.settle(index, group, rating, migrations.as_deref_mut(), &mut num_defaults);
+                }
 
                 index += 1;
             }
+
+            // Second pass: a defaulted parent downgrades the other members, at most to default,
+            // members in an absorbing rating stay
+            if let Some(parent) = parent {
+                let dragged = arena.is_default(parent, ratings[parent]);
+                for member in first..end {
+                    if dragged && member != parent && !arena.absorbing[member] {
+                        let last = arena.offsets[member + 1] - arena.offsets[member] - 1;
+                        ratings[member] = (ratings[member] + self.contagion_notches).min(last);
+                    }
+                    out_borr[member] = arena.settle(member, group, ratings[member], migrations.as_deref_mut(), &mut num_defaults);
+                }
+            }
+        }
+
+        // Covered fractions of defaulted obligors are valued at the rating of their guarantor
+        for guarantee in arena.guarantees.iter() {
+            if ratings[guarantee.obligor] == guarantee.default {
+                out_borr[guarantee.obligor] -= guarantee.relief[ratings[guarantee.guarantor]];
+            }
+        }
+
+        if let Some(counts) = migrations {
+            counts.add_trial(num_defaults);
         }
 
         // return
-        out_borr
+        (out_borr, ratings)
+    }
+
+    /// Perform simulation of many trials in parallel with the given random number generator.
+    /// Returns the loss distribution, the expected loss per borrower, if requested, the counts
+    /// of migrations into each rating class and the losses of the tracked borrowers (given by
+    /// their index) per trial. The seed is either a single seed or separate seeds of the
+    /// systematic and the idiosyncratic draws (see `rng::Seeds`)
+    pub fn simulate<R: rng::SimulationRng>(&self, num_trials: usize, chunk_size: usize, seeds: impl Into<rng::Seeds>, tracking: migration::MigrationTracking, tracked: &[usize]) -> simulation::SimulationResult {
+        self.simulate_until::<R, f64, _>(num_trials, chunk_size, seeds, tracking, tracked, usize::MAX, |_| false)
     }
 
-    /// Perform simulation of many trials in parallel
-    pub fn simulate(&self, num_trials: usize, chunk_size: usize, seed: u64) -> (Vec<f64>, Array1<f64>) {
-        // Create container of loss distribution
-        let mut out = vec![0_f64; num_trials];
-        let out_borr = Arc::new(Mutex::new(Array1::<f64>::zeros(self.num_borrower)));
+    /// Perform simulation in rounds of `round_chunks` chunks until `stop` returns true given the
+    /// losses simulated so far (in order of the trials) or all `num_trials` are simulated. The
+    /// trials are the same as those of `simulate`, so the result equals `simulate` with the
+    /// number of trials at the stopping point. The losses are stored with the precision `L`
+    #[allow(clippy::too_many_arguments)]
+    pub fn simulate_until<R, L, F>(&self, num_trials: usize, chunk_size: usize, seeds: impl Into<rng::Seeds>, tracking: migration::MigrationTracking, tracked: &[usize], round_chunks: usize, stop: F) -> simulation::SimulationResult<L>
+    where
+        R: rng::SimulationRng,
+        L: LossScalar,
+        F: Fn(&[L]) -> bool,
+    {
+        // Create containers of the results
+        let mut out: Vec<L> = Vec::with_capacity(num_trials);
+        let mut out_borr: Vec<CompensatedSum> = vec![CompensatedSum::default(); self.num_borrower];
+        let mut out_counts = self.new_counts(tracking);
+        let mut out_tracked: Vec<f64> = Vec::new();
 
         // Get number of chunks
-        let num_chunks: usize = (num_trials + chunk_size - 1) / chunk_size;
+        let num_chunks: usize = num_trials.div_ceil(chunk_size);
+        let seeds = seeds.into();
+
+        for first in (0..num_chunks).step_by(round_chunks.max(1)) {
+            let round = first..(first + round_chunks.max(1)).min(num_chunks);
+            let start = out.len();
+            out.resize((start + round.len() * chunk_size).min(num_trials), L::default());
+
+            let (loc_borr, loc_counts, loc_tracked) = self.simulate_chunks::<R, L>(&mut out[start..], chunk_size, seeds, round, tracking, tracked, None);
+            out_borr.iter_mut().zip(loc_borr.iter()).for_each(|(a, b)| a.merge(b));
+            if let (Some(share), Some(loc)) = (out_counts.as_mut(), loc_counts.as_ref()) {
+                share.merge(loc);
+            }
+            out_tracked.extend(loc_tracked);
+
+            if out.len() < num_trials && stop(&out) {
+                break;
+            }
+        }
+
+        // Get expected loss
+        let el: Array1<f64> = out_borr.iter().map(|sum| sum.value() / out.len() as f64).collect();
+
+        // return
+        simulation::SimulationResult {
+            loss: out,
+            expected_loss: el,
+            migrations: out_counts,
+            tracked: out_tracked,
+        }
+    }
+
+    /// Simulate only the trials of a range of chunks, e.g. the share of one shard of a run split
+    /// across processes. The trials are the same as the corresponding ones of `simulate` with the
+    /// same number of trials, chunk size and seed, so the losses of consecutive ranges concatenate
+    /// to its loss distribution. The expected loss per borrower is averaged over the trials of the
+    /// range
+    pub fn simulate_chunk_range<R: rng::SimulationRng>(&self, num_trials: usize, chunk_size: usize, seeds: impl Into<rng::Seeds>, chunks: Range<usize>) -> simulation::SimulationResult {
+        let num_chunks: usize = num_trials.div_ceil(chunk_size);
+        let chunks = chunks.start.min(num_chunks)..chunks.end.min(num_chunks);
+
+        let first = chunks.start * chunk_size;
+        let mut out: Vec<f64> = vec![0.; (chunks.end * chunk_size).min(num_trials).saturating_sub(first)];
+        let (out_borr, _, _) = self.simulate_chunks::<R, f64>(&mut out, chunk_size, seeds.into(), chunks, migration::MigrationTracking::None, &[], None);
+        let el: Array1<f64> = out_borr.iter().map(|sum| sum.value() / out.len() as f64).collect();
+
+        simulation::SimulationResult {
+            loss: out,
+            expected_loss: el,
+            migrations: None,
+            tracked: Vec::new(),
+        }
+    }
 
-        // Get streams
-        let mut base_rng = Pcg64::seed_from_u64(seed);
-        let streams: Vec<u128> = (0..num_chunks).map(|_| (base_rng.r#gen::<u128>() << 1) | 1).collect();
+    /// Simulate the trials of `simulate` with the systematic factors given by the rows of `factors`
+    /// instead of drawn, e.g. macro scenarios generated outside of this crate. The number of
+    /// trials is the number of rows, each row holds all risk factors in index order. Only the
+    /// idiosyncratic draws of the risk groups and borrowers are sampled, from the generator of the
+    /// idiosyncratic draws with split seeds, so that the trials of `simulate` are reproduced given
+    /// their factors (see `replay_factors`)
+    pub fn simulate_factors<R: rng::SimulationRng, L: LossScalar>(&self, factors: &Array2<f64>, chunk_size: usize, seeds: impl Into<rng::Seeds>, tracking: migration::MigrationTracking, tracked: &[usize]) -> simulation::SimulationResult<L> {
+        assert_eq!(factors.ncols(), self.risk_factors, "Factor draws have {} risk factors, but the covariance matrix has {}", factors.ncols(), self.risk_factors);
+
+        let mut out: Vec<L> = vec![L::default(); factors.nrows()];
+        let num_chunks = factors.nrows().div_ceil(chunk_size);
+        let (out_borr, out_counts, out_tracked) = self.simulate_chunks::<R, L>(&mut out, chunk_size, seeds.into(), 0..num_chunks, tracking, tracked, Some(factors.view()));
+        let el: Array1<f64> = out_borr.iter().map(|sum| sum.value() / out.len() as f64).collect();
+
+        simulation::SimulationResult {
+            loss: out,
+            expected_loss: el,
+            migrations: out_counts,
+            tracked: out_tracked,
+        }
+    }
+
+    /// Simulate a range of consecutive chunks in parallel and write the portfolio losses, with the
+    /// systematic factors of the trials given by the rows of `factors` if provided. Returns the sum
+    /// of the losses per borrower, the migration counts and the losses of the tracked borrowers.
+    /// Losses are summed with compensation, so that the small losses of many trials are not lost
+    /// to rounding
+    #[allow(clippy::too_many_arguments)]
+    fn simulate_chunks<R: rng::SimulationRng, L: LossScalar>(&self, out: &mut [L], chunk_size: usize, seeds: rng::Seeds, chunks: Range<usize>, tracking: migration::MigrationTracking, tracked: &[usize], factors: Option<ArrayView2<f64>>) -> (Vec<CompensatedSum>, Option<migration::MigrationCounts>, Vec<f64>) {
+        let out_borr = Arc::new(Mutex::new(vec![CompensatedSum::default(); self.num_borrower]));
+        let out_counts = Mutex::new(self.new_counts(tracking));
 
         // Loop
-        out.par_chunks_mut(chunk_size).zip(streams.par_iter()).for_each(|(chunk, stream)| {
-            let mut rng = Pcg64::new(seed as u128, *stream);
+        let out_tracked: Vec<Vec<f64>> = out.par_chunks_mut(chunk_size).zip(chunks.into_par_iter()).map(|(chunk, index)| {
+            let mut draws = self.chunk_draws::<R>(&seeds, index, if factors.is_some() { 0 } else { chunk.len() });
 
             // local container for sum of all chunks
-            let mut loc_borr: Array1<f64> = Array1::zeros(self.num_borrower);
+            let mut loc_borr: Vec<CompensatedSum> = vec![CompensatedSum::default(); self.num_borrower];
+            let mut loc_counts = self.new_counts(tracking);
+            let mut loc_tracked: Vec<f64> = Vec::with_capacity(chunk.len() * tracked.len());
+            let mut normals: Vec<f64> = vec![0.; self.num_draws()];
 
-            for val in chunk.iter_mut() {
-                let loss_borr = self.trial(&mut rng);
-                *val = loss_borr.sum();
-                loc_borr += &loss_borr;
+            for (trial, val) in (index * chunk_size..).zip(chunk.iter_mut()) {
+                let loss_borr = match factors {
+                    Some(factors) => self.trial_given(factors.row(trial), draws.idiosyncratic.as_mut().unwrap_or(&mut draws.rng), &mut normals, loc_counts.as_mut()),
+                    None => {
+                        self.draw_next(&mut draws, &mut normals);
+                        self.draw_outcomes(&normals, loc_counts.as_mut(), false).0
+                    }
+                };
+                *val = trial_loss(&loss_borr);
+                loc_tracked.extend(tracked.iter().map(|&index| loss_borr[index]));
+                loc_borr.iter_mut().zip(loss_borr.iter()).for_each(|(sum, &loss)| sum.add(loss));
             }
 
             // Lock and update output borr
             let mut share = out_borr.lock().unwrap();
-            *share += &loc_borr;
+            share.iter_mut().zip(loc_borr.iter()).for_each(|(a, b)| a.merge(b));
+
+            if let (Some(share), Some(loc)) = (out_counts.lock().unwrap().as_mut(), loc_counts.as_ref()) {
+                share.merge(loc);
+            }
+
+            loc_tracked
+        }).collect();
+
+        let out_borr = Arc::try_unwrap(out_borr).expect("Multiple references encountered").into_inner().unwrap();
+        (out_borr, out_counts.into_inner().unwrap(), out_tracked.concat())
+    }
+
+    /// Create empty migration counts for the level of detail
+    fn new_counts(&self, tracking: migration::MigrationTracking) -> Option<migration::MigrationCounts> {
+        match tracking {
+            migration::MigrationTracking::None => None,
+            migration::MigrationTracking::Portfolio => Some(migration::MigrationCounts::new(self.risk_group.len(), self.num_rating_columns(), false)),
+            migration::MigrationTracking::RiskGroup => Some(migration::MigrationCounts::new(self.risk_group.len(), self.num_rating_columns(), true)),
+        }
+    }
+
+    /// Average loss per risk group over all trials with a portfolio loss at or above the threshold,
+    /// i.e. the contributions of the risk groups to the expected shortfall. The trials of
+    /// `simulate` with the same number of trials, chunk size and seed are replayed, so the
+    /// threshold can be derived from its loss distribution. The trials are compared to the
+    /// threshold with the precision `L` the losses were stored with
+    pub fn tail_contributions<R: rng::SimulationRng, L: LossScalar>(&self, num_trials: usize, chunk_size: usize, seeds: impl Into<rng::Seeds>, threshold: f64) -> Array1<f64> {
+        // Risk group of each borrower in order of the losses per borrower
+        let group: Vec<usize> = self.iter_risk_group().enumerate()
+            .flat_map(|(index, rg)| std::iter::repeat_n(index, rg.num_borrower()))
+            .collect();
+
+        let num_chunks: usize = num_trials.div_ceil(chunk_size);
+        let seeds = seeds.into();
+
+        let (sum, count) = (0..num_chunks).into_par_iter().map(|chunk| {
+            let chunk_trials = chunk_size.min(num_trials - chunk * chunk_size);
+            let mut draws = self.chunk_draws::<R>(&seeds, chunk, chunk_trials);
+            let mut loc_sum: Array1<f64> = Array1::zeros(self.risk_group.len());
+            let mut loc_count: usize = 0;
+            let mut normals: Vec<f64> = vec![0.; self.num_draws()];
+
+            for _ in 0..chunk_trials {
+                self.draw_next(&mut draws, &mut normals);
+                let loss_borr = self.draw_outcomes(&normals, None, false).0;
+                if trial_loss::<L>(&loss_borr).to_f64() >= threshold {
+                    loss_borr.iter().zip(group.iter()).for_each(|(l, &g)| loc_sum[g] += l);
+                    loc_count += 1;
+                }
+            }
+
+            (loc_sum, loc_count)
+        }).reduce(|| (Array1::zeros(self.risk_group.len()), 0), |(a, n), (b, m)| (a + b, n + m));
+
+        tail_average(sum, count, "tail")
+    }
+
+    /// Average loss per borrower over the trials with a portfolio loss within the band `(lower,
+    /// upper)` around the value at risk and over those at or above the threshold, i.e. the Euler
+    /// allocations of the value at risk and of the expected shortfall to the borrowers. Both are
+    /// accumulated in the same replay of the trials of `simulate` with the same number of trials,
+    /// chunk size and seed like `tail_contributions`. The averages are zero without trials in the
+    /// band or tail
+    pub fn borrower_contributions<R: rng::SimulationRng, L: LossScalar>(&self, num_trials: usize, chunk_size: usize, seeds: impl Into<rng::Seeds>, band: (f64, f64), threshold: f64) -> (Array1<f64>, Array1<f64>) {
+        let num_chunks: usize = num_trials.div_ceil(chunk_size);
+        let seeds = seeds.into();
+        let empty = || (Array1::zeros(self.num_borrower), 0, Array1::zeros(self.num_borrower), 0);
+
+        let (band_sum, band_count, tail_sum, tail_count) = (0..num_chunks).into_par_iter().map(|chunk| {
+            let chunk_trials = chunk_size.min(num_trials - chunk * chunk_size);
+            let mut draws = self.chunk_draws::<R>(&seeds, chunk, chunk_trials);
+            let (mut loc_band, mut loc_band_count, mut loc_tail, mut loc_tail_count): (Array1<f64>, usize, Array1<f64>, usize) = empty();
+            let mut normals: Vec<f64> = vec![0.; self.num_draws()];
+
+            for _ in 0..chunk_trials {
+                self.draw_next(&mut draws, &mut normals);
+                let loss_borr = self.draw_outcomes(&normals, None, false).0;
+                let loss = trial_loss::<L>(&loss_borr).to_f64();
+                if (band.0..=band.1).contains(&loss) {
+                    loc_band += &loss_borr;
+                    loc_band_count += 1;
+                }
+                if loss >= threshold {
+                    loc_tail += &loss_borr;
+                    loc_tail_count += 1;
+                }
+            }
+
+            (loc_band, loc_band_count, loc_tail, loc_tail_count)
+        }).reduce(empty, |(a, n, b, m), (c, k, d, l)| (a + c, n + k, b + d, m + l));
+
+        (tail_average(band_sum, band_count, "band around the value at risk"), tail_average(tail_sum, tail_count, "tail"))
+    }
+
+    /// Default frequency of each borrower over the trials with a portfolio loss at or above the
+    /// threshold, i.e. its probability of default conditional on a tail loss. The trials of
+    /// `simulate` with the same number of trials, chunk size and seed are replayed like
+    /// `tail_contributions`. Defaults are counted after contagion
+    pub fn tail_default_probabilities<R: rng::SimulationRng, L: LossScalar>(&self, num_trials: usize, chunk_size: usize, seeds: impl Into<rng::Seeds>, threshold: f64) -> Array1<f64> {
+        let arena = self.arena();
+        let num_chunks: usize = num_trials.div_ceil(chunk_size);
+        let seeds = seeds.into();
+
+        let (defaults, count) = (0..num_chunks).into_par_iter().map(|chunk| {
+            let chunk_trials = chunk_size.min(num_trials - chunk * chunk_size);
+            let mut draws = self.chunk_draws::<R>(&seeds, chunk, chunk_trials);
+            let mut loc_defaults: Array1<f64> = Array1::zeros(self.num_borrower);
+            let mut loc_count: usize = 0;
+            let mut normals: Vec<f64> = vec![0.; self.num_draws()];
+
+            for _ in 0..chunk_trials {
+                self.draw_next(&mut draws, &mut normals);
+                let (loss_borr, ratings) = self.draw_outcomes(&normals, None, true);
+                if trial_loss::<L>(&loss_borr).to_f64() >= threshold {
+                    for (index, &rating) in ratings.iter().enumerate() {
+                        if arena.is_default(index, rating) {
+                            loc_defaults[index] += 1.;
+                        }
+                    }
+                    loc_count += 1;
+                }
+            }
+
+            (loc_defaults, loc_count)
+        }).reduce(|| (Array1::zeros(self.num_borrower), 0), |(a, n), (b, m)| (a + b, n + m));
+
+        tail_average(defaults, count, "tail")
+    }
+
+    /// Loss per destination rating class, i.e. the losses of the borrowers summed by the column
+    /// (see `num_rating_columns`) of the rating they migrated to, averaged over all trials and over
+    /// the trials with a portfolio loss at or above the threshold. Both sum to the mean portfolio
+    /// loss of the respective trials. The trials of `simulate` with the same number of trials,
+    /// chunk size and seed are replayed like `tail_contributions`
+    pub fn destination_contributions<R: rng::SimulationRng, L: LossScalar>(&self, num_trials: usize, chunk_size: usize, seeds: impl Into<rng::Seeds>, threshold: f64) -> (Array1<f64>, Array1<f64>) {
+        let num_chunks: usize = num_trials.div_ceil(chunk_size);
+        let seeds = seeds.into();
+        let arena = self.arena();
+        let empty = || (Array1::zeros(arena.layout.len), Array1::zeros(arena.layout.len), 0);
+
+        let (sum, tail_sum, count) = (0..num_chunks).into_par_iter().map(|chunk| {
+            let chunk_trials = chunk_size.min(num_trials - chunk * chunk_size);
+            let mut draws = self.chunk_draws::<R>(&seeds, chunk, chunk_trials);
+            let (mut loc_sum, mut loc_tail, mut loc_count): (Array1<f64>, Array1<f64>, usize) = empty();
+            let mut trial_sum: Array1<f64> = Array1::zeros(arena.layout.len);
+            let mut normals: Vec<f64> = vec![0.; self.num_draws()];
+
+            for _ in 0..chunk_trials {
+                self.draw_next(&mut draws, &mut normals);
+                let (loss_borr, ratings) = self.draw_outcomes(&normals, None, true);
+                trial_sum.fill(0.);
+                loss_borr.iter().zip(ratings.iter()).enumerate().for_each(|(index, (l, &rating))| trial_sum[arena.columns[arena.offsets[index] + rating]] += l);
+                loc_sum += &trial_sum;
+                if trial_loss::<L>(&loss_borr).to_f64() >= threshold {
+                    loc_tail += &trial_sum;
+                    loc_count += 1;
+                }
+            }
+
+            (loc_sum, loc_tail, loc_count)
+        }).reduce(empty, |(a, b, n), (c, d, m)| (a + c, b + d, n + m));
+
+        (sum / num_trials as f64, tail_average(tail_sum, count, "tail"))
+    }
+
+    /// Whether `conditional_losses` applies, i.e. the borrowers are joined by the Gaussian copula
+    /// without guarantees and contagion
+    pub fn supports_conditional_losses(&self) -> bool {
+        let arena = self.arena();
+        arena.guarantees.is_empty() && self.contagion_notches == 0 && arena.mixing.is_empty()
+    }
+
+    /// Portfolio loss of each trial of `simulate` with the same number of trials, chunk size and
+    /// seed, in order of the trials, expected conditional on its systematic factors and risk group
+    /// draws (see `Borrower::conditional_expected_loss`). The trials are replayed, only the
+    /// idiosyncratic draws of the borrowers are integrated out. Guarantees, contagion and the
+    /// Student-t copula couple the borrowers beyond the risk group draws and are not supported, see
+    /// `supports_conditional_losses`
+    pub fn conditional_losses<R: rng::SimulationRng>(&self, num_trials: usize, chunk_size: usize, seeds: impl Into<rng::Seeds>) -> Vec<f64> {
+        assert!(self.supports_conditional_losses(), "Conditional losses need the Gaussian copula without guarantees and contagion");
+
+        let seeds = seeds.into();
+        let mut out: Vec<f64> = vec![0.; num_trials];
+        out.par_chunks_mut(chunk_size).enumerate().for_each(|(chunk, losses)| {
+            let mut draws = self.chunk_draws::<R>(&seeds, chunk, losses.len());
+            let mut normals: Vec<f64> = vec![0.; self.num_draws()];
+            for loss in losses.iter_mut() {
+                self.draw_next(&mut draws, &mut normals);
+                let (systematic, idiosyncratic) = normals.split_at(self.lower.ncols());
+                let rf = &self.mean + &self.lower.dot(&ArrayView1::from(systematic));
+                *loss = self.conditional_loss(&rf, idiosyncratic);
+            }
         });
+        out
+    }
 
-        // Get expected loss
-        let mut el: Array1<f64> = Arc::try_unwrap(out_borr).expect("Multiple references encountered").into_inner().unwrap();
-        el /= num_trials as f64;
+    /// Portfolio loss expected given the systematic factors and the draws of the risk groups, the
+    /// draws of the borrowers in between are skipped
+    fn conditional_loss(&self, rf: &Array1<f64>, idiosyncratic: &[f64]) -> f64 {
+        let arena = self.arena();
+        let y_slot: Vec<f64> = self.factor_slots.iter().map(|(weights, norm)| rf.dot(weights) / norm).collect();
+        let mut shocks: Vec<f64> = Vec::with_capacity(self.risk_group.len());
+        let mut loss = CompensatedSum::default();
 
-        // return
-        (out, el)
+        // Each risk group draw is followed by those of its borrowers, nested groups combine their
+        // draw with the shock of their parent like `outcomes`
+        let mut position = 0;
+        for (group, rg) in self.risk_group.iter().enumerate() {
+            let x = idiosyncratic[position];
+            let e2 = match arena.links[group] {
+                Some((parent, weight, own)) => weight * shocks[parent] + own * x,
+                None => x,
+            };
+            shocks.push(e2);
+            for borr in rg.iter_borrower() {
+                loss.add(borr.conditional_expected_loss(y_slot[borr.factor_slot()], e2));
+            }
+            position += 1 + rg.num_borrower();
+        }
+        loss.value()
+    }
+}
+
+/// Cholesky decomposition of a covariance matrix. If it fails, it is retried once with the jitter
+/// added to the diagonal, and the flag tells whether that was needed. Panics with the eigenvalue
+/// spectrum if no decomposition is possible
+fn decompose(cov: &Array2<f64>, jitter: f64) -> (Array2<f64>, bool) {
+    if let Ok(lower) = cov.cholesky(UPLO::Lower) {
+        return (lower, false);
+    }
+    if jitter > 0. {
+        let jittered = cov + &(Array2::<f64>::eye(cov.nrows()) * jitter);
+        if let Ok(lower) = jittered.cholesky(UPLO::Lower) {
+            return (lower, true);
+        }
+    }
+    panic!("No Cholesky decomposition possible with diagonal jitter {}: {}", jitter, spectrum_diagnostics(cov));
+}
+
+/// Target a covariance matrix is shrunk towards, both keep the variances of the risk factors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShrinkageTarget {
+    /// Uncorrelated risk factors, i.e. the diagonal of the matrix
+    Identity,
+    /// The average correlation between all pairs of risk factors
+    ConstantCorrelation,
+}
+
+/// Blend a covariance matrix with a target as `(1 - lambda) * cov + lambda * target`, e.g. to
+/// stabilize a noisy estimate. Shrinking towards the identity also repairs a matrix that is only
+/// mildly indefinite
+pub fn shrink_covariance(cov: &Array2<f64>, lambda: f64, target: ShrinkageTarget) -> Array2<f64> {
+    assert!((0. ..=1.).contains(&lambda), "Shrinkage intensity {} outside of [0, 1]", lambda);
+    let n = cov.nrows();
+    let std: Vec<f64> = cov.diag().iter().map(|var| var.sqrt()).collect();
+
+    let correlation = match target {
+        ShrinkageTarget::Identity => 0.,
+        ShrinkageTarget::ConstantCorrelation if n > 1 => {
+            let sum: f64 = cov.indexed_iter().filter(|((i, j), _)| i != j).map(|((i, j), x)| x / (std[i] * std[j])).sum();
+            sum / (n * (n - 1)) as f64
+        }
+        ShrinkageTarget::ConstantCorrelation => 0.,
+    };
+
+    Array2::from_shape_fn((n, n), |(i, j)| {
+        let target = if i == j { cov[[i, i]] } else { correlation * std[i] * std[j] };
+        (1. - lambda) * cov[[i, j]] + lambda * target
+    })
+}
+
+/// Condition number of a symmetric matrix, the ratio of its largest to its smallest eigenvalue.
+/// Infinite if the matrix is not positive definite
+pub fn condition_number(cov: &Array2<f64>) -> f64 {
+    match cov.eigvalsh(UPLO::Lower) {
+        Ok(eigenvalues) if !eigenvalues.is_empty() && eigenvalues[0] > 0. => eigenvalues[eigenvalues.len() - 1] / eigenvalues[0],
+        _ => f64::INFINITY,
+    }
+}
+
+/// Smallest eigenvalues and condition number of a symmetric matrix, to tell how far it is from
+/// being positive definite
+pub fn spectrum_diagnostics(cov: &Array2<f64>) -> String {
+    let eigenvalues = match cov.eigvalsh(UPLO::Lower) {
+        Ok(eigenvalues) => eigenvalues,
+        Err(err) => return format!("eigenvalues not available ({})", err),
+    };
+    if eigenvalues.is_empty() {
+        return "no eigenvalues".to_string();
+    }
+
+    // Eigenvalues are in ascending order
+    let smallest: Vec<String> = eigenvalues.iter().take(5).map(|x| format!("{:e}", x)).collect();
+    let (min, max) = (eigenvalues[0], eigenvalues[eigenvalues.len() - 1]);
+    let condition = if min > 0. { max / min } else { f64::INFINITY };
+    format!("smallest eigenvalues [{}], condition number {:e}", smallest.join(", "), condition)
+}
+
+/// First cycle of a directed graph given as edges from a node to its successor, e.g. borrowers
+/// guaranteeing each other or a risk group that is its own parent, as the nodes along the cycle
+pub fn find_cycle(edges: &[(&str, &str)]) -> Option<Vec<String>> {
+    let mut successors: HashMap<&str, Vec<&str>> = HashMap::new();
+    for &(node, successor) in edges.iter() {
+        successors.entry(node).or_default().push(successor);
+    }
+    successors.values_mut().for_each(|list| { list.sort(); list.dedup(); });
+    let mut nodes: Vec<&str> = successors.keys().copied().collect();
+    nodes.sort();
+
+    // Depth-first search, a node on the current path reached again closes a cycle
+    let mut done: HashSet<&str> = HashSet::new();
+    for &start in nodes.iter() {
+        let mut path: Vec<&str> = vec![start];
+        let mut next: Vec<usize> = vec![0];
+        while let Some(&node) = path.last() {
+            let position = next.len() - 1;
+            match successors.get(node).and_then(|list| list.get(next[position])) {
+                Some(&successor) => {
+                    next[position] += 1;
+                    if let Some(begin) = path.iter().position(|&n| n == successor) {
+                        let mut cycle: Vec<String> = path[begin..].iter().map(|n| n.to_string()).collect();
+                        cycle.push(successor.to_string());
+                        return Some(cycle);
+                    }
+                    if !done.contains(successor) {
+                        path.push(successor);
+                        next.push(0);
+                    }
+                }
+                None => {
+                    done.insert(node);
+                    path.pop();
+                    next.pop();
+                }
+            }
+        }
     }
+    None
 }
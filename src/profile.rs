@@ -0,0 +1,118 @@
+use csv::Writer;
+use serde::Serialize;
+use std::fs::File;
+use std::path::Path;
+
+use crate::portfolio;
+
+/// Profile of the portfolio composition by current value, available without simulation
+#[derive(Debug, Clone, Serialize)]
+pub struct PortfolioProfile {
+    /// Current value of all borrowers
+    pub total_value: f64,
+    /// Number of borrowers
+    pub borrowers: usize,
+    /// Number of exposures
+    pub exposures: usize,
+    /// Herfindahl index, i.e. sum of squared value shares of the borrowers
+    pub herfindahl_index: f64,
+    /// Largest borrowers by current value
+    pub top_borrowers: Vec<BorrowerProfile>,
+    /// Borrowers and value per current rating class
+    pub ratings: Vec<GroupProfile>,
+    /// Borrowers and value per risk group, ordered by value
+    pub risk_groups: Vec<GroupProfile>,
+}
+
+/// Current value of a single borrower
+#[derive(Debug, Clone, Serialize)]
+pub struct BorrowerProfile {
+    pub id: String,
+    pub risk_group: String,
+    pub rating: String,
+    pub value: f64,
+    pub share: f64,
+}
+
+/// Number of borrowers and current value of a group of borrowers
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupProfile {
+    pub name: String,
+    pub borrowers: usize,
+    pub value: f64,
+    pub share: f64,
+}
+
+impl PortfolioProfile {
+    /// Build the profile of a portfolio with the given number of largest borrowers
+    pub fn new(pf: &portfolio::Portfolio, top: usize) -> Self {
+        let total_value: f64 = pf.iter_risk_group().flat_map(|rg| rg.iter_borrower()).map(|borr| borr.value()).sum();
+        let share = |value: f64| value / total_value;
+
+        let mut top_borrowers: Vec<BorrowerProfile> = pf.iter_risk_group()
+            .flat_map(|rg| rg.iter_borrower().map(move |borr| (rg, borr)))
+            .map(|(rg, borr)| BorrowerProfile {
+                id: borr.id().to_string(),
+                risk_group: rg.id().to_string(),
+                rating: pf.rating_column_label(pf.rating_column(borr, borr.rating())),
+                value: borr.value(),
+                share: share(borr.value()),
+            })
+            .collect();
+        let herfindahl_index = top_borrowers.iter().map(|borr| borr.share * borr.share).sum();
+        top_borrowers.sort_by(|a, b| b.value.total_cmp(&a.value));
+        top_borrowers.truncate(top);
+
+        let mut ratings: Vec<GroupProfile> = (0..pf.num_rating_columns())
+            .map(|column| GroupProfile { name: pf.rating_column_label(column), borrowers: 0, value: 0., share: 0. })
+            .collect();
+        for borr in pf.iter_risk_group().flat_map(|rg| rg.iter_borrower()) {
+            let column = pf.rating_column(borr, borr.rating());
+            ratings[column].borrowers += 1;
+            ratings[column].value += borr.value();
+        }
+        ratings.iter_mut().for_each(|r| r.share = share(r.value));
+
+        let mut risk_groups: Vec<GroupProfile> = pf.iter_risk_group()
+            .map(|rg| {
+                let value: f64 = rg.iter_borrower().map(|borr| borr.value()).sum();
+                GroupProfile { name: rg.id().to_string(), borrowers: rg.num_borrower(), value, share: share(value) }
+            })
+            .collect();
+        risk_groups.sort_by(|a, b| b.value.total_cmp(&a.value));
+
+        Self {
+            total_value,
+            borrowers: pf.num_borrower(),
+            exposures: pf.num_exposures(),
+            herfindahl_index,
+            top_borrowers,
+            ratings,
+            risk_groups,
+        }
+    }
+
+    /// Write the profile as table with one row per portfolio, borrower, rating class and risk
+    /// group distinguished by the section column
+    pub fn write_csv(&self, path: &Path) {
+        let mut writer = Writer::from_path(path).expect("Output path not found");
+        writer.write_record(vec!["section", "name", "borrowers", "value", "share"]).unwrap();
+        writer.write_record(vec!["portfolio".to_string(), "total".to_string(), self.borrowers.to_string(), self.total_value.to_string(), 1.0.to_string()]).unwrap();
+
+        for borr in self.top_borrowers.iter() {
+            writer.write_record(vec!["borrower".to_string(), borr.id.to_string(), 1.to_string(), borr.value.to_string(), borr.share.to_string()]).unwrap();
+        }
+        for (section, groups) in [("rating", &self.ratings), ("risk_group", &self.risk_groups)] {
+            for group in groups.iter() {
+                writer.write_record(vec![section.to_string(), group.name.to_string(), group.borrowers.to_string(), group.value.to_string(), group.share.to_string()]).unwrap();
+            }
+        }
+        writer.flush().unwrap();
+    }
+
+    /// Write the profile as pretty-printed JSON
+    pub fn write_json(&self, path: &Path) {
+        let file = File::create(path).expect("Profile file could not be created");
+        serde_json::to_writer_pretty(file, self).expect("Profile could not be written");
+    }
+}
@@ -0,0 +1,109 @@
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+
+/// Labels of the rating classes (e.g. AAA, AA, A, ...). Ratings are handled as indices in the
+/// model, the labels are only used to make output and diagnostics readable and to resolve
+/// ratings given by label in the input files
+#[derive(Clone, Default)]
+pub struct RatingScale {
+    /// Label for each rating index
+    labels: Vec<String>,
+    /// Rating index of each label
+    indices: HashMap<String, usize>,
+    /// Rating index of each label that is a number, e.g. of a master scale from `1` to `10`
+    numeric_labels: HashMap<usize, usize>,
+}
+
+impl RatingScale {
+    /// Create a new instance with labels ordered by rating index
+    pub fn new(labels: Vec<String>) -> Self {
+        let indices = labels.iter().enumerate().map(|(index, label)| (label.to_string(), index)).collect();
+        let numeric_labels = labels.iter().enumerate()
+            .filter_map(|(index, label)| match label.parse::<usize>() {
+                Ok(number) if number.to_string() == *label => Some((number, index)),
+                _ => None,
+            })
+            .collect();
+        Self {
+            labels,
+            indices,
+            numeric_labels,
+        }
+    }
+
+    /// Get the label of a rating class by its index. Falls back to the index itself if no label
+    /// is defined
+    pub fn label(&self, index: usize) -> String {
+        self.labels.get(index).cloned().unwrap_or_else(|| index.to_string())
+    }
+
+    /// Number of defined labels
+    pub fn num_labels(&self) -> usize {
+        self.labels.len()
+    }
+
+    /// Rating index of a rating given in an input file. Labels take precedence over indices, so
+    /// that numeric labels like `1` to `10` of a master scale resolve to their rating class. An
+    /// index must lie within the scale if labels are defined
+    pub fn resolve(&self, key: &RatingKey) -> Result<usize, String> {
+        match key {
+            RatingKey::Index(index) => match self.numeric_labels.get(index) {
+                Some(&index) => Ok(index),
+                None if self.labels.is_empty() || *index < self.labels.len() => Ok(*index),
+                None => Err(format!("Rating {} is not in the rating scale with {} classes", index, self.labels.len())),
+            },
+            RatingKey::Label(label) => self.indices.get(label).copied()
+                .ok_or_else(|| format!("Rating {} is not a label of the rating scale", label)),
+        }
+    }
+}
+
+/// Rating class in a rating column of an input file, given either by its index or by its label
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RatingKey {
+    Index(usize),
+    Label(String),
+}
+
+impl std::str::FromStr for RatingKey {
+    type Err = String;
+
+    /// Parse a rating given on the command line like one of an input file
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.parse::<usize>() {
+            Ok(index) if value.len() == 1 || !value.starts_with('0') => Ok(RatingKey::Index(index)),
+            _ => Ok(RatingKey::Label(value.to_string())),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RatingKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KeyVisitor;
+
+        impl serde::de::Visitor<'_> for KeyVisitor {
+            type Value = RatingKey;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a rating index or label")
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, value: u64) -> Result<RatingKey, E> {
+                Ok(RatingKey::Index(value as usize))
+            }
+
+            // TOML has signed integers only
+            fn visit_i64<E: serde::de::Error>(self, value: i64) -> Result<RatingKey, E> {
+                usize::try_from(value).map(RatingKey::Index).map_err(|_| E::custom(format!("rating index {} is negative", value)))
+            }
+
+            // Only the plain notation of a number is an index, so that a label like `01` is kept,
+            // and a label is only allocated if the value is not an index
+            fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<RatingKey, E> {
+                Ok(value.parse().unwrap())
+            }
+        }
+
+        deserializer.deserialize_str(KeyVisitor)
+    }
+}
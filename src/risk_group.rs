@@ -1,36 +1,136 @@
 use ndarray::Array2;
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::HashSet;
 
 use crate::borrower;
 
-/// Risk group which is a simple container of multiple borrowers sharing a common random variable
+/// Risk group which is a simple container of multiple borrowers sharing a common random variable.
+/// Risk groups may be nested: the shock of a child group combines its own variable with the
+/// shock of its parent, so borrowers of sibling groups are correlated through their parent
+#[derive(Clone)]
 pub struct RiskGroup {
+    /// Identifier of the risk group
+    id: String,
     /// Empty container
     borrower: Vec<borrower::Borrower>,
+    /// Child groups with the weight of the parent's shock in their shock
+    children: Vec<(RiskGroup, f64)>,
+    /// Degrees of freedom of the Student-t copula of the borrowers, None for the Gaussian copula
+    degrees_of_freedom: Option<f64>,
 }
 
 impl RiskGroup {
-    /// Create instance with empty container
-    pub fn new() -> Self {
+    /// Create instance with its identifier and an empty container
+    pub fn new(id: String) -> Self {
         Self {
+            id,
             borrower: Vec::new(),
+            children: Vec::new(),
+            degrees_of_freedom: None,
         }
     }
 
-    /// Add borrower to the risk group
-    pub fn add_borrower(&mut self, borrower: borrower::Borrower) {
+    /// Get identifier of the risk group
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Add borrower to the risk group, all exposures of the borrower must have been added. A risk
+    /// group has at most one parent borrower
+    pub fn add_borrower(&mut self, mut borrower: borrower::Borrower) {
+        if borrower.is_parent() && let Some(parent) = self.parent() {
+            panic!("Risk group {} has parent borrowers {} and {}, only one is allowed", self.id, self.borrower[parent].id(), borrower.id());
+        }
+        borrower.finalize();
         self.borrower.push(borrower);
     }
 
+    /// Add a child group whose shock is `weight * s + sqrt(1 - weight²) * x` given the shock `s`
+    /// of this group and the child's own standard normal variable `x`, i.e. the weight is the
+    /// correlation of both shocks
+    pub fn add_child(&mut self, child: RiskGroup, weight: f64) {
+        assert!((0. ..=1.).contains(&weight), "Risk group {} has weight {} on its parent {} outside of [0, 1]", child.id, weight, self.id);
+        self.children.push((child, weight));
+    }
+
+    /// Get iterator of the child groups with their weight on this group
+    pub fn iter_children(&self) -> impl Iterator<Item = (&RiskGroup, f64)> {
+        self.children.iter().map(|(child, weight)| (child, *weight))
+    }
+
+    /// Remove the child groups with their weight, e.g. to flatten the hierarchy
+    pub fn take_children(&mut self) -> Vec<(RiskGroup, f64)> {
+        std::mem::take(&mut self.children)
+    }
+
+    /// Join the borrowers by a Student-t copula with the degrees of freedom instead of the Gaussian
+    /// copula: the asset values of all borrowers of the group are scaled by `sqrt(df / w)` with a
+    /// chi-squared variable `w` drawn once per trial for the group, and their thresholds are
+    /// those of the Student-t distribution, so the migration probabilities are kept
+    pub fn set_degrees_of_freedom(&mut self, degrees_of_freedom: Option<f64>) {
+        if let Some(df) = degrees_of_freedom {
+            assert!(df > 0. && df.is_finite(), "Risk group {} has degrees of freedom {}, they must be positive", self.id, df);
+        }
+        self.degrees_of_freedom = degrees_of_freedom;
+    }
+
+    /// Degrees of freedom of the Student-t copula, None for the Gaussian copula
+    pub fn degrees_of_freedom(&self) -> Option<f64> {
+        self.degrees_of_freedom
+    }
+
     /// Get iterator of all borrowers
     pub fn iter_borrower(&self) -> impl Iterator<Item = &borrower::Borrower> {
         self.borrower.iter()
     }
 
+    /// Get iterator of mutable references of all borrowers
+    pub fn iter_borrower_mut(&mut self) -> impl Iterator<Item = &mut borrower::Borrower> {
+        self.borrower.iter_mut()
+    }
+
+    /// Get mutable reference of a borrower by its index within the risk group
+    pub fn borrower_mut(&mut self, index: usize) -> Option<&mut borrower::Borrower> {
+        self.borrower.get_mut(index)
+    }
+
+    /// Index of the parent borrower within the risk group if one is designated
+    pub fn parent(&self) -> Option<usize> {
+        self.borrower.iter().position(|borr| borr.is_parent())
+    }
+
     /// Get number of borrowers
     pub fn num_borrower(&self) -> usize {
         self.borrower.len()
     }
 
+    /// Merge structurally identical borrowers into buckets, each simulated as one borrower named
+    /// after its first member. The members of a bucket then share their idiosyncratic draw, i.e.
+    /// they are perfectly correlated. Borrowers with the given identifiers, e.g. guarantors that
+    /// are referred to by their identifier, are kept on their own. Returns the number of borrowers
+    /// after bucketing
+    pub fn bucket_identical(&mut self, separate: &HashSet<String>) -> usize {
+        let mut index: HashMap<Vec<u64>, usize> = HashMap::new();
+        let mut buckets: Vec<borrower::Borrower> = Vec::new();
+
+        for borr in self.borrower.drain(..) {
+            if separate.contains(borr.id()) {
+                buckets.push(borr);
+                continue;
+            }
+            match index.entry(borr.structure_key()) {
+                Entry::Occupied(entry) => buckets[*entry.get()].merge(borr),
+                Entry::Vacant(entry) => {
+                    entry.insert(buckets.len());
+                    buckets.push(borr);
+                }
+            }
+        }
+
+        self.borrower = buckets;
+        self.borrower.len()
+    }
+
     /// Set the norm of all borrowers given a covariance matrix
     pub fn set_norm(&mut self, cov: &Array2<f64>) {
         self.borrower.iter_mut().for_each(|borr| borr.set_norm(cov));
@@ -0,0 +1,289 @@
+//! Random number generators for the simulation.
+//!
+//! The trials are split into chunks and every chunk draws from its own generator. The generator of
+//! a chunk is derived from the seed and a stream value that depends on the chunk index only, so
+//! results are reproducible independent of the number of threads. The stream of a chunk is
+//! obtained by hashing seed and chunk index through SplitMix64 (`chunk_stream`): both 64-bit halves
+//! are outputs of the SplitMix64 sequence of the seed at positions unique to the chunk, and as the
+//! output function is a bijection, no two chunks of a seed share either half. All generators
+//! support independent streams without drawing the numbers of the preceding chunks:
+//!
+//! * `Pcg64` - The stream selects the increment of the LCG, the initial state is derived from the
+//!   stream and the seed, so that chunks neither share the increment nor the state. Jumping ahead
+//!   within a stream is possible in logarithmic time (`advance`)
+//! * `ChaCha8` - The stream (lower half) is the 64-bit nonce of the cipher. Jumping ahead within a
+//!   stream is possible in constant time (`set_word_pos`)
+//! * `Philox4x32` - Counter-based, the stream (lower half) occupies the upper half of the 128-bit
+//!   counter, so every chunk owns a distinct block of 2^64 counters. Jumping ahead is possible in
+//!   constant time by setting the counter
+//! * `Xoshiro256PlusPlus` - The 256-bit state is the stream together with the hashed seed, i.e. a
+//!   random starting point within the period of 2^256 - 1, overlaps of chunks are negligible.
+//!   Jumping ahead by 2^128 values is possible (`jump`)
+//!
+//! By default all draws of a trial come from the generator of its chunk. With `Seeds::Split`, every
+//! chunk has two generators instead, one for the systematic factors and one for the idiosyncratic
+//! draws of the risk groups and borrowers, derived from their own seeds as above. The trials stay
+//! reproducible per chunk, and holding one seed fixed while varying the other changes only the
+//! corresponding draws of every trial. The idiosyncratic seed is hashed with a fixed tag first, so
+//! the two generators are independent even for equal seeds. Split seeds never reproduce a single
+//! seed run
+//!
+//! With `Sampler::LatinHypercube`, the systematic draws of all trials of a chunk are generated up
+//! front from the generator of the systematic factors: every free factor hits each of the
+//! equiprobable strata of the chunk exactly once (`latin_hypercube`), which reduces the variance
+//! of estimates driven by the systematic factors. The idiosyncratic draws stay pseudo-random and
+//! the trials remain reproducible per chunk, but depend on the chunk size. Moment matching draws
+//! the block of systematic draws of a chunk up front as well and rescales it to the exact mean
+//! zero and identity covariance (`match_moments`) before the factors are correlated
+
+use ndarray::{Array1, Array2, Axis};
+use ndarray_linalg::{Cholesky, UPLO};
+use rand::distributions::Open01;
+use rand::seq::SliceRandom;
+use rand::{Rng, RngCore};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rand_pcg::Pcg64;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use statrs::distribution::{ContinuousCDF, Normal};
+
+/// Increment of the SplitMix64 state, the golden ratio scaled to 64 bits
+const GOLDEN_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// Tag separating the generators of the idiosyncratic draws from those of the systematic factors
+const IDIOSYNCRATIC_TAG: u64 = 0x6964_696F_7379_6E63;
+
+/// Output function of SplitMix64 (Steele, Lea and Flood, 2014), a bijection of 64-bit values
+pub fn splitmix64(x: u64) -> u64 {
+    let mut z = x;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Stream of the chunk with the given index, the outputs of the SplitMix64 sequence of the seed at
+/// positions `2 * chunk` (upper half) and `2 * chunk + 1` (lower half)
+pub fn chunk_stream(seed: u64, chunk: u64) -> u128 {
+    let output = |position: u64| splitmix64(seed.wrapping_add(position.wrapping_add(1).wrapping_mul(GOLDEN_GAMMA)));
+    ((output(2 * chunk) as u128) << 64) | output(2 * chunk + 1) as u128
+}
+
+/// Seeds of the generators of a simulation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Seeds {
+    /// One generator per chunk for all draws of a trial
+    Single(u64),
+    /// Separate generators per chunk for the systematic factors and the idiosyncratic draws
+    Split { systematic: u64, idiosyncratic: u64 },
+}
+
+impl From<u64> for Seeds {
+    fn from(seed: u64) -> Self {
+        Self::Single(seed)
+    }
+}
+
+impl Seeds {
+    /// Generators of the chunk with the given index: the generator of the systematic factors, or
+    /// of all draws for a single seed, and the generator of the idiosyncratic draws for split seeds
+    pub fn chunk_rng<R: SimulationRng>(&self, chunk: u64) -> (R, Option<R>) {
+        let rng = |seed: u64| R::from_stream(seed, chunk_stream(seed, chunk));
+        match *self {
+            Self::Single(seed) => (rng(seed), None),
+            Self::Split { systematic, idiosyncratic } => (rng(systematic), Some(rng(splitmix64(idiosyncratic ^ IDIOSYNCRATIC_TAG)))),
+        }
+    }
+
+    /// Seeds offset by the given number, e.g. for independent simulations of several scenarios
+    pub fn offset(&self, offset: u64) -> Self {
+        match *self {
+            Self::Single(seed) => Self::Single(seed.wrapping_add(offset)),
+            Self::Split { systematic, idiosyncratic } => Self::Split {
+                systematic: systematic.wrapping_add(offset),
+                idiosyncratic: idiosyncratic.wrapping_add(offset),
+            },
+        }
+    }
+}
+
+/// Sampling of the systematic draws of the trials of a chunk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Sampler {
+    /// Independent pseudo-random draws
+    #[default]
+    MonteCarlo,
+    /// Latin hypercube sample of the draws of all trials of a chunk
+    LatinHypercube,
+}
+
+/// Latin hypercube sample of standard normal draws with one row per trial and one column per
+/// dimension: each column hits every one of the equiprobable strata exactly once, in a random
+/// order and at a uniform position within the stratum, mapped through the inverse normal CDF
+pub fn latin_hypercube<R: Rng>(rng: &mut R, num_trials: usize, dimensions: usize) -> Array2<f64> {
+    let normal = Normal::new(0., 1.).unwrap();
+    let mut sample = Array2::zeros((num_trials, dimensions));
+    let mut strata: Vec<usize> = (0..num_trials).collect();
+
+    for mut column in sample.columns_mut() {
+        strata.shuffle(rng);
+        for (value, &stratum) in column.iter_mut().zip(strata.iter()) {
+            let u: f64 = rng.sample(Open01);
+            *value = normal.inverse_cdf((stratum as f64 + u) / num_trials as f64);
+        }
+    }
+    sample
+}
+
+/// Rescale a sample with one row per trial to a mean of exactly zero and a sample covariance
+/// (divisor `n - 1`) of exactly the identity: the rows are centered and multiplied by the inverse
+/// of the Cholesky factor of their covariance. Samples with no more rows than columns have a
+/// singular covariance and are left unchanged, like degenerate samples
+pub fn match_moments(sample: &mut Array2<f64>) {
+    let (num_trials, dimensions) = sample.dim();
+    if num_trials <= dimensions {
+        return;
+    }
+
+    let mean: Array1<f64> = sample.mean_axis(Axis(0)).expect("Sample has trials");
+    let centered = &*sample - &mean;
+    let cov = centered.t().dot(&centered) / (num_trials - 1) as f64;
+    let Ok(lower) = cov.cholesky(UPLO::Lower) else { return };
+
+    // Forward substitution of every row, i.e. the rows of the centered sample times L⁻ᵀ
+    for (mut row, centered) in sample.rows_mut().into_iter().zip(centered.rows()) {
+        for i in 0..dimensions {
+            let sum: f64 = (0..i).map(|j| lower[[i, j]] * row[j]).sum();
+            row[i] = (centered[i] - sum) / lower[[i, i]];
+        }
+    }
+}
+
+/// Random number generator usable for the simulation
+pub trait SimulationRng: RngCore + Sized {
+    /// Create the generator of a chunk given the seed of the simulation and the stream of the chunk
+    fn from_stream(seed: u64, stream: u128) -> Self;
+}
+
+impl SimulationRng for Pcg64 {
+    fn from_stream(seed: u64, stream: u128) -> Self {
+        Pcg64::new(stream.rotate_left(64) ^ splitmix64(seed) as u128, stream)
+    }
+}
+
+impl SimulationRng for ChaCha8Rng {
+    fn from_stream(seed: u64, stream: u128) -> Self {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        rng.set_stream(stream as u64);
+        rng
+    }
+}
+
+impl SimulationRng for Xoshiro256PlusPlus {
+    fn from_stream(seed: u64, stream: u128) -> Self {
+        let words = [(stream >> 64) as u64, stream as u64, splitmix64(seed), splitmix64(!seed)];
+        let mut state = [0_u8; 32];
+        for (bytes, word) in state.chunks_exact_mut(8).zip(words) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        Xoshiro256PlusPlus::from_seed(state)
+    }
+}
+
+impl SimulationRng for Philox4x32 {
+    fn from_stream(seed: u64, stream: u128) -> Self {
+        Philox4x32::new(seed, (stream as u64 as u128) << 64)
+    }
+}
+
+/// Philox4x32-10 counter-based generator (Salmon et al., 2011). Each 128-bit counter value is
+/// encrypted with the 64-bit key to four 32-bit outputs
+#[derive(Debug, Clone)]
+pub struct Philox4x32 {
+    /// Key, i.e. the seed
+    key: [u32; 2],
+    /// Counter of the next block
+    counter: u128,
+    /// Outputs of the current block
+    buffer: [u32; 4],
+    /// Position of the next output within the buffer
+    index: usize,
+}
+
+impl Philox4x32 {
+    const M0: u32 = 0xD251_1F53;
+    const M1: u32 = 0xCD9E_8D57;
+    const W0: u32 = 0x9E37_79B9;
+    const W1: u32 = 0xBB67_AE85;
+
+    /// Create a new generator with given key and starting counter
+    pub fn new(key: u64, counter: u128) -> Self {
+        Self {
+            key: [key as u32, (key >> 32) as u32],
+            counter,
+            buffer: [0; 4],
+            index: 4,
+        }
+    }
+
+    /// Set the counter of the next block, i.e. jump to an arbitrary position
+    pub fn set_counter(&mut self, counter: u128) {
+        self.counter = counter;
+        self.index = 4;
+    }
+
+    /// Encrypt a single counter block with a key
+    pub fn block(counter: [u32; 4], key: [u32; 2]) -> [u32; 4] {
+        let mut ctr = counter;
+        let mut key = key;
+
+        for round in 0..10 {
+            if round > 0 {
+                key[0] = key[0].wrapping_add(Self::W0);
+                key[1] = key[1].wrapping_add(Self::W1);
+            }
+
+            let prod0 = Self::M0 as u64 * ctr[0] as u64;
+            let prod1 = Self::M1 as u64 * ctr[2] as u64;
+            ctr = [
+                (prod1 >> 32) as u32 ^ ctr[1] ^ key[0],
+                prod1 as u32,
+                (prod0 >> 32) as u32 ^ ctr[3] ^ key[1],
+                prod0 as u32,
+            ];
+        }
+
+        ctr
+    }
+
+    /// Generate the outputs of the next block
+    fn refill(&mut self) {
+        let counter = [self.counter as u32, (self.counter >> 32) as u32, (self.counter >> 64) as u32, (self.counter >> 96) as u32];
+        self.buffer = Self::block(counter, self.key);
+        self.counter = self.counter.wrapping_add(1);
+        self.index = 0;
+    }
+}
+
+impl RngCore for Philox4x32 {
+    fn next_u32(&mut self) -> u32 {
+        if self.index >= 4 {
+            self.refill();
+        }
+        let value = self.buffer[self.index];
+        self.index += 1;
+        value
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_u32(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand_core::impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
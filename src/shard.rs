@@ -0,0 +1,211 @@
+use csv::{Reader, Writer};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::input;
+use crate::summary;
+
+/// Share of the chunks of trials simulated by one of several independent processes, given as
+/// `index/count` with a zero-based index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl std::str::FromStr for Shard {
+    type Err = String;
+
+    /// Parse `index/count`, e.g. `0/20` for the first of 20 shards
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (index, count) = s.split_once('/').ok_or_else(|| format!("Invalid shard {}, expected index/count", s))?;
+        let index: usize = index.trim().parse().map_err(|_| format!("Invalid shard index in {}", s))?;
+        let count: usize = count.trim().parse().map_err(|_| format!("Invalid shard count in {}", s))?;
+
+        if index >= count {
+            return Err(format!("Shard index {} must be below the shard count {}", index, count));
+        }
+        Ok(Self { index, count })
+    }
+}
+
+impl Shard {
+    /// Contiguous range of the chunks simulated by the shard, the shards together cover every
+    /// chunk exactly once
+    pub fn chunks(&self, num_chunks: usize) -> Range<usize> {
+        num_chunks * self.index / self.count..num_chunks * (self.index + 1) / self.count
+    }
+}
+
+/// Description of the partial output of a shard, written as `shard_<index>.json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShardInfo {
+    /// Version of the crate that produced the partial output
+    pub version: String,
+    /// Input path the portfolio was read from
+    pub input: String,
+    /// Random number generator
+    pub rng: String,
+    /// Sampler of the systematic draws
+    pub sampler: String,
+    /// Whether the systematic draws of each chunk are moment matched
+    pub moment_matching: bool,
+    /// Seed of the random number generator
+    pub seed: u64,
+    /// Number of trials of the whole run
+    pub num_trials: usize,
+    /// Number of simulated trials per thread
+    pub chunk_size: usize,
+    /// Zero-based index of the shard
+    pub shard_index: usize,
+    /// Number of shards of the run
+    pub shard_count: usize,
+    /// Index of the first trial of the shard
+    pub first_trial: usize,
+    /// Number of trials simulated by the shard
+    pub trials: usize,
+    /// Wall-clock seconds of reading input and setting up the portfolio
+    pub initialization: f64,
+    /// Wall-clock seconds of the simulation
+    pub simulation: f64,
+    /// Size of the portfolio
+    pub portfolio: summary::PortfolioSize,
+    /// Analytical expected loss
+    pub expected_loss: f64,
+    /// Deterministic loss at the mean of all randomness
+    pub mean_scenario_loss: f64,
+    /// Current value of the portfolio
+    pub total_value: f64,
+    /// Outstanding amount of all exposures of the portfolio
+    pub total_outstanding: f64,
+    /// Average current rating index weighted by the current value of the borrowers
+    pub average_rating: Option<f64>,
+    /// Average expected rating index after one period weighted by the current value of the
+    /// borrowers
+    pub expected_average_rating: Option<f64>,
+    /// Fixed systematic risk factors if the simulation is conditional on a stress scenario
+    pub scenario: Option<Vec<input::ScenarioFactor>>,
+    /// Currency all amounts are converted to, if declared
+    pub base_currency: Option<String>,
+}
+
+/// Losses simulated by a shard, or by all shards once merged
+pub struct PartialResult {
+    /// Description of the shard
+    pub info: ShardInfo,
+    /// Portfolio loss of each trial in order of the trials
+    pub loss: Vec<f64>,
+    /// Identifier of each borrower in the order of simulation
+    pub borrower_ids: Vec<String>,
+    /// Sum of the losses of each borrower over all trials
+    pub borrower_loss: Vec<f64>,
+}
+
+impl PartialResult {
+    /// Expected loss per borrower over the trials
+    pub fn expected_loss(&self) -> Vec<f64> {
+        self.borrower_loss.iter().map(|sum| sum / self.loss.len() as f64).collect()
+    }
+
+    /// Write `shard_<index>.json` with the description, `shard_<index>_loss.csv` with the loss
+    /// of each trial and `shard_<index>_borrower_loss.csv` with the loss sum of each borrower
+    pub fn write(&self, dir: &Path) {
+        let name = format!("shard_{}", self.info.shard_index);
+
+        let file = File::create(dir.join(format!("{}.json", name))).expect("Shard file could not be created");
+        serde_json::to_writer_pretty(file, &self.info).expect("Shard description could not be written");
+
+        let mut writer = Writer::from_path(dir.join(format!("{}_loss.csv", name))).expect("Output path not found");
+        writer.write_record(vec!["Loss"]).unwrap();
+        self.loss.iter().for_each(|loss| writer.write_record(vec![loss.to_string()]).unwrap());
+        writer.flush().unwrap();
+
+        let mut writer = Writer::from_path(dir.join(format!("{}_borrower_loss.csv", name))).expect("Output path not found");
+        writer.write_record(vec!["borrower_id", "loss_sum"]).unwrap();
+        for (id, sum) in self.borrower_ids.iter().zip(self.borrower_loss.iter()) {
+            writer.write_record(vec![id.to_string(), sum.to_string()]).unwrap();
+        }
+        writer.flush().unwrap();
+    }
+
+    /// Read the partial output of a shard given its description file `shard_<index>.json`
+    pub fn read(path: &Path) -> Self {
+        let file = File::open(path).expect("Shard file not found");
+        let info: ShardInfo = serde_json::from_reader(file).expect("Shard description could not be read");
+        let dir = path.parent().expect("Shard file has no directory");
+        let name = format!("shard_{}", info.shard_index);
+
+        let mut reader = Reader::from_path(dir.join(format!("{}_loss.csv", name))).expect("Shard loss file not found");
+        let loss: Vec<f64> = reader.records()
+            .map(|record| record.expect("Shard losses could not be read")[0].parse::<f64>().expect("Shard loss is not a number"))
+            .collect();
+        assert_eq!(loss.len(), info.trials, "Shard {} has {} losses, but {} trials", info.shard_index, loss.len(), info.trials);
+
+        let mut borrower_ids: Vec<String> = Vec::new();
+        let mut borrower_loss: Vec<f64> = Vec::new();
+        let mut reader = Reader::from_path(dir.join(format!("{}_borrower_loss.csv", name))).expect("Shard borrower loss file not found");
+        for record in reader.records() {
+            let record = record.expect("Shard borrower losses could not be read");
+            borrower_ids.push(record[0].to_string());
+            borrower_loss.push(record[1].parse::<f64>().expect("Shard borrower loss is not a number"));
+        }
+
+        Self { info, loss, borrower_ids, borrower_loss }
+    }
+}
+
+/// Read the partial outputs of all shards within a directory in order of the shards
+pub fn read_dir(dir: &Path) -> Vec<PartialResult> {
+    let mut parts: Vec<PartialResult> = std::fs::read_dir(dir).expect("Shard directory not found")
+        .map(|entry| entry.expect("Shard directory could not be read").path())
+        .filter(|path| {
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            name.starts_with("shard_") && name.ends_with(".json")
+        })
+        .map(|path| PartialResult::read(&path))
+        .collect();
+    parts.sort_by_key(|part| part.info.shard_index);
+    parts
+}
+
+/// Combine the partial outputs of all shards of a run, given in order of the shards, into the
+/// result of the whole run. The losses equal those of a single run with the same seed, number of
+/// trials and chunk size
+pub fn merge(parts: Vec<PartialResult>) -> PartialResult {
+    let first = parts.first().expect("No shards to merge");
+    let count = first.info.shard_count;
+    assert_eq!(parts.len(), count, "Run has {} shards, but {} were found", count, parts.len());
+
+    let mut info = first.info.clone();
+    let borrower_ids = first.borrower_ids.clone();
+    let mut loss: Vec<f64> = Vec::with_capacity(info.num_trials);
+    let mut borrower_loss: Vec<f64> = vec![0.; borrower_ids.len()];
+
+    for (index, part) in parts.into_iter().enumerate() {
+        let other = &part.info;
+        assert_eq!(other.shard_index, index, "Shard {} is missing", index);
+        assert!(
+            (&other.rng, &other.sampler, other.moment_matching, other.seed, other.num_trials, other.chunk_size, other.shard_count) == (&info.rng, &info.sampler, info.moment_matching, info.seed, info.num_trials, info.chunk_size, count),
+            "Shard {} belongs to a different run than shard 0", index
+        );
+        assert_eq!(part.borrower_ids, borrower_ids, "Shard {} has different borrowers than shard 0", index);
+        assert_eq!(other.first_trial, loss.len(), "Shard {} does not continue the trials of the previous shard", index);
+
+        // Shards run concurrently, so the run takes as long as its slowest shard
+        info.initialization = info.initialization.max(other.initialization);
+        info.simulation = info.simulation.max(other.simulation);
+
+        loss.extend(part.loss);
+        borrower_loss.iter_mut().zip(part.borrower_loss.iter()).for_each(|(a, b)| *a += b);
+    }
+    assert_eq!(loss.len(), info.num_trials, "Shards have {} trials, but the run has {}", loss.len(), info.num_trials);
+
+    info.shard_index = 0;
+    info.shard_count = 1;
+    info.first_trial = 0;
+    info.trials = loss.len();
+
+    PartialResult { info, loss, borrower_ids, borrower_loss }
+}
@@ -0,0 +1,90 @@
+use ndarray::Array1;
+
+use crate::migration;
+use crate::statistics;
+
+/// Scalar type the portfolio losses of the trials are stored in. The simulation itself always
+/// computes in `f64`, `f32` halves the memory of the loss distribution of large runs
+pub trait LossScalar: Copy + Default + PartialOrd + Send + Sync + std::fmt::Display + 'static {
+    /// Convert a simulated loss for storage
+    fn from_f64(value: f64) -> Self;
+    /// Convert a stored loss for computation
+    fn to_f64(self) -> f64;
+}
+
+impl LossScalar for f64 {
+    fn from_f64(value: f64) -> Self {
+        value
+    }
+
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+impl LossScalar for f32 {
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+/// Result of a simulation of many trials
+pub struct SimulationResult<L: LossScalar = f64> {
+    /// Portfolio loss of each trial in the order of the trials
+    pub loss: Vec<L>,
+    /// Expected loss per borrower
+    pub expected_loss: Array1<f64>,
+    /// Counts of migrations into each rating class, if requested
+    pub migrations: Option<migration::MigrationCounts>,
+    /// Losses of the tracked borrowers, a row of one loss per tracked borrower for each trial
+    pub tracked: Vec<f64>,
+}
+
+impl<L: LossScalar> SimulationResult<L> {
+    /// Number of simulated trials
+    pub fn num_trials(&self) -> usize {
+        self.loss.len()
+    }
+
+    /// Mean of the loss distribution
+    pub fn mean(&self) -> f64 {
+        self.loss.iter().map(|l| l.to_f64()).sum::<f64>() / self.loss.len() as f64
+    }
+
+    /// Quantile of the loss distribution
+    pub fn quantile(&self, level: f64) -> f64 {
+        statistics::quantile(&mut self.loss.clone(), level)
+    }
+
+    /// Value at risk, i.e. the quantile of the loss distribution
+    pub fn var(&self, level: f64) -> f64 {
+        self.quantile(level)
+    }
+
+    /// Expected shortfall, i.e. the average loss at or above the value at risk
+    pub fn es(&self, level: f64) -> f64 {
+        statistics::expected_shortfall(&self.loss, self.var(level))
+    }
+}
+
+/// Split the trials across the scenarios of a mixture proportionally to their weights by the
+/// largest remainder method, so that the counts sum to the number of trials and differ from the
+/// exact shares by less than one
+pub fn allocate_trials(weights: &[f64], num_trials: usize) -> Vec<usize> {
+    let total: f64 = weights.iter().sum();
+    let shares: Vec<f64> = weights.iter().map(|w| w / total * num_trials as f64).collect();
+    let mut counts: Vec<usize> = shares.iter().map(|share| share.floor() as usize).collect();
+
+    // Remaining trials go to the largest remainders, ties to the first scenario
+    let mut order: Vec<usize> = (0..weights.len()).collect();
+    order.sort_by(|&a, &b| (shares[b] - shares[b].floor()).total_cmp(&(shares[a] - shares[a].floor())));
+    let remaining = num_trials.saturating_sub(counts.iter().sum());
+    for &index in order.iter().take(remaining) {
+        counts[index] += 1;
+    }
+    counts
+}
@@ -0,0 +1,640 @@
+use rand::Rng;
+use rand_pcg::Pcg64;
+use rayon::prelude::*;
+use statrs::distribution::{ChiSquared, ContinuousCDF};
+
+use crate::rng::{self, SimulationRng};
+use crate::simulation::LossScalar;
+use crate::summary;
+
+/// Value of rank `k` (from zero) of the losses, partially reorders the losses
+fn select<L: LossScalar>(loss: &mut [L], k: usize) -> f64 {
+    loss.select_nth_unstable_by(k, |a, b| a.to_f64().total_cmp(&b.to_f64())).1.to_f64()
+}
+
+/// Quantile of the losses by the approximately median-unbiased definition (R-8), which is also
+/// used by `statrs`. Partially reorders the losses instead of sorting them
+pub fn quantile<L: LossScalar>(loss: &mut [L], tau: f64) -> f64 {
+    if loss.is_empty() || !(0.0..=1.0).contains(&tau) {
+        return f64::NAN;
+    }
+
+    let n = loss.len() as f64;
+    let h = (n + 1. / 3.) * tau + 1. / 3.;
+    let hf = h as i64;
+
+    if hf <= 0 || tau == 0. {
+        select(loss, 0)
+    } else if hf >= loss.len() as i64 || (tau - 1.).abs() <= 4. * f64::EPSILON {
+        select(loss, loss.len() - 1)
+    } else {
+        let a = select(loss, hf as usize - 1);
+        let b = select(loss, hf as usize);
+        a + (h - hf as f64) * (b - a)
+    }
+}
+
+/// Median of the losses, partially reorders the losses
+pub fn median<L: LossScalar>(loss: &mut [L]) -> f64 {
+    let k = loss.len() / 2;
+    if !loss.len().is_multiple_of(2) {
+        select(loss, k)
+    } else {
+        (select(loss, k - 1) + select(loss, k)) / 2.
+    }
+}
+
+/// Expected shortfall, i.e. the average of all losses at or above the value at risk `var`
+pub fn expected_shortfall<L: LossScalar>(loss: &[L], var: f64) -> f64 {
+    let (sum, count) = loss.iter()
+        .map(|l| l.to_f64())
+        .filter(|&l| l >= var)
+        .fold((0.0_f64, 0_usize), |(sum, count), l| (sum + l, count + 1));
+
+    sum / count as f64
+}
+
+/// Value at risk and expected shortfall of a loss distribution for all quantile levels, partially
+/// reorders the losses
+pub fn risk_measures<L: LossScalar>(loss: &mut [L], levels: &[f64]) -> Vec<summary::QuantileSummary> {
+    levels.iter().map(|&level| {
+        let var = quantile(loss, level);
+        let es = expected_shortfall(loss, var);
+        summary::QuantileSummary { level, var, es, var_standard_error: None, es_standard_error: None }
+    }).collect()
+}
+
+/// Value at risk and expected shortfall like `risk_measures`, of the weighted trials if weights
+/// are given (see `weighted_risk_measures`), which leaves the losses in order
+pub fn risk_measures_with<L: LossScalar>(loss: &mut [L], weights: Option<&[f64]>, levels: &[f64]) -> Vec<summary::QuantileSummary> {
+    match weights {
+        Some(weights) => weighted_risk_measures(loss, weights, levels),
+        None => risk_measures(loss, levels),
+    }
+}
+
+/// Losses with a positive weight in ascending order with their weights scaled to sum to the
+/// number of these losses, and the plotting position of each, i.e. the cumulative scaled weight
+/// up to the middle of the loss plus one half. Equal weights give the positions `1..=n`
+fn weighted_positions<L: LossScalar>(loss: &[L], weights: &[f64]) -> (Vec<f64>, Vec<f64>, Vec<f64>) {
+    assert_eq!(loss.len(), weights.len(), "Number of weights does not match the number of losses");
+    assert!(weights.iter().all(|&w| w >= 0. && w.is_finite()), "Weights of the trials must be finite and not negative");
+
+    let mut pairs: Vec<(f64, f64)> = loss.iter().zip(weights.iter())
+        .filter(|&(_, &w)| w > 0.)
+        .map(|(l, &w)| (l.to_f64(), w))
+        .collect();
+    pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let scale = pairs.len() as f64 / pairs.iter().map(|p| p.1).sum::<f64>();
+    let mut cumulative = 0.;
+    let (values, scaled): (Vec<f64>, Vec<f64>) = pairs.into_iter().map(|(l, w)| (l, w * scale)).unzip();
+    let positions = scaled.iter().map(|&w| {
+        let position = cumulative + w / 2. + 0.5;
+        cumulative += w;
+        position
+    }).collect();
+    (values, scaled, positions)
+}
+
+/// Quantile of sorted losses given their plotting positions, interpolated linearly between the
+/// positions around `(n + 1/3) tau + 1/3` like the unweighted definition (R-8)
+fn interpolate_quantile(values: &[f64], positions: &[f64], tau: f64) -> f64 {
+    if values.is_empty() || !(0.0..=1.0).contains(&tau) {
+        return f64::NAN;
+    }
+
+    let h = (values.len() as f64 + 1. / 3.) * tau + 1. / 3.;
+    match positions.partition_point(|&p| p <= h) {
+        0 => values[0],
+        k if k == values.len() => values[k - 1],
+        k => values[k - 1] + (h - positions[k - 1]) / (positions[k] - positions[k - 1]) * (values[k] - values[k - 1]),
+    }
+}
+
+/// Quantile of the weighted empirical distribution of the losses, e.g. of trials of scenarios
+/// blended with different weights or of importance sampling. Every loss takes up a share of the
+/// plotting positions proportional to its weight, so equal weights give `quantile`. Losses with
+/// zero weight are ignored
+pub fn weighted_quantile<L: LossScalar>(loss: &[L], weights: &[f64], alpha: f64) -> f64 {
+    let (values, _, positions) = weighted_positions(loss, weights);
+    interpolate_quantile(&values, &positions, alpha)
+}
+
+/// Expected shortfall of weighted losses, i.e. the weighted average of all losses at or above the
+/// value at risk `var`
+pub fn weighted_expected_shortfall<L: LossScalar>(loss: &[L], weights: &[f64], var: f64) -> f64 {
+    let (sum, weight) = loss.iter().zip(weights.iter())
+        .map(|(l, &w)| (l.to_f64(), w))
+        .filter(|&(l, _)| l >= var)
+        .fold((0.0_f64, 0.0_f64), |(sum, weight), (l, w)| (sum + w * l, weight + w));
+
+    sum / weight
+}
+
+/// Value at risk and expected shortfall of weighted losses for all quantile levels, the losses are
+/// sorted once for all levels
+pub fn weighted_risk_measures<L: LossScalar>(loss: &[L], weights: &[f64], levels: &[f64]) -> Vec<summary::QuantileSummary> {
+    let (values, scaled, positions) = weighted_positions(loss, weights);
+    levels.iter().map(|&level| {
+        let var = interpolate_quantile(&values, &positions, level);
+        let es = weighted_expected_shortfall(&values, &scaled, var);
+        summary::QuantileSummary { level, var, es, var_standard_error: None, es_standard_error: None }
+    }).collect()
+}
+
+/// Compensated sum (Neumaier), accumulates values of very different magnitude without losing the
+/// small ones to rounding
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CompensatedSum {
+    /// Running sum
+    sum: f64,
+    /// Running compensation of the rounding errors
+    compensation: f64,
+}
+
+impl CompensatedSum {
+    /// Add a value
+    pub fn add(&mut self, value: f64) {
+        let t = self.sum + value;
+        if self.sum.abs() >= value.abs() {
+            self.compensation += (self.sum - t) + value;
+        } else {
+            self.compensation += (value - t) + self.sum;
+        }
+        self.sum = t;
+    }
+
+    /// Add another sum
+    pub fn merge(&mut self, other: &CompensatedSum) {
+        self.add(other.sum);
+        self.add(other.compensation);
+    }
+
+    /// Compensated value of the sum
+    pub fn value(&self) -> f64 {
+        self.sum + self.compensation
+    }
+}
+
+impl std::iter::Sum<f64> for CompensatedSum {
+    fn sum<I: Iterator<Item = f64>>(iter: I) -> Self {
+        let mut sum = Self::default();
+        iter.for_each(|value| sum.add(value));
+        sum
+    }
+}
+
+/// Running central moments up to fourth order, accumulated value by value and mergeable across
+/// chunks (Pébay, 2008)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Moments {
+    /// Number of values
+    n: u64,
+    /// Mean
+    mean: f64,
+    /// Sums of second, third and fourth powers of the deviations from the mean
+    m2: f64,
+    m3: f64,
+    m4: f64,
+}
+
+impl Moments {
+    /// Add a value
+    pub fn add(&mut self, value: f64) {
+        let n1 = self.n as f64;
+        self.n += 1;
+        let n = self.n as f64;
+        let delta = value - self.mean;
+        let delta_n = delta / n;
+        let term = delta * delta_n * n1;
+
+        self.mean += delta_n;
+        self.m4 += term * delta_n * delta_n * (n * n - 3. * n + 3.) + 6. * delta_n * delta_n * self.m2 - 4. * delta_n * self.m3;
+        self.m3 += term * delta_n * (n - 2.) - 3. * delta_n * self.m2;
+        self.m2 += term;
+    }
+
+    /// Combine with the moments of other values
+    pub fn merge(&mut self, other: &Moments) {
+        if other.n == 0 {
+            return;
+        }
+        if self.n == 0 {
+            *self = other.clone();
+            return;
+        }
+
+        let (na, nb) = (self.n as f64, other.n as f64);
+        let n = na + nb;
+        let delta = other.mean - self.mean;
+        let delta2 = delta * delta;
+
+        let m2 = self.m2 + other.m2 + delta2 * na * nb / n;
+        let m3 = self.m3 + other.m3 + delta * delta2 * na * nb * (na - nb) / (n * n)
+            + 3. * delta * (na * other.m2 - nb * self.m2) / n;
+        let m4 = self.m4 + other.m4 + delta2 * delta2 * na * nb * (na * na - na * nb + nb * nb) / (n * n * n)
+            + 6. * delta2 * (na * na * other.m2 + nb * nb * self.m2) / (n * n)
+            + 4. * delta * (na * other.m3 - nb * self.m3) / n;
+
+        self.mean += delta * nb / n;
+        self.m2 = m2;
+        self.m3 = m3;
+        self.m4 = m4;
+        self.n += other.n;
+    }
+
+    /// Moments of weighted values, with the weights scaled to sum to the number of values so that
+    /// equal weights give the moments of the values added one by one
+    pub fn weighted<L: LossScalar>(values: &[L], weights: &[f64]) -> Self {
+        let n = values.len() as f64;
+        let total: f64 = weights.iter().sum();
+        let mean = values.iter().zip(weights.iter()).map(|(v, w)| w * v.to_f64()).sum::<f64>() / total;
+        let central = |power: i32| values.iter().zip(weights.iter()).map(|(v, w)| w * (v.to_f64() - mean).powi(power)).sum::<f64>() * n / total;
+
+        Self { n: values.len() as u64, mean, m2: central(2), m3: central(3), m4: central(4) }
+    }
+
+    /// Number of values
+    pub fn count(&self) -> u64 {
+        self.n
+    }
+
+    /// Mean of the values
+    pub fn mean(&self) -> f64 {
+        self.mean
+    }
+
+    /// Variance of the values (sample formula)
+    pub fn variance(&self) -> f64 {
+        self.m2 / (self.n as f64 - 1.)
+    }
+
+    /// Skewness of the values (population formula)
+    pub fn skewness(&self) -> f64 {
+        (self.n as f64).sqrt() * self.m3 / self.m2.powf(1.5)
+    }
+
+    /// Excess kurtosis of the values (population formula), zero for a normal distribution
+    pub fn excess_kurtosis(&self) -> f64 {
+        self.n as f64 * self.m4 / (self.m2 * self.m2) - 3.
+    }
+}
+
+/// Standard errors of value at risk and expected shortfall by batch means: the losses are split
+/// into consecutive batches of equal size, e.g. the independent chunks of the simulation, the
+/// measures are computed per batch and the standard error is the standard deviation of the batch
+/// measures divided by the square root of the number of batches. An incomplete last batch is
+/// ignored. Returns `(var, es)` standard errors for each level, `None` with less than two batches
+pub fn batch_standard_errors<L: LossScalar>(loss: &[L], batch_size: usize, levels: &[f64]) -> Option<Vec<(f64, f64)>> {
+    let num_batches = loss.len() / batch_size;
+    if num_batches < 2 {
+        return None;
+    }
+
+    let batches: Vec<Vec<summary::QuantileSummary>> = loss.par_chunks_exact(batch_size)
+        .map(|batch| risk_measures(&mut batch.to_vec(), levels))
+        .collect();
+
+    let standard_error = |values: Vec<f64>| {
+        let mut moments = Moments::default();
+        values.into_iter().for_each(|v| moments.add(v));
+        (moments.variance() / num_batches as f64).sqrt()
+    };
+    Some((0..levels.len()).map(|index| (
+        standard_error(batches.iter().map(|b| b[index].var).collect()),
+        standard_error(batches.iter().map(|b| b[index].es).collect()),
+    )).collect())
+}
+
+/// Risk measure whose relative standard error controls early stopping of the simulation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TargetMetric {
+    /// Mean of the loss distribution
+    Mean,
+    /// Value at risk of a quantile level
+    Var(f64),
+    /// Expected shortfall of a quantile level
+    Es(f64),
+}
+
+impl std::str::FromStr for TargetMetric {
+    type Err = String;
+
+    /// Parse `mean`, `q<percent>` (value at risk) or `es<percent>` (expected shortfall), e.g.
+    /// `q99.9`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let level = |percent: &str| percent.parse::<f64>()
+            .ok()
+            .filter(|p| *p > 0. && *p < 100.)
+            .map(|p| p / 100.)
+            .ok_or_else(|| format!("Invalid quantile level in target metric {}", s));
+
+        if s == "mean" {
+            Ok(Self::Mean)
+        } else if let Some(percent) = s.strip_prefix("es") {
+            Ok(Self::Es(level(percent)?))
+        } else if let Some(percent) = s.strip_prefix('q') {
+            Ok(Self::Var(level(percent)?))
+        } else {
+            Err(format!("Unknown target metric {}, expected mean, q<percent> or es<percent>", s))
+        }
+    }
+}
+
+impl TargetMetric {
+    /// Standard error of the metric relative to its absolute value, by batch means over batches of
+    /// the given size for value at risk and expected shortfall. `None` if it cannot be estimated
+    /// yet
+    pub fn relative_error<L: LossScalar>(&self, loss: &[L], batch_size: usize) -> Option<f64> {
+        let (estimate, standard_error) = match *self {
+            Self::Mean => {
+                if loss.len() < 2 {
+                    return None;
+                }
+                let mut moments = Moments::default();
+                loss.iter().for_each(|l| moments.add(l.to_f64()));
+                (moments.mean(), (moments.variance() / loss.len() as f64).sqrt())
+            }
+            Self::Var(level) | Self::Es(level) => {
+                let (var_se, es_se) = batch_standard_errors(loss, batch_size, &[level])?[0];
+                let q = &risk_measures(&mut loss.to_vec(), &[level])[0];
+                if matches!(self, Self::Var(_)) { (q.var, var_se) } else { (q.es, es_se) }
+            }
+        };
+
+        Some(standard_error / estimate.abs())
+    }
+}
+
+/// Bootstrap of the `alpha`-quantile of a loss distribution: the losses are resampled with
+/// replacement `reps` times. Returns the quantile of the losses together with the lower and upper
+/// bound of the 95% percentile interval of the resampled quantiles. Every repetition draws from
+/// its own stream, so the result does not depend on the number of threads
+pub fn bootstrap_quantile<L: LossScalar>(loss: &[L], alpha: f64, reps: usize, seed: u64) -> (f64, f64, f64) {
+    let estimate = quantile(&mut loss.to_vec(), alpha);
+
+    let mut quantiles: Vec<f64> = (0..reps).into_par_iter().map(|rep| {
+        // Streams derived from a hashed seed, so that they differ from those of the simulation
+        let mut rng = Pcg64::from_stream(seed, rng::chunk_stream(rng::splitmix64(seed), rep as u64));
+        let mut sample: Vec<L> = (0..loss.len()).map(|_| loss[rng.gen_range(0..loss.len())]).collect();
+        quantile(&mut sample, alpha)
+    }).collect();
+
+    (estimate, quantile(&mut quantiles, 0.025), quantile(&mut quantiles, 0.975))
+}
+
+/// Point of the loss exceedance curve
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExceedancePoint {
+    /// Loss threshold
+    pub threshold: f64,
+    /// Probability of a loss above the threshold
+    pub probability: f64,
+    /// Average loss in excess of the threshold given the threshold is exceeded, zero if it is
+    /// never exceeded
+    pub mean_excess: f64,
+}
+
+/// Evenly spaced order statistics of a loss distribution sorted in ascending order, including the
+/// minimum and maximum, e.g. a small sample with the empirical distribution of all losses. All
+/// losses are returned if there are no more than requested
+pub fn order_statistics<L: LossScalar>(sorted_loss: &[L], count: usize) -> Vec<L> {
+    if count >= sorted_loss.len() {
+        return sorted_loss.to_vec();
+    }
+    match count {
+        0 => Vec::new(),
+        1 => vec![sorted_loss[(sorted_loss.len() - 1) / 2]],
+        _ => (0..count).map(|i| {
+            let rank = (i as f64 * (sorted_loss.len() - 1) as f64 / (count - 1) as f64).round() as usize;
+            sorted_loss[rank]
+        }).collect(),
+    }
+}
+
+/// Differences `F_a(x) - F_b(x)` of the empirical distribution functions of two loss distributions
+/// sorted in ascending order, at each distinct loss of either distribution in ascending order
+fn distribution_differences<'a>(sorted_a: &'a [f64], sorted_b: &'a [f64]) -> impl Iterator<Item = (f64, f64)> + 'a {
+    let (mut i, mut j) = (0, 0);
+    std::iter::from_fn(move || {
+        let x = match (sorted_a.get(i), sorted_b.get(j)) {
+            (Some(&a), Some(&b)) => a.min(b),
+            (Some(&a), None) => a,
+            (None, Some(&b)) => b,
+            (None, None) => return None,
+        };
+        while i < sorted_a.len() && sorted_a[i] <= x {
+            i += 1;
+        }
+        while j < sorted_b.len() && sorted_b[j] <= x {
+            j += 1;
+        }
+        Some((x, i as f64 / sorted_a.len() as f64 - j as f64 / sorted_b.len() as f64))
+    })
+}
+
+/// Two-sample Kolmogorov–Smirnov statistic, the largest absolute difference of the empirical
+/// distribution functions of two loss distributions sorted in ascending order
+pub fn ks_statistic(sorted_a: &[f64], sorted_b: &[f64]) -> f64 {
+    if sorted_a.is_empty() || sorted_b.is_empty() {
+        return f64::NAN;
+    }
+    distribution_differences(sorted_a, sorted_b).fold(0., |max, (_, d)| d.abs().max(max))
+}
+
+/// Wasserstein distance of order one between two loss distributions sorted in ascending order, the
+/// area between their empirical distribution functions, i.e. the mean absolute difference of
+/// their quantiles. It is in units of the loss
+pub fn wasserstein_distance(sorted_a: &[f64], sorted_b: &[f64]) -> f64 {
+    if sorted_a.is_empty() || sorted_b.is_empty() {
+        return f64::NAN;
+    }
+    let mut sum = CompensatedSum::default();
+    let mut previous: Option<(f64, f64)> = None;
+    for (x, d) in distribution_differences(sorted_a, sorted_b) {
+        if let Some((x_prev, d_prev)) = previous {
+            sum.add(d_prev.abs() * (x - x_prev));
+        }
+        previous = Some((x, d));
+    }
+    sum.value()
+}
+
+/// Loss exceedance curve `P(L > x)` of a loss distribution sorted in ascending order. The curve is
+/// computed in a single pass from the largest loss downwards for all thresholds
+pub fn exceedance_curve<L: LossScalar>(sorted_loss: &[L], thresholds: &[f64]) -> Vec<ExceedancePoint> {
+    let mut thresholds = thresholds.to_vec();
+    thresholds.sort_by(|a, b| b.total_cmp(a));
+
+    let mut out: Vec<ExceedancePoint> = Vec::with_capacity(thresholds.len());
+    let mut index = sorted_loss.len();
+    let mut sum = 0.0_f64;
+
+    for threshold in thresholds {
+        while index > 0 && sorted_loss[index - 1].to_f64() > threshold {
+            index -= 1;
+            sum += sorted_loss[index].to_f64();
+        }
+
+        let count = sorted_loss.len() - index;
+        out.push(ExceedancePoint {
+            threshold,
+            probability: count as f64 / sorted_loss.len() as f64,
+            mean_excess: if count > 0 { sum / count as f64 - threshold } else { 0. },
+        });
+    }
+
+    out.reverse();
+    out
+}
+
+/// Histogram with fixed bin edges. Values below the first or above the last edge are counted
+/// separately, so that histograms of different chunks can be accumulated and merged
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    /// Ascending bin edges, bin `i` covers `[edges[i], edges[i + 1])`, the last bin includes its
+    /// upper edge
+    edges: Vec<f64>,
+    /// Counts per bin
+    counts: Vec<u64>,
+    /// Count of values below the first edge
+    underflow: u64,
+    /// Count of values above the last edge
+    overflow: u64,
+    /// Count of NaN values, which are out of the range of all bins and not part of the total
+    nan: u64,
+}
+
+impl Histogram {
+    /// Create an empty histogram with given ascending bin edges
+    pub fn new(edges: Vec<f64>) -> Self {
+        assert!(edges.len() >= 2, "At least two bin edges are needed");
+        assert!(edges.windows(2).all(|w| w[0] < w[1]), "Bin edges must be strictly increasing");
+
+        Self {
+            counts: vec![0; edges.len() - 1],
+            edges,
+            underflow: 0,
+            overflow: 0,
+            nan: 0,
+        }
+    }
+
+    /// Create an empty histogram with equidistant bins between `min` and `max`
+    pub fn equidistant(min: f64, max: f64, bins: usize) -> Self {
+        // A degenerate range still gets a bin of positive width
+        let max = if max > min { max } else { min + 1. };
+        let edges = (0..=bins).map(|i| min + (max - min) * i as f64 / bins as f64).collect();
+        Self::new(edges)
+    }
+
+    /// Count a value, infinite values below the first or above the last edge and NaN separately
+    pub fn add(&mut self, value: f64) {
+        let last = self.edges.len() - 1;
+        if value.is_nan() {
+            self.nan += 1;
+        } else if value < self.edges[0] {
+            self.underflow += 1;
+        } else if value > self.edges[last] {
+            self.overflow += 1;
+        } else {
+            let index = self.edges.partition_point(|&e| e <= value).min(last) - 1;
+            self.counts[index] += 1;
+        }
+    }
+
+    /// Add the counts of another histogram with the same edges
+    pub fn merge(&mut self, other: &Histogram) {
+        assert_eq!(self.edges, other.edges, "Histograms with different bin edges cannot be merged");
+        self.counts.iter_mut().zip(other.counts.iter()).for_each(|(a, b)| *a += b);
+        self.underflow += other.underflow;
+        self.overflow += other.overflow;
+        self.nan += other.nan;
+    }
+
+    /// Bins as `(lower, upper, count)` including open-ended bins for values outside of the edges
+    /// if there are any
+    pub fn bins(&self) -> Vec<(f64, f64, u64)> {
+        let mut out: Vec<(f64, f64, u64)> = Vec::with_capacity(self.counts.len() + 2);
+        if self.underflow > 0 {
+            out.push((f64::NEG_INFINITY, self.edges[0], self.underflow));
+        }
+        out.extend(self.edges.windows(2).zip(self.counts.iter()).map(|(w, &count)| (w[0], w[1], count)));
+        if self.overflow > 0 {
+            out.push((self.edges[self.edges.len() - 1], f64::INFINITY, self.overflow));
+        }
+        out
+    }
+
+    /// Number of NaN values, which are not counted in any bin
+    pub fn nan_count(&self) -> u64 {
+        self.nan
+    }
+
+    /// Total number of counted values without NaN
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum::<u64>() + self.underflow + self.overflow
+    }
+
+    /// Probability of a value above the threshold, interpolated linearly within the bin of the
+    /// threshold. At a bin edge it is exact up to values equal to the edge. Zero without values
+    pub fn exceedance_probability(&self, threshold: f64) -> f64 {
+        if self.total() == 0 {
+            return 0.;
+        }
+        let last = self.edges.len() - 1;
+        let above: f64 = if threshold < self.edges[0] {
+            (self.total() - self.underflow) as f64
+        } else if threshold >= self.edges[last] {
+            self.overflow as f64
+        } else {
+            let index = self.edges.partition_point(|&e| e <= threshold) - 1;
+            let share = (self.edges[index + 1] - threshold) / (self.edges[index + 1] - self.edges[index]);
+            self.counts[index + 1..].iter().sum::<u64>() as f64 + share * self.counts[index] as f64 + self.overflow as f64
+        };
+        above / self.total() as f64
+    }
+}
+
+/// Result of a chi-square goodness-of-fit test
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChiSquareTest {
+    /// Test statistic `\sum_i{(O_i-E_i)^2/E_i}`
+    pub statistic: f64,
+    /// Degrees of freedom, i.e. number of classes with positive probability minus one
+    pub degrees_of_freedom: usize,
+    /// Probability of a statistic at least as large given the probabilities are correct
+    pub p_value: f64,
+}
+
+/// Chi-square goodness-of-fit test of observed counts against probabilities of the classes.
+/// Observations in a class with zero probability result in a p-value of zero
+pub fn chi_square_test(observed: &[u64], probabilities: &[f64]) -> ChiSquareTest {
+    let total: u64 = observed.iter().sum();
+    let sum_p: f64 = probabilities.iter().sum();
+
+    let mut statistic = 0.0_f64;
+    let mut classes: usize = 0;
+    let mut impossible = false;
+    for (&o, &p) in observed.iter().zip(probabilities.iter()) {
+        let expected = total as f64 * p / sum_p;
+        if expected > 0. {
+            statistic += (o as f64 - expected).powi(2) / expected;
+            classes += 1;
+        } else if o > 0 {
+            impossible = true;
+        }
+    }
+
+    let degrees_of_freedom = classes.saturating_sub(1);
+    let p_value = if impossible {
+        0.
+    } else if degrees_of_freedom == 0 {
+        1.
+    } else {
+        ChiSquared::new(degrees_of_freedom as f64).unwrap().sf(statistic)
+    };
+
+    ChiSquareTest { statistic, degrees_of_freedom, p_value }
+}
@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use crate::input;
+
+/// Machine-readable summary of a simulation run, written as `summary.json` into the output
+/// directory. Fields are only ever added, so that existing consumers keep working
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    /// Version of the crate that produced the summary
+    pub version: String,
+    /// Input path the portfolio was read from
+    pub input: String,
+    /// Seed of the random number generator
+    pub seed: u64,
+    /// Separate seed of the systematic factors, if given
+    pub systematic_seed: Option<u64>,
+    /// Separate seed of the idiosyncratic draws, if given
+    pub idiosyncratic_seed: Option<u64>,
+    /// Number of simulated trials
+    pub num_trials: usize,
+    /// Number of simulated trials per thread
+    pub chunk_size: usize,
+    /// Wall-clock durations of the run phases
+    pub durations: Durations,
+    /// Size of the portfolio
+    pub portfolio: PortfolioSize,
+    /// Analytical expected loss
+    pub expected_loss: f64,
+    /// Expected loss from the simulation
+    pub expected_loss_simulated: f64,
+    /// Deterministic loss at the mean of all randomness
+    pub mean_scenario_loss: Option<f64>,
+    /// Current value of the portfolio, e.g. to express the value at risk as a percentage
+    pub total_value: Option<f64>,
+    /// Outstanding amount of all exposures of the portfolio
+    pub total_outstanding: Option<f64>,
+    /// Mean of the simulated loss distribution
+    pub mean: f64,
+    /// Standard error of the mean
+    pub mean_standard_error: Option<f64>,
+    /// Confidence level of the intervals reported on the console
+    pub confidence: Option<f64>,
+    /// Skewness of the simulated loss distribution
+    pub skewness: Option<f64>,
+    /// Excess kurtosis of the simulated loss distribution
+    pub excess_kurtosis: Option<f64>,
+    /// Median of the simulated loss distribution
+    pub median: f64,
+    /// Value at risk and expected shortfall for all requested quantiles
+    pub quantiles: Vec<QuantileSummary>,
+    /// Probability of a loss above each requested threshold
+    pub exceedance: Option<Vec<ExceedanceSummary>>,
+    /// Average current rating index weighted by the current value of the borrowers
+    pub average_rating: Option<f64>,
+    /// Average expected rating index after one period weighted by the current value of the
+    /// borrowers
+    pub expected_average_rating: Option<f64>,
+    /// Fixed systematic risk factors if the simulation is conditional on a stress scenario
+    pub scenario: Option<Vec<input::ScenarioFactor>>,
+    /// Statistics conditional on each macro scenario if the loss distribution is a mixture
+    pub macro_scenarios: Option<Vec<MacroScenarioSummary>>,
+    /// Statistics of the losses expected conditional on the systematic factors and risk group
+    /// draws of each trial, if requested
+    pub conditional_loss: Option<ConditionalLossSummary>,
+    /// Currency all amounts are converted to, if declared
+    pub base_currency: Option<String>,
+    /// Statistics of the portfolio value at the horizon, if requested
+    pub value: Option<ValueSummary>,
+    /// Statistics of the losses relative to the current portfolio value, unless the value is not
+    /// positive
+    pub loss_rates: Option<LossRateSummary>,
+}
+
+/// Risk measures of the losses divided by the current portfolio value, comparable across
+/// portfolios of different size
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LossRateSummary {
+    /// Mean loss rate
+    pub mean: f64,
+    /// Median loss rate
+    pub median: f64,
+    /// Value at risk and expected shortfall as loss rates with their standard errors
+    pub quantiles: Vec<QuantileSummary>,
+}
+
+/// Statistics of the portfolio value at the horizon, i.e. the current value less the loss of each
+/// trial, whose lower quantiles are adverse
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValueSummary {
+    /// Mean of the portfolio value
+    pub mean: f64,
+    /// Median of the portfolio value
+    pub median: f64,
+    /// Lower quantiles of the portfolio value at the complements of the quantile levels
+    pub quantiles: Vec<ValueQuantileSummary>,
+}
+
+/// Lower quantile of the portfolio value
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValueQuantileSummary {
+    /// Quantile level, e.g. `0.001`
+    pub level: f64,
+    /// Quantile of the value distribution
+    pub value: f64,
+    /// Average value at or below the quantile
+    pub tail_mean: f64,
+}
+
+/// Risk measures of the loss distribution with the idiosyncratic risk of the borrowers integrated
+/// out of each trial
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConditionalLossSummary {
+    /// Mean of the conditional losses, an estimate of the expected loss with less noise
+    pub mean: f64,
+    /// Median of the conditional losses
+    pub median: f64,
+    /// Value at risk and expected shortfall of the conditional losses
+    pub quantiles: Vec<QuantileSummary>,
+}
+
+/// Risk measures of the trials of a macro scenario of a mixture
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MacroScenarioSummary {
+    /// Identifier of the scenario
+    pub id: String,
+    /// Probability weight of the scenario, normalized to sum to one
+    pub weight: f64,
+    /// Number of trials allocated to the scenario
+    pub num_trials: usize,
+    /// Analytical expected loss given the stressed probabilities of default
+    pub expected_loss: f64,
+    /// Mean of the losses of the scenario
+    pub mean: f64,
+    /// Value at risk and expected shortfall of the losses of the scenario
+    pub quantiles: Vec<QuantileSummary>,
+}
+
+/// Wall-clock durations in seconds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Durations {
+    /// Reading input and setting up the portfolio
+    pub initialization: f64,
+    /// Reading the input files, part of the initialization
+    pub reading: Option<f64>,
+    /// Cholesky decomposition of the covariance matrix, part of the initialization
+    pub cholesky: Option<f64>,
+    /// Norms of the borrowers given the covariance matrix, part of the initialization
+    pub norms: Option<f64>,
+    /// Simulation of all trials
+    pub simulation: f64,
+    /// Risk measures and moments of the loss distribution
+    pub statistics: Option<f64>,
+    /// Writing output files
+    pub output: f64,
+}
+
+/// Number of entities within the portfolio
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortfolioSize {
+    pub borrowers: usize,
+    pub exposures: usize,
+    pub risk_factors: usize,
+    pub rating_classes: usize,
+}
+
+/// Risk measures of a single quantile level
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuantileSummary {
+    /// Quantile level, e.g. `0.999`
+    pub level: f64,
+    /// Value at risk, i.e. the quantile of the loss distribution
+    pub var: f64,
+    /// Expected shortfall, i.e. the average loss at or above the value at risk
+    pub es: f64,
+    /// Standard error of the value at risk by batch means over the chunks
+    pub var_standard_error: Option<f64>,
+    /// Standard error of the expected shortfall by batch means over the chunks
+    pub es_standard_error: Option<f64>,
+}
+
+/// Probability of a portfolio loss above a threshold
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExceedanceSummary {
+    /// Loss threshold
+    pub threshold: f64,
+    /// Fraction of the trials with a loss above the threshold
+    pub probability: f64,
+}
+
+impl Summary {
+    /// Write the summary as pretty-printed JSON
+    pub fn write(&self, path: &Path) {
+        self.write_to(File::create(path).expect("Summary file could not be created"));
+    }
+
+    /// Write the summary as pretty-printed JSON to any writer, e.g. stdout
+    pub fn write_to(&self, mut writer: impl Write) {
+        serde_json::to_writer_pretty(&mut writer, self).expect("Summary could not be written");
+        writeln!(writer).expect("Summary could not be written");
+    }
+}
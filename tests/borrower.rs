@@ -0,0 +1,244 @@
+use credit_portfolio_model::borrower::Borrower;
+use credit_portfolio_model::exposure::Exposure;
+use credit_portfolio_model::migration::{MigrationTracking, ZConvention};
+use credit_portfolio_model::portfolio::Portfolio;
+use credit_portfolio_model::risk_group::RiskGroup;
+use ndarray::Array2;
+use rand_pcg::Pcg64;
+
+/// Fully provisioned borrower with a single rating class
+fn single_class() -> Borrower {
+    let mut borr = Borrower::new("B0".to_string(), vec![1.0], 0, 0.2, 0.3, vec![1.0]);
+    borr.add_exposure(Exposure::from_values(vec![40.0], 100.0));
+    borr.finalize();
+    borr
+}
+
+#[test]
+fn single_class_always_stays_current() {
+    let borr = single_class();
+
+    for z in [f64::MIN, -40.0, -1.0, 0.0, 1.0, 40.0, f64::MAX] {
+        assert_eq!(borr.migration(&z), 0);
+    }
+    assert!(!borr.is_default(0));
+    assert_eq!(borr.expected_loss(), 0.0);
+    assert_eq!(*borr.get_loss(&0), 0.0);
+}
+
+#[test]
+fn single_class_neither_loses_nor_defaults() {
+    let mut rg = RiskGroup::new("G0".to_string());
+    rg.add_borrower(single_class());
+    let mut pf = Portfolio::new(Array2::eye(1));
+    pf.add_risk_group(rg);
+
+    let result = pf.simulate::<Pcg64>(1_000, 100, 0, MigrationTracking::Portfolio, &[]);
+
+    assert!(result.loss.iter().all(|&l| l == 0.0));
+    let migrations = result.migrations.unwrap();
+    assert_eq!(migrations.counts().as_slice().unwrap(), &[1_000]);
+    assert_eq!(migrations.defaults(), &[1_000]);
+}
+
+#[test]
+#[should_panic(expected = "needs at least one rating class")]
+fn no_rating_class_is_rejected() {
+    Borrower::new("B0".to_string(), vec![1.0], 0, 0.2, 0.3, vec![]);
+}
+
+#[test]
+fn default_probability_override_keeps_migration_shape() {
+    let mut borr = Borrower::new("B0".to_string(), vec![1.0], 1, 0.2, 0.3, vec![0.1, 0.8, 0.06, 0.04]);
+    borr.override_default_probability(0.2);
+
+    let expected = Borrower::new("B0".to_string(), vec![1.0], 1, 0.2, 0.3, vec![0.1 / 1.2, 0.8 / 1.2, 0.06 / 1.2, 0.2]);
+    let probs = borr.migration_probabilities();
+    assert!((probs.sum() - 1.0).abs() < 1e-12);
+    assert_eq!(probs[3], 0.2);
+    for (a, b) in probs.iter().zip(expected.migration_probabilities().iter()) {
+        assert!((a - b).abs() < 1e-12);
+    }
+    for (a, b) in borr.thresholds().iter().zip(expected.thresholds().iter()) {
+        assert!((a - b).abs() < 1e-9);
+    }
+}
+
+#[test]
+#[should_panic(expected = "Borrower B0 has no probability outside of default to scale to 0.5")]
+fn default_probability_override_needs_probability_to_scale() {
+    let mut borr = Borrower::new("B0".to_string(), vec![1.0], 1, 0.2, 0.3, vec![0.0, 0.0, 1.0]);
+    borr.override_default_probability(0.5);
+}
+
+#[test]
+fn thresholds_set_directly_imply_the_migration_probabilities() {
+    let derived = Borrower::new("B0".to_string(), vec![1.0], 1, 0.2, 0.3, vec![0.1, 0.8, 0.06, 0.04]);
+    let mut borr = Borrower::new("B0".to_string(), vec![1.0], 1, 0.2, 0.3, vec![0.25, 0.25, 0.25, 0.25]);
+    let c_mig = vec![-1.28, 1.28, 1.75];
+    borr.set_thresholds(c_mig.clone());
+
+    // The thresholds are simulated as given, the probabilities follow from them
+    assert_eq!(borr.thresholds(), &c_mig[..]);
+    let probs = borr.migration_probabilities();
+    assert!((probs.sum() - 1.0).abs() < 1e-12);
+    for (a, b) in borr.thresholds().iter().zip(derived.thresholds().iter()) {
+        assert!((a - b).abs() < 0.01);
+    }
+    assert!((borr.default_probability() - 0.04).abs() < 0.001);
+
+    // Overriding the probability of default derives the thresholds again
+    borr.override_default_probability(0.1);
+    let expected = Borrower::new("B0".to_string(), vec![1.0], 1, 0.2, 0.3, borr.migration_probabilities().to_vec());
+    assert_eq!(borr.thresholds(), expected.thresholds());
+    assert!(borr.thresholds()[2] < 1.75);
+}
+
+#[test]
+#[should_panic(expected = "Borrower B0 has thresholds that are not strictly increasing, the one of rating 2 is not above the one of rating 1")]
+fn thresholds_must_increase() {
+    let mut borr = Borrower::new("B0".to_string(), vec![1.0], 1, 0.2, 0.3, vec![0.1, 0.8, 0.06, 0.04]);
+    borr.set_thresholds(vec![-1.3, 1.2, 1.2]);
+}
+
+#[test]
+fn pd_stress_rebuilds_thresholds() {
+    let mut borr = Borrower::new("B0".to_string(), vec![1.0], 1, 0.2, 0.3, vec![0.1, 0.8, 0.06, 0.04]);
+    borr.stress_default_probability(2.5);
+
+    // The doubled and a half probability of default of 0.1 leaves 0.9 for the other classes
+    let expected = Borrower::new("B0".to_string(), vec![1.0], 1, 0.2, 0.3, vec![0.1 * 0.9 / 0.96, 0.8 * 0.9 / 0.96, 0.06 * 0.9 / 0.96, 0.1]);
+    assert!((borr.default_probability() - 0.1).abs() < 1e-12);
+    for (a, b) in borr.thresholds().iter().zip(expected.thresholds().iter()) {
+        assert!((a - b).abs() < 1e-9);
+    }
+
+    // The probability of default is capped at one
+    borr.stress_default_probability(20.);
+    assert_eq!(borr.default_probability(), 1.);
+    assert_eq!(borr.migration_probabilities().to_vec(), vec![0., 0., 0., 1.]);
+}
+
+#[test]
+fn facility_rating_sets_the_loss_base_of_its_exposure() {
+    let mut borr = Borrower::new("B0".to_string(), vec![1.0], 1, 0.2, 0.3, vec![0.05, 0.9, 0.05]);
+    borr.add_exposure(Exposure::from_values(vec![105.0, 100.0, 40.0], 100.0));
+    let mut notched = Exposure::from_values(vec![50.0, 48.0, 30.0], 50.0);
+    notched.set_rating(0);
+    borr.add_exposure(notched);
+    borr.finalize();
+
+    // The notched exposure is valued at its own rating, but migrates with the borrower
+    assert_eq!(borr.value(), 150.0);
+    assert_eq!(borr.losses().to_vec(), vec![-5.0, 2.0, 80.0]);
+    assert!((borr.expected_loss() - (0.05 * -5.0 + 0.9 * 2.0 + 0.05 * 80.0)).abs() < 1e-12);
+    assert_eq!(borr.migration(&0.0), 1);
+}
+
+#[test]
+#[should_panic(expected = "Facility rating 3 exceeds the 3 valuations of the exposure")]
+fn facility_rating_needs_valuation() {
+    Exposure::from_values(vec![50.0, 48.0, 30.0], 50.0).set_rating(3);
+}
+
+#[test]
+fn conditional_expected_loss_integrates_out_the_idiosyncratic_draw() {
+    use statrs::distribution::{ContinuousCDF, Normal};
+    let (pd, rho, eps) = (0.05, 0.3, 0.2);
+    let mut borr = Borrower::new("B0".to_string(), vec![1.0], 0, rho, eps, vec![1. - pd, pd]);
+    borr.add_exposure(Exposure::from_values(vec![100.0, 40.0], 100.0));
+    borr.finalize();
+
+    // The borrower defaults if its asset value exceeds the threshold Φ⁻¹(1 - pd)
+    let normal = Normal::new(0., 1.).unwrap();
+    let (y, e2) = (1.5, -0.5);
+    let mean = rho.sqrt() * y + ((1. - rho) * eps).sqrt() * e2;
+    let conditional = 1. - normal.cdf((normal.inverse_cdf(1. - pd) - mean) / ((1. - rho) * (1. - eps)).sqrt());
+    assert!((borr.conditional_expected_loss(y, e2) - 60. * conditional).abs() < 1e-12);
+
+    // Averaged over the systematic and risk group draws, it is the expected loss
+    let nodes: Vec<f64> = (0..400).map(|i| -8. + 16. * (i as f64 + 0.5) / 400.).collect();
+    let density = |x: f64| (-x * x / 2.).exp() / (2. * std::f64::consts::PI).sqrt() * 16. / 400.;
+    let average: f64 = nodes.iter().flat_map(|&y| nodes.iter().map(move |&e2| (y, e2)))
+        .map(|(y, e2)| density(y) * density(e2) * borr.conditional_expected_loss(y, e2))
+        .sum();
+    assert!((average - borr.expected_loss()).abs() < 1e-6, "{} vs {}", average, borr.expected_loss());
+
+    // Without idiosyncratic variance the rating is certain
+    let mut certain = Borrower::new("B1".to_string(), vec![1.0], 0, 1.0, 0.0, vec![1. - pd, pd]);
+    certain.add_exposure(Exposure::from_values(vec![100.0, 40.0], 100.0));
+    certain.finalize();
+    assert_eq!((certain.conditional_expected_loss(1.0, 0.), certain.conditional_expected_loss(2.0, 0.)), (0., 60.));
+}
+
+#[test]
+fn defaulted_borrower_is_absorbed() {
+    // Already in default, but with migration probabilities out of it
+    let defaulted = || {
+        let mut borr = Borrower::new("B0".to_string(), vec![1.0], 2, 0.2, 0.3, vec![0.3, 0.3, 0.4]);
+        borr.add_exposure(Exposure::from_values(vec![100.0, 80.0, 40.0], 100.0));
+        borr.finalize();
+        borr
+    };
+    let mut borr = defaulted();
+    assert!(borr.is_absorbing());
+    for z in [-40.0, -1.0, 0.0, 1.0, 40.0] {
+        assert_eq!(borr.migration(&z), 2);
+    }
+    assert_eq!((borr.expected_loss(), borr.default_probability()), (0.0, 1.0));
+    borr.stress_default_probability(0.5);
+    assert_eq!(borr.migration_probabilities().as_slice().unwrap(), &[0., 0., 1.]);
+
+    // Another absorbing class lets the defaulted borrower migrate with its input probabilities
+    borr.set_absorbing_rating(Some(0));
+    assert!(!borr.is_absorbing());
+    assert_eq!(borr.migration(&-1.0), 0);
+    assert!((borr.expected_loss() - (0.3 * -60.0 + 0.3 * -40.0)).abs() < 1e-12);
+
+    // In a portfolio, the absorbed borrower keeps its loss and the draws of the others are unchanged
+    let portfolio = |absorbing: Option<usize>| {
+        let mut rg = RiskGroup::new("G0".to_string());
+        rg.add_borrower(defaulted());
+        let mut other = Borrower::new("B1".to_string(), vec![1.0], 1, 0.2, 0.3, vec![0.05, 0.9, 0.05]);
+        other.add_exposure(Exposure::from_values(vec![100.0, 80.0, 40.0], 100.0));
+        rg.add_borrower(other);
+        let mut pf = Portfolio::new(Array2::eye(1));
+        pf.add_risk_group(rg);
+        pf.set_absorbing_rating(absorbing);
+        pf.simulate::<Pcg64>(1_000, 100, 0, MigrationTracking::None, &[0, 1])
+    };
+    let (absorbed, migrating) = (portfolio(None), portfolio(Some(0)));
+    assert!(absorbed.tracked.chunks(2).all(|losses| losses[0] == 0.0));
+    assert!(migrating.tracked.chunks(2).any(|losses| losses[0] != 0.0));
+    let other = |result: &credit_portfolio_model::simulation::SimulationResult| result.tracked.chunks(2).map(|losses| losses[1]).collect::<Vec<f64>>();
+    assert_eq!(other(&absorbed), other(&migrating));
+}
+
+#[test]
+fn adverse_systematic_shock_downgrades_in_either_z_convention() {
+    for (convention, adverse) in [(ZConvention::LowIsBest, 3.0), (ZConvention::LowIsDefault, -3.0)] {
+        let mut borr = Borrower::new("B0".to_string(), vec![1.0], 1, 0.5, 0.0, vec![0.1, 0.8, 0.07, 0.03]);
+        borr.add_exposure(Exposure::from_values(vec![102.0, 100.0, 95.0, 40.0], 100.0));
+        borr.finalize();
+        borr.set_z_convention(convention);
+
+        // The shock alone moves the median asset value below or above the default threshold
+        assert_eq!(borr.migration(&borr.asset_value(&adverse, &0., &0.)), 3);
+        assert_eq!(borr.migration(&borr.asset_value(&-adverse, &0., &0.)), 0);
+        assert!(borr.conditional_expected_loss(adverse, 0.) > borr.expected_loss());
+        assert!(borr.conditional_expected_loss(-adverse, 0.) < borr.expected_loss());
+
+        // The simulation downgrades conditional on the shock as well
+        let mut rg = RiskGroup::new("G0".to_string());
+        rg.add_borrower(borr);
+        let mut pf = Portfolio::new(Array2::eye(1));
+        pf.add_risk_group(rg);
+        pf.set_z_convention(convention);
+        pf.set_scenario(&[(0, adverse)]);
+        let result = pf.simulate::<Pcg64>(2_000, 500, 0, MigrationTracking::Portfolio, &[]);
+
+        let counts = result.migrations.unwrap().counts().clone();
+        assert!(counts[[0, 2]] + counts[[0, 3]] > counts[[0, 0]] + counts[[0, 1]], "{:?}: {}", convention, counts);
+        assert!(result.loss.iter().sum::<f64>() / 2_000. > pf.expected_loss(), "{:?}", convention);
+    }
+}
@@ -0,0 +1,115 @@
+use credit_portfolio_model::borrower::Borrower;
+use credit_portfolio_model::exposure::{DiscountCurve, Direction, Exposure};
+
+const PRICES: [f64; 4] = [1.02, 1.0, 0.95, 0.4];
+
+fn borrower() -> Borrower {
+    Borrower::new("B0".to_string(), vec![1.0], 1, 0.2, 0.3, vec![0.1, 0.8, 0.07, 0.03])
+}
+
+fn unit_price_borrower(outstanding: f64) -> Borrower {
+    let mut borr = borrower();
+    borr.add_exposure(Exposure::new(PRICES.to_vec(), outstanding));
+    borr.finalize();
+    borr
+}
+
+#[test]
+fn unit_price_scales_with_outstanding() {
+    let single = unit_price_borrower(100.0);
+    let double = unit_price_borrower(200.0);
+
+    assert_eq!(double.value(), 2.0 * single.value());
+    for rating in 0..4 {
+        assert!((double.get_loss(&rating) - 2.0 * single.get_loss(&rating)).abs() < 1e-9);
+    }
+    assert!((double.expected_loss() - 2.0 * single.expected_loss()).abs() < 1e-9);
+}
+
+#[test]
+fn many_small_exposures_equal_one_combined() {
+    let mut many = borrower();
+    for _ in 0..1_000 {
+        many.add_exposure(Exposure::from_values(PRICES.to_vec(), 1.0));
+    }
+    many.finalize();
+
+    let mut combined = borrower();
+    combined.add_exposure(Exposure::from_values(PRICES.iter().map(|p| 1_000.0 * p).collect(), 1_000.0));
+    combined.finalize();
+
+    assert_eq!(many.num_exposures(), 1_000);
+    assert!((many.value() - combined.value()).abs() < 1e-9);
+    assert!((many.expected_loss() - combined.expected_loss()).abs() < 1e-9);
+    for rating in 0..4 {
+        assert!((many.get_loss(&rating) - combined.get_loss(&rating)).abs() < 1e-9);
+    }
+}
+
+#[test]
+fn loss_fractions_scale_with_exposure_at_default() {
+    let mut borr = borrower();
+    borr.add_exposure(Exposure::from_loss_fractions(vec![0.0, 0.0, 0.1, 0.45], 1_000.0));
+    borr.finalize();
+
+    assert_eq!(borr.value(), 1_000.0);
+    for (rating, loss) in [0.0, 0.0, 100.0, 450.0].iter().enumerate() {
+        assert!((borr.get_loss(&rating) - loss).abs() < 1e-9);
+    }
+}
+
+#[test]
+fn offsetting_short_exposure_nets_to_zero_loss() {
+    let mut hedge = Exposure::new(PRICES.to_vec(), 100.0);
+    hedge.set_direction(Direction::Short);
+
+    let mut borr = borrower();
+    borr.add_exposure(Exposure::new(PRICES.to_vec(), 100.0));
+    borr.add_exposure(hedge);
+    borr.finalize();
+
+    assert_eq!(borr.num_exposures(), 2);
+    assert!(borr.value().abs() < 1e-9);
+    for rating in 0..4 {
+        assert!(borr.get_loss(&rating).abs() < 1e-9);
+    }
+    assert!(borr.expected_loss().abs() < 1e-9);
+
+    let mut short = borrower();
+    let mut hedge = Exposure::new(PRICES.to_vec(), 100.0);
+    hedge.set_direction(Direction::Short);
+    short.add_exposure(hedge);
+    short.finalize();
+    assert!(*short.get_loss(&3) < 0.0);
+}
+
+#[test]
+fn discount_curve_interpolates_log_linearly_and_extrapolates_the_last_zero_rate() {
+    let flat = DiscountCurve::Flat(0.05);
+    assert!((flat.discount_factor(2.0) - 1.0 / 1.05f64.powi(2)).abs() < 1e-12);
+
+    let curve = DiscountCurve::Curve(vec![(1.0, 0.96), (2.0, 0.9)]);
+    assert_eq!(curve.discount_factor(0.0), 1.0);
+    assert!((curve.discount_factor(0.5) - 0.96f64.sqrt()).abs() < 1e-12);
+    assert!((curve.discount_factor(1.0) - 0.96).abs() < 1e-12);
+    assert!((curve.discount_factor(1.5) - (0.96f64 * 0.9).sqrt()).abs() < 1e-12);
+    assert!((curve.discount_factor(4.0) - 0.81).abs() < 1e-12);
+}
+
+#[test]
+fn discounted_losses_reconcile_with_the_expected_loss() {
+    let undiscounted = unit_price_borrower(100.0);
+    let mut exposure = Exposure::new(PRICES.to_vec(), 100.0);
+    exposure.set_discount_factor(0.9);
+    let mut borr = borrower();
+    borr.add_exposure(exposure);
+    borr.finalize();
+
+    assert!((borr.value() - 0.9 * undiscounted.value()).abs() < 1e-9);
+    for rating in 0..4 {
+        assert!((borr.get_loss(&rating) - 0.9 * undiscounted.get_loss(&rating)).abs() < 1e-9);
+    }
+    let expected: f64 = borr.migration_probabilities().iter().zip(borr.losses().iter()).map(|(p, l)| p * l).sum();
+    assert!((borr.expected_loss() - expected).abs() < 1e-9);
+    assert!((borr.expected_loss() - 0.9 * undiscounted.expected_loss()).abs() < 1e-9);
+}
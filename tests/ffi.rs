@@ -0,0 +1,67 @@
+#![cfg(feature = "ffi")]
+
+use std::ffi::CStr;
+use std::ptr;
+
+use credit_portfolio_model::borrower::Borrower;
+use credit_portfolio_model::exposure::Exposure;
+use credit_portfolio_model::ffi::*;
+use credit_portfolio_model::migration::MigrationTracking;
+use credit_portfolio_model::portfolio::Portfolio;
+use credit_portfolio_model::risk_group::RiskGroup;
+use ndarray::Array2;
+use rand_pcg::Pcg64;
+
+const COVARIANCE: [f64; 4] = [1.0, 0.3, 0.3, 1.0];
+const RISK_GROUPS: [usize; 3] = [1, 0, 1];
+const RATINGS: [usize; 3] = [0, 1, 1];
+const RHO: [f64; 3] = [0.2, 0.3, 0.25];
+const EPS: [f64; 3] = [0.1, 0.0, 0.2];
+const WEIGHTS: [f64; 6] = [1.0, 0.0, 0.5, 0.5, 0.0, 1.0];
+const PROBABILITIES: [f64; 9] = [0.9, 0.08, 0.02, 0.1, 0.8, 0.1, 0.05, 0.85, 0.1];
+const VALUATIONS: [f64; 9] = [100.0, 95.0, 40.0, 52.0, 50.0, 20.0, 210.0, 200.0, 90.0];
+
+fn new_portfolio(ratings: &[usize], out: *mut *mut CpmPortfolio) -> i32 {
+    unsafe { cpm_portfolio_new(2, COVARIANCE.as_ptr(), 3, 3, RISK_GROUPS.as_ptr(), ratings.as_ptr(), RHO.as_ptr(), EPS.as_ptr(), WEIGHTS.as_ptr(), PROBABILITIES.as_ptr(), VALUATIONS.as_ptr(), out) }
+}
+
+#[test]
+fn simulated_losses_equal_those_of_the_rust_api() {
+    let mut pf: *mut CpmPortfolio = ptr::null_mut();
+    assert_eq!(new_portfolio(&RATINGS, &mut pf), CPM_OK);
+    let (mut losses, mut len): (*mut f64, usize) = (ptr::null_mut(), 0);
+    assert_eq!(unsafe { cpm_simulate(pf, 1_000, 100, 7, &mut losses, &mut len) }, CPM_OK);
+    let simulated = unsafe { std::slice::from_raw_parts(losses, len) }.to_vec();
+    unsafe {
+        cpm_losses_free(losses, len);
+        cpm_portfolio_free(pf);
+    }
+
+    // The same portfolio with the risk groups in order of their index
+    let mut expected = Portfolio::new(Array2::from_shape_vec((2, 2), COVARIANCE.to_vec()).unwrap());
+    for group in 0..2 {
+        let mut rg = RiskGroup::new(group.to_string());
+        for index in (0..3).filter(|&index| RISK_GROUPS[index] == group) {
+            let mut borr = Borrower::new(index.to_string(), WEIGHTS[2 * index..2 * index + 2].to_vec(), RATINGS[index], RHO[index], EPS[index], PROBABILITIES[3 * index..3 * index + 3].to_vec());
+            borr.add_exposure(Exposure::from_values(VALUATIONS[3 * index..3 * index + 3].to_vec(), VALUATIONS[3 * index + RATINGS[index]]));
+            rg.add_borrower(borr);
+        }
+        expected.add_risk_group(rg);
+    }
+    assert_eq!(simulated, expected.simulate::<Pcg64>(1_000, 100, 7, MigrationTracking::None, &[]).loss);
+}
+
+#[test]
+fn failures_return_error_codes_with_their_message() {
+    let mut pf: *mut CpmPortfolio = ptr::null_mut();
+    assert_eq!(new_portfolio(&RATINGS, ptr::null_mut()), CPM_INVALID_ARGUMENT);
+    assert_eq!(unsafe { CStr::from_ptr(cpm_last_error()) }.to_str().unwrap(), "Output pointer is null");
+
+    // A panic of the model does not unwind across the boundary
+    assert_eq!(new_portfolio(&[0, 1, 3], &mut pf), CPM_PANIC);
+    assert!(pf.is_null());
+    assert_eq!(unsafe { CStr::from_ptr(cpm_last_error()) }.to_str().unwrap(), "Borrower 2 has current rating 3 without migration probability");
+
+    let (mut losses, mut len): (*mut f64, usize) = (ptr::null_mut(), 0);
+    assert_eq!(unsafe { cpm_simulate(pf, 1_000, 100, 7, &mut losses, &mut len) }, CPM_INVALID_ARGUMENT);
+}
@@ -0,0 +1,235 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use credit_portfolio_model::borrower::Borrower;
+use credit_portfolio_model::exposure::Exposure;
+use credit_portfolio_model::input::{read_loss_distribution, Input};
+use credit_portfolio_model::migration::MigrationTracking;
+use credit_portfolio_model::portfolio::Portfolio;
+use credit_portfolio_model::risk_group::RiskGroup;
+use credit_portfolio_model::simulation::SimulationResult;
+use credit_portfolio_model::statistics;
+use ndarray::array;
+use rand_pcg::Pcg64;
+use statrs::distribution::{ContinuousCDF, Normal};
+
+/// Small golden portfolio of five borrowers in two risk groups on two correlated risk factors
+fn golden_portfolio() -> Portfolio {
+    let mut pf = Portfolio::new(array![[1.0, 0.3], [0.3, 1.0]]);
+    let borrowers = [
+        ("G0", vec![1.0, 0.0], 1, 0.2, 0.1, vec![0.05, 0.9, 0.03, 0.02], 100.0),
+        ("G0", vec![0.6, 0.4], 0, 0.3, 0.2, vec![0.9, 0.07, 0.02, 0.01], 250.0),
+        ("G0", vec![0.0, 1.0], 2, 0.1, 0.3, vec![0.01, 0.1, 0.74, 0.15], 50.0),
+        ("G1", vec![1.0, 1.0], 1, 0.25, 0.0, vec![0.04, 0.85, 0.08, 0.03], 400.0),
+        ("G1", vec![0.2, 0.8], 1, 0.15, 0.4, vec![0.06, 0.88, 0.04, 0.02], 150.0),
+    ];
+
+    for group in ["G0", "G1"] {
+        let mut rg = RiskGroup::new(group.to_string());
+        for (index, (_, weights, rating, rho, eps, p_mig, outstanding)) in borrowers.iter().enumerate().filter(|(_, b)| b.0 == group) {
+            let mut borr = Borrower::new(format!("B{}", index), weights.clone(), *rating, *rho, *eps, p_mig.clone());
+            borr.add_exposure(Exposure::new(vec![1.02, 1.0, 0.9, 0.45], *outstanding));
+            rg.add_borrower(borr);
+        }
+        pf.add_risk_group(rg);
+    }
+    pf
+}
+
+#[test]
+fn simulated_expected_loss_converges_to_analytic() {
+    let pf = golden_portfolio();
+    let result = pf.simulate::<Pcg64>(200_000, 10_000, 42, MigrationTracking::None, &[]);
+
+    let mean = result.mean();
+    let std = (result.loss.iter().map(|l| (l - mean).powi(2)).sum::<f64>() / (result.num_trials() - 1) as f64).sqrt();
+    let standard_error = std / (result.num_trials() as f64).sqrt();
+    assert!((mean - pf.expected_loss()).abs() < 4. * standard_error, "{} vs {} ± {}", mean, pf.expected_loss(), standard_error);
+    assert!((result.expected_loss.sum() - mean).abs() < 1e-6 * mean.abs().max(1.));
+}
+
+/// Single borrower with two rating classes, migrating from 100 to 40 on default
+fn single_borrower(pd: f64, rho: f64) -> Portfolio {
+    let mut borr = Borrower::new("B0".to_string(), vec![1.0], 0, rho, 0.0, vec![1. - pd, pd]);
+    borr.add_exposure(Exposure::from_values(vec![100.0, 40.0], 100.0));
+    let mut rg = RiskGroup::new("G0".to_string());
+    rg.add_borrower(borr);
+    let mut pf = Portfolio::new(array![[1.0]]);
+    pf.add_risk_group(rg);
+    pf
+}
+
+/// Fraction of the trials with a default and its standard error given the probability
+fn default_frequency(pf: &Portfolio, num_trials: usize, p: f64) -> (f64, f64) {
+    let result = pf.simulate::<Pcg64>(num_trials, 10_000, 7, MigrationTracking::None, &[]);
+    let defaults = result.loss.iter().filter(|&&l| l == 60.0).count();
+    (defaults as f64 / num_trials as f64, (p * (1. - p) / num_trials as f64).sqrt())
+}
+
+#[test]
+fn single_borrower_matches_one_factor_default_probability() {
+    let (pd, rho) = (0.02, 0.25);
+    let normal = Normal::new(0., 1.).unwrap();
+
+    // Unconditionally, the borrower defaults with its probability of default
+    let mut pf = single_borrower(pd, rho);
+    let (frequency, standard_error) = default_frequency(&pf, 200_000, pd);
+    assert!((frequency - pd).abs() < 4. * standard_error, "{} vs {}", frequency, pd);
+
+    // Given the systematic factor y, the borrower defaults if its asset value exceeds the default
+    // threshold, i.e. with probability 1 - Φ((Φ⁻¹(1 - pd) - √ρ y) / √(1 - ρ))
+    let y = 2.0;
+    let conditional = 1. - normal.cdf((normal.inverse_cdf(1. - pd) - rho.sqrt() * y) / (1. - rho).sqrt());
+    pf.set_scenario(&[(0, y)]);
+    let (frequency, standard_error) = default_frequency(&pf, 200_000, conditional);
+    assert!((frequency - conditional).abs() < 4. * standard_error, "{} vs {}", frequency, conditional);
+}
+
+#[test]
+fn shifted_factor_mean_matches_one_factor_default_probability() {
+    let (pd, rho, shift) = (0.02, 0.25, 1.5);
+    let normal = Normal::new(0., 1.).unwrap();
+
+    // With the factor y ~ N(shift, 1) the asset value is N(√ρ shift, 1), a stressed copy leaves
+    // the portfolio it was cloned from unconditional
+    let pf = single_borrower(pd, rho);
+    let mut shifted = pf.clone();
+    shifted.shift_factors(&[(0, shift)]);
+    let expected = 1. - normal.cdf(normal.inverse_cdf(1. - pd) - rho.sqrt() * shift);
+    let (frequency, standard_error) = default_frequency(&shifted, 200_000, expected);
+    assert!((frequency - expected).abs() < 4. * standard_error, "{} vs {}", frequency, expected);
+
+    let (frequency, standard_error) = default_frequency(&pf, 200_000, pd);
+    assert!((frequency - pd).abs() < 4. * standard_error, "{} vs {}", frequency, pd);
+}
+
+#[test]
+fn student_t_copula_keeps_default_probabilities_and_fattens_the_joint_tail() {
+    let (pd, num_trials) = (0.02, 200_000);
+
+    // Two borrowers of one risk group, defaults lose 60 each
+    let pair = |degrees_of_freedom: Option<f64>| {
+        let mut rg = RiskGroup::new("G0".to_string());
+        rg.set_degrees_of_freedom(degrees_of_freedom);
+        for index in 0..2 {
+            let mut borr = Borrower::new(format!("B{}", index), vec![1.0], 0, 0.25, 0.0, vec![1. - pd, pd]);
+            borr.add_exposure(Exposure::from_values(vec![100.0, 40.0], 100.0));
+            rg.add_borrower(borr);
+        }
+        let mut pf = Portfolio::new(array![[1.0]]);
+        pf.add_risk_group(rg);
+        pf.simulate::<Pcg64>(num_trials, 10_000, 7, MigrationTracking::None, &[])
+    };
+    let frequency = |loss: &[f64], value: f64| loss.iter().filter(|&&l| l == value).count() as f64 / num_trials as f64;
+
+    let (gaussian, student) = (pair(None), pair(Some(3.)));
+    let single = frequency(&student.loss, 60.) / 2. + frequency(&student.loss, 120.);
+    let standard_error = (pd * (1. - pd) / num_trials as f64).sqrt();
+    assert!((single - pd).abs() < 4. * standard_error, "{} vs {}", single, pd);
+
+    // The shared mixing variable makes joint defaults more likely than under the Gaussian copula
+    assert!(frequency(&student.loss, 120.) > 1.5 * frequency(&gaussian.loss, 120.));
+}
+
+#[test]
+fn portfolio_wide_student_t_copula_shares_the_mixing_variable_of_all_risk_groups() {
+    let (num_trials, pd) = (200_000, 0.02);
+    // Two borrowers only correlated through the copula, in one or two risk groups
+    let pair = |groups: usize, group_df: Option<f64>, portfolio_df: Option<f64>| {
+        let mut pf = Portfolio::new(array![[1.0]]);
+        for group in 0..groups {
+            let mut rg = RiskGroup::new(format!("G{}", group));
+            rg.set_degrees_of_freedom(group_df);
+            for index in 0..2 / groups {
+                let mut borr = Borrower::new(format!("B{}{}", group, index), vec![1.0], 0, 0.0, 0.0, vec![1. - pd, pd]);
+                borr.add_exposure(Exposure::from_values(vec![100.0, 40.0], 100.0));
+                rg.add_borrower(borr);
+            }
+            pf.add_risk_group(rg);
+        }
+        pf.set_degrees_of_freedom(portfolio_df);
+        pf.simulate::<Pcg64>(num_trials, 10_000, 7, MigrationTracking::None, &[])
+    };
+    let frequency = |loss: &[f64], value: f64| loss.iter().filter(|&&l| l == value).count() as f64 / num_trials as f64;
+
+    // The mixing variable of a single risk group is the portfolio-wide one
+    assert_eq!(pair(1, Some(3.), None).loss, pair(1, None, Some(3.)).loss);
+
+    // Across risk groups the portfolio-wide copula keeps the marginals, but unlike a mixing variable
+    // per group it joins the defaults of the groups like those of a single group
+    let (per_group, portfolio_wide, single_group) = (pair(2, Some(3.), None), pair(2, None, Some(3.)), pair(1, Some(3.), None));
+    let standard_error = (pd * (1. - pd) / num_trials as f64).sqrt();
+    let single = frequency(&portfolio_wide.loss, 60.) / 2. + frequency(&portfolio_wide.loss, 120.);
+    assert!((single - pd).abs() < 4. * standard_error, "{} vs {}", single, pd);
+    let (joint, joint_single) = (frequency(&portfolio_wide.loss, 120.), frequency(&single_group.loss, 120.));
+    let joint_error = (joint_single * (1. - joint_single) / num_trials as f64).sqrt();
+    assert!((joint - joint_single).abs() < 4. * 2f64.sqrt() * joint_error, "{} vs {}", joint, joint_single);
+    assert!(joint > 1.5 * frequency(&per_group.loss, 120.), "{} vs {}", joint, frequency(&per_group.loss, 120.));
+}
+
+/// Directory of a checked-in fixture input
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join(name)
+}
+
+/// Compare the losses in order of the trials with the golden loss distribution of the fixture.
+/// Run with `UPDATE_GOLDEN=1` to rewrite it after a deliberate change of the model
+fn assert_golden_losses(name: &str, result: &SimulationResult) {
+    let path = fixture(name).join("expected_loss_distribution.csv");
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        let rows: String = result.loss.iter().map(|loss| format!("{}\n", loss)).collect();
+        fs::write(&path, format!("Loss\n{}", rows)).unwrap();
+    }
+
+    let expected = read_loss_distribution(&path);
+    assert_eq!(expected.len(), result.num_trials());
+    for (trial, (&loss, &golden)) in result.loss.iter().zip(expected.iter()).enumerate() {
+        assert!((loss - golden).abs() < 1e-9 * golden.abs().max(1.), "Trial {}: {} vs {}", trial, loss, golden);
+    }
+}
+
+/// Assert the values are equal up to the tolerance
+fn assert_close(actual: &[f64], expected: &[f64], tolerance: f64) {
+    assert_eq!(actual.len(), expected.len());
+    for (a, e) in actual.iter().zip(expected.iter()) {
+        assert!((a - e).abs() < tolerance, "{:?} vs {:?}", actual, expected);
+    }
+}
+
+#[test]
+fn golden_fixture_reproduces_the_checked_in_results() {
+    let input = Input::read(&fixture("golden"));
+    let num_borrowers: usize = input.risk_groups.values().map(Vec::len).sum();
+    assert_eq!((num_borrowers, input.cov.nrows(), input.rating_labels.len()), (5, 3, 4));
+
+    let pf = input.into_portfolio();
+    let result = pf.simulate::<Pcg64>(2_000, 500, 42, MigrationTracking::None, &[]);
+    assert_golden_losses("golden", &result);
+
+    assert_close(result.expected_loss.as_slice().unwrap(), &[1.0295, 4.67375, 2.854, 8.902, 2.35875], 1e-9);
+    assert_close(&[pf.expected_loss(), result.mean()], &[19.065, 19.818], 1e-9);
+    let mut loss = result.loss.clone();
+    let measures = statistics::risk_measures(&mut loss, &[0.9, 0.99]);
+    assert_close(&measures.iter().flat_map(|q| [q.var, q.es]).collect::<Vec<f64>>(), &[57.0, 141.78048780487805, 231.32666666666637, 279.05], 1e-9);
+}
+
+#[test]
+fn edge_case_fixture_reproduces_the_checked_in_results() {
+    // Rating classes of zero probability, single-borrower risk groups G1 and G2 and borrower B1
+    // with two exposures
+    let pf = Input::read(&fixture("edge_cases")).into_portfolio();
+    let result = pf.simulate::<Pcg64>(2_000, 500, 42, MigrationTracking::Portfolio, &[]);
+    assert_golden_losses("edge_cases", &result);
+
+    let migrations = result.migrations.as_ref().unwrap().matrix();
+    for (current, rating) in [(0, 2), (0, 3), (1, 0), (1, 2), (2, 0), (2, 1)] {
+        assert_eq!(migrations[[current, rating]], 0, "Migrations from {} into {}", current, rating);
+    }
+    assert_eq!(migrations.sum(), 4 * 2_000);
+
+    assert_close(result.expected_loss.as_slice().unwrap(), &[0.0915, 9.92, 13.695, 9.888], 1e-9);
+    assert_close(&[pf.expected_loss(), result.mean()], &[30.64, 33.5945], 1e-9);
+    let mut loss = result.loss.clone();
+    let measures = statistics::risk_measures(&mut loss, &[0.9, 0.99]);
+    assert_close(&measures.iter().flat_map(|q| [q.var, q.es]).collect::<Vec<f64>>(), &[155.0, 178.6642857142857, 213.0, 244.94], 1e-9);
+}
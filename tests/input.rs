@@ -0,0 +1,691 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use credit_portfolio_model::generate::Generator;
+use credit_portfolio_model::input::{read_factor_draws, read_scenarios, Duplicates, EmptyBorrowers, Input, MissingValuations};
+use credit_portfolio_model::rating::RatingScale;
+
+/// Fresh directory with the files of a generated portfolio
+fn input_dir(name: &str) -> (PathBuf, Input) {
+    let dir = std::env::temp_dir().join(format!("cpm_{}_{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let generator = Generator { borrowers: 20, risk_factors: 4, ..Default::default() };
+    generator.input().write(&dir);
+    (dir, generator.input())
+}
+
+/// Replace the list of cells by a dense grid with the given rows
+fn write_dense(dir: &Path, rows: &[Vec<f64>]) {
+    fs::remove_file(dir.join("correlation_matrix.csv")).unwrap();
+    let grid: String = rows.iter()
+        .map(|row| row.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(",") + "\n")
+        .collect();
+    fs::write(dir.join("correlation_matrix_dense.csv"), grid).unwrap();
+}
+
+#[test]
+fn dense_covariance_equals_cells() {
+    let (dir, expected) = input_dir("dense");
+    let rows: Vec<Vec<f64>> = expected.cov.rows().into_iter().map(|row| row.to_vec()).collect();
+    write_dense(&dir, &rows);
+
+    let input = Input::read(&dir);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(input.cov, expected.cov);
+}
+
+#[test]
+#[should_panic(expected = "Dense covariance matrix has 4 rows, but row 2 has 3 columns")]
+fn dense_covariance_must_be_square() {
+    let (dir, expected) = input_dir("dense_ragged");
+    let mut rows: Vec<Vec<f64>> = expected.cov.rows().into_iter().map(|row| row.to_vec()).collect();
+    rows[2].pop();
+    write_dense(&dir, &rows);
+
+    let result = std::panic::catch_unwind(|| Input::read(&dir));
+    fs::remove_dir_all(&dir).unwrap();
+    std::panic::resume_unwind(result.err().unwrap());
+}
+
+#[test]
+fn read_equals_written_input() {
+    let (dir, expected) = input_dir("roundtrip");
+    let input = Input::read(&dir);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(input.cov, expected.cov);
+    assert_eq!(input.mig_probs, expected.mig_probs);
+    assert_eq!(input.risk_factors, expected.risk_factors);
+    assert_eq!(input.valuations, expected.valuations);
+    assert_eq!(input.rating_labels, expected.rating_labels);
+    assert_eq!(input.volatilities, expected.volatilities);
+    assert_eq!(input.read_durations.len(), 15);
+}
+
+#[test]
+fn failures_of_all_files_are_reported() {
+    let (dir, _) = input_dir("failures");
+    let borrowers = fs::read_to_string(dir.join("borrower.csv")).unwrap();
+    fs::write(dir.join("borrower.csv"), borrowers.replacen(",0.", ",x0.", 1)).unwrap();
+    fs::remove_file(dir.join("valuations.csv")).unwrap();
+
+    let error = Input::try_read(&dir).err().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let files: Vec<&str> = error.failures.iter().map(|(file, _)| file.as_str()).collect();
+    assert_eq!(files, vec!["borrower.csv", "valuations.csv"]);
+    assert!(error.to_string().starts_with("Input could not be read, 2 failures"));
+}
+
+#[test]
+fn volatilities_scale_correlation_matrix() {
+    let (dir, expected) = input_dir("volatilities");
+    fs::write(dir.join("volatilities.csv"), "risk_factor,volatility\n0,2\n1,1\n2,0.5\n3,1\n").unwrap();
+
+    let mut input = Input::read(&dir);
+    assert_eq!(input.cov[[0, 0]], 4.);
+    assert_eq!(input.cov[[0, 2]], expected.cov[[0, 2]]);
+    assert_eq!(input.cov[[1, 3]], expected.cov[[1, 3]]);
+    assert!(input.covariance_warnings().is_empty());
+
+    // A covariance matrix cannot be declared as correlation matrix
+    input.volatilities = None;
+    input.write(&dir);
+    fs::write(dir.join("volatilities.csv"), "risk_factor,volatility\n0,2\n1,1\n2,0.5\n3,1\n").unwrap();
+    let error = Input::try_read(&dir).err().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(error.failures[0].0, "volatilities.csv");
+}
+
+#[test]
+fn unit_diagonal_without_volatilities_warns() {
+    let (dir, expected) = input_dir("unit_diagonal");
+    fs::remove_file(dir.join("volatilities.csv")).unwrap();
+    let input = Input::read(&dir);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(input.cov, expected.cov);
+    assert_eq!(input.volatilities, None);
+    assert_eq!(input.covariance_warnings().len(), 1);
+}
+
+#[test]
+fn negative_risk_factor_weights_warn() {
+    let mut input = Generator { borrowers: 20, risk_factors: 4, ..Default::default() }.input();
+    let mut ids: Vec<String> = input.risk_factors.keys().cloned().collect();
+    ids.sort();
+    let weights = input.risk_factors.get_mut(&ids[0]).unwrap();
+    weights[0] = -0.5;
+    weights[2] = -0.1;
+
+    let warnings: Vec<String> = input.warnings().into_iter().filter(|warning| warning.contains("negative")).collect();
+    assert_eq!(warnings, [format!("Borrower {} has negative weights on risk factors 0, 2", ids[0])]);
+    assert!(input.validate().is_empty());
+}
+
+/// Replace the rating indices of a file by their labels
+fn label_ratings(dir: &Path, file: &str, labels: &[String]) {
+    let mut reader = csv::Reader::from_path(dir.join(file)).unwrap();
+    let headers = reader.headers().unwrap().clone();
+    let column = headers.iter().position(|header| header == "rating").unwrap();
+    let rows: Vec<csv::StringRecord> = reader.records().map(|record| record.unwrap()).collect();
+
+    let mut writer = csv::Writer::from_path(dir.join(file)).unwrap();
+    writer.write_record(&headers).unwrap();
+    for row in rows {
+        let labelled: Vec<String> = row.iter().enumerate()
+            .map(|(index, value)| if index == column { labels[value.parse::<usize>().unwrap()].to_string() } else { value.to_string() })
+            .collect();
+        writer.write_record(&labelled).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+/// Write the rating scale of the labels with the last rating as default
+fn write_rating_scale(dir: &Path, labels: &[String]) {
+    let scale: String = labels.iter().enumerate()
+        .map(|(index, label)| format!("{},{},{}\n", label, index, index + 1 == labels.len()))
+        .collect();
+    fs::write(dir.join("rating_scale.csv"), format!("label,index,is_default\n{}", scale)).unwrap();
+}
+
+#[test]
+fn rating_labels_resolve_with_rating_scale() {
+    let (dir, expected) = input_dir("rating_scale");
+    fs::remove_file(dir.join("rating_labels.csv")).unwrap();
+    write_rating_scale(&dir, &expected.rating_labels);
+    for file in ["borrower.csv", "transition_probabilities.csv", "valuations.csv"] {
+        label_ratings(&dir, file, &expected.rating_labels);
+    }
+
+    let input = Input::read(&dir);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(input.rating_labels, expected.rating_labels);
+    assert_eq!(input.mig_probs, expected.mig_probs);
+    assert_eq!(input.valuations, expected.valuations);
+    let ratings = |input: &Input| {
+        let mut ratings: Vec<(String, usize)> = input.risk_groups.values().flatten().map(|borr| (borr.borrower_id.to_string(), borr.rating)).collect();
+        ratings.sort();
+        ratings
+    };
+    assert_eq!(ratings(&input), ratings(&expected));
+}
+
+#[test]
+fn rating_scale_needs_contiguous_indices_and_one_default() {
+    let (dir, expected) = input_dir("rating_scale_invalid");
+    fs::remove_file(dir.join("rating_labels.csv")).unwrap();
+    let mut labels = expected.rating_labels.clone();
+    write_rating_scale(&dir, &labels);
+    let scale = fs::read_to_string(dir.join("rating_scale.csv")).unwrap();
+
+    fs::write(dir.join("rating_scale.csv"), scale.replace("R2,1,", "R2,9,")).unwrap();
+    let gap = Input::try_read(&dir).err().unwrap();
+
+    fs::write(dir.join("rating_scale.csv"), scale.replace("R1,0,false", "R1,0,true")).unwrap();
+    let defaults = Input::try_read(&dir).err().unwrap();
+
+    labels[0] = "unknown".to_string();
+    label_ratings(&dir, "valuations.csv", &labels);
+    fs::write(dir.join("rating_scale.csv"), &scale).unwrap();
+    let unknown = Input::try_read(&dir).err().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(gap.failures, vec![("rating_scale.csv".to_string(), "Rating indices must be contiguous from 0, but rating R3 has index 2 instead of 1".to_string())]);
+    assert_eq!(defaults.failures, vec![("rating_scale.csv".to_string(), "Exactly one rating must be flagged as default, but 2 are".to_string())]);
+    assert_eq!(unknown.failures.len(), 1);
+    assert_eq!(unknown.failures[0].0, "valuations.csv");
+    assert!(unknown.failures[0].1.ends_with("Rating unknown is not a label of the rating scale"));
+}
+
+#[test]
+fn missing_valuations_fail_unless_filled_with_current() {
+    let (dir, expected) = input_dir("missing_valuations");
+    let borr = expected.risk_groups.values().flatten().next().unwrap();
+    let exposure = &expected.exposures[&borr.borrower_id][0];
+    let missing = if borr.rating == 0 { 1 } else { 0 };
+    let row = format!("{},{},", exposure.exposure_id, missing);
+    let valuations = fs::read_to_string(dir.join("valuations.csv")).unwrap();
+    fs::write(dir.join("valuations.csv"), valuations.lines().filter(|line| !line.starts_with(&row)).collect::<Vec<&str>>().join("\n")).unwrap();
+
+    let error = Input::try_read(&dir).err().unwrap();
+    let filled = Input::try_read_with(&dir, MissingValuations::Current, EmptyBorrowers::Error, Duplicates::Error).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(error.failures, vec![("valuations.csv".to_string(), format!("Exposure {} has no valuation of rating {}", exposure.exposure_id, expected.rating_labels[missing]))]);
+    let val = &filled.valuations[&exposure.exposure_id];
+    assert_eq!(val[missing], val[borr.rating]);
+    assert_eq!(val.len(), expected.valuations[&exposure.exposure_id].len());
+}
+
+#[test]
+fn migration_probabilities_cover_all_rating_classes() {
+    let (dir, expected) = input_dir("short_probabilities");
+    let id = &expected.risk_groups.values().flatten().next().unwrap().borrower_id;
+    let last = format!("{},{},", id, expected.rating_labels.len() - 1);
+    let probs = fs::read_to_string(dir.join("transition_probabilities.csv")).unwrap();
+    fs::write(dir.join("transition_probabilities.csv"), probs.lines().filter(|line| !line.starts_with(&last)).collect::<Vec<&str>>().join("\n")).unwrap();
+
+    let error = Input::try_read(&dir).err().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let message = format!("Borrower {} has migration probabilities up to rating {}, but there are {} rating classes", id, expected.rating_labels[expected.rating_labels.len() - 2], expected.rating_labels.len());
+    assert_eq!(error.failures, vec![("transition_probabilities.csv".to_string(), message)]);
+}
+
+#[test]
+fn pd_overrides_are_read_and_validated() {
+    let (dir, expected) = input_dir("pd_override");
+    let mut ids: Vec<&String> = expected.mig_probs.keys().collect();
+    ids.sort();
+    fs::write(dir.join("pd_override.csv"), format!("borrower_id,pd\n{},0.1\n{},1.5\nunknown,0.1\n", ids[0], ids[1])).unwrap();
+
+    let input = Input::read(&dir);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(input.pd_overrides[ids[0]], 0.1);
+    let issues = input.validate();
+    assert!(issues.contains(&format!("Borrower {} has PD override 1.5 outside of [0, 1]", ids[1])));
+    assert!(issues.contains(&"PD override of unknown borrower unknown".to_string()));
+    assert_eq!(issues.len(), 2);
+}
+
+#[test]
+fn factor_model_replaces_correlation_matrix() {
+    let (dir, _) = input_dir("factor_model");
+    fs::write(dir.join("correlation_matrix.csv"), "not,a,matrix\n").unwrap();
+    fs::write(dir.join("factor_loadings.csv"), "risk_factor,latent_factor,loading\n0,0,0.6\n1,0,0.8\n2,1,0.5\n3,0,0.3\n3,1,0.4\n").unwrap();
+    fs::write(dir.join("factor_residuals.csv"), "risk_factor,variance\n0,0.64\n1,0.36\n2,0.75\n3,0.75\n").unwrap();
+
+    let input = Input::read(&dir);
+    assert_eq!(input.cov.dim(), (4, 4));
+    assert!(input.cov.diag().iter().all(|x| (x - 1.).abs() < 1e-12));
+    assert!((input.cov[[0, 1]] - 0.48).abs() < 1e-12);
+    assert!((input.cov[[3, 0]] - 0.18).abs() < 1e-12);
+    assert!((input.cov[[2, 3]] - 0.2).abs() < 1e-12);
+    assert_eq!(input.cov[[1, 2]], 0.);
+    assert!(input.validate().is_empty());
+
+    // Every risk factor needs a residual variance
+    fs::write(dir.join("factor_residuals.csv"), "risk_factor,variance\n0,0.64\n1,0.36\n2,0.75\n").unwrap();
+    let error = Input::try_read(&dir).err().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(error.failures, vec![("factor_loadings.csv".to_string(), "No residual variance given for risk factor 3".to_string())]);
+}
+
+#[test]
+fn facility_ratings_are_read_from_exposures() {
+    let (dir, expected) = input_dir("facility_rating");
+    let mut reader = csv::Reader::from_path(dir.join("exposures.csv")).unwrap();
+    let rows: Vec<csv::StringRecord> = reader.records().map(|record| record.unwrap()).collect();
+    let mut exposures = String::from("exposure_id,borrower_id,outstanding,rating\n");
+    for (index, row) in rows.iter().enumerate() {
+        let rating = if index == 0 { expected.rating_labels[0].as_str() } else { "" };
+        exposures.push_str(&format!("{},{},{},{}\n", &row[0], &row[1], &row[2], rating));
+    }
+    fs::write(dir.join("exposures.csv"), exposures).unwrap();
+    let input = Input::read(&dir);
+    fs::remove_dir_all(&dir).unwrap();
+
+    let ratings: Vec<Option<usize>> = input.exposures.values().flatten().map(|exp| exp.rating).collect();
+    assert_eq!(ratings.iter().filter(|rating| rating.is_some()).count(), 1);
+    assert!(ratings.contains(&Some(0)));
+    assert!(input.validate().is_empty());
+}
+
+#[test]
+fn circular_guarantees_are_rejected() {
+    let (dir, expected) = input_dir("guarantees");
+    let mut exposures: Vec<&credit_portfolio_model::input::Exposure> = expected.exposures.values().flatten().collect();
+    exposures.sort_by(|a, b| a.exposure_id.cmp(&b.exposure_id));
+    let (first, second) = (exposures[0], exposures.iter().find(|exp| exp.borrower_id != exposures[0].borrower_id).unwrap());
+    let guarantees = format!("exposure_id,guarantor_borrower_id,coverage_fraction\n{},{},0.8\n", first.exposure_id, second.borrower_id);
+    fs::write(dir.join("guarantees.csv"), &guarantees).unwrap();
+
+    let input = Input::read(&dir);
+    assert_eq!(input.guarantees[&first.exposure_id].guarantor_borrower_id, second.borrower_id);
+    input.write(&dir);
+    assert_eq!(Input::read(&dir).guarantees, input.guarantees);
+
+    // The guarantor guarantees an exposure of the obligor in turn
+    fs::write(dir.join("guarantees.csv"), format!("{}{},{},0.5\n", guarantees, second.exposure_id, first.borrower_id)).unwrap();
+    let error = Input::try_read(&dir).err().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(error.failures.len(), 1);
+    assert_eq!(error.failures[0].0, "guarantees.csv");
+    assert!(error.failures[0].1.starts_with("Circular guarantee"), "{}", error.failures[0].1);
+}
+
+#[test]
+fn duplicate_keys_are_rejected() {
+    let (dir, _) = input_dir("duplicates");
+    // Repeat the first row of a file
+    let repeat_first_row = |file: &str| -> String {
+        let text = fs::read_to_string(dir.join(file)).unwrap();
+        let row = text.lines().nth(1).unwrap().to_string();
+        fs::write(dir.join(file), format!("{}{}\n", text, row)).unwrap();
+        row.split(',').next().unwrap().to_string()
+    };
+    let borrower = repeat_first_row("borrower.csv");
+    let exposure = repeat_first_row("valuations.csv");
+    let error = Input::try_read(&dir).err().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(error.failures.len(), 2);
+    assert!(error.failures.contains(&("borrower.csv".to_string(), format!("Borrower {} is listed more than once", borrower))), "{:?}", error.failures);
+    assert!(error.failures.iter().any(|(file, message)| file == "valuations.csv" && message.starts_with(&format!("Exposure {} has more than one valuation of rating", exposure))), "{:?}", error.failures);
+}
+
+#[test]
+fn risk_group_hierarchy_nests_groups_under_their_parent() {
+    let (dir, expected) = input_dir("hierarchy");
+    let mut groups: Vec<&String> = expected.risk_groups.keys().collect();
+    groups.sort();
+    let hierarchy = format!("risk_group,parent,weight\n{},Global,0.6\n{},Global,0.8\n", groups[0], groups[1]);
+    fs::write(dir.join("risk_group_hierarchy.csv"), &hierarchy).unwrap();
+
+    let input = Input::read(&dir);
+    assert_eq!(input.hierarchy[groups[1]].weight, 0.8);
+    input.write(&dir);
+    assert_eq!(Input::read(&dir).hierarchy, input.hierarchy);
+
+    // The global parent has no borrowers and is added before its children
+    let pf = input.into_portfolio();
+    let ids: Vec<&str> = pf.iter_risk_group().map(|rg| rg.id()).collect();
+    let global = ids.iter().position(|&id| id == "Global").unwrap();
+    assert_eq!(pf.iter_risk_group().nth(global).unwrap().num_borrower(), 0);
+    assert_eq!((ids[global + 1], ids[global + 2]), (groups[0].as_str(), groups[1].as_str()));
+    assert_eq!(pf.group_parent(global + 2), Some(("Global", 0.8)));
+    assert_eq!(pf.group_parent(global), None);
+
+    // A group that is its own grandparent
+    fs::write(dir.join("risk_group_hierarchy.csv"), format!("{}Global,{},0.5\n", hierarchy, groups[0])).unwrap();
+    let error = Input::try_read(&dir).err().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(error.failures.len(), 1);
+    assert_eq!(error.failures[0].0, "risk_group_hierarchy.csv");
+    assert!(error.failures[0].1.starts_with("Circular risk group hierarchy"), "{}", error.failures[0].1);
+}
+
+#[test]
+fn scenarios_normalize_weights_and_resolve_ratings() {
+    let dir = std::env::temp_dir().join(format!("cpm_scenarios_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("scenarios.toml");
+    fs::write(&path, r#"
+[[scenarios]]
+id = "baseline"
+weight = 3
+
+[[scenarios]]
+id = "adverse"
+weight = 1
+pd_multipliers = [{ rating = "R2", multiplier = 2.5 }, { rating = 0, multiplier = 1 }]
+factor_shifts = [{ risk_factor = 1, shift = 0.5 }]
+"#).unwrap();
+    let scale = RatingScale::new(vec!["R1".to_string(), "R2".to_string(), "D".to_string()]);
+    let scenarios = read_scenarios(&path, &scale);
+    fs::remove_dir_all(&dir).unwrap();
+
+    let ids: Vec<&str> = scenarios.iter().map(|scenario| scenario.id.as_str()).collect();
+    assert_eq!(ids, ["baseline", "adverse"]);
+    assert_eq!((scenarios[0].weight, scenarios[1].weight), (0.75, 0.25));
+    assert!(scenarios[0].pd_multipliers.is_empty() && scenarios[0].factor_shifts.is_empty());
+    assert_eq!(scenarios[1].pd_multipliers, [1.0, 2.5]);
+    assert_eq!(scenarios[1].factor_shifts, [(1, 0.5)]);
+}
+
+#[test]
+fn risk_group_copula_sets_the_degrees_of_freedom() {
+    let (dir, expected) = input_dir("copula");
+    let mut groups: Vec<&String> = expected.risk_groups.keys().collect();
+    groups.sort();
+    fs::write(dir.join("risk_group_copula.csv"), format!("risk_group,degrees_of_freedom\n{},4\n", groups[0])).unwrap();
+
+    let input = Input::read(&dir);
+    input.write(&dir);
+    assert_eq!(Input::read(&dir).copula, input.copula);
+    let pf = input.into_portfolio();
+    let df = |id: &str| pf.iter_risk_group().find(|rg| rg.id() == id).unwrap().degrees_of_freedom();
+    assert_eq!((df(groups[0]), df(groups[1])), (Some(4.), None));
+
+    fs::write(dir.join("risk_group_copula.csv"), format!("risk_group,degrees_of_freedom\n{},0\n", groups[0])).unwrap();
+    let error = Input::try_read(&dir).err().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(error.failures, [("risk_group_copula.csv".to_string(), format!("Risk group {} has degrees of freedom 0, they must be positive", groups[0]))]);
+}
+
+#[test]
+fn thresholds_replace_those_of_the_migration_probabilities() {
+    let (dir, expected) = input_dir("thresholds");
+    let mut ids: Vec<&String> = expected.mig_probs.keys().collect();
+    ids.sort();
+    let num_ratings = expected.mig_probs[ids[0]].len();
+    let c_mig: Vec<f64> = (0..num_ratings - 1).map(|rating| -2. + rating as f64).collect();
+    let rows: String = c_mig.iter().enumerate().map(|(rating, c)| format!("{},{},{}\n", ids[0], rating, c)).collect();
+    fs::write(dir.join("thresholds.csv"), format!("borrower_id,rating,threshold\n{}", rows)).unwrap();
+
+    let input = Input::read(&dir);
+    input.write(&dir);
+    assert_eq!(Input::read(&dir).thresholds, input.thresholds);
+    let pf = input.into_portfolio();
+    let borr = pf.iter_risk_group().flat_map(|rg| rg.iter_borrower()).find(|borr| borr.id() == ids[0]).unwrap();
+    assert_eq!(borr.thresholds(), &c_mig[..]);
+
+    // Thresholds must increase and cover all rating classes
+    let labels = &expected.rating_labels;
+    let decreasing = rows.replacen(&format!("{},1,-1", ids[0]), &format!("{},1,-3", ids[0]), 1);
+    fs::write(dir.join("thresholds.csv"), format!("borrower_id,rating,threshold\n{}{},0,0.5\n", decreasing, ids[1])).unwrap();
+    let error = Input::try_read(&dir).err().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(error.failures, [
+        ("thresholds.csv".to_string(), format!("Borrower {} has thresholds that are not strictly increasing, the one of rating {} is not above the one of rating {}", ids[0], labels[1], labels[0])),
+        ("thresholds.csv".to_string(), format!("Borrower {} has no threshold of rating {}", ids[1], labels[1])),
+    ]);
+}
+
+#[test]
+#[should_panic(expected = "Factor input has the column factor_3, but the covariance matrix has 3 risk factors")]
+fn factor_input_must_match_the_risk_factors() {
+    let dir = std::env::temp_dir().join(format!("cpm_factor_input_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("factors.csv");
+    fs::write(&path, "trial,loss,factor_0,factor_1,factor_2,factor_3\n0,1.5,0.1,-0.2,0.3,0.4\n").unwrap();
+
+    // The extra columns of other inputs are ignored
+    let factors = read_factor_draws(&path, 4);
+    assert_eq!(factors, ndarray::array![[0.1, -0.2, 0.3, 0.4]]);
+
+    let result = std::panic::catch_unwind(|| read_factor_draws(&path, 3));
+    fs::remove_dir_all(&dir).unwrap();
+    std::panic::resume_unwind(result.err().unwrap());
+}
+
+/// Rows of a csv file by key and rating bucketed in one batch after reading all of them
+fn batched(path: &Path, key: &str, value: &str) -> HashMap<String, Vec<f64>> {
+    let rows: Vec<csv::StringRecord> = csv::Reader::from_path(path).unwrap().records().map(Result::unwrap).collect();
+    let headers = csv::Reader::from_path(path).unwrap().headers().unwrap().clone();
+    let column = |name: &str| headers.iter().position(|h| h == name).unwrap();
+    let (key, rating, value) = (column(key), column("rating"), column(value));
+
+    let mut buckets: HashMap<String, Vec<f64>> = HashMap::new();
+    for row in rows.iter() {
+        let values = buckets.entry(row[key].to_string()).or_default();
+        let index: usize = row[rating].parse().unwrap();
+        if values.len() <= index {
+            values.resize(index + 1, f64::NAN);
+        }
+        values[index] = row[value].parse().unwrap();
+    }
+    buckets
+}
+
+#[test]
+fn streamed_rows_bucket_like_a_batch() {
+    let (dir, _) = input_dir("streamed");
+
+    // Interleave the rows of all keys by reversing their order
+    for file in ["valuations.csv", "transition_probabilities.csv"] {
+        let text = fs::read_to_string(dir.join(file)).unwrap();
+        let mut lines: Vec<&str> = text.lines().collect();
+        lines[1..].reverse();
+        fs::write(dir.join(file), lines.join("\n") + "\n").unwrap();
+    }
+    let valuations = batched(&dir.join("valuations.csv"), "exposure_id", "valuation");
+    let mig_probs = batched(&dir.join("transition_probabilities.csv"), "borrower_id", "probability");
+    let input = Input::read(&dir);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(input.valuations, valuations);
+    assert_eq!(input.mig_probs, mig_probs);
+}
+
+#[test]
+fn borrowers_without_exposures_fail_unless_allowed() {
+    let (dir, expected) = input_dir("empty_borrowers");
+    let mut ids: Vec<&str> = expected.risk_groups.values().flatten().map(|borr| borr.borrower_id.as_str()).collect();
+    ids.sort();
+    let empty = [ids[0], ids[1]];
+    let exposures = fs::read_to_string(dir.join("exposures.csv")).unwrap();
+    let kept: Vec<&str> = exposures.lines().filter(|line| !empty.iter().any(|id| line.split(',').nth(1) == Some(*id))).collect();
+    fs::write(dir.join("exposures.csv"), kept.join("\n")).unwrap();
+
+    let error = Input::try_read(&dir).err().unwrap();
+    let allowed = Input::try_read_with(&dir, MissingValuations::Error, EmptyBorrowers::Allow, Duplicates::Error).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(error.failures, vec![("exposures.csv".to_string(), format!("2 borrowers have no exposures: {}, {}", empty[0], empty[1]))]);
+    assert_eq!(allowed.num_empty_borrowers(), 2);
+
+    // The empty borrowers are kept with no value, so they lose nothing
+    let pf = allowed.into_portfolio();
+    assert_eq!(pf.num_borrower(), ids.len());
+    let full = expected.into_portfolio();
+    assert!(pf.expected_loss() < full.expected_loss());
+}
+
+#[test]
+fn duplicate_valuations_fail_unless_summed() {
+    let (dir, expected) = input_dir("duplicate_valuations");
+    let exposure = expected.exposures.values().flatten().next().unwrap();
+    let row = format!("{},0,", exposure.exposure_id);
+    let valuations = fs::read_to_string(dir.join("valuations.csv")).unwrap();
+    let duplicate = valuations.lines().find(|line| line.starts_with(&row)).unwrap().to_string();
+    fs::write(dir.join("valuations.csv"), format!("{}\n{}\n", valuations.trim_end(), duplicate)).unwrap();
+
+    let error = Input::try_read(&dir).err().unwrap();
+    let summed = Input::try_read_with(&dir, MissingValuations::Error, EmptyBorrowers::Error, Duplicates::Sum).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(error.failures, vec![("valuations.csv".to_string(), format!("Exposure {} has more than one valuation of rating {}", exposure.exposure_id, expected.rating_labels[0]))]);
+    let (val, original) = (&summed.valuations[&exposure.exposure_id], &expected.valuations[&exposure.exposure_id]);
+    assert_eq!(val[0], 2. * original[0]);
+    assert_eq!(val[1..], original[1..]);
+}
+
+#[test]
+fn duplicate_exposures_fail_unless_summed_under_the_same_borrower() {
+    let (dir, expected) = input_dir("duplicate_exposures");
+    let mut borrowers: Vec<&String> = expected.exposures.keys().collect();
+    borrowers.sort();
+    let exposure = &expected.exposures[borrowers[0]][0];
+    let exposures = fs::read_to_string(dir.join("exposures.csv")).unwrap();
+    let row = exposures.lines().find(|line| line.starts_with(&format!("{},", exposure.exposure_id))).unwrap().to_string();
+
+    // Repeated under the same borrower
+    fs::write(dir.join("exposures.csv"), format!("{}\n{}\n", exposures.trim_end(), row)).unwrap();
+    let error = Input::try_read(&dir).err().unwrap();
+    let summed = Input::try_read_with(&dir, MissingValuations::Error, EmptyBorrowers::Error, Duplicates::Sum).unwrap();
+    assert_eq!(error.failures, vec![("exposures.csv".to_string(), format!("Exposure {} is listed more than once", exposure.exposure_id))]);
+    let outstanding = summed.exposures[borrowers[0]].iter().find(|exp| exp.exposure_id == exposure.exposure_id).unwrap().outstanding;
+    assert_eq!(outstanding, 2. * exposure.outstanding);
+    assert_eq!(summed.exposures[borrowers[0]].len(), expected.exposures[borrowers[0]].len());
+
+    // Under another borrower
+    let moved = row.replacen(&format!(",{},", borrowers[0]), &format!(",{},", borrowers[1]), 1);
+    fs::write(dir.join("exposures.csv"), format!("{}\n{}\n", exposures.trim_end(), moved)).unwrap();
+    let failures = [Duplicates::Error, Duplicates::Sum].map(|duplicates| Input::try_read_with(&dir, MissingValuations::Error, EmptyBorrowers::Error, duplicates).err().unwrap().failures);
+    fs::remove_dir_all(&dir).unwrap();
+
+    let message = format!("Exposure {} is listed under borrowers {} and {}", exposure.exposure_id, borrowers[0], borrowers[1]);
+    assert_eq!(failures, [0, 1].map(|_| vec![("exposures.csv".to_string(), message.clone())]));
+}
+
+#[test]
+fn discount_factors_scale_the_losses_by_the_horizon_of_the_exposures() {
+    let (dir, expected) = input_dir("discounting");
+    let mut reader = csv::Reader::from_path(dir.join("exposures.csv")).unwrap();
+    let rows: Vec<csv::StringRecord> = reader.records().map(|record| record.unwrap()).collect();
+    let mut exposures = String::from("exposure_id,borrower_id,outstanding,horizon\n");
+    for row in rows.iter() {
+        exposures.push_str(&format!("{},{},{},2\n", &row[0], &row[1], &row[2]));
+    }
+    fs::write(dir.join("exposures.csv"), exposures).unwrap();
+    fs::write(dir.join("discounting.csv"), "tenor,discount_factor\n3,0.85\n1,0.95\n").unwrap();
+
+    let input = Input::read(&dir);
+    input.write(&dir);
+    assert_eq!(Input::read(&dir).discounting, input.discounting);
+
+    // The horizon of two years interpolates log-linearly between the tenors
+    let discount_factor = (0.95f64 * 0.85).sqrt();
+    let base = expected.into_portfolio().expected_loss();
+    let discounted = input.into_portfolio().expected_loss();
+    assert!((discounted - discount_factor * base).abs() < 1e-9 * base.abs(), "{} vs {}", discounted, discount_factor * base);
+
+    fs::write(dir.join("discounting.csv"), "tenor,discount_factor\n1,0.95\n1,0.9\n").unwrap();
+    let error = Input::try_read(&dir).err().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(error.failures, [("discounting.csv".to_string(), "Tenor 1 has more than one discount factor".to_string())]);
+}
+
+#[test]
+fn exposures_are_converted_to_the_base_currency() {
+    let (dir, expected) = input_dir("currency");
+    let mut reader = csv::Reader::from_path(dir.join("exposures.csv")).unwrap();
+    let rows: Vec<csv::StringRecord> = reader.records().map(|record| record.unwrap()).collect();
+    let write_exposures = |currencies: &dyn Fn(usize) -> &'static str| {
+        let mut exposures = String::from("exposure_id,borrower_id,outstanding,currency\n");
+        for (index, row) in rows.iter().enumerate() {
+            exposures.push_str(&format!("{},{},{},{}\n", &row[0], &row[1], &row[2], currencies(index)));
+        }
+        fs::write(dir.join("exposures.csv"), exposures).unwrap();
+    };
+    write_exposures(&|_| "USD");
+    fs::write(dir.join("fx_rates.csv"), "currency,rate_to_base\nEUR,1\nUSD,0.9\n").unwrap();
+
+    // The input keeps the original amounts, the portfolio is in the base currency
+    let input = Input::read(&dir);
+    input.write(&dir);
+    let reread = Input::read(&dir);
+    assert_eq!(reread.fx_rates, input.fx_rates);
+    assert!(reread.exposures.values().flatten().all(|exp| exp.currency.as_deref() == Some("USD")));
+    let outstanding: f64 = expected.exposures.values().flatten().map(|exp| exp.outstanding).sum();
+    assert!((input.exposures.values().flatten().map(|exp| exp.outstanding).sum::<f64>() - outstanding).abs() < 1e-9 * outstanding);
+    let base = expected.into_portfolio();
+    let converted = input.into_portfolio();
+    assert!((converted.total_outstanding() - 0.9 * base.total_outstanding()).abs() < 1e-9 * base.total_outstanding());
+    assert!((converted.total_value() - 0.9 * base.total_value()).abs() < 1e-9 * base.total_value());
+    assert!((converted.expected_loss() - 0.9 * base.expected_loss()).abs() < 1e-9 * base.expected_loss().abs());
+
+    // Exposures in a currency without rate fail the reading
+    write_exposures(&|index| if index < 2 { "JPY" } else { "" });
+    let error = Input::try_read(&dir).err().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    let mut ids = [rows[0][0].to_string(), rows[1][0].to_string()];
+    ids.sort();
+    assert_eq!(error.failures, [("fx_rates.csv".to_string(), format!("2 exposures have currency JPY without rate: {}", ids.join(", ")))]);
+}
+
+#[test]
+fn borrowers_on_their_own_rating_scale_have_their_own_number_of_classes() {
+    let (dir, mut input) = input_dir("own_scale");
+    let borr = input.risk_groups.values_mut().flatten().next().unwrap();
+    borr.rating = 0;
+    borr.rating_classes = Some(3);
+    let id = borr.borrower_id.clone();
+    input.mig_probs.insert(id.clone(), vec![0.9, 0.08, 0.02]);
+    for exp in input.exposures[&id].iter() {
+        input.valuations.insert(exp.exposure_id.clone(), vec![100., 95., 40.]);
+    }
+    input.write(&dir);
+
+    // The borrower migrates on its own scale, the others on the common one
+    let read = Input::read(&dir);
+    assert_eq!(read.mig_probs[&id].len(), 3);
+    let pf = read.into_portfolio();
+    let borrowers: Vec<_> = pf.iter_risk_group().flat_map(|rg| rg.iter_borrower()).collect();
+    assert!(borrowers.iter().all(|b| b.num_ratings() == if b.id() == id { 3 } else { input.rating_labels.len() }));
+    assert_eq!(pf.num_ratings(), input.rating_labels.len());
+    let result = pf.simulate::<rand_pcg::Pcg64>(200, 50, 1, credit_portfolio_model::migration::MigrationTracking::Portfolio, &[]);
+    let migrations = result.migrations.unwrap();
+    assert_eq!(migrations.counts().sum(), 200 * pf.num_borrower() as u64);
+
+    // Only its default is pooled with the common default, its other classes get their own columns
+    let common = input.rating_labels.len();
+    let own = borrowers.iter().find(|b| b.id() == id).unwrap();
+    assert_eq!(pf.num_rating_columns(), common + 2);
+    assert_eq!(pf.rating_column(own, 2), common - 1);
+    assert_eq!((pf.rating_column(own, 0), pf.rating_column(own, 1)), (common, common + 1));
+    assert_eq!(pf.rating_column_label(common), "0 of 3");
+    assert_eq!(pf.rating_column_label(common - 1), input.rating_labels[common - 1]);
+    let expected = pf.expected_migration_matrix();
+    assert!((expected[[common, common - 1]] - 0.02).abs() < 1e-12);
+    assert!((expected[[common, common + 1]] - 0.08).abs() < 1e-12);
+    assert_eq!(migrations.matrix().row(common).sum(), 200);
+
+    // Probabilities must cover exactly the own scale
+    input.mig_probs.insert(id.clone(), vec![0.9, 0.1]);
+    input.write(&dir);
+    let error = Input::try_read(&dir).err().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    let message = format!("Borrower {} has migration probabilities up to rating {}, but its own rating scale has 3 rating classes", id, input.rating_labels[1]);
+    assert!(error.failures.contains(&("transition_probabilities.csv".to_string(), message)));
+}
@@ -0,0 +1,117 @@
+use credit_portfolio_model::borrower::Borrower;
+use credit_portfolio_model::migration::{binary_search, linear_scan, rating_index, ZConvention};
+use proptest::prelude::*;
+use rand::SeedableRng;
+use rand_distr::{Distribution, StandardNormal};
+use rand_pcg::Pcg64;
+
+/// Ascending thresholds, duplicates included as they arise from zero migration probabilities
+fn thresholds(max_len: usize) -> impl Strategy<Value = Vec<f64>> {
+    prop::collection::vec(-8.0..8.0_f64, 0..max_len).prop_map(|mut t| {
+        t.sort_by(|a, b| a.total_cmp(b));
+        t
+    })
+}
+
+proptest! {
+    #[test]
+    fn scan_and_search_agree(thresholds in thresholds(64), z in -10.0..10.0_f64) {
+        let expected = thresholds.iter().filter(|&&t| t < z).count();
+        prop_assert_eq!(linear_scan(&thresholds, z), expected);
+        prop_assert_eq!(binary_search(&thresholds, z), expected);
+        prop_assert_eq!(rating_index(&thresholds, z), expected);
+    }
+
+    #[test]
+    fn scan_and_search_agree_at_thresholds(thresholds in thresholds(64), index in any::<prop::sample::Index>()) {
+        prop_assume!(!thresholds.is_empty());
+        let z = thresholds[index.index(thresholds.len())];
+        let expected = thresholds.iter().filter(|&&t| t < z).count();
+        prop_assert_eq!(linear_scan(&thresholds, z), expected);
+        prop_assert_eq!(binary_search(&thresholds, z), expected);
+        prop_assert_eq!(rating_index(&thresholds, z), expected);
+    }
+}
+
+#[test]
+fn asset_values_on_a_threshold_fall_into_the_class_below_it() {
+    // Short scales are scanned, long ones searched, the duplicate encloses a class of zero width
+    let short = vec![-1.5, -0.5, -0.5, 0.25, 2.];
+    let long: Vec<f64> = (0..40).map(|i| i as f64 / 4. - 5.).collect();
+    for thresholds in [short, long] {
+        for (index, &t) in thresholds.iter().enumerate() {
+            let expected = thresholds.partition_point(|&c| c < t);
+            assert_eq!(expected, if index > 0 && thresholds[index - 1] == t { index - 1 } else { index });
+            assert_eq!(linear_scan(&thresholds, t), expected);
+            assert_eq!(binary_search(&thresholds, t), expected);
+            assert_eq!(rating_index(&thresholds, t), expected);
+            assert_eq!(rating_index(&thresholds, t.next_up()), expected + thresholds.iter().filter(|&&c| c == t).count());
+        }
+    }
+
+    // In either z convention, the asset value on a threshold keeps the lower rating index
+    let mut borr = borrower(vec![0.6, 0.3, 0., 0.1]);
+    for convention in [ZConvention::LowIsBest, ZConvention::LowIsDefault] {
+        borr.set_z_convention(convention);
+        let thresholds = borr.thresholds().to_vec();
+        assert_eq!(thresholds[1], thresholds[2]);
+        let ratings: Vec<usize> = thresholds.iter().map(|&t| borr.migration(&(convention.sign() * t))).collect();
+        assert_eq!(ratings, vec![0, 1, 1]);
+        assert!(ratings.iter().all(|&rating| borr.migration_probabilities()[rating] > 0.));
+    }
+}
+
+/// Migration probabilities of up to 12 rating classes, some of them zero, summing to one up to
+/// rounding
+fn probabilities() -> impl Strategy<Value = Vec<f64>> {
+    prop::collection::vec(prop_oneof![Just(0.0), 1e-6..1.0_f64], 1..12)
+        .prop_filter("needs a class of positive probability", |w| w.iter().any(|&w| w > 0.))
+        .prop_map(|w| {
+            let sum: f64 = w.iter().sum();
+            w.iter().map(|w| w / sum).collect()
+        })
+}
+
+/// Borrower in the first rating class with the migration probabilities
+fn borrower(p_mig: Vec<f64>) -> Borrower {
+    Borrower::new("B0".to_string(), vec![1.0], 0, 0.2, 0.3, p_mig)
+}
+
+proptest! {
+    #[test]
+    fn migration_stays_within_the_rating_classes(p_mig in probabilities(), z in -40.0..40.0_f64) {
+        let borr = borrower(p_mig.clone());
+        let rating = borr.migration(&z);
+        prop_assert!(rating < p_mig.len());
+        // Classes of zero probability have no room between their thresholds
+        prop_assert!(p_mig[rating] > 0., "Migrated into rating {} of zero probability, {:?}", rating, borr.thresholds());
+    }
+
+    #[test]
+    fn larger_asset_values_never_migrate_into_better_ratings(p_mig in probabilities(), z in -10.0..10.0_f64, dz in 0.0..5.0_f64) {
+        // High asset values are adverse, the rating index grows towards the default class
+        let borr = borrower(p_mig);
+        prop_assert!(borr.migration(&z) <= borr.migration(&(z + dz)));
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn migration_frequencies_converge_to_the_probabilities(p_mig in probabilities(), seed in any::<u64>()) {
+        let borr = borrower(p_mig.clone());
+        let num_draws = 20_000;
+        let mut counts = vec![0usize; p_mig.len()];
+        let mut rng = Pcg64::seed_from_u64(seed);
+        for z in StandardNormal.sample_iter(&mut rng).take(num_draws) {
+            counts[borr.migration(&z)] += 1;
+        }
+        for (&count, &p) in counts.iter().zip(p_mig.iter()) {
+            let frequency = count as f64 / num_draws as f64;
+            let standard_error = (p * (1. - p) / num_draws as f64).sqrt();
+            // A few draws of slack for the classes of tiny probability
+            prop_assert!((frequency - p).abs() <= 5. * standard_error + 2. / num_draws as f64, "{} vs {}", frequency, p);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::fs;
+use std::process::{Command, Output};
+
+use credit_portfolio_model::generate::Generator;
+use credit_portfolio_model::summary::Summary;
+
+/// Run the binary with the arguments and assert it succeeds
+fn run(args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_credit_portfolio_model")).args(args).output().unwrap();
+    assert!(output.status.success(), "Run with {:?} failed", args);
+    output
+}
+
+#[test]
+fn stdout_carries_only_the_requested_data() {
+    let root = std::env::temp_dir().join(format!("cpm_stdout_{}", std::process::id()));
+    let input = root.join("input");
+    let files = root.join("files");
+    fs::create_dir_all(&input).unwrap();
+    fs::create_dir_all(&files).unwrap();
+    Generator { borrowers: 40, risk_factors: 3, ..Default::default() }.input().write(&input);
+
+    let common = ["-i", input.to_str().unwrap(), "-n", "500", "--seed", "3"];
+    let printed = run(&[&common[..], &["-o", files.to_str().unwrap()]].concat());
+    let loss = run(&[&common[..], &["-o", "-"]].concat());
+    let summary = run(&[&common[..], &["-o", "-", "--stdout-data", "summary"]].concat());
+    let loss_file = fs::read(files.join("loss_distribution.csv")).unwrap();
+    let summary_file: Summary = serde_json::from_reader(fs::File::open(files.join("summary.json")).unwrap()).unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(loss.stdout, loss_file);
+    let summary: Summary = serde_json::from_slice(&summary.stdout).unwrap();
+    assert_eq!((summary.mean, summary.median), (summary_file.mean, summary_file.median));
+
+    // The printed results move to stderr
+    let printed = String::from_utf8(printed.stdout).unwrap();
+    let moved = String::from_utf8(loss.stderr).unwrap();
+    let line = |text: &str| text.lines().find(|line| line.starts_with("Exp Loss Sim")).map(str::to_string);
+    assert!(line(&printed).is_some());
+    assert_eq!(line(&moved), line(&printed));
+}
+
+#[test]
+fn pnl_convention_negates_the_reported_losses() {
+    let root = std::env::temp_dir().join(format!("cpm_pnl_{}", std::process::id()));
+    let input = root.join("input");
+    let (loss_dir, pnl_dir) = (root.join("loss"), root.join("pnl"));
+    for dir in [&input, &loss_dir, &pnl_dir] {
+        fs::create_dir_all(dir).unwrap();
+    }
+    Generator { borrowers: 40, risk_factors: 3, ..Default::default() }.input().write(&input);
+
+    let common = ["-i", input.to_str().unwrap(), "-n", "500", "--seed", "3", "-q", "0.99"];
+    let loss = run(&[&common[..], &["-o", loss_dir.to_str().unwrap()]].concat());
+    let pnl = run(&[&common[..], &["-o", pnl_dir.to_str().unwrap(), "--convention", "pnl"]].concat());
+    let read = |dir: &std::path::Path| -> Vec<String> {
+        fs::read_to_string(dir.join("loss_distribution.csv")).unwrap().lines().map(str::to_string).collect()
+    };
+    let (loss_rows, pnl_rows) = (read(&loss_dir), read(&pnl_dir));
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!((loss_rows[0].as_str(), pnl_rows[0].as_str()), ("Loss", "PnL"));
+    for (l, p) in loss_rows.iter().zip(pnl_rows.iter()).skip(1) {
+        assert_eq!(l.parse::<f64>().unwrap(), -p.parse::<f64>().unwrap());
+    }
+
+    // The 99% quantile of the loss is the negated 1% quantile of the P&L
+    let value = |output: &Output, label: &str| -> f64 {
+        let text = String::from_utf8(output.stdout.clone()).unwrap();
+        let line = text.lines().find(|line| line.starts_with(label)).unwrap().to_string();
+        line[14..].split_whitespace().next().unwrap().parse().unwrap()
+    };
+    assert_eq!(value(&loss, "(99.0%):"), -value(&pnl, "(1.0%):"));
+    assert_eq!(value(&loss, "ES (99.0%):"), -value(&pnl, "ES (1.0%):"));
+    assert_eq!(value(&loss, "Exp Loss Sim:"), -value(&pnl, "Exp PnL Sim:"));
+}
+
+#[test]
+fn compare_reports_the_differences_of_two_runs() {
+    let root = std::env::temp_dir().join(format!("cpm_compare_{}", std::process::id()));
+    let input = root.join("input");
+    let (before, after) = (root.join("before"), root.join("after"));
+    for dir in [&input, &before, &after] {
+        fs::create_dir_all(dir).unwrap();
+    }
+    Generator { borrowers: 40, risk_factors: 3, ..Default::default() }.input().write(&input);
+
+    let common = ["-i", input.to_str().unwrap(), "-n", "500", "-q", "0.99"];
+    run(&[&common[..], &["-o", before.to_str().unwrap(), "--seed", "3"]].concat());
+    run(&[&common[..], &["-o", after.to_str().unwrap(), "--seed", "4", "--convention", "pnl"]].concat());
+    let compare = |a: &std::path::Path, b: &std::path::Path| -> String {
+        let distribution = |dir: &std::path::Path| dir.join("loss_distribution.csv").to_str().unwrap().to_string();
+        let output = run(&["compare", "--before", &distribution(a), "--after", &distribution(b), "-q", "0.99"]);
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let (same, different) = (compare(&before, &before), compare(&before, &after));
+    fs::remove_dir_all(&root).unwrap();
+
+    let values = |text: &str, label: &str| -> Vec<f64> {
+        let line = text.lines().find(|line| line.starts_with(label)).unwrap().to_string();
+        line[14..].split_whitespace().map(|value| value.parse().unwrap()).collect()
+    };
+    assert_eq!(values(&same, "KS Statistic:"), [0.]);
+    assert_eq!(values(&same, "Wasserstein:"), [0.]);
+    assert_eq!(values(&same, "(99.0%):")[2], 0.);
+
+    // The P&L of the second run is read back as losses
+    let mean = values(&different, "Mean:");
+    assert!(mean[0] > 0. && mean[1] > 0.);
+    assert!((mean[2] - (mean[1] - mean[0])).abs() < 0.011);
+    assert!(values(&different, "KS Statistic:")[0] > 0.);
+}
+
+#[test]
+fn progress_messages_are_logged_to_stderr() {
+    let root = std::env::temp_dir().join(format!("cpm_logging_{}", std::process::id()));
+    let input = root.join("input");
+    let files = root.join("files");
+    fs::create_dir_all(&input).unwrap();
+    fs::create_dir_all(&files).unwrap();
+    Generator { borrowers: 40, risk_factors: 3, ..Default::default() }.input().write(&input);
+
+    let common = ["-i", input.to_str().unwrap(), "-o", files.to_str().unwrap(), "-n", "500"];
+    let default = run(&common);
+    let quiet = run(&[&common[..], &["--quiet"]].concat());
+    let json = run(&[&common[..], &["--log-format", "json"]].concat());
+    fs::remove_dir_all(&root).unwrap();
+
+    let (stdout, stderr) = (String::from_utf8(default.stdout).unwrap(), String::from_utf8(default.stderr).unwrap());
+    assert!(stderr.contains("Finished initialization"));
+    assert!(!stdout.contains("Finished initialization"));
+    assert!(stdout.contains("Exp Loss Sim"));
+
+    // Quiet runs keep the results but not the progress
+    assert_eq!(String::from_utf8(quiet.stdout).unwrap(), stdout);
+    assert!(!String::from_utf8(quiet.stderr).unwrap().contains("Finished initialization"));
+
+    let messages: Vec<serde_json::Value> = String::from_utf8(json.stderr).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert!(messages.iter().any(|message| message["level"] == "INFO" && message["message"].as_str().unwrap().starts_with("Done after")));
+}
+
+#[test]
+fn conditional_loss_of_an_unsupported_portfolio_fails_before_simulating() {
+    let root = std::env::temp_dir().join(format!("cpm_conditional_{}", std::process::id()));
+    let input = root.join("input");
+    let files = root.join("files");
+    fs::create_dir_all(&input).unwrap();
+    fs::create_dir_all(&files).unwrap();
+    let mut data = Generator { borrowers: 40, risk_factors: 3, ..Default::default() }.input();
+    let group = data.risk_groups.keys().next().unwrap().clone();
+    data.copula.insert(group, 4.);
+    data.write(&input);
+
+    let args = ["-i", input.to_str().unwrap(), "-o", files.to_str().unwrap(), "-n", "500", "--conditional-loss"];
+    let output = Command::new(env!("CARGO_BIN_EXE_credit_portfolio_model")).args(args).output().unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("--conditional-loss needs the Gaussian copula"));
+    assert!(!String::from_utf8(output.stdout).unwrap().contains("Exp Loss Sim"));
+}
+
+#[test]
+fn loss_rates_relate_the_losses_to_the_portfolio_value() {
+    let root = std::env::temp_dir().join(format!("cpm_rates_{}", std::process::id()));
+    let input = root.join("input");
+    let files = root.join("files");
+    fs::create_dir_all(&input).unwrap();
+    fs::create_dir_all(&files).unwrap();
+    Generator { borrowers: 40, risk_factors: 3, ..Default::default() }.input().write(&input);
+
+    let args = ["-i", input.to_str().unwrap(), "-o", files.to_str().unwrap(), "-n", "500", "--seed", "3", "-q", "0.99", "--thresholds", "100,500"];
+    let output = run(&args);
+    let summary: Summary = serde_json::from_reader(fs::File::open(files.join("summary.json")).unwrap()).unwrap();
+    let curve = fs::read_to_string(files.join("exceedance_curve.csv")).unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    let value = summary.total_value.unwrap();
+    let rates = summary.loss_rates.unwrap();
+    assert!(value > 0.);
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-12 * b.abs().max(1e-12);
+    assert!(close(rates.mean, summary.mean / value));
+    assert!(close(rates.median, summary.median / value));
+    assert!(close(rates.quantiles[0].var, summary.quantiles[0].var / value));
+    assert!(close(rates.quantiles[0].es, summary.quantiles[0].es / value));
+
+    // Printed in percent
+    let text = String::from_utf8(output.stdout).unwrap();
+    let percent = |label: &str| -> f64 {
+        let line = text.lines().find(|line| line.starts_with(label)).unwrap();
+        line[14..].trim().trim_end_matches('%').parse().unwrap()
+    };
+    assert!((percent("Rate Mean:") - rates.mean * 100.).abs() < 1e-4);
+    assert!((percent("Rate (99.0%):") - rates.quantiles[0].var * 100.).abs() < 1e-4);
+
+    // Each point of the exceedance curve with its threshold relative to the value
+    let mut rows = curve.lines();
+    assert_eq!(rows.next(), Some("threshold,exceedance_probability,mean_excess_loss,loss_rate"));
+    for row in rows {
+        let fields: Vec<&str> = row.split(',').collect();
+        assert!(close(fields[3].parse().unwrap(), fields[0].parse::<f64>().unwrap() / value));
+    }
+}
+
+#[test]
+fn loss_rates_are_not_reported_without_a_positive_portfolio_value() {
+    let root = std::env::temp_dir().join(format!("cpm_no_rates_{}", std::process::id()));
+    let input = root.join("input");
+    let files = root.join("files");
+    fs::create_dir_all(&input).unwrap();
+    fs::create_dir_all(&files).unwrap();
+    // Shifting the valuations of each exposure below zero keeps the losses but makes the current
+    // value negative
+    let mut data = Generator { borrowers: 40, risk_factors: 3, ..Default::default() }.input();
+    for val in data.valuations.values_mut() {
+        let shift = 2. * val[0];
+        val.iter_mut().for_each(|v| *v -= shift);
+    }
+    data.write(&input);
+
+    let args = ["-i", input.to_str().unwrap(), "-o", files.to_str().unwrap(), "-n", "500", "--seed", "3", "--thresholds", "100,500"];
+    let output = run(&args);
+    let summary: Summary = serde_json::from_reader(fs::File::open(files.join("summary.json")).unwrap()).unwrap();
+    let curve = fs::read_to_string(files.join("exceedance_curve.csv")).unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert!(summary.total_value.unwrap() < 0.);
+    assert!(summary.loss_rates.is_none());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("is not positive, loss rates are not reported"));
+    assert!(!String::from_utf8(output.stdout).unwrap().contains("Rate Mean"));
+    for row in curve.lines().skip(1) {
+        assert!(row.ends_with(','), "{}", row);
+    }
+}
@@ -0,0 +1,732 @@
+use credit_portfolio_model::borrower::Borrower;
+use credit_portfolio_model::exposure::Exposure;
+use credit_portfolio_model::generate::Generator;
+use credit_portfolio_model::migration::MigrationTracking;
+use credit_portfolio_model::portfolio::{condition_number, find_cycle, shrink_covariance, spectrum_diagnostics, Portfolio, PortfolioError, ShrinkageTarget};
+use credit_portfolio_model::risk_group::RiskGroup;
+use credit_portfolio_model::rng::{Sampler, Seeds};
+use credit_portfolio_model::simulation::{LossScalar, SimulationResult};
+use credit_portfolio_model::statistics;
+use ndarray::{array, s, Array1, Array2};
+use rand::SeedableRng;
+use rand_distr::{Distribution, StandardNormal};
+use rand_pcg::Pcg64;
+use statrs::distribution::{ContinuousCDF, Normal};
+
+#[test]
+fn loss_given_factors_reproduces_trial() {
+    let pf = Generator { borrowers: 50, risk_factors: 3, ..Default::default() }.input().into_portfolio();
+
+    let mut rng = Pcg64::seed_from_u64(7);
+    let mut replay = rng.clone();
+
+    for _ in 0..10 {
+        let loss = pf.trial(&mut rng, None);
+
+        let factors = pf.systematic_factors(&mut replay);
+        let external = pf.loss_given_factors(&factors, &mut replay);
+
+        assert_eq!(loss, external);
+    }
+}
+
+#[test]
+fn mean_scenario_loss_from_median_migration() {
+    let portfolio = |p_mig: Vec<f64>| {
+        let mut borr = Borrower::new("B0".to_string(), vec![1.0], 1, 0.2, 0.3, p_mig);
+        borr.add_exposure(Exposure::from_values(vec![102.0, 100.0, 95.0, 40.0], 100.0));
+        let mut rg = RiskGroup::new("G0".to_string());
+        rg.add_borrower(borr);
+        let mut pf = Portfolio::new(Array2::eye(1));
+        pf.add_risk_group(rg);
+        pf
+    };
+
+    // Median asset value in the current rating class
+    assert_eq!(portfolio(vec![0.1, 0.8, 0.07, 0.03]).mean_scenario_loss(), 0.0);
+    // Median asset value in the class below the current one
+    assert_eq!(portfolio(vec![0.1, 0.3, 0.57, 0.03]).mean_scenario_loss(), 5.0);
+}
+
+/// Retail-like pool of structurally identical borrowers of varying size in two risk groups
+fn retail_pool() -> Portfolio {
+    let mut pf = Portfolio::new(Array2::eye(2));
+    for group in 0..2 {
+        let mut rg = RiskGroup::new(format!("G{}", group));
+        for index in 0..100 {
+            let mut borr = Borrower::new(format!("B{}_{}", group, index), vec![1.0, 0.5], 1, 0.2, 0.1, vec![0.05, 0.9, 0.03, 0.02]);
+            let size = 1.0 + (index % 7) as f64;
+            borr.add_exposure(Exposure::from_values(vec![1.02 * size, size, 0.9 * size, 0.4 * size], size));
+            rg.add_borrower(borr);
+        }
+        pf.add_risk_group(rg);
+    }
+    pf
+}
+
+#[test]
+fn bucket_identical_approximation() {
+    let pf = retail_pool();
+    let mut bucketed = retail_pool();
+    assert_eq!(bucketed.bucket_identical(), 2);
+    assert_eq!(bucketed.num_borrower(), 2);
+    assert_eq!(bucketed.num_exposures(), pf.num_exposures());
+    assert!((bucketed.expected_loss() - pf.expected_loss()).abs() < 1e-9);
+
+    let exact = pf.simulate::<Pcg64>(20_000, 1_000, 1, MigrationTracking::None, &[]);
+    let approx = bucketed.simulate::<Pcg64>(20_000, 1_000, 1, MigrationTracking::None, &[]);
+
+    // Same expected loss within the Monte Carlo error, perfect correlation within the buckets
+    // fattens the tail
+    let std = approx.loss.iter().map(|l| (l - approx.mean()).powi(2)).sum::<f64>().sqrt() / approx.num_trials() as f64;
+    assert!((approx.mean() - exact.mean()).abs() < 5. * std, "{} vs {}", approx.mean(), exact.mean());
+    assert!(approx.var(0.99) > exact.var(0.99));
+}
+
+#[test]
+fn factor_slots_bit_identical_to_borrower_factors() {
+    let pf = Generator { borrowers: 300, risk_factors: 5, weight_classes: 7, ..Default::default() }.input().into_portfolio();
+    assert_eq!(pf.num_factor_slots(), 7);
+
+    let mut rng = Pcg64::seed_from_u64(3);
+    let mut replay = rng.clone();
+
+    for _ in 0..20 {
+        let loss = pf.trial(&mut rng, None);
+
+        // Ungrouped path, the systematic factor of every borrower from its own weights
+        let rf = pf.systematic_factors(&mut replay);
+        let mut normals = StandardNormal.sample_iter(&mut replay);
+        let mut expected: Vec<f64> = Vec::new();
+        for rg in pf.iter_risk_group() {
+            let e2: f64 = normals.next().unwrap();
+            for borr in rg.iter_borrower() {
+                let e1: f64 = normals.next().unwrap();
+                let z = borr.asset_value(&borr.risk_factor(&rf), &e1, &e2);
+                expected.push(*borr.get_loss(&borr.migration(&z)));
+            }
+        }
+
+        let bits = |values: &[f64]| values.iter().map(|v| v.to_bits()).collect::<Vec<u64>>();
+        assert_eq!(bits(loss.as_slice().unwrap()), bits(&expected));
+    }
+}
+
+/// Borrowers loading onto the first and last of three correlated risk factors
+fn sparse_loading() -> Portfolio {
+    let mut pf = Portfolio::new(array![[1.0, 0.5, 0.3], [0.5, 1.0, 0.4], [0.3, 0.4, 1.0]]);
+    let mut rg = RiskGroup::new("G0".to_string());
+    for (index, weights) in [vec![1.0, 0.0, 0.0], vec![0.0, 0.0, 1.0], vec![0.5, 0.0, 0.5]].into_iter().enumerate() {
+        let mut borr = Borrower::new(format!("B{}", index), weights, 1, 0.3, 0.1, vec![0.05, 0.9, 0.03, 0.02]);
+        borr.add_exposure(Exposure::from_values(vec![102.0, 100.0, 95.0, 40.0], 100.0));
+        rg.add_borrower(borr);
+    }
+    pf.add_risk_group(rg);
+    pf
+}
+
+#[test]
+fn unused_factors_dropped_from_simulation() {
+    let mut pf = sparse_loading();
+    assert_eq!(pf.drop_unused_factors(), 1);
+    assert_eq!(pf.drop_unused_factors(), 0);
+    assert_eq!((pf.num_risk_factors(), pf.num_simulated_factors()), (3, 2));
+
+    // Factors are given and returned for all risk factors, the dropped one is zero
+    let mut rng = Pcg64::seed_from_u64(5);
+    let mut replay = rng.clone();
+    for _ in 0..10 {
+        let loss = pf.trial(&mut rng, None);
+        let factors = pf.systematic_factors(&mut replay);
+        assert_eq!(factors[1], 0.);
+        assert_eq!(loss, pf.loss_given_factors(&factors, &mut replay));
+    }
+
+    // A scenario of the dropped factor still conditions the correlated simulated factors
+    pf.set_scenario(&[(1, -2.0)]);
+    let num_samples = 20_000;
+    let mean = (0..num_samples).map(|_| pf.systematic_factors(&mut rng)).fold(Array1::<f64>::zeros(3), |sum, f| sum + f) / num_samples as f64;
+    assert!((mean[0] + 1.0).abs() < 0.05, "{}", mean[0]);
+    assert!((mean[2] + 0.8).abs() < 0.05, "{}", mean[2]);
+
+    let borr = Borrower::new("B3".to_string(), vec![0.0, 1.0, 0.0], 1, 0.3, 0.1, vec![0.05, 0.9, 0.03, 0.02]);
+    assert_eq!(pf.try_add_borrower("G0", borr), Err(PortfolioError::DroppedRiskFactor(1)));
+}
+
+#[test]
+fn borrower_without_systematic_variance_is_rejected() {
+    let mut pf = sparse_loading();
+    let borr = Borrower::new("B3".to_string(), vec![0.0, 0.0, 0.0], 1, 0.3, 0.1, vec![0.05, 0.9, 0.03, 0.02]);
+    assert_eq!(pf.try_add_borrower("G0", borr), Err(PortfolioError::ZeroSystematicVariance("B3".to_string())));
+
+    // Negative loadings are valid
+    let borr = Borrower::new("B4".to_string(), vec![-1.0, 0.0, 0.5], 1, 0.3, 0.1, vec![0.05, 0.9, 0.03, 0.02]);
+    assert_eq!(pf.try_add_borrower("G0", borr), Ok(()));
+}
+
+#[test]
+#[should_panic(expected = "Borrower B0 has no systematic variance")]
+fn risk_group_with_zero_risk_factor_weights_fails() {
+    let mut rg = RiskGroup::new("G0".to_string());
+    rg.add_borrower(Borrower::new("B0".to_string(), vec![0.0, 0.0], 1, 0.3, 0.1, vec![0.05, 0.9, 0.03, 0.02]));
+    Portfolio::new(array![[1.0, 0.5], [0.5, 1.0]]).add_risk_group(rg);
+}
+
+/// Correlation matrix of two risk factors that is slightly indefinite due to rounding
+fn nearly_psd() -> Array2<f64> {
+    array![[1.0, 1.0 + 1e-12], [1.0 + 1e-12, 1.0]]
+}
+
+#[test]
+fn nearly_psd_covariance_decomposed_with_jitter() {
+    let diagnostics = spectrum_diagnostics(&nearly_psd());
+    assert!(diagnostics.starts_with("smallest eigenvalues [-"), "{}", diagnostics);
+    assert!(diagnostics.ends_with("condition number inf"), "{}", diagnostics);
+
+    let mut pf = Portfolio::with_psd_jitter(nearly_psd(), 1e-10);
+    assert_eq!(pf.jitter_applied(), Some(1e-10));
+    pf.set_scenario(&[(0, 1.0)]);
+    assert_eq!(pf.jitter_applied(), Some(1e-10));
+
+    let pf = Portfolio::with_psd_jitter(Array2::eye(2), 1e-10);
+    assert_eq!(pf.jitter_applied(), None);
+}
+
+#[test]
+#[should_panic(expected = "smallest eigenvalues")]
+fn nearly_psd_covariance_fails_without_jitter() {
+    Portfolio::new(nearly_psd());
+}
+
+#[test]
+fn variance_decomposition_weighted_by_outstanding() {
+    let mut rg = RiskGroup::new("G0".to_string());
+    for (index, (rho, eps, outstanding)) in [(0.4, 0.5, 300.0), (0.2, 0.0, 100.0)].into_iter().enumerate() {
+        let mut borr = Borrower::new(format!("B{}", index), vec![1.0], 0, rho, eps, vec![0.99, 0.01]);
+        borr.add_exposure(Exposure::from_values(vec![outstanding, 0.0], outstanding));
+        rg.add_borrower(borr);
+    }
+    let mut pf = Portfolio::new(Array2::eye(1));
+    pf.add_risk_group(rg);
+
+    let first = pf.iter_risk_group().next().unwrap().iter_borrower().next().unwrap().variance_decomposition();
+    assert!((first.systematic - 0.4).abs() < 1e-12);
+    assert!((first.group - 0.3).abs() < 1e-12);
+    assert!((first.idiosyncratic - 0.3).abs() < 1e-12);
+
+    let total = pf.variance_decomposition();
+    assert!((total.systematic - 0.35).abs() < 1e-12);
+    assert!((total.group - 0.225).abs() < 1e-12);
+    assert!((total.idiosyncratic - 0.425).abs() < 1e-12);
+}
+
+#[test]
+fn variance_decomposition_without_outstanding_weights_borrowers_equally() {
+    let mut rg = RiskGroup::new("G0".to_string());
+    for (index, (rho, eps)) in [(0.4, 0.5), (0.2, 0.0)].into_iter().enumerate() {
+        let mut borr = Borrower::new(format!("B{}", index), vec![1.0], 0, rho, eps, vec![0.99, 0.01]);
+        borr.add_exposure(Exposure::from_values(vec![100.0, 0.0], 0.0));
+        rg.add_borrower(borr);
+    }
+    let mut pf = Portfolio::new(Array2::eye(1));
+    pf.add_risk_group(rg);
+
+    let total = pf.variance_decomposition();
+    assert!((total.systematic - 0.3).abs() < 1e-12);
+    assert!((total.group - 0.15).abs() < 1e-12);
+    assert!((total.idiosyncratic - 0.55).abs() < 1e-12);
+}
+
+/// Obligor that always defaults with a half guaranteed exposure, and a guarantor that always
+/// stays in the given rating class
+fn guaranteed(guarantor_rating: usize) -> Portfolio {
+    let mut obligor = Borrower::new("O".to_string(), vec![1.0], 0, 0.2, 0.1, vec![0.0, 0.0, 1.0]);
+    let mut covered = Exposure::from_values(vec![100.0, 90.0, 40.0], 100.0);
+    covered.set_guarantee("G".to_string(), 0.5);
+    obligor.add_exposure(covered);
+    obligor.add_exposure(Exposure::from_values(vec![50.0, 45.0, 10.0], 50.0));
+
+    let mut p_mig = vec![0.0; 3];
+    p_mig[guarantor_rating] = 1.0;
+    let mut guarantor = Borrower::new("G".to_string(), vec![1.0], guarantor_rating, 0.2, 0.1, p_mig);
+    guarantor.add_exposure(Exposure::from_values(vec![10.0, 9.0, 4.0], 10.0));
+
+    let mut rg = RiskGroup::new("G0".to_string());
+    rg.add_borrower(obligor);
+    rg.add_borrower(guarantor);
+    let mut pf = Portfolio::new(Array2::eye(1));
+    pf.add_risk_group(rg);
+    pf
+}
+
+#[test]
+fn guarantee_values_covered_fraction_at_guarantor_rating() {
+    // Without a guarantee the obligor loses 60 + 40 on default, the covered half of the first
+    // exposure is valued at the guarantor's rating instead
+    let mut rng = Pcg64::seed_from_u64(3);
+    assert_eq!(guaranteed(0).trial(&mut rng, None).to_vec(), vec![30.0 + 40.0, 0.0]);
+    assert_eq!(guaranteed(1).trial(&mut rng, None).to_vec(), vec![35.0 + 40.0, 0.0]);
+    assert_eq!(guaranteed(2).trial(&mut rng, None).to_vec(), vec![60.0 + 40.0, 0.0]);
+
+    assert_eq!(find_cycle(&[("A", "B"), ("B", "C")]), None);
+    assert_eq!(find_cycle(&[("A", "B"), ("B", "C"), ("C", "A")]), Some(vec!["A".to_string(), "B".to_string(), "C".to_string(), "A".to_string()]));
+    assert_eq!(find_cycle(&[("A", "A")]), Some(vec!["A".to_string(), "A".to_string()]));
+}
+
+#[test]
+fn guarantors_are_not_bucketed_with_identical_borrowers() {
+    // A twin of the guarantor in its risk group would take it over as the first member of a bucket
+    let mut guarantor = Borrower::new("G".to_string(), vec![1.0], 0, 0.2, 0.1, vec![0.9, 0.05, 0.05]);
+    guarantor.add_exposure(Exposure::from_values(vec![10.0, 9.0, 4.0], 10.0));
+    let mut twin = Borrower::new("T".to_string(), vec![1.0], 0, 0.2, 0.1, vec![0.9, 0.05, 0.05]);
+    twin.add_exposure(Exposure::from_values(vec![20.0, 18.0, 8.0], 20.0));
+    let mut obligor = Borrower::new("O".to_string(), vec![1.0], 0, 0.2, 0.1, vec![0.8, 0.1, 0.1]);
+    let mut covered = Exposure::from_values(vec![100.0, 90.0, 40.0], 100.0);
+    covered.set_guarantee("G".to_string(), 0.5);
+    obligor.add_exposure(covered);
+
+    let mut guarantors = RiskGroup::new("G0".to_string());
+    guarantors.add_borrower(twin);
+    guarantors.add_borrower(guarantor);
+    let mut obligors = RiskGroup::new("G1".to_string());
+    obligors.add_borrower(obligor);
+    let mut pf = Portfolio::new(Array2::eye(1));
+    pf.add_risk_group(guarantors);
+    pf.add_risk_group(obligors);
+
+    let expected_loss = pf.expected_loss();
+    assert_eq!(pf.bucket_identical(), 3);
+    assert!((pf.expected_loss() - expected_loss).abs() < 1e-9);
+    let result = pf.simulate::<Pcg64>(1_000, 100, 1, MigrationTracking::None, &[]);
+    assert_eq!(result.num_trials(), 1_000);
+}
+
+/// Risk group of a parent with a loss of 100 and a member with a loss of 10 on default
+fn parent_and_member(notches: usize) -> Portfolio {
+    let mut parent = Borrower::new("P".to_string(), vec![1.0], 0, 0.2, 0.3, vec![0.95, 0.05]);
+    parent.add_exposure(Exposure::from_values(vec![100.0, 0.0], 100.0));
+    parent.set_parent();
+    let mut member = Borrower::new("M".to_string(), vec![1.0], 0, 0.2, 0.3, vec![0.95, 0.05]);
+    member.add_exposure(Exposure::from_values(vec![10.0, 0.0], 10.0));
+
+    let mut rg = RiskGroup::new("G0".to_string());
+    rg.add_borrower(member);
+    rg.add_borrower(parent);
+    let mut pf = Portfolio::new(Array2::eye(1));
+    pf.add_risk_group(rg);
+    pf.set_contagion_notches(notches);
+    pf.freeze();
+    pf
+}
+
+#[test]
+fn parent_default_drags_down_the_risk_group() {
+    let num_trials = 100_000;
+    // Default frequency of the member given the parent defaulted
+    let conditional = |notches: usize| -> f64 {
+        let pf = parent_and_member(notches);
+        let result = pf.simulate::<Pcg64>(num_trials, 10_000, 5, MigrationTracking::None, &[]);
+        let parent = result.loss.iter().filter(|&&l| l >= 100.).count();
+        let joint = result.loss.iter().filter(|&&l| l == 110.).count();
+        joint as f64 / parent as f64
+    };
+
+    assert!(!parent_and_member(0).has_contagion());
+    assert!(parent_and_member(1).has_contagion());
+    let (without, with) = (conditional(0), conditional(1));
+    assert!(without < 0.5, "{}", without);
+    assert_eq!(with, 1.);
+}
+
+#[test]
+#[should_panic(expected = "Risk group G0 has parent borrowers")]
+fn risk_group_allows_a_single_parent() {
+    let mut rg = RiskGroup::new("G0".to_string());
+    for id in ["P0", "P1"] {
+        let mut borr = Borrower::new(id.to_string(), vec![1.0], 0, 0.2, 0.3, vec![0.95, 0.05]);
+        borr.set_parent();
+        rg.add_borrower(borr);
+    }
+}
+
+#[test]
+fn adding_a_second_parent_is_an_error() {
+    let parent = |id: &str| {
+        let mut borr = Borrower::new(id.to_string(), vec![1.0], 0, 0.2, 0.3, vec![0.95, 0.05]);
+        borr.add_exposure(Exposure::from_values(vec![100.0, 40.0], 100.0));
+        borr.set_parent();
+        borr
+    };
+    let mut rg = RiskGroup::new("G0".to_string());
+    rg.add_borrower(parent("P0"));
+    let mut pf = Portfolio::new(Array2::eye(1));
+    pf.add_risk_group(rg);
+
+    let error = PortfolioError::MultipleParents { risk_group: "G0".to_string(), existing: "P0".to_string(), added: "P1".to_string() };
+    assert_eq!(pf.try_add_borrower("G0", parent("P1")), Err(error));
+    assert_eq!(pf.num_borrower(), 1);
+}
+
+#[test]
+fn total_value_and_outstanding_sum_the_borrowers() {
+    // The guarantor is valued at its current rating
+    let pf = guaranteed(1);
+    assert_eq!(pf.total_value(), 100.0 + 50.0 + 9.0);
+    assert_eq!(pf.total_outstanding(), 160.0);
+}
+
+/// Two borrowers in sibling risk groups nested under a common parent with the given weights,
+/// driven by their risk group only and defaulting with probability one half
+fn siblings(weights: Option<(f64, f64)>) -> Portfolio {
+    let leaf = |id: &str| {
+        let mut borr = Borrower::new(id.to_string(), vec![1.0], 0, 0.0, 1.0, vec![0.5, 0.5]);
+        borr.add_exposure(Exposure::from_values(vec![1.0, 0.0], 1.0));
+        let mut rg = RiskGroup::new(format!("G{}", id));
+        rg.add_borrower(borr);
+        rg
+    };
+
+    let mut pf = Portfolio::new(Array2::eye(1));
+    match weights {
+        Some((left, right)) => {
+            let mut parent = RiskGroup::new("P".to_string());
+            parent.add_child(leaf("A"), left);
+            parent.add_child(leaf("B"), right);
+            pf.add_risk_group(parent);
+        }
+        None => {
+            pf.add_risk_group(leaf("A"));
+            pf.add_risk_group(leaf("B"));
+        }
+    }
+    pf
+}
+
+#[test]
+fn nested_risk_groups_correlate_through_their_parent() {
+    let num_trials = 100_000;
+    // Frequency of joint defaults, for standard normal shocks with correlation r above their
+    // median it is 1/4 + asin(r) / (2π)
+    let joint = |pf: &Portfolio| -> f64 {
+        let result = pf.simulate::<Pcg64>(num_trials, 10_000, 11, MigrationTracking::None, &[]);
+        result.loss.iter().filter(|&&l| l == 2.).count() as f64 / num_trials as f64
+    };
+    let standard_error = (0.25_f64 * 0.75 / num_trials as f64).sqrt();
+
+    let flat = siblings(None);
+    assert_eq!(flat.iter_risk_group().count(), 2);
+    assert!((joint(&flat) - 0.25).abs() < 4. * standard_error);
+
+    let nested = siblings(Some((0.6, 0.8)));
+    assert_eq!(nested.iter_risk_group().count(), 3);
+    assert_eq!(nested.group_parent(2), Some(("P", 0.8)));
+    let expected = 0.25 + (0.6_f64 * 0.8).asin() / (2. * std::f64::consts::PI);
+    assert!((joint(&nested) - expected).abs() < 4. * standard_error, "{} vs {}", joint(&nested), expected);
+}
+
+#[test]
+fn shrinkage_blends_covariance_with_target() {
+    // Variances are kept, covariances move towards the target
+    let cov = array![[4.0, 1.2, 0.0], [1.2, 1.0, 0.3], [0.0, 0.3, 1.0]];
+    let identity = shrink_covariance(&cov, 0.25, ShrinkageTarget::Identity);
+    assert_eq!(identity.diag(), cov.diag());
+    assert!((identity[[0, 1]] - 0.9).abs() < 1e-12);
+    assert!((identity[[1, 2]] - 0.225).abs() < 1e-12);
+
+    // Correlations 0.6, 0 and 0.3 average to 0.3
+    let constant = shrink_covariance(&cov, 1.0, ShrinkageTarget::ConstantCorrelation);
+    assert!((constant[[0, 1]] - 0.3 * 2.0).abs() < 1e-12);
+    assert!((constant[[0, 2]] - 0.3 * 2.0).abs() < 1e-12);
+    assert!((constant[[1, 2]] - 0.3).abs() < 1e-12);
+    assert_eq!(shrink_covariance(&cov, 0.0, ShrinkageTarget::ConstantCorrelation), cov);
+
+    // An indefinite estimate becomes decomposable
+    let indefinite = array![[1.0, 0.9, 0.9], [0.9, 1.0, -0.9], [0.9, -0.9, 1.0]];
+    assert_eq!(condition_number(&indefinite), f64::INFINITY);
+    let shrunk = shrink_covariance(&indefinite, 0.6, ShrinkageTarget::Identity);
+    assert!(condition_number(&shrunk).is_finite());
+    Portfolio::new(shrunk);
+}
+
+#[test]
+fn pd_stress_scales_default_probability_by_current_rating() {
+    let mut pf = Generator { borrowers: 50, risk_factors: 3, ratings: 4, ..Default::default() }.input().into_portfolio();
+    let base: Vec<(usize, f64)> = pf.iter_risk_group().flat_map(|rg| rg.iter_borrower()).map(|borr| (borr.rating(), borr.default_probability())).collect();
+    let before = pf.average_default_probability();
+
+    // Ratings without a multiplier keep their probability of default
+    pf.stress_default_probabilities(&[2.0, 1.5]);
+    let stressed: Vec<f64> = pf.iter_risk_group().flat_map(|rg| rg.iter_borrower()).map(|borr| borr.default_probability()).collect();
+    for (&(rating, pd), stressed) in base.iter().zip(stressed.iter()) {
+        let multiplier = [2.0, 1.5, 1.0][rating];
+        assert!((stressed - pd * multiplier).abs() < 1e-12);
+    }
+    assert!(pf.average_default_probability() > before);
+    assert!((before - base.iter().map(|(_, pd)| pd).sum::<f64>() / 50.).abs() < 1e-12);
+}
+
+#[test]
+fn trial_ratings_are_behind_the_losses_of_the_trial() {
+    let pf = Generator { borrowers: 50, risk_factors: 3, ..Default::default() }.input().into_portfolio();
+    let borrowers: Vec<&Borrower> = pf.iter_risk_group().flat_map(|rg| rg.iter_borrower()).collect();
+
+    let mut rng = Pcg64::seed_from_u64(3);
+    let mut replay = rng.clone();
+    for _ in 0..10 {
+        let loss = pf.trial(&mut rng, None);
+        let ratings = pf.trial_ratings(&mut replay);
+        for ((borr, &rating), &loss) in borrowers.iter().zip(ratings.iter()).zip(loss.iter()) {
+            assert_eq!(*borr.get_loss(&rating), loss);
+        }
+    }
+
+    // Replayed ratings of the first trials of a simulation value to its losses
+    let result = pf.simulate::<Pcg64>(25, 10, 9, MigrationTracking::None, &[]);
+    let replayed = pf.replay_ratings::<Pcg64>(25, 10, 9);
+    assert_eq!(replayed.len(), 25);
+    for (ratings, &loss) in replayed.iter().zip(result.loss.iter()) {
+        let valued: f64 = borrowers.iter().zip(ratings.iter()).map(|(borr, rating)| *borr.get_loss(rating)).sum();
+        assert!((valued - loss).abs() < 1e-6 * loss.abs().max(1.));
+    }
+}
+
+#[test]
+fn replayed_factors_are_behind_the_losses_of_the_trials() {
+    let mut pf = sparse_loading();
+    pf.drop_unused_factors();
+    let result = pf.simulate::<Pcg64>(25, 10, 5, MigrationTracking::None, &[]);
+
+    let mut factors: Vec<Array1<f64>> = Vec::new();
+    pf.replay_factors::<Pcg64>(25, 10, 5, |first, round| {
+        assert_eq!(first, factors.len());
+        factors.extend_from_slice(round);
+    });
+    assert_eq!(factors.len(), 25);
+
+    // The factors with the remaining draws of the trial give its loss
+    for (chunk, (replayed, loss)) in factors.chunks(10).zip(result.loss.chunks(10)).enumerate() {
+        let (mut rng, _) = Seeds::Single(5).chunk_rng::<Pcg64>(chunk as u64);
+        for (expected, loss) in replayed.iter().zip(loss.iter()) {
+            let rf = pf.systematic_factors(&mut rng);
+            assert_eq!(&rf, expected);
+            assert_eq!(rf.len(), pf.num_risk_factors());
+            assert!((pf.loss_given_factors(&rf, &mut rng).sum() - loss).abs() < 1e-9);
+        }
+    }
+}
+
+#[test]
+fn given_factors_reproduce_the_trials_with_split_seeds() {
+    let pf = Generator { borrowers: 60, risk_factors: 4, ..Default::default() }.input().into_portfolio();
+    let seeds = Seeds::Split { systematic: 3, idiosyncratic: 8 };
+    let result = pf.simulate::<Pcg64>(45, 10, seeds, MigrationTracking::None, &[]);
+
+    let mut factors = Array2::<f64>::zeros((45, pf.num_risk_factors()));
+    pf.replay_factors::<Pcg64>(45, 10, seeds, |first, round| {
+        for (index, rf) in round.iter().enumerate() {
+            factors.row_mut(first + index).assign(rf);
+        }
+    });
+
+    // The idiosyncratic draws only depend on their own seed
+    let given = pf.simulate_factors::<Pcg64, f64>(&factors, 10, Seeds::Split { systematic: 0, idiosyncratic: 8 }, MigrationTracking::None, &[]);
+    assert_eq!(given.loss, result.loss);
+    assert_eq!(given.num_trials(), 45);
+}
+
+#[test]
+fn latin_hypercube_reduces_the_variance_of_the_mean_loss() {
+    // Many small borrowers with a high correlation, the loss is driven by the single factor
+    let mut pf = Portfolio::new(array![[1.0]]);
+    let mut rg = RiskGroup::new("G0".to_string());
+    for index in 0..200 {
+        let mut borr = Borrower::new(format!("B{}", index), vec![1.0], 0, 0.5, 0.0, vec![0.95, 0.05]);
+        borr.add_exposure(Exposure::from_values(vec![1.0, 0.4], 1.0));
+        rg.add_borrower(borr);
+    }
+    pf.add_risk_group(rg);
+
+    let variance = |pf: &Portfolio| {
+        let means: Vec<f64> = (0..40).map(|seed| pf.simulate::<Pcg64>(200, 100, seed, MigrationTracking::None, &[]).mean()).collect();
+        let mean = means.iter().sum::<f64>() / means.len() as f64;
+        means.iter().map(|m| (m - mean).powi(2)).sum::<f64>() / (means.len() - 1) as f64
+    };
+    let plain = variance(&pf);
+    pf.set_sampler(Sampler::LatinHypercube);
+    let stratified = variance(&pf);
+    assert!(stratified < 0.25 * plain, "{} vs {}", stratified, plain);
+
+    // Reproducible per seed, and the replays stratify like the simulation
+    let result = pf.simulate::<Pcg64>(250, 100, 3, MigrationTracking::None, &[]);
+    assert_eq!(result.loss, pf.simulate::<Pcg64>(250, 100, 3, MigrationTracking::None, &[]).loss);
+    let mut factors: Vec<Array1<f64>> = Vec::new();
+    pf.replay_factors::<Pcg64>(250, 100, 3, |_, round| factors.extend_from_slice(round));
+    let normal = Normal::new(0., 1.).unwrap();
+    let mut strata: Vec<usize> = factors[..100].iter().map(|rf| (normal.cdf(rf[0]) * 100.) as usize).collect();
+    strata.sort();
+    assert_eq!(strata, (0..100).collect::<Vec<usize>>());
+}
+
+#[test]
+fn conditional_losses_keep_the_mean_with_less_noise() {
+    let pf = Generator { borrowers: 200, risk_factors: 3, seed: 5, ..Default::default() }.input().into_portfolio();
+    let result = pf.simulate::<Pcg64>(20_000, 1_000, 9, MigrationTracking::None, &[]);
+    let conditional = pf.conditional_losses::<Pcg64>(20_000, 1_000, 9);
+    assert_eq!(conditional, pf.conditional_losses::<Pcg64>(20_000, 1_000, 9));
+
+    let variance = |loss: &[f64]| {
+        let mean = loss.iter().sum::<f64>() / loss.len() as f64;
+        (mean, loss.iter().map(|l| (l - mean).powi(2)).sum::<f64>() / (loss.len() - 1) as f64)
+    };
+    let (realized_mean, realized_variance) = variance(&result.loss);
+    let (conditional_mean, conditional_variance) = variance(&conditional);
+    assert!(conditional_variance < realized_variance);
+
+    // Both are unbiased, the realized losses vary around their conditional expectation
+    let standard_error = (realized_variance / result.loss.len() as f64).sqrt();
+    assert!((conditional_mean - pf.expected_loss()).abs() < 4. * (conditional_variance / conditional.len() as f64).sqrt());
+    assert!((conditional_mean - realized_mean).abs() < 4. * standard_error);
+}
+
+// Trials of the simulation of the tail replays, which replay them with the same number of trials,
+// chunk size and seed
+const NUM_TRIALS: usize = 5_000;
+const CHUNK_SIZE: usize = 500;
+const SEED: u64 = 4;
+
+/// Generated portfolio of the tail replays with the result of its simulation
+fn replayed_portfolio() -> (Portfolio, SimulationResult) {
+    let pf = Generator { borrowers: 100, risk_factors: 3, seed: 2, ..Default::default() }.input().into_portfolio();
+    let result = pf.simulate::<Pcg64>(NUM_TRIALS, CHUNK_SIZE, SEED, MigrationTracking::None, &[]);
+    (pf, result)
+}
+
+#[test]
+fn destination_contributions_add_up_to_the_mean_and_tail_loss() {
+    let (pf, result) = replayed_portfolio();
+    let threshold = statistics::quantile(&mut result.loss.clone(), 0.99);
+    let (mean, tail) = pf.destination_contributions::<Pcg64, f64>(NUM_TRIALS, CHUNK_SIZE, SEED, threshold);
+
+    assert_eq!(mean.len(), pf.num_ratings());
+    assert!((mean.sum() - result.mean()).abs() < 1e-6 * result.mean().abs());
+    let tail_trials: Vec<f64> = result.loss.iter().copied().filter(|&l| l >= threshold).collect();
+    let tail_mean = tail_trials.iter().sum::<f64>() / tail_trials.len() as f64;
+    assert!((tail.sum() - tail_mean).abs() < 1e-6 * tail_mean.abs());
+
+    // Defaults pile up in the tail
+    let default = pf.num_ratings() - 1;
+    assert!(tail[default] > 2. * mean[default]);
+}
+
+#[test]
+fn tail_default_probabilities_exceed_the_unconditional_ones() {
+    let (pf, result) = replayed_portfolio();
+
+    // Over all trials, the default frequencies estimate the probabilities of default
+    let all = pf.tail_default_probabilities::<Pcg64, f64>(NUM_TRIALS, CHUNK_SIZE, SEED, f64::NEG_INFINITY);
+    let average = all.mean().unwrap();
+    let standard_error = (average / (NUM_TRIALS * pf.num_borrower()) as f64).sqrt();
+    assert!((average - pf.average_default_probability()).abs() < 4. * standard_error, "{} vs {}", average, pf.average_default_probability());
+
+    let threshold = statistics::quantile(&mut result.loss.clone(), 0.99);
+    let tail = pf.tail_default_probabilities::<Pcg64, f64>(NUM_TRIALS, CHUNK_SIZE, SEED, threshold);
+    assert!(tail.mean().unwrap() > 2. * average);
+    assert!(tail.iter().all(|&p| (0. ..=1.).contains(&p)));
+}
+
+#[test]
+fn borrower_contributions_add_up_to_the_tail_and_the_risk_groups() {
+    let (pf, result) = replayed_portfolio();
+    let mut loss = result.loss.clone();
+    let threshold = statistics::quantile(&mut loss, 0.99);
+    let band = (statistics::quantile(&mut loss, 0.985), statistics::quantile(&mut loss, 0.995));
+    let (var, es) = pf.borrower_contributions::<Pcg64, f64>(NUM_TRIALS, CHUNK_SIZE, SEED, band, threshold);
+
+    // The expected shortfall contributions sum to the average tail loss
+    assert_eq!(es.len(), pf.num_borrower());
+    let tail_trials: Vec<f64> = result.loss.iter().copied().filter(|&l| l >= threshold).collect();
+    let tail_mean = tail_trials.iter().sum::<f64>() / tail_trials.len() as f64;
+    assert!((es.sum() - tail_mean).abs() < 1e-6 * tail_mean.abs());
+
+    // The value at risk contributions sum to the average loss of the band around it
+    let band_trials: Vec<f64> = result.loss.iter().copied().filter(|&l| (band.0..=band.1).contains(&l)).collect();
+    let band_mean = band_trials.iter().sum::<f64>() / band_trials.len() as f64;
+    assert!((var.sum() - band_mean).abs() < 1e-6 * band_mean.abs());
+    assert!(var.sum() <= es.sum());
+
+    // Summed by risk group, they are the contributions of the risk groups
+    let groups = pf.tail_contributions::<Pcg64, f64>(NUM_TRIALS, CHUNK_SIZE, SEED, threshold);
+    let mut start = 0;
+    for (rg, group) in pf.iter_risk_group().zip(groups.iter()) {
+        let sum: f64 = es.slice(s![start..start + rg.num_borrower()]).sum();
+        assert!((sum - group).abs() < 1e-9 * tail_mean.abs());
+        start += rg.num_borrower();
+    }
+}
+
+#[test]
+fn realized_value_is_the_current_value_less_the_loss() {
+    let pf = Generator { borrowers: 40, risk_factors: 2, ..Default::default() }.input().into_portfolio();
+
+    let result = pf.simulate::<Pcg64>(50, 20, 3, MigrationTracking::None, &[]);
+    let replayed = pf.replay_ratings::<Pcg64>(50, 20, 3);
+    let total = pf.total_value();
+    for (ratings, &loss) in replayed.iter().zip(result.loss.iter()) {
+        let value = pf.realized_value(ratings);
+        assert!((total - value - loss).abs() < 1e-6 * total.abs().max(1.));
+    }
+}
+
+/// Replay the tail of the trials with the losses stored with the precision `L`, the borrower
+/// losses of the replays are summed in double precision, hence the tolerance
+fn check_tail_replays_at_simulated_losses<L: LossScalar>(tolerance: f64) {
+    let pf = Generator { borrowers: 300, risk_factors: 3, seed: 5, ..Default::default() }.input().into_portfolio();
+    let result = pf.simulate_until::<Pcg64, L, _>(2_000, 250, 6, MigrationTracking::None, &[], usize::MAX, |_| false);
+    let loss: Vec<f64> = result.loss.iter().map(|l| l.to_f64()).collect();
+    let mut sorted = loss.clone();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let ratings = pf.replay_ratings::<Pcg64>(2_000, 250, 6);
+    let defaults: Vec<usize> = pf.iter_risk_group().flat_map(|rg| rg.iter_borrower()).map(|borr| borr.num_ratings() - 1).collect();
+
+    // Thresholds exactly at simulated losses, each trial at one of them belongs to the tail
+    for &threshold in sorted[sorted.len() - 200..].iter().step_by(10) {
+        let tail: Vec<f64> = loss.iter().copied().filter(|&l| l >= threshold).collect();
+        let tail_mean = tail.iter().sum::<f64>() / tail.len() as f64;
+        let groups = pf.tail_contributions::<Pcg64, L>(2_000, 250, 6, threshold);
+        assert!((groups.sum() - tail_mean).abs() < tolerance * tail_mean.abs(), "{} vs {} at {}", groups.sum(), tail_mean, threshold);
+        let (_, destinations) = pf.destination_contributions::<Pcg64, L>(2_000, 250, 6, threshold);
+        assert!((destinations.sum() - tail_mean).abs() < tolerance * tail_mean.abs());
+        let tail_defaults: usize = ratings.iter().zip(loss.iter()).filter(|(_, l)| **l >= threshold)
+            .map(|(trial, _)| trial.iter().zip(defaults.iter()).filter(|(r, d)| r == d).count())
+            .sum();
+        let tail_pds = pf.tail_default_probabilities::<Pcg64, L>(2_000, 250, 6, threshold);
+        assert!((tail_pds.sum() - tail_defaults as f64 / tail.len() as f64).abs() < 1e-9);
+        let (at_threshold, borrowers) = pf.borrower_contributions::<Pcg64, L>(2_000, 250, 6, (threshold, threshold), threshold);
+        assert!((at_threshold.sum() - threshold).abs() < tolerance * threshold.abs());
+        assert!((borrowers.sum() - tail_mean).abs() < tolerance * tail_mean.abs());
+    }
+}
+
+#[test]
+fn tail_replays_count_the_trials_at_the_threshold_like_the_simulation() {
+    check_tail_replays_at_simulated_losses::<f64>(1e-9);
+}
+
+#[test]
+fn tail_replays_count_the_trials_at_a_single_precision_threshold_like_the_simulation() {
+    check_tail_replays_at_simulated_losses::<f32>(1e-6);
+}
+
+#[test]
+fn tail_replays_without_trials_in_the_tail_are_zero() {
+    let (pf, result) = replayed_portfolio();
+    let threshold = result.loss.iter().copied().fold(f64::NEG_INFINITY, f64::max) + 1.;
+
+    assert!(pf.tail_contributions::<Pcg64, f64>(NUM_TRIALS, CHUNK_SIZE, SEED, threshold).iter().all(|&c| c == 0.));
+    assert!(pf.tail_default_probabilities::<Pcg64, f64>(NUM_TRIALS, CHUNK_SIZE, SEED, threshold).iter().all(|&p| p == 0.));
+    let (mean, tail) = pf.destination_contributions::<Pcg64, f64>(NUM_TRIALS, CHUNK_SIZE, SEED, threshold);
+    assert!((mean.sum() - result.mean()).abs() < 1e-6 * result.mean().abs());
+    assert!(tail.iter().all(|&c| c == 0.));
+    let (var, es) = pf.borrower_contributions::<Pcg64, f64>(NUM_TRIALS, CHUNK_SIZE, SEED, (threshold, threshold), threshold);
+    assert!(var.iter().chain(es.iter()).all(|&c| c == 0.));
+}
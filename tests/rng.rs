@@ -0,0 +1,161 @@
+use std::collections::HashSet;
+
+use credit_portfolio_model::generate::Generator;
+use credit_portfolio_model::migration::MigrationTracking;
+use credit_portfolio_model::rng::{chunk_stream, match_moments, Philox4x32, Seeds, SimulationRng};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_pcg::Pcg64;
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+#[test]
+fn philox_known_answers() {
+    // Known-answer vectors of the Random123 reference implementation
+    assert_eq!(Philox4x32::block([0, 0, 0, 0], [0, 0]), [0x6627e8d5, 0xe169c58d, 0xbc57ac4c, 0x9b00dbd8]);
+    assert_eq!(Philox4x32::block([0xffffffff; 4], [0xffffffff; 2]), [0x408f276d, 0x41c83b0e, 0xa20bc7c6, 0x6d5451fd]);
+    assert_eq!(
+        Philox4x32::block([0x243f6a88, 0x85a308d3, 0x13198a2e, 0x03707344], [0xa4093822, 0x299f31d0]),
+        [0xd16cfe09, 0x94fdcceb, 0x5001e420, 0x24126ea1]
+    );
+}
+
+#[test]
+fn philox_counter_jump() {
+    let mut rng = Philox4x32::new(7, 0);
+    let skipped: Vec<u32> = (0..12).map(|_| rng.next_u32()).collect();
+
+    let mut jumped = Philox4x32::new(7, 0);
+    jumped.set_counter(2);
+    assert_eq!(jumped.next_u32(), skipped[8]);
+}
+
+#[test]
+fn philox_streams_differ() {
+    let mut a = Philox4x32::from_stream(0, 1);
+    let mut b = Philox4x32::from_stream(0, 3);
+    assert_ne!(a.next_u64(), b.next_u64());
+}
+
+#[test]
+fn chunk_streams_are_distinct() {
+    let streams: HashSet<u128> = (0..10_000).map(|chunk| chunk_stream(42, chunk)).collect();
+    let upper: HashSet<u64> = streams.iter().map(|&s| (s >> 64) as u64).collect();
+    let lower: HashSet<u64> = streams.iter().map(|&s| s as u64).collect();
+
+    assert_eq!(streams.len(), 10_000);
+    assert_eq!(upper.len(), 10_000);
+    assert_eq!(lower.len(), 10_000);
+    assert_ne!(chunk_stream(42, 0), chunk_stream(43, 0));
+}
+
+/// Pearson correlation of two series of equal length
+fn correlation(a: &[f64], b: &[f64]) -> f64 {
+    let n = a.len() as f64;
+    let (mean_a, mean_b) = (a.iter().sum::<f64>() / n, b.iter().sum::<f64>() / n);
+    let cov: f64 = a.iter().zip(b).map(|(x, y)| (x - mean_a) * (y - mean_b)).sum();
+    let var_a: f64 = a.iter().map(|x| (x - mean_a).powi(2)).sum();
+    let var_b: f64 = b.iter().map(|y| (y - mean_b).powi(2)).sum();
+    cov / (var_a * var_b).sqrt()
+}
+
+fn assert_chunks_uncorrelated<R: SimulationRng>() {
+    const CHUNK: usize = 2_000;
+    let pf = Generator { borrowers: 50, risk_factors: 3, ..Default::default() }.input().into_portfolio();
+
+    let loss = pf.simulate::<R>(6 * CHUNK, CHUNK, 42, MigrationTracking::None, &[]).loss;
+    assert_eq!(loss, pf.simulate::<R>(6 * CHUNK, CHUNK, 42, MigrationTracking::None, &[]).loss);
+
+    // Independent series have a sample correlation of standard deviation 1 / sqrt(CHUNK)
+    let chunks: Vec<&[f64]> = loss.chunks(CHUNK).collect();
+    for i in 0..chunks.len() {
+        for j in i + 1..chunks.len() {
+            let rho = correlation(chunks[i], chunks[j]);
+            assert!(rho.abs() < 5. / (CHUNK as f64).sqrt(), "chunks {} and {} correlated: {}", i, j, rho);
+        }
+    }
+}
+
+#[test]
+fn chunk_losses_uncorrelated() {
+    assert_chunks_uncorrelated::<Pcg64>();
+    assert_chunks_uncorrelated::<ChaCha8Rng>();
+    assert_chunks_uncorrelated::<Philox4x32>();
+    assert_chunks_uncorrelated::<Xoshiro256PlusPlus>();
+}
+
+/// Portfolio of borrowers in two risk groups with the given dependency on the systematic factor
+fn portfolio_with_rho(rho: f64) -> credit_portfolio_model::portfolio::Portfolio {
+    use credit_portfolio_model::{borrower::Borrower, exposure::Exposure, portfolio::Portfolio, risk_group::RiskGroup};
+
+    let mut pf = Portfolio::new(ndarray::Array2::eye(2));
+    for group in 0..2 {
+        let mut rg = RiskGroup::new(format!("G{}", group));
+        for index in 0..10 {
+            let mut borr = Borrower::new(format!("B{}_{}", group, index), vec![1.0, 0.5], 1, rho, 0.3, vec![0.05, 0.85, 0.07, 0.03]);
+            borr.add_exposure(Exposure::from_values(vec![102.0, 100.0, 95.0, 40.0], 100.0));
+            rg.add_borrower(borr);
+        }
+        pf.add_risk_group(rg);
+    }
+    pf
+}
+
+#[test]
+fn split_seeds_vary_only_their_draws() {
+    let split = |systematic, idiosyncratic| Seeds::Split { systematic, idiosyncratic };
+
+    // Without systematic dependency only the idiosyncratic seed matters
+    let pf = portfolio_with_rho(0.0);
+    let loss = pf.simulate::<Pcg64>(3_000, 1_000, split(1, 7), MigrationTracking::None, &[]).loss;
+    assert_eq!(loss, pf.simulate::<Pcg64>(3_000, 1_000, split(2, 7), MigrationTracking::None, &[]).loss);
+    assert_ne!(loss, pf.simulate::<Pcg64>(3_000, 1_000, split(1, 8), MigrationTracking::None, &[]).loss);
+
+    // Without idiosyncratic risk only the systematic seed matters
+    let pf = portfolio_with_rho(1.0);
+    let loss = pf.simulate::<Pcg64>(3_000, 1_000, split(1, 7), MigrationTracking::None, &[]).loss;
+    assert_eq!(loss, pf.simulate::<Pcg64>(3_000, 1_000, split(1, 8), MigrationTracking::None, &[]).loss);
+    assert_ne!(loss, pf.simulate::<Pcg64>(3_000, 1_000, split(2, 7), MigrationTracking::None, &[]).loss);
+}
+
+#[test]
+fn split_seeds_independent_for_equal_seeds() {
+    let (mut systematic, idiosyncratic) = Seeds::Split { systematic: 5, idiosyncratic: 5 }.chunk_rng::<Pcg64>(0);
+    let mut idiosyncratic = idiosyncratic.unwrap();
+    assert_ne!(systematic.next_u64(), idiosyncratic.next_u64());
+
+    // A single seed keeps all draws of a trial in one generator
+    let (mut single, none) = Seeds::from(5).chunk_rng::<Pcg64>(0);
+    assert!(none.is_none());
+    assert_eq!(single.next_u64(), Pcg64::from_stream(5, chunk_stream(5, 0)).next_u64());
+}
+
+#[test]
+fn matched_moments_are_exact() {
+    use rand_distr::{Distribution, StandardNormal};
+
+    let mut rng = Pcg64::seed_from_u64(11);
+    let mut sample = ndarray::Array2::from_shape_fn((50, 3), |_| StandardNormal.sample(&mut rng));
+    match_moments(&mut sample);
+
+    let mean = sample.mean_axis(ndarray::Axis(0)).unwrap();
+    let cov = sample.t().dot(&sample) / 49.;
+    assert!(mean.iter().all(|m| m.abs() < 1e-12), "{}", mean);
+    assert!(cov.indexed_iter().all(|((i, j), c)| (c - if i == j { 1. } else { 0. }).abs() < 1e-12), "{}", cov);
+
+    // A sample of no more trials than dimensions is left unchanged
+    let short = ndarray::Array2::from_shape_fn((3, 3), |_| StandardNormal.sample(&mut rng));
+    let mut matched = short.clone();
+    match_moments(&mut matched);
+    assert_eq!(matched, short);
+
+    // The replayed factors of a chunk have the exact moments of the covariance matrix
+    let mut pf = portfolio_with_rho(0.3);
+    pf.set_moment_matching(true);
+    let mut factors = ndarray::Array2::<f64>::zeros((40, 2));
+    pf.replay_factors::<Pcg64>(40, 40, 5, |first, round| {
+        round.iter().enumerate().for_each(|(index, rf)| factors.row_mut(first + index).assign(rf));
+    });
+    let mean = factors.mean_axis(ndarray::Axis(0)).unwrap();
+    let cov = factors.t().dot(&factors) / 39.;
+    assert!(mean.iter().all(|m| m.abs() < 1e-12) && (cov - ndarray::Array2::<f64>::eye(2)).iter().all(|c| c.abs() < 1e-12));
+}
@@ -0,0 +1,80 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use credit_portfolio_model::generate::Generator;
+use credit_portfolio_model::shard::Shard;
+use credit_portfolio_model::summary::Summary;
+
+/// Fresh empty directory below a common test directory
+fn dir(root: &Path, name: &str) -> PathBuf {
+    let dir = root.join(name);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Run the binary with the arguments and assert it succeeds
+fn run(args: &[&str]) {
+    let status = Command::new(env!("CARGO_BIN_EXE_credit_portfolio_model")).args(args).status().unwrap();
+    assert!(status.success(), "Run with {:?} failed", args);
+}
+
+/// Sorted losses of a loss distribution file
+fn sorted_losses(path: &Path) -> Vec<f64> {
+    let mut loss: Vec<f64> = csv::Reader::from_path(path).unwrap().records()
+        .map(|record| record.unwrap()[0].parse().unwrap())
+        .collect();
+    loss.sort_by(f64::total_cmp);
+    loss
+}
+
+fn summary(path: &Path) -> Summary {
+    serde_json::from_reader(fs::File::open(path).unwrap()).unwrap()
+}
+
+#[test]
+fn shards_cover_all_chunks_once() {
+    for num_chunks in [0, 1, 2, 7, 20] {
+        let shards: Vec<Shard> = (0..3).map(|index| Shard { index, count: 3 }).collect();
+        let chunks: Vec<usize> = shards.iter().flat_map(|shard| shard.chunks(num_chunks)).collect();
+        assert_eq!(chunks, (0..num_chunks).collect::<Vec<usize>>());
+    }
+    assert!("3/3".parse::<Shard>().is_err());
+    assert_eq!("2/3".parse::<Shard>(), Ok(Shard { index: 2, count: 3 }));
+}
+
+#[test]
+fn merged_shards_equal_single_run() {
+    let root = std::env::temp_dir().join(format!("cpm_shard_{}", std::process::id()));
+    let input = dir(&root, "input");
+    let single = dir(&root, "single");
+    let shards = dir(&root, "shards");
+    let merged = dir(&root, "merged");
+    Generator { borrowers: 60, risk_factors: 3, ..Default::default() }.input().write(&input);
+
+    let common = ["-i", input.to_str().unwrap(), "-n", "2500", "-c", "300", "--seed", "11"];
+    run(&[&common[..], &["-o", single.to_str().unwrap()]].concat());
+    for index in 0..3 {
+        let shard = format!("{}/3", index);
+        run(&[&common[..], &["-o", shards.to_str().unwrap(), "--shard", &shard]].concat());
+    }
+    run(&["merge", "-i", shards.to_str().unwrap(), "-o", merged.to_str().unwrap()]);
+
+    let single_loss = sorted_losses(&single.join("loss_distribution.csv"));
+    let merged_loss = sorted_losses(&merged.join("loss_distribution.csv"));
+    let single_summary = summary(&single.join("summary.json"));
+    let merged_summary = summary(&merged.join("summary.json"));
+    let borrowers = csv::Reader::from_path(merged.join("expected_loss_by_borrower.csv")).unwrap().records().count();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(merged_loss.len(), 2500);
+    assert_eq!(merged_loss, single_loss);
+    assert_eq!(borrowers, 60);
+    assert_eq!(merged_summary.num_trials, single_summary.num_trials);
+    assert_eq!(merged_summary.median, single_summary.median);
+    for (merged, single) in merged_summary.quantiles.iter().zip(single_summary.quantiles.iter()) {
+        assert_eq!((merged.level, merged.var, merged.es), (single.level, single.var, single.es));
+    }
+    assert!((merged_summary.mean - single_summary.mean).abs() <= 1e-9 * single_summary.mean.abs());
+    assert!((merged_summary.expected_loss_simulated - single_summary.expected_loss_simulated).abs() <= 1e-9 * single_summary.expected_loss_simulated.abs());
+}
@@ -0,0 +1,9 @@
+use credit_portfolio_model::simulation::allocate_trials;
+
+#[test]
+fn trials_are_allocated_by_largest_remainder() {
+    assert_eq!(allocate_trials(&[0.6, 0.3, 0.1], 1_000), [600, 300, 100]);
+    assert_eq!(allocate_trials(&[1., 1., 1.], 10), [4, 3, 3]);
+    assert_eq!(allocate_trials(&[0.5, 0.25, 0.25], 7), [3, 2, 2]);
+    assert_eq!(allocate_trials(&[0., 2.], 5), [0, 5]);
+}
@@ -0,0 +1,154 @@
+use credit_portfolio_model::statistics::{self, CompensatedSum};
+use statrs::statistics::{Data, OrderStatistics};
+
+#[test]
+fn compensated_sum_keeps_small_values() {
+    let mut values = vec![1e16];
+    values.extend(std::iter::repeat_n(1.0, 1_000));
+    values.push(-1e16);
+
+    let naive: f64 = values.iter().sum();
+    let compensated = values.iter().copied().sum::<CompensatedSum>().value();
+
+    assert_eq!(naive, 0.0);
+    assert_eq!(compensated, 1_000.0);
+}
+
+#[test]
+fn compensated_sum_alternating_magnitudes() {
+    let values: Vec<f64> = (0..1_000).flat_map(|_| [1e16, 1.0, -1e16, 1.0]).collect();
+
+    let naive: f64 = values.iter().sum();
+    let compensated = values.iter().copied().sum::<CompensatedSum>().value();
+
+    assert_ne!(naive, 2_000.0);
+    assert_eq!(compensated, 2_000.0);
+}
+
+#[test]
+fn compensated_sum_merge() {
+    let mut a: CompensatedSum = [1e16, 1.0, 1.0].into_iter().sum();
+    let b: CompensatedSum = [1.0, -1e16].into_iter().sum();
+    a.merge(&b);
+
+    assert_eq!(a.value(), 3.0);
+}
+
+#[test]
+fn quantile_matches_statrs_in_both_precisions() {
+    let values: Vec<f64> = (0..1_001).map(|i| ((i * 7919) % 1_001) as f64 * 0.37).collect();
+
+    for level in [0., 0.001, 0.5, 0.9, 0.999, 1.] {
+        let expected = Data::new(values.clone()).quantile(level);
+        assert_eq!(statistics::quantile(&mut values.clone(), level), expected);
+
+        let mut single: Vec<f32> = values.iter().map(|&v| v as f32).collect();
+        assert!((statistics::quantile(&mut single, level) - expected).abs() < 1e-3);
+    }
+}
+
+#[test]
+fn exceedance_probability_from_losses_and_histogram() {
+    use credit_portfolio_model::portfolio::Portfolio;
+
+    let loss: Vec<f64> = (0..1_000).map(|i| ((i * 7919) % 1_000) as f64 + 0.5).collect();
+    let mut histogram = statistics::Histogram::equidistant(0., 1_000., 20);
+    loss.iter().for_each(|&l| histogram.add(l));
+
+    for threshold in [-1., 0., 100., 250., 999., 1_000., 2_000.] {
+        let expected = loss.iter().filter(|&&l| l > threshold).count() as f64 / 1_000.;
+        assert_eq!(Portfolio::exceedance_probability(&loss, threshold), expected);
+        assert!((histogram.exceedance_probability(threshold) - expected).abs() < 1e-12, "Threshold {}", threshold);
+    }
+
+    let single: Vec<f32> = loss.iter().map(|&l| l as f32).collect();
+    assert_eq!(Portfolio::exceedance_probability(&single, 250.), 0.75);
+
+    // Nothing exceeds a threshold without losses
+    assert_eq!(Portfolio::exceedance_probability::<f64>(&[], 0.), 0.);
+    assert_eq!(statistics::Histogram::equidistant(0., 1_000., 20).exceedance_probability(0.), 0.);
+}
+
+#[test]
+fn histogram_counts_nan_in_no_bin() {
+    let mut histogram = statistics::Histogram::equidistant(0., 10., 5);
+    for value in [1., f64::NAN, 11., f64::INFINITY, f64::NEG_INFINITY] {
+        histogram.add(value);
+    }
+
+    assert_eq!((histogram.nan_count(), histogram.total()), (1, 4));
+    let bins = histogram.bins();
+    assert_eq!((bins[0].2, bins[1].2, bins[bins.len() - 1].2), (1, 1, 2));
+    assert_eq!(histogram.exceedance_probability(10.), 0.5);
+}
+
+#[test]
+fn weighted_quantile_with_equal_weights_matches_quantile() {
+    let values: Vec<f64> = (0..1_000).map(|i| ((i * 7919) % 1_000) as f64 * 0.37).collect();
+
+    for weight in [1., 2.5] {
+        let weights = vec![weight; values.len()];
+        for level in [0., 0.001, 0.5, 0.9, 0.999, 1.] {
+            let expected = statistics::quantile(&mut values.clone(), level);
+            assert!((statistics::weighted_quantile(&values, &weights, level) - expected).abs() < 1e-9, "{} at {}", weight, level);
+        }
+
+        let weighted = statistics::weighted_risk_measures(&values, &weights, &[0.9, 0.99]);
+        let unweighted = statistics::risk_measures(&mut values.clone(), &[0.9, 0.99]);
+        for (w, u) in weighted.iter().zip(unweighted.iter()) {
+            assert!((w.var - u.var).abs() < 1e-9 && (w.es - u.es).abs() < 1e-9);
+        }
+
+        let mut moments = statistics::Moments::default();
+        values.iter().for_each(|&v| moments.add(v));
+        let weighted = statistics::Moments::weighted(&values, &weights);
+        assert!((weighted.mean() - moments.mean()).abs() < 1e-9);
+        assert!((weighted.variance() - moments.variance()).abs() < 1e-6 && (weighted.skewness() - moments.skewness()).abs() < 1e-9);
+    }
+}
+
+#[test]
+fn weighted_quantile_follows_the_weights() {
+    // Losses with zero weight are ignored
+    let loss = [1., 2., 3., 100., 4.];
+    assert_eq!(statistics::weighted_quantile(&loss, &[1., 1., 1., 0., 1.], 1.), 4.);
+    assert_eq!(statistics::weighted_quantile(&loss, &[1., 1., 1., 0., 1.], 0.5), statistics::quantile(&mut [1., 2., 3., 4.], 0.5));
+
+    // A heavy loss takes up most of the distribution
+    let weights = [1., 1., 1., 97., 1.];
+    assert_eq!(statistics::weighted_quantile(&loss, &weights, 0.9), 100.);
+    assert!(statistics::weighted_quantile(&loss, &weights, 0.5) > 90.);
+    let es = statistics::weighted_expected_shortfall(&loss, &weights, 3.);
+    assert!((es - (3. + 97. * 100. + 4.) / 99.).abs() < 1e-12);
+}
+
+#[test]
+fn order_statistics_are_evenly_spaced() {
+    let sorted: Vec<f64> = (0..101).map(|i| i as f64).collect();
+    assert_eq!(statistics::order_statistics(&sorted, 5), [0., 25., 50., 75., 100.]);
+    assert_eq!(statistics::order_statistics(&sorted, 1), [50.]);
+    assert_eq!(statistics::order_statistics(&sorted, 200), sorted);
+
+    // The sample keeps the extremes and the median of the distribution
+    let mut sample = statistics::order_statistics(&sorted, 11);
+    assert_eq!((sample[0], sample[10]), (0., 100.));
+    assert_eq!(statistics::median(&mut sample), statistics::median(&mut sorted.clone()));
+}
+
+#[test]
+fn distances_between_loss_distributions() {
+    let sorted: Vec<f64> = (0..100).map(|i| i as f64).collect();
+    assert_eq!(statistics::ks_statistic(&sorted, &sorted), 0.);
+    assert_eq!(statistics::wasserstein_distance(&sorted, &sorted), 0.);
+
+    // A shift moves every quantile by its amount
+    let shifted: Vec<f64> = sorted.iter().map(|l| l + 2.5).collect();
+    assert!((statistics::wasserstein_distance(&sorted, &shifted) - 2.5).abs() < 1e-12);
+    assert!((statistics::ks_statistic(&sorted, &shifted) - 0.03).abs() < 1e-12);
+
+    // Samples of different size, the distribution functions differ by 1/6 on [0, 1) and by 1/3
+    // on [1, 3)
+    let (a, b) = ([0., 0., 1., 1.], [0., 1., 3.]);
+    assert!((statistics::ks_statistic(&a, &b) - 1. / 3.).abs() < 1e-12);
+    assert!((statistics::wasserstein_distance(&a, &b) - (1. / 6. + 2. / 3.)).abs() < 1e-12);
+}
@@ -0,0 +1,74 @@
+use credit_portfolio_model::input::ScenarioFactor;
+use credit_portfolio_model::summary::{Durations, ExceedanceSummary, PortfolioSize, QuantileSummary, Summary};
+
+#[test]
+fn summary_schema_is_stable() {
+    let json = r#"{
+        "version": "0.1.1",
+        "input": "data/input",
+        "seed": 42,
+        "num_trials": 100000,
+        "chunk_size": 10000,
+        "durations": { "initialization": 1.5, "simulation": 12.25, "output": 0.5 },
+        "portfolio": { "borrowers": 20, "exposures": 40, "risk_factors": 3, "rating_classes": 4 },
+        "expected_loss": 591.48,
+        "expected_loss_simulated": 594.52,
+        "mean": 594.52,
+        "median": 288.96,
+        "quantiles": [
+            { "level": 0.99, "var": 3821.76, "es": 4512.0 },
+            { "level": 0.999, "var": 5698.96, "es": 6210.5 }
+        ]
+    }"#;
+
+    let summary: Summary = serde_json::from_str(json).unwrap();
+
+    assert_eq!(summary.seed, 42);
+    assert_eq!(summary.num_trials, 100_000);
+    assert_eq!(summary.durations.simulation, 12.25);
+    assert_eq!(summary.portfolio.rating_classes, 4);
+    assert_eq!(summary.quantiles.len(), 2);
+    assert_eq!(summary.quantiles[1].level, 0.999);
+    assert_eq!(summary.quantiles[1].es, 6210.5);
+}
+
+#[test]
+fn summary_roundtrip() {
+    let summary = Summary {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        input: "input".to_string(),
+        seed: 0,
+        systematic_seed: Some(1),
+        idiosyncratic_seed: None,
+        num_trials: 10,
+        chunk_size: 5,
+        durations: Durations { initialization: 0.1, reading: Some(0.05), cholesky: Some(0.01), norms: None, simulation: 0.2, statistics: Some(0.02), output: 0.3 },
+        portfolio: PortfolioSize { borrowers: 1, exposures: 2, risk_factors: 1, rating_classes: 3 },
+        expected_loss: 1.0,
+        expected_loss_simulated: 1.1,
+        mean_scenario_loss: Some(0.0),
+        total_value: Some(250.0),
+        total_outstanding: Some(240.0),
+        mean: 1.1,
+        median: 0.0,
+        mean_standard_error: Some(0.1),
+        confidence: Some(0.95),
+        skewness: Some(2.5),
+        excess_kurtosis: Some(9.0),
+        quantiles: vec![QuantileSummary { level: 0.9, var: 2.0, es: 3.0, var_standard_error: Some(0.2), es_standard_error: None }],
+        exceedance: Some(vec![ExceedanceSummary { threshold: 1e8, probability: 0.001 }]),
+        average_rating: Some(1.5),
+        expected_average_rating: None,
+        scenario: Some(vec![ScenarioFactor { risk_factor: 2, value: -3.0 }]),
+        macro_scenarios: None,
+        conditional_loss: None,
+        base_currency: Some("EUR".to_string()),
+        value: None,
+        loss_rates: None,
+    };
+
+    let json = serde_json::to_string(&summary).unwrap();
+    let parsed: Summary = serde_json::from_str(&json).unwrap();
+
+    assert_eq!(parsed, summary);
+}